@@ -0,0 +1,89 @@
+/// Throughput of parsing and applying synthetic transaction logs (see `synthetic`).
+///
+/// Run with `cargo bench`. Each benchmark handles the same log of `ROWS` rows, so results are
+/// comparable between the parsing and processing paths.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use csv::{ReaderBuilder, Trim};
+
+use payment_engine::input::{csv_transactions, fast_csv_transactions};
+use payment_engine::synthetic::Generator;
+use payment_engine::{process_parallel, process_transactions, Engine, Transaction};
+
+/// Rows in the benchmarked log.
+const ROWS: u64 = 100_000;
+
+fn synthetic_log() -> Vec<u8> {
+    let mut log = Vec::new();
+    Generator {
+        rows: ROWS,
+        ..Default::default()
+    }
+    .write_csv(&mut log)
+    .unwrap();
+
+    log
+}
+
+/// A reader configured the same way as `open_transactions`.
+fn reader(log: &[u8]) -> csv::Reader<&[u8]> {
+    ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(log)
+}
+
+fn benchmarks(c: &mut Criterion) {
+    let log = synthetic_log();
+    let transactions: Vec<Transaction> = csv_transactions(reader(&log))
+        .collect::<Result<_, _>>()
+        .unwrap();
+
+    let mut group = c.benchmark_group("synthetic");
+    group.throughput(Throughput::Elements(ROWS));
+
+    group.bench_function("parse", |b| {
+        b.iter(|| csv_transactions(reader(&log)).filter(Result::is_ok).count())
+    });
+    group.bench_function("fast_parse", |b| {
+        b.iter(|| {
+            fast_csv_transactions(reader(&log))
+                .filter(Result::is_ok)
+                .count()
+        })
+    });
+    group.bench_function("apply", |b| {
+        b.iter(|| {
+            let mut engine = Engine::new();
+            for tx in &transactions {
+                let _ = engine.apply(tx);
+            }
+            engine.into_client_states()
+        })
+    });
+    group.bench_function("process", |b| {
+        b.iter(|| {
+            process_transactions(
+                Engine::new(),
+                fast_csv_transactions(reader(&log)),
+                |_, _| Ok(()),
+            )
+            .unwrap()
+        })
+    });
+    group.bench_function("process_parallel", |b| {
+        b.iter(|| {
+            process_parallel(
+                Engine::new,
+                4,
+                fast_csv_transactions(reader(&log)),
+                |_, _| Ok(()),
+            )
+            .unwrap()
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
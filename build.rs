@@ -0,0 +1,20 @@
+/// Generates the C header for the `ffi` module when the `ffi` feature is enabled, so it can't drift
+/// from the functions it declares. The header is written to `OUT_DIR`, and a test checks the copy
+/// committed to `include/` matches it. Other builds don't need it, and skip cbindgen entirely.
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let out_dir = std::env::var("OUT_DIR").unwrap();
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir))
+            .expect("cbindgen.toml is invalid");
+        cbindgen::Builder::new()
+            .with_crate(&crate_dir)
+            .with_config(config)
+            .generate()
+            .expect("couldn't generate the C header")
+            .write_to_file(format!("{}/payment_engine.h", out_dir));
+    }
+}
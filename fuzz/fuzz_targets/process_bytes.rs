@@ -0,0 +1,8 @@
+#![no_main]
+/// Fuzz target applying arbitrary bytes as a CSV transaction log. Any panic is a bug: malformed
+/// input should only ever be an error.
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = payment_engine::process_bytes(data);
+});
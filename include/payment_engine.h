@@ -0,0 +1,80 @@
+/* C API for payment-engine. Generated by cbindgen from src/ffi.rs, don't edit by hand. */
+
+#ifndef PAYMENT_ENGINE_H
+#define PAYMENT_ENGINE_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The outcome of applying a row.
+typedef enum EngineStatus {
+  // The transaction was applied.
+  ENGINE_STATUS_OK = 0,
+  // The transaction was well-formed, but had no effect (e.g. insufficient funds).
+  ENGINE_STATUS_REJECTED = 1,
+  // The row couldn't be parsed as a single transaction, or wasn't UTF-8.
+  ENGINE_STATUS_INVALID_INPUT = 2,
+  // The engine can't go on (e.g. a transaction broke a balance invariant). It keeps the state it
+  // had, and can still be exported.
+  ENGINE_STATUS_FATAL = 3,
+  // A pointer argument was null.
+  ENGINE_STATUS_NULL_POINTER = 4,
+} EngineStatus;
+
+// An engine owned by the host, which only sees it through a pointer.
+typedef struct PaymentEngine PaymentEngine;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Create an engine with the default policies. It must be destroyed with `engine_free`.
+struct PaymentEngine *engine_new(void);
+
+// Apply a single CSV row (without a trailing newline) to `engine`. Unless the transaction was
+// applied, the reason is kept for `engine_last_error`.
+//
+// # Safety
+//
+// `engine` must come from `engine_new` and not have been freed, and `line` must be a
+// NUL-terminated string. Neither is retained after the call.
+enum EngineStatus engine_apply_csv_line(struct PaymentEngine *engine, const char *line);
+
+// Why the last row given to `engine` wasn't applied, or null if it was. The string belongs to the
+// engine, and is only valid until the next call to `engine_apply_csv_line` or `engine_free`.
+//
+// # Safety
+//
+// `engine` must come from `engine_new` and not have been freed.
+const char *engine_last_error(const struct PaymentEngine *engine);
+
+// Every client's balances as a JSON array (like `--output-format json`), in ascending order of
+// client ID, or null if they can't be exported. The string must be freed with
+// `engine_free_string`.
+//
+// # Safety
+//
+// `engine` must come from `engine_new` and not have been freed.
+char *engine_export_balances_json(const struct PaymentEngine *engine);
+
+// Free a string returned by `engine_export_balances_json`. Null is ignored.
+//
+// # Safety
+//
+// `string` must come from `engine_export_balances_json`, and not have been freed already.
+void engine_free_string(char *string);
+
+// Destroy an engine. Null is ignored.
+//
+// # Safety
+//
+// `engine` must come from `engine_new`, and not have been freed already.
+void engine_free(struct PaymentEngine *engine);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* PAYMENT_ENGINE_H */
@@ -0,0 +1,1359 @@
+/// Command line option handling, with clap.
+///
+/// ```text
+/// payment-engine [process] [<path>...] [options]   apply transactions and export balances
+/// payment-engine validate [<path>...] [options]    check every row, only reporting problems
+/// payment-engine verify-digest <digest> [...]      check outputs against an audit digest
+/// payment-engine verify-proof <root> <proof>       check a client's balance proof against a root
+/// payment-engine serve --listen <addr> [...]       apply transactions sent over TCP (see `server`)
+/// payment-engine serve-http --listen <addr> [...]  HTTP API (`http` feature, see `http`)
+/// payment-engine gen [--rows <n>] [...]            write a synthetic transaction log to stdout
+/// payment-engine compact [<path>...] -o <output>   rewrite a transaction log as a minimal equivalent
+/// payment-engine history <client> [<path>...]      list the transactions applied to a client
+/// payment-engine follow --leader <addr> [...]      serve reads replicated from a leader (see
+///                                                  `replication`)
+/// payment-engine consume --brokers <hosts> [...]   apply transactions from a Kafka topic (`kafka`
+///                                                  feature, see `kafka`)
+/// ```
+///
+/// Without a subcommand, arguments are for `process`. A transaction log which happens to be named
+/// after a subcommand needs a path prefix (e.g. `./validate`). `--help` lists every subcommand's
+/// options.
+///
+/// Options are grouped by what they're for (reading logs, configuring the engine, writing
+/// outputs), and each subcommand only accepts the groups it uses. Options which can't be combined
+/// are declared as clap conflicts, and the few checks which depend on values (e.g. reading stdin
+/// twice) are made once the options are parsed, and reported as clap errors too.
+///
+/// `--log-level` and `--log-format` (see `LogOptions`) are accepted anywhere, by every subcommand.
+/// Runs only log errors unless they're asked for more, except for `validate`, which reports
+/// rejected transactions (warnings) by default, as that's what it's for.
+use clap::error::ErrorKind;
+use clap::{ArgGroup, Args, CommandFactory, Parser, Subcommand};
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::clock::ClockKind;
+use crate::config::ConfigFile;
+use crate::input::{ClientFilter, InputFormat, Sample, Schema, STDIN_PATH};
+use crate::invariants::InvariantCheck;
+use crate::journal::JournalSync;
+use crate::limits;
+use crate::logging::{self, LogFormat};
+use crate::money::{Money, Precision, Rounding};
+use crate::output::{parse_delimiter, parse_quote_style, OutputDialect, OutputFormat, SortBy};
+use crate::store::parse_size;
+use crate::synthetic::{parse_rate, Generator};
+use crate::{
+    DisputeWindow, DuplicateTxPolicy, LockedAccountPolicy, RedisputePolicy, UnknownTypePolicy,
+    WithdrawalDisputePolicy,
+};
+
+/// What the program was asked to do.
+#[derive(Debug)]
+pub enum Command {
+    /// Apply transactions and export client balances.
+    Process(Options),
+    /// Apply transactions and report rejected transactions, without exporting balances.
+    Validate(Options),
+    /// Check archived outputs against an audit digest.
+    VerifyDigest(VerifyDigestOptions),
+    /// Check a single client's balance proof (a line of `proofs.ndjson`) against a Merkle root.
+    VerifyProof { root: String, proof_path: String },
+    /// Apply transactions sent over TCP connections.
+    Serve(ServeOptions),
+    /// Serve the HTTP API. Only available with the `http` feature.
+    ServeHttp(ServeOptions),
+    /// Write a synthetic transaction log.
+    Gen(Generator),
+    /// Follow a replicating leader, serving reads from the replicated states.
+    Follow(FollowOptions),
+    /// Rewrite a transaction log into a minimal one with the same outcome.
+    Compact(CompactOptions),
+    /// Report how a policy configuration handles the canonical scenarios.
+    PolicyReport(PolicyReportOptions),
+    /// Apply transactions and list those applied to one client.
+    History(HistoryOptions),
+    /// Apply transactions consumed from a Kafka topic. Only available with the `kafka` feature.
+    Consume(ConsumeOptions),
+}
+
+impl Command {
+    /// The subcommand's name, as it's given on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Process(_) => "process",
+            Command::Validate(_) => "validate",
+            Command::VerifyDigest(_) => "verify-digest",
+            Command::VerifyProof { .. } => "verify-proof",
+            Command::Serve(_) => "serve",
+            Command::ServeHttp(_) => "serve-http",
+            Command::Gen(_) => "gen",
+            Command::Follow(_) => "follow",
+            Command::Compact(_) => "compact",
+            Command::PolicyReport(_) => "policy-report",
+            Command::History(_) => "history",
+            Command::Consume(_) => "consume",
+        }
+    }
+
+    /// The level logged at unless `--log-level` says otherwise.
+    fn default_log_level(&self) -> LevelFilter {
+        match self {
+            Command::Validate(_) => LevelFilter::WARN,
+            _ => LevelFilter::ERROR,
+        }
+    }
+
+    /// Parse a command from command line arguments (excluding the program name).
+    pub fn from_args<I>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        parse(args).map(|(_, command)| command)
+    }
+}
+
+/// Parse the logging options and the command from command line arguments (excluding the program
+/// name).
+pub fn parse<I>(args: I) -> Result<(LogOptions, Command), clap::Error>
+where
+    I: IntoIterator<Item = String>,
+{
+    let args: Vec<String> = args.into_iter().collect();
+    let cli =
+        Cli::try_parse_from(std::iter::once(BIN_NAME.to_string()).chain(args.iter().cloned()))?;
+
+    let command = match cli.command {
+        None => Command::Process(cli.process.into_options()?),
+        Some(SubcommandArgs::Process(args)) => Command::Process(args.into_options()?),
+        Some(SubcommandArgs::Validate(args)) => Command::Validate(args.into_options()?),
+        Some(SubcommandArgs::VerifyDigest(options)) => Command::VerifyDigest(options),
+        Some(SubcommandArgs::VerifyProof { root, proof_path }) => {
+            Command::VerifyProof { root, proof_path }
+        }
+        Some(SubcommandArgs::Serve(args)) => {
+            if args.history {
+                return Err(error("--history is only used by serve-http"));
+            }
+            Command::Serve(args.into_options()?)
+        }
+        Some(SubcommandArgs::ServeHttp(args)) => {
+            if !cfg!(feature = "http") {
+                return Err(error("serve-http requires the http feature"));
+            }
+            Command::ServeHttp(args.into_options()?)
+        }
+        Some(SubcommandArgs::Gen(args)) => Command::Gen(args.into_generator()),
+        Some(SubcommandArgs::Follow(options)) => Command::Follow(options),
+        Some(SubcommandArgs::Compact(args)) => Command::Compact(args.into_options()?),
+        Some(SubcommandArgs::PolicyReport(report_args)) => {
+            let start = args
+                .iter()
+                .position(|arg| arg == "policy-report")
+                .map_or(0, |i| i + 1);
+            Command::PolicyReport(PolicyReportOptions {
+                arguments: subcommand_arguments(&args[start..]),
+                options: report_args.into_options()?,
+            })
+        }
+        Some(SubcommandArgs::History(args)) => Command::History(args.into_options()?),
+        Some(SubcommandArgs::Consume(args)) => {
+            if !cfg!(feature = "kafka") {
+                return Err(error("consume requires the kafka feature"));
+            }
+            Command::Consume(args.into_options()?)
+        }
+    };
+
+    let log = LogOptions {
+        level: cli.log.level.unwrap_or_else(|| command.default_log_level()),
+        format: cli.log.format,
+    };
+
+    Ok((log, command))
+}
+
+/// Name the program is invoked as, for help and errors.
+const BIN_NAME: &str = "payment-engine";
+
+#[derive(Debug, Parser)]
+#[command(
+    name = BIN_NAME,
+    about = "Applies a log of transactions to client accounts, and exports their balances",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(flatten)]
+    log: LogArgs,
+    #[command(subcommand)]
+    command: Option<SubcommandArgs>,
+    /// Arguments for `process`, which is the default.
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum SubcommandArgs {
+    /// Apply transactions and export client balances (the default)
+    Process(Box<ProcessArgs>),
+    /// Check every row, only reporting rejected transactions
+    Validate(Box<ValidateArgs>),
+    /// Check archived outputs against an audit digest
+    VerifyDigest(VerifyDigestOptions),
+    /// Check a client's balance proof against a Merkle root
+    VerifyProof {
+        /// Merkle root of client totals
+        root: String,
+        /// The client's proof (a line of `proofs.ndjson`)
+        proof_path: String,
+    },
+    /// Apply transactions sent over TCP connections
+    Serve(Box<ServeArgs>),
+    /// Serve the HTTP API (`http` feature)
+    ServeHttp(Box<ServeArgs>),
+    /// Write a synthetic transaction log to stdout
+    Gen(GenArgs),
+    /// Serve reads replicated from a leader
+    Follow(FollowOptions),
+    /// Rewrite a transaction log as a minimal equivalent
+    Compact(Box<CompactArgs>),
+    /// Report how a policy configuration handles the canonical scenarios
+    PolicyReport(Box<PolicyReportArgs>),
+    /// List the transactions applied to a client
+    History(Box<HistoryArgs>),
+    /// Apply transactions from a Kafka topic (`kafka` feature)
+    Consume(Box<ConsumeArgs>),
+}
+
+/// Options accepted by `process` (and, other than those writing outputs, `validate`). Transactions
+/// are read from stdin if the path is `-` or omitted. Further paths are read in sequence after the
+/// first (or merged with it by timestamp, with `--chronological`), and paths may be patterns, with
+/// `*` and `?` in their file name.
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Path to the transaction log (`None` or `-` for stdin).
+    pub csv_path: Option<String>,
+    /// Further transaction logs to read after the first, in order.
+    pub more_paths: Vec<String>,
+    /// Format of the transaction log.
+    pub input_format: InputFormat,
+    /// Which CSV columns hold which transaction fields, where they aren't named after them (or
+    /// aren't named at all, without a header), and how they're separated.
+    pub schema: Schema,
+    /// Further transaction logs to merge with the first by timestamp, if any.
+    pub merge_paths: Vec<String>,
+    /// How far behind the latest timestamp on its own log a merged transaction may arrive.
+    pub lateness: u64,
+    /// Require transactions to be in timestamp order (within `lateness`), and track when each
+    /// client was last active. Always set when logs are merged.
+    pub chronological: bool,
+    /// Number of transactions to buffer to put them in ID order, if any.
+    pub reorder_window: Option<usize>,
+    /// Format of the balance export.
+    pub output_format: OutputFormat,
+    /// CSV dialect used for the balance export.
+    pub output_dialect: OutputDialect,
+    /// Where to write the log of rejected transactions, if anywhere.
+    pub rejects_path: Option<String>,
+    /// How to handle deposits/withdrawals which reuse a transaction ID.
+    pub duplicate_tx_policy: DuplicateTxPolicy,
+    /// How to handle disputes against withdrawals.
+    pub withdrawal_dispute_policy: WithdrawalDisputePolicy,
+    /// Whether resolved transactions can be disputed again.
+    pub redispute_policy: RedisputePolicy,
+    /// How to handle transactions of an unknown type.
+    pub unknown_type_policy: UnknownTypePolicy,
+    /// How to handle transactions for locked accounts.
+    pub locked_account_policy: LockedAccountPolicy,
+    /// How long transactions can be disputed for, if not forever.
+    pub dispute_window: Option<DisputeWindow>,
+    /// Most disputes each client may have open at once, if limited.
+    pub max_open_disputes: Option<usize>,
+    /// Balance invariants to check after every applied transaction, if any.
+    pub invariant_check: Option<InvariantCheck>,
+    /// Decimal places amounts are rounded to, and how.
+    pub precision: Precision,
+    /// Clock for time-based policies.
+    pub clock: ClockKind,
+    /// File to write the balance export to, rather than stdout.
+    pub output_path: Option<String>,
+    /// Number of files to split the balance export into. Balances are written to stdout (or
+    /// `output_path`) when this isn't set.
+    pub output_shards: Option<usize>,
+    /// Directory for sharded output.
+    pub output_dir: Option<String>,
+    /// Order of the balance export. Not supported in two-pass mode, where clients are written in
+    /// the order they're finalized.
+    pub sort_by: Option<SortBy>,
+    /// Earlier balance export to diff against, writing only the clients which changed, if any.
+    pub diff_path: Option<String>,
+    /// Stream client states to the output as they're finalized, rather than holding all of them
+    /// until the end. Requires reading the input twice.
+    pub two_pass: bool,
+    /// Number of worker threads to apply transactions on. Transactions are applied on the main
+    /// thread when this isn't set.
+    pub threads: Option<usize>,
+    /// Treat every rejected transaction as an error which stops processing.
+    pub strict: bool,
+    /// Skip rows which can't be parsed, rather than stopping.
+    pub skip_bad_rows: bool,
+    /// Apply administrative transactions, which are rejected by default.
+    pub allow_admin_ops: bool,
+    /// Memory (in bytes) for disputable transactions, beyond which they're spilled to disk.
+    pub max_memory: Option<usize>,
+    /// Fee schedule (TOML) for deposits and withdrawals, if fees are charged.
+    pub fees_path: Option<String>,
+    /// Exchange rates (CSV) for conversions, if any.
+    pub rates_path: Option<String>,
+    /// Per-client credit limits (CSV), if any.
+    pub limits_path: Option<String>,
+    /// Credit limit for clients without their own, if clients can overdraw.
+    pub credit_limit: Option<Money>,
+    /// Only process transactions for this sample of clients.
+    pub sample: Option<Sample>,
+    /// Only process transactions for these clients.
+    pub clients: Option<ClientFilter>,
+    /// Where to write the per-terminal report, if anywhere.
+    pub terminal_report_path: Option<String>,
+    /// Directory for the Merkle root of client totals and per-client proofs, if any.
+    pub balance_proofs_dir: Option<String>,
+    /// Snapshot of engine state to resume from, if any.
+    pub snapshot_in: Option<String>,
+    /// Earlier balance export to seed client states from, if any.
+    pub initial_balances_path: Option<String>,
+    /// Where to write a snapshot of engine state once the log has been applied, if anywhere.
+    pub snapshot_out: Option<String>,
+    /// SQLite ledger to keep the engine state in across runs, if any.
+    pub db_path: Option<String>,
+    /// Where to write the engine state if the run is interrupted (see `interrupt`), if anywhere.
+    pub interrupt_snapshot: Option<String>,
+    /// Skip transactions the snapshot being resumed from already reflects.
+    pub skip_backfilled: bool,
+    /// Skip deposits, withdrawals, and conversions which were already processed.
+    pub dedup: bool,
+    /// Where to write metrics once the log has been applied, if anywhere.
+    pub metrics_path: Option<String>,
+    /// Where to write the transactions still under dispute once the log has been applied, if
+    /// anywhere.
+    pub open_disputes_path: Option<String>,
+    /// Where to write an event for every change to client funds, if anywhere (`-` for stdout).
+    pub events_path: Option<String>,
+    /// Report summary statistics after processing.
+    pub summary: bool,
+    /// Where to write the summary statistics, if not stderr.
+    pub summary_path: Option<String>,
+    /// Where to journal applied transactions, if anywhere.
+    pub journal_path: Option<String>,
+    /// How often the journal is synced to disk.
+    pub journal_sync: JournalSync,
+    /// Journal of an interrupted run to recover from, if any.
+    pub recover_path: Option<String>,
+    /// Where to write a signed digest of every output of this run, if anywhere.
+    pub digest_path: Option<String>,
+    /// Show progress through the log on stderr, if it's a terminal.
+    pub progress: bool,
+    /// Only check the log (`validate`), rather than applying it for outputs.
+    pub validate_only: bool,
+}
+
+impl Options {
+    /// Parse options for `process` from command line arguments (excluding the program name).
+    pub fn from_args<I>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        match Command::from_args(args)? {
+            Command::Process(options) => Ok(options),
+            _ => Err(error("expected options for process")),
+        }
+    }
+
+    /// Whether events are written to stdout instead of balances, which are only written when they
+    /// go to files.
+    pub fn events_replace_balances(&self) -> bool {
+        self.events_path.as_deref() == Some(STDIN_PATH)
+            && self.output_path.is_none()
+            && self.output_shards.is_none()
+    }
+
+    /// Take the settings a config file gives, as if they were given as flags.
+    fn apply_config_file(&mut self, file: ConfigFile) -> Result<(), String> {
+        self.precision = file.precision()?;
+        self.duplicate_tx_policy = file.duplicate_tx_policy.unwrap_or_default();
+        self.withdrawal_dispute_policy = file.withdrawal_disputes.unwrap_or_default();
+        self.redispute_policy = file.redisputes.unwrap_or_default();
+        self.unknown_type_policy = file.unknown_type_policy.unwrap_or_default();
+        self.locked_account_policy = file.locked_accounts.unwrap_or_default();
+        self.strict = file.strict.unwrap_or_default();
+        self.allow_admin_ops = file.allow_admin_ops.unwrap_or_default();
+        self.dedup = file.dedup.unwrap_or_default();
+        self.schema = file.schema.unwrap_or_default();
+        self.dispute_window = file.dispute_window;
+        self.max_open_disputes = file.max_open_disputes;
+        self.invariant_check = file.check_invariants;
+        self.max_memory = file.max_memory;
+        self.fees_path = file.fees;
+        self.rates_path = file.rates;
+        self.limits_path = file.limits;
+        self.credit_limit = file.credit_limit;
+
+        Ok(())
+    }
+
+    /// Checks which depend on the values given, rather than just which options were.
+    fn check(&self) -> Result<(), String> {
+        if !self.schema.is_default() && self.input_format != InputFormat::Csv {
+            return Err(
+                "--schema, --no-header, and --delimiter only support csv input".to_string(),
+            );
+        }
+        if (self.input_format == InputFormat::Parquet
+            || self.output_format == OutputFormat::Parquet)
+            && !cfg!(feature = "parquet")
+        {
+            return Err("parquet requires the parquet feature".to_string());
+        }
+        if self.output_shards.is_some() && self.output_format != OutputFormat::Csv {
+            return Err("--output-shards only supports csv output".to_string());
+        }
+        if self.diff_path.is_some() && self.output_format != OutputFormat::Csv {
+            return Err("--diff only supports unsharded csv output".to_string());
+        }
+
+        if self.two_pass
+            && std::iter::once(self.csv_path.as_deref().unwrap_or(STDIN_PATH))
+                .chain(self.more_paths.iter().map(String::as_str))
+                .chain(self.merge_paths.iter().map(String::as_str))
+                .any(|path| path == STDIN_PATH)
+        {
+            return Err("--two-pass can't read from stdin, a path is required".to_string());
+        }
+
+        // Validation checks every row in order on a single engine.
+        if self.validate_only && self.threads.is_some() {
+            return Err("validate can't use --threads".to_string());
+        }
+        // Each worker only sees its own clients' transactions, so they'd count differently.
+        if matches!(self.dispute_window, Some(DisputeWindow::Transactions(_)))
+            && self.threads.is_some()
+        {
+            return Err(
+                "--threads needs a --dispute-window duration, not a number of transactions"
+                    .to_string(),
+            );
+        }
+
+        // A ledger is the state runs start from, and is committed by the engine which applied the
+        // whole log. Runs which fail leave it as it was, so it doesn't need journaling.
+        if self.db_path.is_some() && !cfg!(feature = "sqlite") {
+            return Err("--db requires the sqlite feature".to_string());
+        }
+        if self.db_path.is_some() && self.max_memory.is_some() {
+            return Err("--db can't be used with --max-memory".to_string());
+        }
+
+        if self.events_replace_balances()
+            && (self.diff_path.is_some() || self.digest_path.is_some())
+        {
+            return Err(
+                "balances aren't written with --events -, so --diff and --digest need --output"
+                    .to_string(),
+            );
+        }
+
+        let journaled = self.journal_path.is_some() || self.recover_path.is_some();
+        // Replaying a journal has to see the same times as the run which wrote it.
+        if journaled && self.clock == ClockKind::System {
+            return Err("journals can't be used with --clock system".to_string());
+        }
+        // Journaled line numbers don't say which log they came from.
+        if journaled && !self.more_paths.is_empty() {
+            return Err("journals can't be used with --merge or several logs".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Arguments for `process`.
+#[derive(Debug, Args)]
+struct ProcessArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    #[command(flatten)]
+    engine: EngineArgs,
+    #[command(flatten)]
+    strict: StrictArgs,
+    #[command(flatten)]
+    dialect: DialectArgs,
+    #[command(flatten)]
+    resume: ResumeArgs,
+    #[command(flatten)]
+    run: RunArgs,
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+impl ProcessArgs {
+    fn into_options(self) -> Result<Options, clap::Error> {
+        let mut options = Options::default();
+        let unknown_type_policy_given = self.engine.apply(&mut options)?;
+        self.input.apply(&mut options)?;
+        self.strict.apply(&mut options, unknown_type_policy_given);
+        self.dialect.apply(&mut options);
+        self.resume.apply(&mut options);
+        self.run.apply(&mut options);
+        self.output.apply(&mut options);
+        options.check().map_err(error)?;
+
+        Ok(options)
+    }
+}
+
+/// Arguments for `validate`, which are those for `process` other than outputs.
+#[derive(Debug, Args)]
+struct ValidateArgs {
+    #[command(flatten)]
+    input: InputArgs,
+    #[command(flatten)]
+    engine: EngineArgs,
+    #[command(flatten)]
+    strict: StrictArgs,
+    #[command(flatten)]
+    dialect: DialectArgs,
+    #[command(flatten)]
+    resume: ResumeArgs,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+impl ValidateArgs {
+    fn into_options(self) -> Result<Options, clap::Error> {
+        let mut options = Options {
+            validate_only: true,
+            ..Options::default()
+        };
+        let unknown_type_policy_given = self.engine.apply(&mut options)?;
+        self.input.apply(&mut options)?;
+        self.strict.apply(&mut options, unknown_type_policy_given);
+        // Every row is checked unless `--strict` stops at the first problem, so rows of an unknown
+        // type are reported like any other bad row, unless a policy was chosen.
+        if !options.strict {
+            options.skip_bad_rows = true;
+            if !unknown_type_policy_given {
+                options.unknown_type_policy = UnknownTypePolicy::Reject;
+            }
+        }
+        self.dialect.apply(&mut options);
+        self.resume.apply(&mut options);
+        self.run.apply(&mut options);
+        options.check().map_err(error)?;
+
+        Ok(options)
+    }
+}
+
+/// Transaction logs, and how they're read.
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("timestamps").args(["merge_paths", "chronological"]).multiple(true)))]
+struct InputArgs {
+    /// Transaction logs (`-` or none for stdin), read in sequence. Paths may be patterns, with `*`
+    /// and `?` in their file name
+    #[arg(value_name = "PATH")]
+    paths: Vec<String>,
+    /// Format of the transaction logs: csv (default) | ndjson | parquet (`parquet` feature)
+    #[arg(long, value_name = "FORMAT")]
+    input_format: Option<InputFormat>,
+    /// Read transaction fields from other csv columns (e.g. `type=txn_type`)
+    #[arg(long, value_name = "FIELD=COLUMN,...")]
+    schema: Option<Schema>,
+    /// Csv input has no header: type,client,tx,amount[,terminal,...]
+    #[arg(long, conflicts_with = "schema")]
+    no_header: bool,
+    /// Csv input delimiter (and the output's, without `--output-delimiter`)
+    #[arg(long, value_name = "CHAR", value_parser = parse_delimiter)]
+    delimiter: Option<u8>,
+    /// Merge another log with the input by timestamp (see `merge`)
+    #[arg(long = "merge", value_name = "PATH")]
+    merge_paths: Vec<String>,
+    /// Require timestamps in order, and export each client's last_activity
+    #[arg(long)]
+    chronological: bool,
+    /// How far behind itself a (merged) log may run (default 0)
+    #[arg(long, value_name = "N", requires = "timestamps")]
+    lateness: Option<u64>,
+    /// Apply transactions in ID order, buffering up to n of them
+    #[arg(long, value_name = "N", value_parser = positive, conflicts_with = "timestamps")]
+    reorder_window: Option<usize>,
+    /// Only process a deterministic sample of clients (e.g. `1%`)
+    #[arg(long, value_name = "PERCENT")]
+    sample: Option<Sample>,
+    /// Only process transactions for this client (repeatable)
+    #[arg(long = "client", value_name = "ID")]
+    clients: Vec<u16>,
+    /// Spill disputable transactions to disk beyond this size (e.g. `512M`)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<usize>,
+}
+
+impl InputArgs {
+    fn apply(self, options: &mut Options) -> Result<(), clap::Error> {
+        let mut paths = self.paths.into_iter();
+        options.csv_path = paths.next();
+        options.more_paths = paths.collect();
+        if let Some(input_format) = self.input_format {
+            options.input_format = input_format;
+        }
+        if let Some(schema) = self.schema {
+            options.schema = schema;
+        }
+        if self.no_header {
+            // The schema might have come from a config file.
+            if !options.schema.is_default() {
+                return Err(error("--no-header can't be used with --schema"));
+            }
+            options.schema = Schema::positional();
+        }
+        if let Some(delimiter) = self.delimiter {
+            options.schema = options.schema.clone().with_delimiter(delimiter);
+            options.output_dialect.delimiter = delimiter;
+        }
+        options.chronological = self.chronological || !self.merge_paths.is_empty();
+        options.merge_paths = self.merge_paths;
+        options.lateness = self.lateness.unwrap_or_default();
+        options.reorder_window = self.reorder_window;
+        options.sample = self.sample;
+        if !self.clients.is_empty() {
+            let mut clients = ClientFilter::new();
+            for client_id in self.clients {
+                clients.insert(client_id);
+            }
+            options.clients = Some(clients);
+        }
+        if self.max_memory.is_some() {
+            options.max_memory = self.max_memory;
+        }
+
+        Ok(())
+    }
+}
+
+/// How the engine applies transactions.
+#[derive(Debug, Args)]
+struct EngineArgs {
+    /// Engine settings from a TOML file, which other options override (see `config`)
+    #[arg(long, value_name = "PATH")]
+    config: Option<String>,
+    /// Reject (default) | ignore | error-out
+    #[arg(long, value_name = "POLICY")]
+    duplicate_tx_policy: Option<DuplicateTxPolicy>,
+    /// Reverse (default) | ignore
+    #[arg(long, value_name = "POLICY")]
+    withdrawal_disputes: Option<WithdrawalDisputePolicy>,
+    /// Repeatable (default) | once (each transaction can be disputed once)
+    #[arg(long, value_name = "POLICY")]
+    redisputes: Option<RedisputePolicy>,
+    /// Error-out (default) | reject | ignore
+    #[arg(long, value_name = "POLICY")]
+    unknown_type_policy: Option<UnknownTypePolicy>,
+    /// Reject (default) | ignore | queue (until an unlock)
+    #[arg(long, value_name = "POLICY")]
+    locked_accounts: Option<LockedAccountPolicy>,
+    /// Only allow disputes within n transactions or a duration (e.g. `90d`)
+    #[arg(long, value_name = "WINDOW")]
+    dispute_window: Option<DisputeWindow>,
+    /// Reject disputes from clients with n disputes already open
+    #[arg(long, value_name = "N", value_parser = positive)]
+    max_open_disputes: Option<usize>,
+    /// Transactions (default, by their timestamps) | system (see `clock`)
+    #[arg(long, value_name = "CLOCK")]
+    clock: Option<ClockKind>,
+    /// Stop at the first transaction breaking balance invariants: balances | non-negative
+    #[arg(long, value_name = "CHECK")]
+    check_invariants: Option<InvariantCheck>,
+    /// Apply `unlock` transactions, which reinstate locked accounts
+    #[arg(long)]
+    allow_admin_ops: bool,
+    /// Skip transactions already processed (kept in snapshots, see `dedup`)
+    #[arg(long)]
+    dedup: bool,
+    /// Charge deposit and withdrawal fees from a TOML schedule (see `fees`)
+    #[arg(long, value_name = "PATH")]
+    fees: Option<String>,
+    /// Exchange rates (CSV) for `convert` transactions (see `rates`)
+    #[arg(long, value_name = "PATH")]
+    rates: Option<String>,
+    /// Per-client credit limits (CSV) for overdrawing (see `limits`)
+    #[arg(long, value_name = "PATH")]
+    limits: Option<String>,
+    /// Credit limit for clients without one in `--limits` (default 0)
+    #[arg(long, value_name = "AMOUNT", value_parser = limits::parse_limit)]
+    credit_limit: Option<Money>,
+    /// Round amounts, fees, and conversions to n places (at most 4, the default)
+    #[arg(long, value_name = "N")]
+    decimal_places: Option<u32>,
+    /// How amounts are rounded: bankers (default) | half-up | truncate
+    #[arg(long, value_name = "ROUNDING")]
+    rounding: Option<Rounding>,
+}
+
+impl EngineArgs {
+    /// Apply the config file, then the options overriding it. Returns whether the unknown type
+    /// policy was given by either.
+    fn apply(self, options: &mut Options) -> Result<bool, clap::Error> {
+        let mut unknown_type_policy_given = false;
+        if let Some(path) = &self.config {
+            let file = ConfigFile::read(std::path::Path::new(path))
+                .map_err(|e| error_of(ErrorKind::Io, e))?;
+            unknown_type_policy_given = file.unknown_type_policy.is_some();
+            options.apply_config_file(file).map_err(error)?;
+        }
+
+        if let Some(policy) = self.duplicate_tx_policy {
+            options.duplicate_tx_policy = policy;
+        }
+        if let Some(policy) = self.withdrawal_disputes {
+            options.withdrawal_dispute_policy = policy;
+        }
+        if let Some(policy) = self.redisputes {
+            options.redispute_policy = policy;
+        }
+        if let Some(policy) = self.unknown_type_policy {
+            options.unknown_type_policy = policy;
+            unknown_type_policy_given = true;
+        }
+        if let Some(policy) = self.locked_accounts {
+            options.locked_account_policy = policy;
+        }
+        if let Some(clock) = self.clock {
+            options.clock = clock;
+        }
+        options.dispute_window = self.dispute_window.or(options.dispute_window);
+        options.max_open_disputes = self.max_open_disputes.or(options.max_open_disputes);
+        options.invariant_check = self.check_invariants.or(options.invariant_check);
+        options.allow_admin_ops |= self.allow_admin_ops;
+        options.dedup |= self.dedup;
+        options.fees_path = self.fees.or(options.fees_path.take());
+        options.rates_path = self.rates.or(options.rates_path.take());
+        options.limits_path = self.limits.or(options.limits_path.take());
+        options.credit_limit = self.credit_limit.or(options.credit_limit);
+        options.precision = Precision::new(
+            (self.decimal_places).unwrap_or(options.precision.decimal_places()),
+            (self.rounding).unwrap_or(options.precision.rounding()),
+        )
+        .map_err(|e| error_of(ErrorKind::ValueValidation, e))?;
+
+        Ok(unknown_type_policy_given)
+    }
+}
+
+/// How strictly rejected transactions and bad rows are treated.
+#[derive(Debug, Args)]
+struct StrictArgs {
+    /// Stop at the first rejected transaction, reporting its line
+    #[arg(long)]
+    strict: bool,
+    /// Log and skip rows which can't be parsed, rather than stopping (and reject unknown types,
+    /// unless `--unknown-type-policy` is given)
+    #[arg(long)]
+    skip_bad_rows: bool,
+}
+
+impl StrictArgs {
+    fn apply(self, options: &mut Options, unknown_type_policy_given: bool) {
+        options.strict |= self.strict;
+        options.skip_bad_rows = self.skip_bad_rows;
+        // Rows of an unknown type are as bad as rows which can't be parsed, so they don't stop a
+        // lenient run either (they're reported as rejected instead).
+        if self.skip_bad_rows && !unknown_type_policy_given {
+            options.unknown_type_policy = UnknownTypePolicy::Reject;
+        }
+    }
+}
+
+/// The CSV dialect outputs are written in.
+#[derive(Debug, Args)]
+struct DialectArgs {
+    /// Delimiter for balance output (`tab` for tab separated)
+    #[arg(long, value_name = "CHAR", value_parser = parse_delimiter)]
+    output_delimiter: Option<u8>,
+    /// Always | necessary | non-numeric | never
+    #[arg(long, value_name = "STYLE", value_parser = parse_quote_style)]
+    quote_style: Option<csv::QuoteStyle>,
+    /// Lf | crlf
+    #[arg(long, value_name = "ENDING")]
+    line_ending: Option<crate::output::LineEnding>,
+}
+
+impl DialectArgs {
+    fn apply(self, options: &mut Options) {
+        if let Some(delimiter) = self.output_delimiter {
+            options.output_dialect.delimiter = delimiter;
+        }
+        if let Some(quote_style) = self.quote_style {
+            options.output_dialect.quote_style = quote_style;
+        }
+        if let Some(line_ending) = self.line_ending {
+            options.output_dialect.line_ending = line_ending;
+        }
+    }
+}
+
+/// State to start from, rather than empty accounts.
+#[derive(Debug, Args)]
+struct ResumeArgs {
+    /// Start from the engine state in a snapshot (see `snapshot`)
+    #[arg(long, value_name = "PATH")]
+    snapshot_in: Option<String>,
+    /// Start from the client balances in an earlier balance export
+    #[arg(long, value_name = "PATH", conflicts_with = "snapshot_in")]
+    initial_balances: Option<String>,
+    /// Skip deposits/withdrawals already reflected in `--snapshot-in`
+    #[arg(long, requires = "snapshot_in")]
+    skip_backfilled: bool,
+}
+
+impl ResumeArgs {
+    fn apply(self, options: &mut Options) {
+        options.snapshot_in = self.snapshot_in;
+        options.initial_balances_path = self.initial_balances;
+        options.skip_backfilled = self.skip_backfilled;
+    }
+}
+
+/// How a batch run goes, and what it reports.
+#[derive(Debug, Args)]
+struct RunArgs {
+    /// Write rejected transactions (and reasons) to a CSV file
+    #[arg(long, value_name = "PATH")]
+    rejects: Option<String>,
+    /// Apply transactions on n worker threads, each owning a set of clients
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = positive,
+        conflicts_with_all = ["snapshot_in", "initial_balances"]
+    )]
+    threads: Option<usize>,
+    /// Show rows and bytes read, throughput, and ETA on stderr (if a terminal)
+    #[arg(long)]
+    progress: bool,
+}
+
+impl RunArgs {
+    fn apply(self, options: &mut Options) {
+        options.rejects_path = self.rejects;
+        options.threads = self.threads;
+        options.progress = self.progress;
+    }
+}
+
+/// Where balances and the other outputs of a batch run are written. Two-pass mode hands clients
+/// off as they're finalized, and workers (`--threads`) only see their own clients, so neither has
+/// the full state for snapshots, ledgers, metrics, open disputes, events, or journals.
+#[derive(Debug, Args)]
+struct OutputArgs {
+    /// Csv (default) | json | ndjson | parquet (`parquet` feature)
+    #[arg(long, value_name = "FORMAT")]
+    output_format: Option<OutputFormat>,
+    /// Write balances to a file, renamed into place once it's complete
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<String>,
+    /// Split balances into n files partitioned by client (plus a manifest)
+    #[arg(long, value_name = "N", value_parser = positive, conflicts_with = "output")]
+    output_shards: Option<usize>,
+    /// Where to write sharded output (defaults to the current directory)
+    #[arg(long, value_name = "DIR")]
+    output_dir: Option<String>,
+    /// Client (default) | total | locked
+    #[arg(long, value_name = "ORDER")]
+    sort_by: Option<SortBy>,
+    /// Only write clients whose balances differ from an earlier export
+    #[arg(long, value_name = "PATH", conflicts_with = "output_shards")]
+    diff: Option<String>,
+    /// Read the input twice, writing each client as soon as it's final
+    #[arg(
+        long,
+        conflicts_with_all = ["threads", "sort_by", "snapshot_in", "initial_balances", "summary"]
+    )]
+    two_pass: bool,
+    /// Write per-terminal volumes and reject/dispute rates to a CSV file
+    #[arg(long, value_name = "PATH")]
+    terminal_report: Option<String>,
+    /// Write a Merkle root of client totals, and a proof for each client
+    #[arg(long, value_name = "DIR")]
+    balance_proofs: Option<String>,
+    /// Write the engine state to a snapshot after processing
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["two_pass", "threads"])]
+    snapshot_out: Option<String>,
+    /// Keep the engine state in a SQLite ledger across runs (`sqlite` feature, see `ledger`)
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "snapshot_in", "initial_balances", "two_pass", "threads", "journal", "recover"
+        ]
+    )]
+    db: Option<String>,
+    /// On Ctrl-C, stop reading and write the state so far to a snapshot
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["two_pass", "threads"])]
+    interrupt_snapshot: Option<String>,
+    /// Write Prometheus metrics after processing (see `metrics`)
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["two_pass", "threads"])]
+    metrics: Option<String>,
+    /// Write the transactions still under dispute after processing, as CSV
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["two_pass", "threads"])]
+    open_disputes: Option<String>,
+    /// Write every change to client funds as NDJSON (`-` for stdout, instead of balances unless
+    /// they're written to files, see `events`). A recovered run would send the journaled
+    /// transactions' events again
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["two_pass", "threads", "recover"]
+    )]
+    events: Option<String>,
+    /// Print summary statistics to stderr after processing (see `summary`)
+    #[arg(long)]
+    summary: bool,
+    /// Write the summary statistics to a file instead
+    #[arg(long, value_name = "PATH", conflicts_with = "two_pass")]
+    summary_file: Option<String>,
+    /// Journal applied transactions, for recovering from a crash (see `journal`). Recovery skips
+    /// journaled lines, so transactions have to be applied in input order
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["two_pass", "threads", "merge_paths", "lateness", "reorder_window"]
+    )]
+    journal: Option<String>,
+    /// Always (default) | never | n (sync the journal every n entries)
+    #[arg(long, value_name = "WHEN")]
+    journal_sync: Option<JournalSync>,
+    /// Replay a journal, and skip the input lines it already covers
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["two_pass", "threads", "merge_paths", "lateness", "reorder_window"]
+    )]
+    recover: Option<String>,
+    /// Write a signed digest of all outputs (see `digest`)
+    #[arg(long, value_name = "PATH")]
+    digest: Option<String>,
+}
+
+impl OutputArgs {
+    fn apply(self, options: &mut Options) {
+        options.output_format = self.output_format.unwrap_or_default();
+        options.output_path = self.output;
+        options.output_shards = self.output_shards;
+        options.output_dir = self.output_dir;
+        options.sort_by = self.sort_by;
+        options.diff_path = self.diff;
+        options.two_pass = self.two_pass;
+        options.terminal_report_path = self.terminal_report;
+        options.balance_proofs_dir = self.balance_proofs;
+        options.snapshot_out = self.snapshot_out;
+        options.db_path = self.db;
+        options.interrupt_snapshot = self.interrupt_snapshot;
+        options.metrics_path = self.metrics;
+        options.open_disputes_path = self.open_disputes;
+        options.events_path = self.events;
+        options.summary = self.summary || self.summary_file.is_some();
+        options.summary_path = self.summary_file;
+        options.journal_path = self.journal;
+        options.journal_sync = self.journal_sync.unwrap_or_default();
+        options.recover_path = self.recover;
+        options.digest_path = self.digest;
+    }
+}
+
+/// Options for `verify-digest`, which checks archived outputs against a digest written by an
+/// earlier run. `--balances` is required when the balances were written to stdout, and gives the
+/// path of the archived copy.
+#[derive(Debug, Args)]
+pub struct VerifyDigestOptions {
+    /// The digest written by the run
+    #[arg(value_name = "DIGEST")]
+    pub digest_path: String,
+    /// Archived copy of balances which were written to stdout
+    #[arg(long = "balances", value_name = "PATH")]
+    pub balances_path: Option<String>,
+}
+
+/// Options for `compact`. `-o` (or `--output`) is required, and the transaction logs and how
+/// they're read, and the engine's configuration, are taken from the same options as `process`.
+#[derive(Debug)]
+pub struct CompactOptions {
+    /// Where to write the compacted log.
+    pub output_path: String,
+    pub options: Options,
+}
+
+#[derive(Debug, Args)]
+struct CompactArgs {
+    /// Where to write the compacted log
+    #[arg(short, long, value_name = "PATH")]
+    output: String,
+    #[command(flatten)]
+    input: InputArgs,
+    #[command(flatten)]
+    engine: EngineArgs,
+}
+
+impl CompactArgs {
+    fn into_options(self) -> Result<CompactOptions, clap::Error> {
+        let mut options = Options::default();
+        self.engine.apply(&mut options)?;
+        self.input.apply(&mut options)?;
+        options.check().map_err(error)?;
+
+        Ok(CompactOptions {
+            output_path: self.output,
+            options,
+        })
+    }
+}
+
+/// Options for `serve` (and `serve-http`). `--listen` is required, and the input format, the
+/// engine's configuration, and strict mode are taken from the same options as `process`.
+/// `--history` keeps past client states for `serve-http`, `--replicate <addr>` streams changes to
+/// followers, and `--metrics-listen <addr>` serves metrics over HTTP (which `serve-http` also
+/// serves at `/metrics`).
+#[derive(Debug)]
+pub struct ServeOptions {
+    /// Address to listen on (e.g. `127.0.0.1:7878`).
+    pub listen_addr: String,
+    /// Keep every past client state for `as_of` queries (`serve-http` only).
+    pub history: bool,
+    /// Address to stream changes to followers on, if any (see `replication`).
+    pub replicate_addr: Option<String>,
+    /// Address to serve metrics on, if any (see `metrics`).
+    pub metrics_addr: Option<String>,
+    pub options: Options,
+}
+
+impl ServeOptions {
+    /// Parse options from the arguments following `serve`.
+    pub fn from_args<I>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let args = std::iter::once("serve".to_string()).chain(args);
+        match Cli::try_parse_from(std::iter::once(BIN_NAME.to_string()).chain(args))?.command {
+            Some(SubcommandArgs::Serve(args)) => args.into_options(),
+            _ => Err(error("expected options for serve")),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct ServeArgs {
+    /// Address to listen on (e.g. `127.0.0.1:7878`)
+    #[arg(long, value_name = "ADDR")]
+    listen: String,
+    /// Keep every past client state for `as_of` queries (`serve-http` only)
+    #[arg(long)]
+    history: bool,
+    /// Stream changes to followers on this address (see `replication`)
+    #[arg(long, value_name = "ADDR")]
+    replicate: Option<String>,
+    /// Serve metrics over HTTP on this address (see `metrics`)
+    #[arg(long, value_name = "ADDR")]
+    metrics_listen: Option<String>,
+    /// Format of transactions sent: csv (default) | ndjson
+    #[arg(long, value_name = "FORMAT")]
+    input_format: Option<InputFormat>,
+    #[command(flatten)]
+    engine: EngineArgs,
+    #[command(flatten)]
+    strict: StrictArgs,
+}
+
+impl ServeArgs {
+    fn into_options(self) -> Result<ServeOptions, clap::Error> {
+        let mut options = Options::default();
+        let unknown_type_policy_given = self.engine.apply(&mut options)?;
+        self.strict.apply(&mut options, unknown_type_policy_given);
+        options.input_format = self.input_format.unwrap_or_default();
+        // Transactions arrive a line at a time, and a Parquet file can only be read whole.
+        if options.input_format == InputFormat::Parquet {
+            return Err(error(
+                "serve doesn't accept parquet, only csv or ndjson lines",
+            ));
+        }
+
+        Ok(ServeOptions {
+            listen_addr: self.listen,
+            history: self.history,
+            replicate_addr: self.replicate,
+            metrics_addr: self.metrics_listen,
+            options,
+        })
+    }
+}
+
+/// Options for `policy-report` (see `policy_report`). The engine's configuration and strict mode
+/// are taken from the same options as `process`. There's no transaction log, and nothing else is
+/// accepted.
+#[derive(Debug)]
+pub struct PolicyReportOptions {
+    /// The options given, recorded in the report.
+    pub arguments: Vec<String>,
+    pub options: Options,
+}
+
+#[derive(Debug, Args)]
+struct PolicyReportArgs {
+    #[command(flatten)]
+    engine: EngineArgs,
+    #[command(flatten)]
+    strict: StrictArgs,
+}
+
+impl PolicyReportArgs {
+    fn into_options(self) -> Result<Options, clap::Error> {
+        let mut options = Options::default();
+        let unknown_type_policy_given = self.engine.apply(&mut options)?;
+        self.strict.apply(&mut options, unknown_type_policy_given);
+
+        Ok(options)
+    }
+}
+
+/// Options for `history`. The client comes first, followed by the transaction logs, and the options
+/// affecting how transactions are applied, as for `process`. The ledger is written as CSV in the
+/// output dialect.
+#[derive(Debug)]
+pub struct HistoryOptions {
+    /// Client whose ledger is listed.
+    pub client_id: u16,
+    pub options: Options,
+}
+
+#[derive(Debug, Args)]
+struct HistoryArgs {
+    /// Client whose ledger is listed
+    #[arg(value_name = "CLIENT")]
+    client_id: u16,
+    #[command(flatten)]
+    input: InputArgs,
+    #[command(flatten)]
+    engine: EngineArgs,
+    #[command(flatten)]
+    strict: StrictArgs,
+    #[command(flatten)]
+    dialect: DialectArgs,
+    #[command(flatten)]
+    resume: ResumeArgs,
+}
+
+impl HistoryArgs {
+    fn into_options(self) -> Result<HistoryOptions, clap::Error> {
+        let mut options = Options::default();
+        let unknown_type_policy_given = self.engine.apply(&mut options)?;
+        self.input.apply(&mut options)?;
+        self.strict.apply(&mut options, unknown_type_policy_given);
+        self.dialect.apply(&mut options);
+        self.resume.apply(&mut options);
+        options.check().map_err(error)?;
+
+        Ok(HistoryOptions {
+            client_id: self.client_id,
+            options,
+        })
+    }
+}
+
+/// Options for `consume` (see `kafka`). The brokers, topic, and snapshot are required, and the
+/// input format, the engine's configuration, strict mode, and `--skip-bad-rows` are taken from the
+/// same options as `process`. The snapshot is restored when the consumer starts (if it exists),
+/// and written every `--snapshot-interval` seconds, along with the balances (with `-o`).
+#[derive(Debug)]
+pub struct ConsumeOptions {
+    /// Kafka brokers to bootstrap from (e.g. `localhost:9092`).
+    pub brokers: String,
+    /// Topic to consume transactions from.
+    pub topic: String,
+    /// Consumer group to commit offsets as.
+    pub group: String,
+    /// Where the engine state is kept between runs.
+    pub snapshot_path: String,
+    /// Where to export balances with every snapshot, if anywhere.
+    pub output_path: Option<String>,
+    /// How often the engine state is snapshot, and offsets committed.
+    pub snapshot_interval: std::time::Duration,
+    pub options: Options,
+}
+
+#[derive(Debug, Args)]
+struct ConsumeArgs {
+    /// Kafka brokers to bootstrap from (e.g. `localhost:9092`)
+    #[arg(long, value_name = "HOSTS")]
+    brokers: String,
+    /// Topic to consume transactions from
+    #[arg(long)]
+    topic: String,
+    /// Consumer group to commit offsets as
+    #[arg(long, default_value = "payment-engine")]
+    group: String,
+    /// Keep the engine state in this snapshot, restored at start (see `snapshot`)
+    #[arg(long, value_name = "PATH")]
+    snapshot: String,
+    /// Export balances to this file with every snapshot
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<String>,
+    /// Seconds between snapshots (and offset commits)
+    #[arg(long, value_name = "SECONDS", value_parser = positive, default_value = "60")]
+    snapshot_interval: usize,
+    /// Format of transaction messages: csv (default) | ndjson
+    #[arg(long, value_name = "FORMAT")]
+    input_format: Option<InputFormat>,
+    #[command(flatten)]
+    engine: EngineArgs,
+    #[command(flatten)]
+    strict: StrictArgs,
+    #[command(flatten)]
+    dialect: DialectArgs,
+}
+
+impl ConsumeArgs {
+    fn into_options(self) -> Result<ConsumeOptions, clap::Error> {
+        let mut options = Options::default();
+        let unknown_type_policy_given = self.engine.apply(&mut options)?;
+        self.strict.apply(&mut options, unknown_type_policy_given);
+        self.dialect.apply(&mut options);
+        options.input_format = self.input_format.unwrap_or_default();
+        // Messages are read a line at a time, and a Parquet file can only be read whole.
+        if options.input_format == InputFormat::Parquet {
+            return Err(error(
+                "consume doesn't accept parquet, only csv or ndjson lines",
+            ));
+        }
+
+        Ok(ConsumeOptions {
+            brokers: self.brokers,
+            topic: self.topic,
+            group: self.group,
+            snapshot_path: self.snapshot,
+            output_path: self.output,
+            snapshot_interval: std::time::Duration::from_secs(self.snapshot_interval as u64),
+            options,
+        })
+    }
+}
+
+/// Options for `follow`. Both addresses are required, and nothing else is accepted.
+#[derive(Debug, Args)]
+pub struct FollowOptions {
+    /// Address the leader streams changes on (its `--replicate` address)
+    #[arg(long = "leader", value_name = "ADDR")]
+    pub leader_addr: String,
+    /// Address to serve reads on
+    #[arg(long = "listen", value_name = "ADDR")]
+    pub listen_addr: String,
+}
+
+/// Logging options, accepted anywhere on the command line and by every subcommand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogOptions {
+    pub level: LevelFilter,
+    pub format: LogFormat,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions {
+            level: LevelFilter::ERROR,
+            format: LogFormat::Text,
+        }
+    }
+}
+
+/// Logging options as they're given, before the level defaults for the command.
+#[derive(Debug, Args)]
+struct LogArgs {
+    /// Off, error, warn, info, debug, or trace [default: error, or warn for validate]
+    #[arg(
+        long = "log-level",
+        value_name = "LEVEL",
+        global = true,
+        value_parser = logging::parse_level
+    )]
+    level: Option<LevelFilter>,
+    /// Text or json
+    #[arg(
+        long = "log-format",
+        value_name = "FORMAT",
+        global = true,
+        default_value = "text"
+    )]
+    format: LogFormat,
+}
+
+/// Options for `gen`, which writes a synthetic transaction log (see `synthetic`) to stdout.
+#[derive(Debug, Args)]
+struct GenArgs {
+    /// Rows to generate (default 1000000)
+    #[arg(long, value_name = "N")]
+    rows: Option<u64>,
+    /// Distinct clients (default 5000)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    clients: Option<u16>,
+    /// Share of rows which are disputes (default 1%)
+    #[arg(long, value_name = "PERCENT", value_parser = parse_rate)]
+    dispute_rate: Option<f64>,
+    /// Share of disputes settled by chargeback (default 10%)
+    #[arg(long, value_name = "PERCENT", value_parser = parse_rate)]
+    chargeback_rate: Option<f64>,
+    /// Seed for the generator (default 0)
+    #[arg(long, value_name = "N")]
+    seed: Option<u64>,
+}
+
+impl GenArgs {
+    fn into_generator(self) -> Generator {
+        let default = Generator::default();
+        Generator {
+            rows: self.rows.unwrap_or(default.rows),
+            clients: self.clients.unwrap_or(default.clients),
+            dispute_rate: self.dispute_rate.unwrap_or(default.dispute_rate),
+            chargeback_rate: self.chargeback_rate.unwrap_or(default.chargeback_rate),
+            seed: self.seed.unwrap_or(default.seed),
+        }
+    }
+}
+
+/// Parse a positive integer.
+fn positive(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) | Err(_) => Err("expected a positive integer".to_string()),
+        Ok(n) => Ok(n),
+    }
+}
+
+/// An error for options which can't be used together, formatted like clap's own.
+fn error(message: impl std::fmt::Display) -> clap::Error {
+    error_of(ErrorKind::ArgumentConflict, message)
+}
+
+fn error_of(kind: ErrorKind, message: impl std::fmt::Display) -> clap::Error {
+    Cli::command().error(kind, message)
+}
+
+/// The arguments following a subcommand, other than logging options (which are global).
+fn subcommand_arguments(args: &[String]) -> Vec<String> {
+    let mut arguments = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--log-level" | "--log-format" => {
+                args.next();
+            }
+            _ if arg.starts_with("--log-level=") || arg.starts_with("--log-format=") => {}
+            _ => arguments.push(arg.clone()),
+        }
+    }
+
+    arguments
+}
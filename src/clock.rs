@@ -0,0 +1,100 @@
+/// Clocks for time-based policies (e.g. dispute windows).
+///
+/// The engine asks its clock for the time as each transaction arrives, in milliseconds. By default
+/// that's the latest transaction timestamp so far (`TransactionClock`), so a run over the same
+/// input always sees the same times, and so does a journal replay, since journals keep each
+/// transaction's timestamp. `SystemClock` uses the wall clock
+/// instead, for transactions which don't carry timestamps (e.g. ones sent to `serve`), and
+/// `SimulatedClock` is set by hand, so tests can move time along as they need.
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::Transaction;
+
+/// The time transactions arrive at.
+pub trait Clock: Send {
+    /// The time (in milliseconds) as `tx` arrives.
+    fn now(&mut self, tx: &Transaction) -> u64;
+}
+
+/// The latest transaction timestamp so far. Transactions without a timestamp (or with one earlier
+/// than the latest) arrive at the latest timestamp.
+#[derive(Debug, Default, Clone)]
+pub struct TransactionClock {
+    latest: u64,
+}
+
+impl Clock for TransactionClock {
+    fn now(&mut self, tx: &Transaction) -> u64 {
+        self.latest = self.latest.max(tx.timestamp.unwrap_or(0));
+        self.latest
+    }
+}
+
+/// Milliseconds since the Unix epoch, by the system's wall clock.
+#[derive(Debug, Default, Clone)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&mut self, _: &Transaction) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+}
+
+/// A clock which only moves when it's told to. Clones share the same time, so a clone can be kept
+/// to move the time of a clock given to an engine.
+#[derive(Debug, Default, Clone)]
+pub struct SimulatedClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl SimulatedClock {
+    pub fn new(millis: u64) -> Self {
+        SimulatedClock {
+            millis: Arc::new(AtomicU64::new(millis)),
+        }
+    }
+
+    pub fn set(&self, millis: u64) {
+        self.millis.store(millis, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, millis: u64) {
+        self.millis.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&mut self, _: &Transaction) -> u64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+}
+
+/// Which clock to use, as chosen on the command line.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClockKind {
+    /// `TransactionClock`
+    #[default]
+    Transactions,
+    /// `SystemClock`
+    System,
+}
+
+impl FromStr for ClockKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "transactions" => Ok(ClockKind::Transactions),
+            "system" => Ok(ClockKind::System),
+            _ => Err(format!(
+                "unknown clock '{}', expected transactions or system",
+                s
+            )),
+        }
+    }
+}
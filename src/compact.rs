@@ -0,0 +1,279 @@
+/// Compaction of transaction logs.
+///
+/// A compacted log leaves an engine with the same client states as the log it was compacted from,
+/// in as few transactions as possible. For each client (in order of client ID) it has:
+///
+/// - for each currency the client holds, a deposit or withdrawal of the client's net funds, and
+///   every transaction still under dispute (with its original ID), followed by its dispute
+/// - a deposit which is disputed and charged back, if the account is locked. Its funds are withdrawn
+///   first if the account is short of funds, otherwise this has no effect on funds
+/// - an unlock, if an account which was unlocked by an admin op is still short of funds (so the
+///   compacted log needs admin ops too)
+///
+/// Other transactions get IDs counting down from `u32::MAX`, so later logs which dispute a
+/// transaction from the original log are rejected rather than hitting the wrong transaction. Only
+/// transactions still under dispute can be settled after compaction, and resolved or charged back
+/// transactions aren't kept (disputing one again is rejected as an unknown transaction). Versions aren't preserved, and
+/// fees are already included in the net funds, so a compacted log should be applied without fees
+/// (but with the same credit limits, which overdrawn accounts rely on).
+use std::collections::HashSet;
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::Path;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::currency::Currency;
+use crate::input::InputFormat;
+use crate::money::Money;
+use crate::output::AtomicFile;
+use crate::store::{DisputableKind, DisputableTx};
+use crate::{apply_transactions, open_transactions, ClientState, Engine, TransactionType};
+
+/// A row of a compacted log.
+#[derive(Serialize)]
+struct Row {
+    r#type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<Money>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+}
+
+impl Engine {
+    /// Write a compacted log of the engine's state as CSV, returning the number of transactions
+    /// written.
+    pub fn write_compacted<W: Write>(&self, writer: W) -> Result<usize, Box<dyn Error>> {
+        if self.queued_count() > 0 {
+            return Err("transactions held for locked accounts can't be compacted".into());
+        }
+
+        let disputed_ids: HashSet<u32> = self
+            .client_states
+            .values()
+            .flat_map(|state| state.open_disputes())
+            .collect();
+        let mut unused_ids = (0..=u32::MAX)
+            .rev()
+            .filter(|tx_id| !disputed_ids.contains(tx_id));
+        let mut next_id = || unused_ids.next().ok_or("no transaction IDs left");
+
+        let mut client_ids: Vec<u16> = self.client_states.keys().copied().collect();
+        client_ids.sort_unstable();
+
+        // Currencies are only written if some client used them, so other logs compact exactly as
+        // they did before currencies existed.
+        let by_currency = self
+            .client_states
+            .values()
+            .any(|state| !state.currencies.is_empty());
+        let mut writer = csv::Writer::from_writer(writer);
+        let mut rows = 0;
+        let mut write = |r#type, client, tx, amount, currency: Currency| {
+            rows += 1;
+            writer.serialize(Row {
+                r#type,
+                client,
+                tx,
+                amount,
+                currency: by_currency.then_some(currency),
+            })
+        };
+
+        for client in client_ids {
+            let state = &self.client_states[&client];
+            let mut disputed_tx_ids: Vec<u32> = state.open_disputes().collect();
+            disputed_tx_ids.sort_unstable();
+
+            let mut disputed = Vec::with_capacity(disputed_tx_ids.len());
+            for tx_id in disputed_tx_ids {
+                let tx = self
+                    .disputable_transactions
+                    .get(tx_id)?
+                    .ok_or_else(|| format!("disputed transaction {} isn't stored", tx_id))?;
+                disputed.push((tx_id, tx));
+            }
+
+            // A chargeback locks the account, so at most one currency can be short of funds, and
+            // the account is locked in that currency.
+            let mut lock = (Currency::IMPLICIT, Decimal::ZERO);
+            let mut client_has_rows = !state.currencies.is_empty();
+            let currencies =
+                std::iter::once(Currency::IMPLICIT).chain(state.currencies.keys().copied());
+            for currency in currencies {
+                let balance = state.balance(currency);
+                let disputed: Vec<(u32, DisputableTx)> = disputed
+                    .iter()
+                    .filter(|(_, tx)| tx.currency == currency)
+                    .copied()
+                    .collect();
+                let mut write = |r#type, tx, amount| write(r#type, client, tx, amount, currency);
+
+                // Disputed withdrawals come out of the net funds, and disputed deposits are held
+                // without ever being available.
+                let disputed_total = |r#type: TransactionType| -> Decimal {
+                    disputed
+                        .iter()
+                        .filter(|(_, tx)| tx.r#type() == r#type)
+                        .map(|(_, tx)| Decimal::from(tx.amount))
+                        .sum()
+                };
+                let deposited = disputed_total(TransactionType::Deposit);
+                let withdrawn = disputed_total(TransactionType::Withdrawal);
+                let available = Decimal::from(balance.available);
+                // Withdrawals can't overdraw beyond the client's credit limit, so funds an account
+                // is short of past that (e.g. a charged back deposit which had already been
+                // withdrawn) are taken when it's locked. Accounts can only be short of funds once
+                // they've been locked.
+                let limit = self
+                    .credit_limits
+                    .as_ref()
+                    .map_or(Decimal::ZERO, |limits| limits.limit(client).into());
+                let shortfall = (-(available + deposited + limit)).max(Decimal::ZERO);
+                if !shortfall.is_zero() {
+                    lock = (currency, shortfall);
+                }
+                let net = available + withdrawn + shortfall;
+                if net.is_sign_positive() && !net.is_zero() {
+                    write(TransactionType::Deposit, next_id()?, Some(Money::new(net)))?;
+                }
+
+                // Deposits go first, so withdrawals (including a net withdrawal) are covered.
+                for (tx_id, tx) in disputed
+                    .iter()
+                    .filter(|(_, tx)| tx.kind == DisputableKind::Deposit)
+                {
+                    write(tx.r#type(), *tx_id, Some(tx.amount))?;
+                }
+                if net.is_sign_negative() && !net.is_zero() {
+                    write(
+                        TransactionType::Withdrawal,
+                        next_id()?,
+                        Some(Money::new(-net)),
+                    )?;
+                }
+                for (tx_id, tx) in disputed
+                    .iter()
+                    .filter(|(_, tx)| tx.kind == DisputableKind::Withdrawal)
+                {
+                    write(tx.r#type(), *tx_id, Some(tx.amount))?;
+                }
+                for (tx_id, _) in &disputed {
+                    write(TransactionType::Dispute, *tx_id, None)?;
+                }
+
+                // Other currencies still need to appear, without any funds.
+                let has_rows = !net.is_zero() || !disputed.is_empty();
+                if !has_rows && !currency.is_implicit() {
+                    let amount = Some(Money::new(Decimal::ONE));
+                    write(TransactionType::Deposit, next_id()?, amount)?;
+                    write(TransactionType::Withdrawal, next_id()?, amount)?;
+                }
+                client_has_rows |= has_rows;
+            }
+
+            let (currency, shortfall) = lock;
+            let mut write = |r#type, tx, amount| write(r#type, client, tx, amount, currency);
+            if state.locked || !shortfall.is_zero() {
+                // Charging back a deposit which was withdrawn takes its amount a second time.
+                let tx_id = next_id()?;
+                if shortfall.is_zero() {
+                    let amount = Some(Money::new(Decimal::ONE));
+                    write(TransactionType::Deposit, tx_id, amount)?;
+                } else {
+                    let amount = Some(Money::new(shortfall));
+                    write(TransactionType::Deposit, tx_id, amount)?;
+                    write(TransactionType::Withdrawal, next_id()?, amount)?;
+                }
+                write(TransactionType::Dispute, tx_id, None)?;
+                write(TransactionType::Chargeback, tx_id, None)?;
+                if !state.locked {
+                    write(TransactionType::Unlock, next_id()?, None)?;
+                }
+            } else if !client_has_rows {
+                // The client still needs to appear, without any funds.
+                let amount = Some(Money::new(Decimal::ONE));
+                write(TransactionType::Deposit, next_id()?, amount)?;
+                write(TransactionType::Withdrawal, next_id()?, amount)?;
+            }
+        }
+        writer.flush()?;
+
+        Ok(rows)
+    }
+}
+
+/// Check that applying the compacted log at `path` gives the same client states (and transactions
+/// under dispute) as `engine` has.
+pub fn verify(engine: &Engine, path: &Path) -> Result<(), Box<dyn Error>> {
+    let path = path
+        .to_str()
+        .ok_or("compacted log path isn't valid UTF-8")?;
+    let mut replayed = Engine::new()
+        .with_withdrawal_dispute_policy(engine.withdrawal_dispute_policy)
+        .with_admin_ops(engine.allow_admin_ops);
+    if let Some(limits) = &engine.credit_limits {
+        replayed = replayed.with_credit_limits(limits.clone());
+    }
+    apply_transactions(
+        &mut replayed,
+        open_transactions(path, InputFormat::Csv)?,
+        |tx, e| Err(format!("compacted log rejected transaction {}: {}", tx.tx_id, e).into()),
+    )?;
+
+    let mismatch =
+        |client_id| format!("compacted log gives client {} a different state", client_id);
+    if replayed.client_states.len() != engine.client_states.len() {
+        return Err("compacted log has a different set of clients".into());
+    }
+    for (client_id, state) in &engine.client_states {
+        let compacted = replayed
+            .client_states
+            .get(client_id)
+            .ok_or_else(|| mismatch(*client_id))?;
+        // Fees aren't charged on replay, so only funds are compared.
+        let funds = |state: &ClientState| -> Vec<_> {
+            std::iter::once(Currency::IMPLICIT)
+                .chain(state.currencies.keys().copied())
+                .map(|currency| {
+                    let balance = state.balance(currency);
+                    (currency, balance.available, balance.held, balance.total)
+                })
+                .collect()
+        };
+        let open_disputes =
+            |state: &ClientState| -> HashSet<u32> { state.open_disputes().collect() };
+        if (funds(compacted), compacted.locked) != (funds(state), state.locked)
+            || open_disputes(compacted) != open_disputes(state)
+        {
+            return Err(mismatch(*client_id).into());
+        }
+
+        for tx_id in state.open_disputes() {
+            let original = engine.disputable_transactions.get(tx_id)?;
+            let compacted = replayed.disputable_transactions.get(tx_id)?;
+            let describe = |tx: Option<DisputableTx>| {
+                tx.map(|tx| (tx.r#type(), tx.client_id, tx.amount, tx.currency))
+            };
+            if describe(original) != describe(compacted) {
+                return Err(mismatch(*client_id).into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a compacted log of `engine`'s state to `path`, returning the number of transactions
+/// written. The log is verified before it's renamed into place, so `path` is only ever replaced by
+/// a log which reproduces the engine's state.
+pub fn write_file(engine: &Engine, path: &Path) -> Result<usize, Box<dyn Error>> {
+    let mut file = AtomicFile::create(path)?;
+    let rows = engine.write_compacted(io::BufWriter::new(&mut file))?;
+    verify(engine, file.temp_path())?;
+    file.commit()?;
+
+    Ok(rows)
+}
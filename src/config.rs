@@ -0,0 +1,365 @@
+/// Engine configuration, for embedders and operators.
+///
+/// `EngineConfig` collects everything about how an engine behaves (policies, limits, rounding,
+/// fees, and so on) in one value, built up with the same `with_*` methods as `Engine`, so it can be
+/// kept, passed around, and turned into any number of identically configured engines. It can also
+/// be read from a TOML file whose keys are named after the command line flags:
+///
+/// ```toml
+/// duplicate-tx-policy = "reject"
+/// withdrawal-disputes = "ignore"
+/// redisputes = "once"
+/// unknown-type-policy = "reject"
+/// locked-accounts = "queue"
+/// strict = false
+/// allow-admin-ops = true
+/// dedup = true
+/// dispute-window = "30d"
+/// max-open-disputes = 3
+/// check-invariants = "balances"
+/// decimal-places = 2
+/// rounding = "half-up"
+/// max-memory = "512M"
+/// fees = "fees.toml"
+/// rates = "rates.csv"
+/// limits = "limits.csv"
+/// credit-limit = "100"
+///
+/// [schema]
+/// type = "txn_type"
+/// client = "cust_id"
+/// ```
+///
+/// Every key is optional, and relative paths are relative to the config file. `--config <path>`
+/// reads the same file on the command line, where flags override it. The `[schema]` table maps CSV
+/// columns to transaction fields (see `input::Schema`), so it's only used to read logs.
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::fees::{self, FeeSchedule};
+use crate::input::Schema;
+use crate::invariants::InvariantCheck;
+use crate::limits::{self, CreditLimits};
+use crate::money::{Money, Precision, Rounding};
+use crate::rates::{self, RateTable};
+use crate::store::parse_size;
+use crate::{
+    DisputeWindow, DuplicateTxPolicy, Engine, LockedAccountPolicy, RedisputePolicy,
+    UnknownTypePolicy, WithdrawalDisputePolicy,
+};
+
+/// How engines are configured. Engines built from the default configuration are the same as
+/// `Engine::new()`.
+#[derive(Debug, Default, Clone)]
+pub struct EngineConfig {
+    duplicate_tx_policy: DuplicateTxPolicy,
+    withdrawal_dispute_policy: WithdrawalDisputePolicy,
+    redispute_policy: RedisputePolicy,
+    unknown_type_policy: UnknownTypePolicy,
+    locked_account_policy: LockedAccountPolicy,
+    strict: bool,
+    allow_admin_ops: bool,
+    dedup: bool,
+    dispute_window: Option<DisputeWindow>,
+    max_open_disputes: Option<usize>,
+    invariant_check: Option<InvariantCheck>,
+    precision: Precision,
+    memory_limit: Option<usize>,
+    fees: Option<FeeSchedule>,
+    rates: Option<RateTable>,
+    credit_limits: Option<CreditLimits>,
+}
+
+impl EngineConfig {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Read a configuration from a TOML file (see the module documentation), along with the fee
+    /// schedule, rates, and credit limits it refers to.
+    pub fn read_file(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = ConfigFile::read(path)?;
+        let mut config = EngineConfig::new()
+            .with_strict(file.strict.unwrap_or_default())
+            .with_admin_ops(file.allow_admin_ops.unwrap_or_default());
+        if file.dedup == Some(true) {
+            config = config.with_dedup();
+        }
+        if let Some(policy) = file.duplicate_tx_policy {
+            config = config.with_duplicate_tx_policy(policy);
+        }
+        if let Some(policy) = file.withdrawal_disputes {
+            config = config.with_withdrawal_dispute_policy(policy);
+        }
+        if let Some(policy) = file.redisputes {
+            config = config.with_redispute_policy(policy);
+        }
+        if let Some(policy) = file.unknown_type_policy {
+            config = config.with_unknown_type_policy(policy);
+        }
+        if let Some(policy) = file.locked_accounts {
+            config = config.with_locked_account_policy(policy);
+        }
+        if let Some(window) = file.dispute_window {
+            config = config.with_dispute_window(window);
+        }
+        if let Some(max) = file.max_open_disputes {
+            config = config.with_max_open_disputes(max);
+        }
+        if let Some(check) = file.check_invariants {
+            config = config.with_invariant_checks(check);
+        }
+        if let Some(bytes) = file.max_memory {
+            config = config.with_memory_limit(bytes);
+        }
+        config = config.with_precision(file.precision()?);
+        if let Some(path) = &file.fees {
+            config = config.with_fees(
+                fees::read_file(Path::new(path))
+                    .map_err(|e| format!("couldn't read fee schedule: {}", e))?,
+            );
+        }
+        if let Some(path) = &file.rates {
+            config = config.with_rates(
+                rates::read_file(path)
+                    .map_err(|e| format!("couldn't read exchange rates: {}", e))?,
+            );
+        }
+        if file.limits.is_some() || file.credit_limit.is_some() {
+            let default = file.credit_limit.unwrap_or(Money::ZERO);
+            config = config.with_credit_limits(match &file.limits {
+                Some(path) => limits::read_file(path, default)
+                    .map_err(|e| format!("couldn't read credit limits: {}", e))?,
+                None => CreditLimits::new(default),
+            });
+        }
+
+        Ok(config)
+    }
+
+    pub fn with_duplicate_tx_policy(mut self, policy: DuplicateTxPolicy) -> Self {
+        self.duplicate_tx_policy = policy;
+        self
+    }
+
+    pub fn with_withdrawal_dispute_policy(mut self, policy: WithdrawalDisputePolicy) -> Self {
+        self.withdrawal_dispute_policy = policy;
+        self
+    }
+
+    pub fn with_redispute_policy(mut self, policy: RedisputePolicy) -> Self {
+        self.redispute_policy = policy;
+        self
+    }
+
+    pub fn with_unknown_type_policy(mut self, policy: UnknownTypePolicy) -> Self {
+        self.unknown_type_policy = policy;
+        self
+    }
+
+    pub fn with_locked_account_policy(mut self, policy: LockedAccountPolicy) -> Self {
+        self.locked_account_policy = policy;
+        self
+    }
+
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn with_admin_ops(mut self, allow_admin_ops: bool) -> Self {
+        self.allow_admin_ops = allow_admin_ops;
+        self
+    }
+
+    pub fn with_dedup(mut self) -> Self {
+        self.dedup = true;
+        self
+    }
+
+    pub fn with_dispute_window(mut self, window: DisputeWindow) -> Self {
+        self.dispute_window = Some(window);
+        self
+    }
+
+    pub fn with_max_open_disputes(mut self, max: usize) -> Self {
+        self.max_open_disputes = Some(max);
+        self
+    }
+
+    pub fn with_invariant_checks(mut self, check: InvariantCheck) -> Self {
+        self.invariant_check = Some(check);
+        self
+    }
+
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Self {
+        self.fees = Some(fees);
+        self
+    }
+
+    pub fn with_rates(mut self, rates: RateTable) -> Self {
+        self.rates = Some(rates);
+        self
+    }
+
+    pub fn with_credit_limits(mut self, limits: CreditLimits) -> Self {
+        self.credit_limits = Some(limits);
+        self
+    }
+
+    /// A new engine configured like this (see the `Engine` methods of the same names).
+    pub fn build(&self) -> Engine {
+        let mut engine = Engine::new()
+            .with_duplicate_tx_policy(self.duplicate_tx_policy)
+            .with_withdrawal_dispute_policy(self.withdrawal_dispute_policy)
+            .with_redispute_policy(self.redispute_policy)
+            .with_unknown_type_policy(self.unknown_type_policy)
+            .with_locked_account_policy(self.locked_account_policy)
+            .with_strict(self.strict)
+            .with_admin_ops(self.allow_admin_ops)
+            .with_precision(self.precision);
+        if self.dedup {
+            engine = engine.with_dedup();
+        }
+        if let Some(window) = self.dispute_window {
+            engine = engine.with_dispute_window(window);
+        }
+        if let Some(max) = self.max_open_disputes {
+            engine = engine.with_max_open_disputes(max);
+        }
+        if let Some(check) = self.invariant_check {
+            engine = engine.with_invariant_checks(check);
+        }
+        if let Some(bytes) = self.memory_limit {
+            engine = engine.with_memory_limit(bytes);
+        }
+        if let Some(fees) = &self.fees {
+            engine = engine.with_fees(fees.clone());
+        }
+        if let Some(rates) = &self.rates {
+            engine = engine.with_rates(rates.clone());
+        }
+        if let Some(limits) = &self.credit_limits {
+            engine = engine.with_credit_limits(limits.clone());
+        }
+
+        engine
+    }
+}
+
+/// The settings in a config file, as given (other than relative paths, which are resolved). Keys
+/// which aren't set are left to defaults, or to command line flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default, deserialize_with = "parsed")]
+    pub duplicate_tx_policy: Option<DuplicateTxPolicy>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub withdrawal_disputes: Option<WithdrawalDisputePolicy>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub redisputes: Option<RedisputePolicy>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub unknown_type_policy: Option<UnknownTypePolicy>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub locked_accounts: Option<LockedAccountPolicy>,
+    pub strict: Option<bool>,
+    pub allow_admin_ops: Option<bool>,
+    pub dedup: Option<bool>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub dispute_window: Option<DisputeWindow>,
+    pub max_open_disputes: Option<usize>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub check_invariants: Option<InvariantCheck>,
+    pub decimal_places: Option<u32>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub rounding: Option<Rounding>,
+    #[serde(default, deserialize_with = "parsed_size")]
+    pub max_memory: Option<usize>,
+    pub fees: Option<String>,
+    pub rates: Option<String>,
+    pub limits: Option<String>,
+    #[serde(default, deserialize_with = "parsed_limit")]
+    pub credit_limit: Option<Money>,
+    /// Columns of CSV logs, rather than anything about the engine.
+    pub schema: Option<Schema>,
+}
+
+impl ConfigFile {
+    /// Parse config file settings from TOML, resolving relative paths against `dir`.
+    pub fn from_toml(s: &str, dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut file: ConfigFile = toml::from_str(s)?;
+        if file.max_open_disputes == Some(0) {
+            return Err("max-open-disputes must be positive".into());
+        }
+        file.precision()?;
+        for path in [&mut file.fees, &mut file.rates, &mut file.limits]
+            .iter_mut()
+            .flat_map(|path| path.as_mut())
+        {
+            *path = dir.join(&path).to_string_lossy().into_owned();
+        }
+
+        Ok(file)
+    }
+
+    /// Read config file settings from a TOML file.
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let toml = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read config {}: {}", path.display(), e))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        ConfigFile::from_toml(&toml, dir)
+            .map_err(|e| format!("invalid config {}: {}", path.display(), e).into())
+    }
+
+    /// The precision set by `decimal-places` and `rounding`, each defaulting to `Precision`'s.
+    pub fn precision(&self) -> Result<Precision, String> {
+        let default = Precision::default();
+        Precision::new(
+            self.decimal_places.unwrap_or(default.decimal_places()),
+            self.rounding.unwrap_or(default.rounding()),
+        )
+    }
+}
+
+/// Deserialize an optional setting from a string, as it would be parsed from the command line.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+fn parsed_size<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_size(&s).map(Some).map_err(serde::de::Error::custom)
+}
+
+fn parsed_limit<'de, D>(deserializer: D) -> Result<Option<Money>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    limits::parse_limit(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
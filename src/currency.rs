@@ -0,0 +1,115 @@
+/// Currencies, and the funds a client holds in each of them.
+///
+/// Transactions without a currency are in a single implicit currency, so inputs which don't mention
+/// currencies are processed exactly as they always were. Any other currency is a three letter code
+/// (e.g. `USD`), and each client has separate funds in every currency it has transacted in.
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::money::Money;
+
+/// A three letter currency code, or the implicit currency (which has an empty code).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    /// The currency of transactions which don't specify one.
+    pub const IMPLICIT: Currency = Currency([0; 3]);
+
+    pub fn is_implicit(&self) -> bool {
+        *self == Currency::IMPLICIT
+    }
+
+    /// The currency's code as bytes, with trailing zeroes for the implicit currency.
+    pub(crate) fn to_bytes(self) -> [u8; 3] {
+        self.0
+    }
+
+    /// Inverse of `to_bytes`.
+    pub(crate) fn from_bytes(bytes: [u8; 3]) -> Self {
+        Currency(bytes)
+    }
+
+    /// Parse a code from bytes. Codes are case insensitive, and an empty code is the implicit
+    /// currency.
+    pub fn parse(code: &[u8]) -> Option<Self> {
+        match code {
+            [] => Some(Currency::IMPLICIT),
+            [a, b, c] if code.iter().all(u8::is_ascii_alphabetic) => Some(Currency([
+                a.to_ascii_uppercase(),
+                b.to_ascii_uppercase(),
+                c.to_ascii_uppercase(),
+            ])),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Currency::parse(s.as_bytes()).ok_or_else(|| {
+            format!(
+                "invalid currency '{}', expected a three letter code (e.g. USD)",
+                s
+            )
+        })
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_implicit() {
+            return Ok(());
+        }
+        // Codes are only ever built from ASCII letters.
+        f.write_str(std::str::from_utf8(&self.0).map_err(|_| fmt::Error)?)
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        code.trim().parse().map_err(D::Error::custom)
+    }
+}
+
+/// Deserialize a currency which may be missing (as the implicit currency). CSV only allows fields
+/// missing from short rows when they're deserialized as an `Option`.
+pub fn deserialize_optional<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Currency, D::Error> {
+    Ok(Option::<Currency>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// A client's funds in a single currency.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    /// Fees charged in this currency so far, if the engine charges fees.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fees_collected: Option<Money>,
+}
+
+impl Balance {
+    pub(crate) fn new(charges_fees: bool) -> Self {
+        Balance {
+            available: Money::ZERO,
+            held: Money::ZERO,
+            total: Money::ZERO,
+            fees_collected: charges_fees.then_some(Money::ZERO),
+        }
+    }
+}
@@ -0,0 +1,91 @@
+/// Transparent decompression of compressed transaction logs (`.gz` with the `gzip` feature, and
+/// `.zst` with the `zstd` feature).
+///
+/// Archives are decoded in-process as they're read, so a multi-gigabyte archive never has to be
+/// decompressed to disk first. An archive which can't be decoded (e.g. one which is truncated or
+/// corrupt) is an error naming the archive, rather than looking like the end of the log.
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// A compression format, recognized by file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// The compression `path` uses, going by its extension, if any.
+    pub fn of(path: &str) -> Option<Self> {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Some(Compression::Gzip),
+            Some("zst") => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Whether support for this format was built in.
+    pub fn is_enabled(self) -> bool {
+        match self {
+            Compression::Gzip => cfg!(feature = "gzip"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    fn feature(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+/// Open the archive at `path` for reading its decompressed contents.
+pub fn open(path: &str, compression: Compression) -> io::Result<Decompressed> {
+    Ok(Decompressed {
+        decoder: decoder(path, compression)?,
+        path: path.to_string(),
+    })
+}
+
+fn decoder(path: &str, compression: Compression) -> io::Result<Box<dyn Read>> {
+    match compression {
+        // Archives written by concatenating gzip files (e.g. rotated logs) have several members.
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => Ok(Box::new(flate2::read::MultiGzDecoder::new(File::open(
+            path,
+        )?))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(File::open(
+            path,
+        )?)?)),
+        #[allow(unreachable_patterns)]
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} is compressed, which requires the {} feature",
+                path,
+                compression.feature()
+            ),
+        )),
+    }
+}
+
+/// The decompressed contents of an archive, as they're decoded.
+pub struct Decompressed {
+    decoder: Box<dyn Read>,
+    path: String,
+}
+
+impl Read for Decompressed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("couldn't decompress {}: {}", self.path, e),
+            )
+        })
+    }
+}
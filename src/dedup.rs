@@ -0,0 +1,211 @@
+/// Remembering which transactions have been processed, so replaying overlapping input (e.g. after
+/// a partial failure) doesn't apply any deposit, withdrawal, or conversion twice.
+///
+/// Each client's processed transaction IDs are kept in a compressed bitmap in the style of roaring
+/// bitmaps. IDs are split by their high 16 bits into containers, and each container holds the low
+/// 16 bits of its IDs either as a sorted array (while it holds at most `ARRAY_MAX` IDs, so sparse
+/// IDs take 2 bytes each) or as a bitmap of all 65536 (8KiB, so dense IDs take a bit each). Arrays
+/// are converted to bitmaps as they fill up, and IDs are never removed.
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+
+/// Most IDs held in an array container. At this size the array is as large as a bitmap.
+const ARRAY_MAX: usize = 4096;
+/// Number of words in a bitmap container.
+const BITMAP_WORDS: usize = 1 << 16 >> 6;
+
+/// The low 16 bits of the IDs sharing some high 16 bits.
+#[derive(Debug, Clone)]
+enum Container {
+    /// Sorted, with no duplicates.
+    Array(Vec<u16>),
+    Bitmap(Box<[u64; BITMAP_WORDS]>),
+}
+
+impl Container {
+    fn insert(&mut self, low: u16) -> bool {
+        match self {
+            Container::Array(lows) => match lows.binary_search(&low) {
+                Ok(_) => false,
+                Err(i) if lows.len() < ARRAY_MAX => {
+                    lows.insert(i, low);
+                    true
+                }
+                Err(_) => {
+                    let mut words = Box::new([0; BITMAP_WORDS]);
+                    for &low in lows.iter() {
+                        words[usize::from(low) >> 6] |= 1 << (low & 63);
+                    }
+                    *self = Container::Bitmap(words);
+                    self.insert(low)
+                }
+            },
+            Container::Bitmap(words) => {
+                let (word, bit) = (usize::from(low) >> 6, 1 << (low & 63));
+                let inserted = words[word] & bit == 0;
+                words[word] |= bit;
+                inserted
+            }
+        }
+    }
+
+    fn contains(&self, low: u16) -> bool {
+        match self {
+            Container::Array(lows) => lows.binary_search(&low).is_ok(),
+            Container::Bitmap(words) => words[usize::from(low) >> 6] & (1 << (low & 63)) != 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Container::Array(lows) => lows.len(),
+            Container::Bitmap(words) => words.iter().map(|word| word.count_ones() as usize).sum(),
+        }
+    }
+
+    /// Every low 16 bits held, in order.
+    fn lows(&self) -> Box<dyn Iterator<Item = u16> + '_> {
+        match self {
+            Container::Array(lows) => Box::new(lows.iter().copied()),
+            Container::Bitmap(words) => Box::new(
+                (0..=u16::MAX)
+                    .filter(move |&low| words[usize::from(low) >> 6] & (1 << (low & 63)) != 0),
+            ),
+        }
+    }
+
+    /// Rough memory used by the container's contents.
+    fn heap_size(&self) -> usize {
+        match self {
+            Container::Array(lows) => lows.capacity() * mem::size_of::<u16>(),
+            Container::Bitmap(_) => BITMAP_WORDS * mem::size_of::<u64>(),
+        }
+    }
+}
+
+/// A compressed set of transaction IDs.
+#[derive(Debug, Default, Clone)]
+pub struct IdBitmap {
+    containers: BTreeMap<u16, Container>,
+}
+
+impl IdBitmap {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add an ID, returning whether it wasn't already in the set.
+    pub fn insert(&mut self, id: u32) -> bool {
+        self.containers
+            .entry((id >> 16) as u16)
+            .or_insert_with(|| Container::Array(Vec::new()))
+            .insert(id as u16)
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.containers
+            .get(&((id >> 16) as u16))
+            .is_some_and(|container| container.contains(id as u16))
+    }
+
+    /// Number of IDs in the set.
+    pub fn len(&self) -> u64 {
+        self.containers
+            .values()
+            .map(|container| container.len() as u64)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.containers.is_empty()
+    }
+
+    /// Every ID in the set, in order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.containers.iter().flat_map(|(&high, container)| {
+            container
+                .lows()
+                .map(move |low| u32::from(high) << 16 | u32::from(low))
+        })
+    }
+
+    /// The set as runs of consecutive IDs (first and last ID of each, inclusive), in order. Runs are
+    /// a compact way to write the IDs of a log, which mostly increase one at a time.
+    pub fn ranges(&self) -> Vec<(u32, u32)> {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for id in self.iter() {
+            match ranges.last_mut() {
+                Some((_, last)) if *last + 1 == id => *last = id,
+                _ => ranges.push((id, id)),
+            }
+        }
+
+        ranges
+    }
+
+    /// Rough memory used by the set.
+    pub fn heap_size(&self) -> usize {
+        self.containers
+            .values()
+            .map(|container| mem::size_of::<(u16, Container)>() + container.heap_size())
+            .sum()
+    }
+}
+
+/// The deposits, withdrawals, and conversions processed so far, by client and transaction ID.
+#[derive(Debug, Default, Clone)]
+pub struct ProcessedSet {
+    clients: HashMap<u16, IdBitmap>,
+}
+
+impl ProcessedSet {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record a transaction as processed, returning whether it wasn't already.
+    pub fn insert(&mut self, client_id: u16, tx_id: u32) -> bool {
+        self.clients.entry(client_id).or_default().insert(tx_id)
+    }
+
+    pub fn contains(&self, client_id: u16, tx_id: u32) -> bool {
+        self.clients
+            .get(&client_id)
+            .is_some_and(|ids| ids.contains(tx_id))
+    }
+
+    /// Number of transactions recorded.
+    pub fn len(&self) -> u64 {
+        self.clients.values().map(IdBitmap::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Each client's processed transaction IDs, in no particular order.
+    pub fn clients(&self) -> impl Iterator<Item = (u16, &IdBitmap)> {
+        self.clients
+            .iter()
+            .map(|(&client_id, ids)| (client_id, ids))
+    }
+
+    /// Record every transaction in some runs of IDs (see `IdBitmap::ranges`) as processed for a
+    /// client.
+    pub fn insert_ranges(&mut self, client_id: u16, ranges: &[(u32, u32)]) {
+        let ids = self.clients.entry(client_id).or_default();
+        for &(first, last) in ranges {
+            for id in first..=last {
+                ids.insert(id);
+            }
+        }
+    }
+
+    /// Rough memory used by the set.
+    pub fn heap_size(&self) -> usize {
+        self.clients
+            .values()
+            .map(|ids| mem::size_of::<(u16, IdBitmap)>() + ids.heap_size())
+            .sum()
+    }
+}
@@ -0,0 +1,227 @@
+/// Tamper-evident digests of the outputs of a processing run.
+///
+/// A digest records the SHA-256 hash of every artifact written by a batch (the balance export, and
+/// the rejects log if there is one), and is signed with HMAC-SHA256 using a key taken from the
+/// environment. Verifying a digest later checks the signature, then recomputes the hash of every
+/// archived artifact, so changes to either the digest or the artifacts are detected.
+///
+/// Digests are plain text:
+///
+/// ```text
+/// payment-engine audit digest v1
+/// created 1650000000
+/// sha256 <hex> <artifact>
+/// ...
+/// hmac-sha256 <hex>
+/// ```
+///
+/// where the HMAC covers every line before it.
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest as _, Sha256};
+
+/// Environment variable holding the key used to sign and verify digests.
+pub const KEY_ENV_VAR: &str = "PAYMENT_ENGINE_DIGEST_KEY";
+
+/// Name of the artifact for balances written to stdout.
+pub const STDOUT_ARTIFACT: &str = "stdout";
+
+const HEADER: &str = "payment-engine audit digest v1";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Read the signing key from the environment.
+pub fn key_from_env() -> Result<Vec<u8>, String> {
+    match std::env::var(KEY_ENV_VAR) {
+        Ok(key) if !key.is_empty() => Ok(key.into_bytes()),
+        _ => Err(format!(
+            "{} must be set to sign or verify digests",
+            KEY_ENV_VAR
+        )),
+    }
+}
+
+/// Passes writes through to some other writer, hashing everything written. Used for artifacts
+/// which can't be read back once written (i.e. stdout).
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Rc<RefCell<Sha256>>,
+}
+
+/// The hash of everything written through a `HashingWriter` so far.
+pub struct WriterHash(Rc<RefCell<Sha256>>);
+
+impl WriterHash {
+    pub fn finish(&self) -> [u8; 32] {
+        self.0.borrow().clone().finalize().into()
+    }
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> (Self, WriterHash) {
+        let hasher = Rc::new(RefCell::new(Sha256::new()));
+
+        (
+            HashingWriter {
+                inner,
+                hasher: hasher.clone(),
+            },
+            WriterHash(hasher),
+        )
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.borrow_mut().update(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// SHA-256 hash of the contents of some file.
+pub fn hash_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 8192];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+/// Hashes of every artifact written by a single batch.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AuditDigest {
+    /// When the batch finished, in seconds since the Unix epoch.
+    pub created: u64,
+    /// Artifact names (paths, or `STDOUT_ARTIFACT`) and their hashes.
+    pub artifacts: Vec<(String, [u8; 32])>,
+}
+
+impl AuditDigest {
+    /// The signed portion of the digest.
+    fn body(&self) -> String {
+        let mut body = format!("{}\ncreated {}\n", HEADER, self.created);
+        for (name, hash) in &self.artifacts {
+            body.push_str(&format!("sha256 {} {}\n", to_hex(hash), name));
+        }
+
+        body
+    }
+
+    /// Write the digest, signed with `key`.
+    pub fn write(&self, path: &Path, key: &[u8]) -> io::Result<()> {
+        let body = self.body();
+        let mut file = File::create(path)?;
+        file.write_all(body.as_bytes())?;
+        writeln!(file, "hmac-sha256 {}", to_hex(&sign(key, &body)))?;
+        file.sync_all()
+    }
+
+    /// Read a digest, checking that it was signed with `key` and hasn't been altered.
+    pub fn read(path: &Path, key: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        let signature_start = contents
+            .rfind("hmac-sha256 ")
+            .ok_or("digest isn't signed")?;
+        let (body, signature) = contents.split_at(signature_start);
+        let signature = from_hex(signature["hmac-sha256 ".len()..].trim_end())
+            .ok_or("digest signature is malformed")?;
+
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(body.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| "digest signature doesn't match, digest was altered or key is wrong")?;
+
+        let mut lines = body.lines();
+        if lines.next() != Some(HEADER) {
+            return Err("unrecognized digest format".into());
+        }
+        let created = lines
+            .next()
+            .and_then(|line| line.strip_prefix("created "))
+            .and_then(|created| created.parse().ok())
+            .ok_or("digest is missing its creation time")?;
+        let mut artifacts = Vec::new();
+        for line in lines {
+            let mut fields = line.splitn(3, ' ');
+            match (
+                fields.next(),
+                fields.next().and_then(from_hex),
+                fields.next(),
+            ) {
+                (Some("sha256"), Some(hash), Some(name)) if hash.len() == 32 => {
+                    let mut bytes = [0; 32];
+                    bytes.copy_from_slice(&hash);
+                    artifacts.push((name.to_string(), bytes));
+                }
+                _ => return Err(format!("malformed digest line: {}", line).into()),
+            }
+        }
+
+        Ok(AuditDigest { created, artifacts })
+    }
+
+    /// Recompute the hash of every artifact, returning the names of any which don't match. Artifacts
+    /// are read from the path they were written to, unless `locate` provides another path (e.g.
+    /// where stdout was archived).
+    pub fn verify<F>(&self, locate: F) -> Result<Vec<String>, Box<dyn Error>>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut mismatched = Vec::new();
+        for (name, hash) in &self.artifacts {
+            let path = match locate(name) {
+                Some(path) => path,
+                None if name == STDOUT_ARTIFACT => {
+                    return Err("balances were written to stdout, provide the archived copy".into())
+                }
+                None => name.clone(),
+            };
+            if hash_file(Path::new(&path))? != *hash {
+                mismatched.push(name.clone());
+            }
+        }
+
+        Ok(mismatched)
+    }
+}
+
+fn sign(key: &[u8], body: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
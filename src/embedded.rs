@@ -0,0 +1,118 @@
+/// Embedding the engine in a host application (requires the `tokio` feature).
+///
+/// `spawn_engine` runs an engine on a thread of its own, fed through a bounded channel, so a host
+/// can submit transactions from async tasks (`send`) or threads (`blocking_send`) without calling
+/// into the engine itself. A full channel makes submitters wait, which pushes back on whatever is
+/// feeding them. The engine's progress is published to a `watch` channel as it goes, and its final
+/// client states are sent once every sender has been dropped.
+use std::collections::HashMap;
+use std::error::Error;
+use std::thread;
+
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::error::TransactionError;
+use crate::{fatal_error, report_released, ClientState, Engine, Transaction};
+
+/// Number of transactions applied between publishing stats while more are waiting. Stats are also
+/// published whenever the engine catches up with its senders.
+const STATS_INTERVAL: u64 = 1024;
+
+/// The result of an embedded engine's run: the final client states, or why it stopped early.
+pub type EngineResult = Result<HashMap<u16, ClientState>, Box<dyn Error + Send + Sync>>;
+
+/// Progress of an embedded engine.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EngineStats {
+    /// Transactions received, including rejected ones.
+    pub received: u64,
+    /// Transactions rejected (including held transactions rejected once their account was
+    /// unlocked).
+    pub rejected: u64,
+    /// Transactions held for locked accounts.
+    pub queued: usize,
+    /// Number of clients seen.
+    pub clients: usize,
+}
+
+/// Run `engine` on a background thread, accepting transactions through a channel holding up to
+/// `capacity` of them. Rejected transactions are passed to `on_reject`, as with
+/// `process_transactions`.
+///
+/// Returns the sender for transactions, a receiver for the engine's stats, and a receiver for the
+/// result, which is sent once every sender has been dropped. If the engine stops early (a fatal
+/// rejection, or `on_reject` returning an error), it stops receiving, so sends fail, and the result
+/// is the error.
+pub fn spawn_engine<F>(
+    mut engine: Engine,
+    capacity: usize,
+    mut on_reject: F,
+) -> (
+    mpsc::Sender<Transaction>,
+    watch::Receiver<EngineStats>,
+    oneshot::Receiver<EngineResult>,
+)
+where
+    F: FnMut(&Transaction, &TransactionError) -> Result<(), Box<dyn Error>> + Send + 'static,
+{
+    let (sender, mut transactions) = mpsc::channel(capacity);
+    let (stats_sender, stats) = watch::channel(EngineStats::default());
+    let (result_sender, result) = oneshot::channel();
+
+    thread::spawn(move || {
+        let mut stats = EngineStats::default();
+        let mut outcome = Ok(());
+
+        while let Some(tx) = transactions.blocking_recv() {
+            stats.received += 1;
+            outcome = apply(&mut engine, &tx, &mut stats, &mut on_reject);
+            if outcome.is_err() {
+                break;
+            }
+            if stats.received % STATS_INTERVAL == 0 || transactions.is_empty() {
+                publish(&engine, &mut stats, &stats_sender);
+            }
+        }
+        // Stop accepting transactions before the result is sent, so senders can't mistake an
+        // engine which has stopped for one which is only busy.
+        drop(transactions);
+        publish(&engine, &mut stats, &stats_sender);
+
+        let result = outcome
+            .map(|()| engine.into_client_states())
+            .map_err(|e| e.to_string().into());
+        // The host may not be waiting for the result.
+        let _ = result_sender.send(result);
+    });
+
+    (sender, stats, result)
+}
+
+/// Apply a single transaction, counting rejections (including any released ones) in `stats`.
+fn apply<F>(
+    engine: &mut Engine,
+    tx: &Transaction,
+    stats: &mut EngineStats,
+    on_reject: &mut F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(&Transaction, &TransactionError) -> Result<(), Box<dyn Error>>,
+{
+    if let Err(e) = engine.apply(tx) {
+        if engine.is_fatal(&e) {
+            return Err(fatal_error(tx, e));
+        }
+        stats.rejected += 1;
+        on_reject(tx, &e)?;
+    }
+    report_released(engine, &mut |tx: &Transaction, e: &TransactionError| {
+        stats.rejected += 1;
+        on_reject(tx, e)
+    })
+}
+
+fn publish(engine: &Engine, stats: &mut EngineStats, sender: &watch::Sender<EngineStats>) {
+    stats.queued = engine.queued_count();
+    stats.clients = engine.client_states().len();
+    sender.send_replace(stats.clone());
+}
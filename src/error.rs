@@ -0,0 +1,284 @@
+/// Errors which can occur while applying individual transactions.
+///
+/// Most `TransactionError`s only describe why a single transaction had no effect on client account
+/// states. Whether one aborts processing is up to the engine (see `Engine::is_fatal`).
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionError {
+    /// A withdrawal was larger than the client's available funds.
+    InsufficientFunds { client_id: u16, tx_id: u32 },
+    /// The client's account is locked/frozen, so no further transactions apply.
+    AccountLocked { client_id: u16, tx_id: u32 },
+    /// A dispute, resolve, or chargeback referenced a transaction which hasn't occurred (or can't
+    /// be disputed).
+    UnknownTx { client_id: u16, tx_id: u32 },
+    /// A deposit or withdrawal reused the ID of an earlier deposit or withdrawal.
+    DuplicateTxId { client_id: u16, tx_id: u32 },
+    /// A deposit or withdrawal didn't include an amount.
+    MissingAmount { client_id: u16, tx_id: u32 },
+    /// A deposit or withdrawal had a negative amount.
+    NegativeAmount { client_id: u16, tx_id: u32 },
+    /// A deposit or withdrawal had an amount of zero (after rounding).
+    ZeroAmount { client_id: u16, tx_id: u32 },
+    /// An amount had more decimal places than the engine keeps, and amounts must be exact.
+    TooManyDecimalPlaces { client_id: u16, tx_id: u32 },
+    /// A dispute referenced a transaction which is already under dispute.
+    AlreadyDisputed { client_id: u16, tx_id: u32 },
+    /// A dispute referenced a transaction which has already been charged back.
+    AlreadyChargedBack { client_id: u16, tx_id: u32 },
+    /// A dispute referenced a transaction which was already disputed and resolved, and each
+    /// transaction can only be disputed once.
+    AlreadyResolved { client_id: u16, tx_id: u32 },
+    /// A resolve or chargeback referenced a transaction which isn't under dispute.
+    NotDisputed { client_id: u16, tx_id: u32 },
+    /// A dispute referenced a withdrawal, and withdrawal disputes are configured to have no effect.
+    WithdrawalDisputeIgnored { client_id: u16, tx_id: u32 },
+    /// A dispute, resolve, or chargeback was in a different currency to the transaction it
+    /// referenced.
+    CurrencyMismatch { client_id: u16, tx_id: u32 },
+    /// A conversion was between currencies the engine has no rate for.
+    UnknownRate { client_id: u16, tx_id: u32 },
+    /// An administrative transaction (e.g. an unlock) was given, and admin ops aren't allowed.
+    AdminOpsDisabled { client_id: u16, tx_id: u32 },
+    /// An unlock referenced an account which isn't locked.
+    NotLocked { client_id: u16, tx_id: u32 },
+    /// A transaction had a type the engine doesn't know.
+    UnknownType { client_id: u16, tx_id: u32 },
+    /// A dispute referenced a transaction from outside the dispute window.
+    DisputeExpired { client_id: u16, tx_id: u32 },
+    /// A dispute was made by a client who already has as many open disputes as are allowed.
+    TooManyDisputes { client_id: u16, tx_id: u32 },
+    /// An amount, or the funds a transaction would leave, was beyond `Money::MAX`.
+    Overflow { client_id: u16, tx_id: u32 },
+    /// A transaction left its client's funds breaking an invariant (see `invariants`). Always
+    /// stops processing.
+    InvariantViolated {
+        client_id: u16,
+        tx_id: u32,
+        message: String,
+    },
+    /// A custom transaction type's handler rejected a transaction (see `handler`), for `reason`
+    /// (a short identifier, like those of built-in errors).
+    Rejected {
+        client_id: u16,
+        tx_id: u32,
+        reason: &'static str,
+        message: String,
+    },
+    /// Transactions spilled to disk couldn't be written or read back. Always stops processing.
+    Storage {
+        client_id: u16,
+        tx_id: u32,
+        message: String,
+    },
+}
+
+impl TransactionError {
+    /// A short, stable identifier for the kind of error. Suitable for machine consumption (e.g. the
+    /// `reason` column of the rejected transaction log).
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TransactionError::InsufficientFunds { .. } => "insufficient_funds",
+            TransactionError::AccountLocked { .. } => "account_locked",
+            TransactionError::UnknownTx { .. } => "unknown_tx",
+            TransactionError::DuplicateTxId { .. } => "duplicate_tx_id",
+            TransactionError::MissingAmount { .. } => "missing_amount",
+            TransactionError::NegativeAmount { .. } => "negative_amount",
+            TransactionError::ZeroAmount { .. } => "zero_amount",
+            TransactionError::TooManyDecimalPlaces { .. } => "too_many_decimal_places",
+            TransactionError::AlreadyDisputed { .. } => "already_disputed",
+            TransactionError::AlreadyChargedBack { .. } => "already_charged_back",
+            TransactionError::AlreadyResolved { .. } => "already_resolved",
+            TransactionError::NotDisputed { .. } => "not_disputed",
+            TransactionError::WithdrawalDisputeIgnored { .. } => "withdrawal_dispute_ignored",
+            TransactionError::CurrencyMismatch { .. } => "currency_mismatch",
+            TransactionError::UnknownRate { .. } => "unknown_rate",
+            TransactionError::AdminOpsDisabled { .. } => "admin_ops_disabled",
+            TransactionError::NotLocked { .. } => "not_locked",
+            TransactionError::UnknownType { .. } => "unknown_type",
+            TransactionError::DisputeExpired { .. } => "dispute_expired",
+            TransactionError::TooManyDisputes { .. } => "too_many_disputes",
+            TransactionError::Overflow { .. } => "overflow",
+            TransactionError::InvariantViolated { .. } => "invariant_violated",
+            TransactionError::Rejected { reason, .. } => reason,
+            TransactionError::Storage { .. } => "storage",
+        }
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::InsufficientFunds { client_id, tx_id } => write!(
+                f,
+                "insufficient funds for client {} (tx {})",
+                client_id, tx_id
+            ),
+            TransactionError::AccountLocked { client_id, tx_id } => {
+                write!(
+                    f,
+                    "account for client {} is locked (tx {})",
+                    client_id, tx_id
+                )
+            }
+            TransactionError::UnknownTx { client_id, tx_id } => write!(
+                f,
+                "client {} referenced unknown transaction {}",
+                client_id, tx_id
+            ),
+            TransactionError::DuplicateTxId { client_id, tx_id } => {
+                write!(f, "client {} reused transaction ID {}", client_id, tx_id)
+            }
+            TransactionError::MissingAmount { client_id, tx_id } => write!(
+                f,
+                "transaction {} for client {} is missing an amount",
+                tx_id, client_id
+            ),
+            TransactionError::NegativeAmount { client_id, tx_id } => write!(
+                f,
+                "transaction {} for client {} has a negative amount",
+                tx_id, client_id
+            ),
+            TransactionError::ZeroAmount { client_id, tx_id } => write!(
+                f,
+                "transaction {} for client {} has an amount of zero",
+                tx_id, client_id
+            ),
+            TransactionError::TooManyDecimalPlaces { client_id, tx_id } => write!(
+                f,
+                "transaction {} for client {} has more decimal places than the ledger keeps",
+                tx_id, client_id
+            ),
+            TransactionError::AlreadyDisputed { client_id, tx_id } => write!(
+                f,
+                "transaction {} for client {} is already disputed",
+                tx_id, client_id
+            ),
+            TransactionError::AlreadyChargedBack { client_id, tx_id } => write!(
+                f,
+                "transaction {} for client {} was already charged back",
+                tx_id, client_id
+            ),
+            TransactionError::AlreadyResolved { client_id, tx_id } => write!(
+                f,
+                "transaction {} for client {} was already disputed and resolved",
+                tx_id, client_id
+            ),
+            TransactionError::NotDisputed { client_id, tx_id } => write!(
+                f,
+                "transaction {} for client {} is not disputed",
+                tx_id, client_id
+            ),
+            TransactionError::WithdrawalDisputeIgnored { client_id, tx_id } => write!(
+                f,
+                "client {} disputed withdrawal {}, withdrawal disputes are ignored",
+                client_id, tx_id
+            ),
+            TransactionError::CurrencyMismatch { client_id, tx_id } => write!(
+                f,
+                "client {} referenced transaction {} in a different currency",
+                client_id, tx_id
+            ),
+            TransactionError::UnknownRate { client_id, tx_id } => write!(
+                f,
+                "no rate for conversion {} by client {}",
+                tx_id, client_id
+            ),
+            TransactionError::AdminOpsDisabled { client_id, tx_id } => write!(
+                f,
+                "transaction {} for client {} is an admin op, which aren't allowed",
+                tx_id, client_id
+            ),
+            TransactionError::NotLocked { client_id, tx_id } => write!(
+                f,
+                "client {} unlocked an account which isn't locked (tx {})",
+                client_id, tx_id
+            ),
+            TransactionError::UnknownType { client_id, tx_id } => write!(
+                f,
+                "transaction {} for client {} has an unknown type",
+                tx_id, client_id
+            ),
+            TransactionError::DisputeExpired { client_id, tx_id } => write!(
+                f,
+                "client {} disputed transaction {} after its dispute window closed",
+                client_id, tx_id
+            ),
+            TransactionError::TooManyDisputes { client_id, tx_id } => write!(
+                f,
+                "client {} disputed transaction {} with too many disputes already open",
+                client_id, tx_id
+            ),
+            TransactionError::Overflow { client_id, tx_id } => write!(
+                f,
+                "transaction {} for client {} is beyond the largest supported amount",
+                tx_id, client_id
+            ),
+            TransactionError::InvariantViolated {
+                client_id,
+                tx_id,
+                message,
+            } => write!(
+                f,
+                "transaction {} for client {} broke a balance invariant: {}",
+                tx_id, client_id, message
+            ),
+            TransactionError::Rejected {
+                client_id,
+                tx_id,
+                message,
+                ..
+            } => write!(
+                f,
+                "transaction {} for client {} was rejected: {}",
+                tx_id, client_id, message
+            ),
+            TransactionError::Storage {
+                client_id,
+                tx_id,
+                message,
+            } => write!(
+                f,
+                "couldn't access stored transactions for client {} (tx {}): {}",
+                client_id, tx_id, message
+            ),
+        }
+    }
+}
+
+impl Error for TransactionError {}
+
+/// A row of a transaction log which couldn't be parsed, with where it was and what it held, so it
+/// can be found and fixed even in a huge log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Transaction log the row was read from, when several are read.
+    pub path: Option<String>,
+    /// Line the row starts on (1-based), if known.
+    pub line: Option<u64>,
+    /// Offset of the start of the row in bytes, if known.
+    pub byte: Option<u64>,
+    /// The row as it was read. CSV fields are trimmed and joined with commas, and bytes which
+    /// aren't UTF-8 are replaced.
+    pub record: String,
+    /// What was wrong with the row.
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}: ", path)?;
+        }
+        match (self.line, self.byte) {
+            (Some(line), Some(byte)) => write!(f, "line {} (byte {}): ", line, byte)?,
+            (Some(line), None) => write!(f, "line {}: ", line)?,
+            _ => {}
+        }
+        write!(f, "{}, in `{}`", self.message, self.record)
+    }
+}
+
+impl Error for ParseError {}
@@ -0,0 +1,161 @@
+/// A stream of balance change events, for systems which consume every change to client funds rather
+/// than the final balances (`--events`).
+///
+/// Every field of a client's funds which an applied transaction changes produces an event, as a
+/// line of JSON, in the order the changes are applied:
+///
+/// ```text
+/// {"client":1,"tx":7,"field":"available","delta":"-2.5000","new_value":"7.5000","cause":"dispute","version":4}
+/// {"client":1,"tx":7,"field":"held","delta":"2.5000","new_value":"2.5000","cause":"dispute","version":4}
+/// ```
+///
+/// `tx` is the transaction which made the change (a dispute, resolve, or chargeback rather than the
+/// transaction it references), `cause` is its type, and `version` is the client's version once it
+/// was applied (see `ClientState::version`). `currency` is only included for currencies other than
+/// the implicit one, and a conversion changes funds in both of its currencies. Fees are part of the
+/// change to `available`, and also change `fees_collected` when the engine charges fees. Rejected
+/// transactions don't change anything, and locking or unlocking an account doesn't change any
+/// funds, so neither produces events.
+///
+/// Events are reconstructed from each transaction's postings (see `posting`), and written as
+/// transactions are applied, so a run which fails part way through has already written the events
+/// of the transactions it applied.
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::currency::Currency;
+use crate::money::Money;
+use crate::posting::{self, Account, Posting};
+use crate::{ClientState, Engine, Transaction, TransactionType};
+
+/// A field of a client's funds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceField {
+    Available,
+    Held,
+    Total,
+    FeesCollected,
+}
+
+/// A change to one field of a client's funds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BalanceEvent {
+    pub client: u16,
+    pub tx: u32,
+    /// Currency of the funds, unless they're in the implicit currency.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub field: BalanceField,
+    pub delta: Money,
+    pub new_value: Money,
+    pub cause: TransactionType,
+    pub version: u64,
+}
+
+/// Somewhere balance change events can be sent, one at a time.
+pub trait EventSink: Send {
+    fn event(&mut self, event: &BalanceEvent) -> io::Result<()>;
+
+    /// Called once all events have been sent.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes events as newline delimited JSON.
+pub struct EventWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> EventWriter<W> {
+    pub fn new(writer: W) -> Self {
+        EventWriter { writer }
+    }
+}
+
+impl<W: Write + Send> EventSink for EventWriter<W> {
+    fn event(&mut self, event: &BalanceEvent) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// The engine's event sink, and the first error it returned. Events aren't sent once the sink has
+/// failed, as the stream would be missing some.
+pub(crate) struct EventLog {
+    sink: Box<dyn EventSink>,
+    error: Option<io::Error>,
+}
+
+impl EventLog {
+    /// Send an event for every field of the client's funds which the `postings` of `tx` changed,
+    /// now `state` is the state they left the client in.
+    pub(crate) fn record(&mut self, tx: &Transaction, postings: &[Posting], state: &ClientState) {
+        for currency in posting::currencies(postings) {
+            let available = posting::net(postings, Account::Available, currency);
+            let held = posting::net(postings, Account::Held, currency);
+            let balance = state.balance(currency);
+            let fields = [
+                (BalanceField::Available, available, balance.available),
+                (BalanceField::Held, held, balance.held),
+                (BalanceField::Total, available + held, balance.total),
+                (
+                    BalanceField::FeesCollected,
+                    posting::net(postings, Account::Fees, currency),
+                    balance.fees_collected.unwrap_or(Money::ZERO),
+                ),
+            ];
+            for (field, delta, new_value) in fields {
+                if self.error.is_some() {
+                    return;
+                }
+                if delta.is_zero() {
+                    continue;
+                }
+                let event = BalanceEvent {
+                    client: tx.client_id,
+                    tx: tx.tx_id,
+                    currency: (!currency.is_implicit()).then_some(currency),
+                    field,
+                    delta,
+                    new_value,
+                    cause: tx.r#type,
+                    version: state.version,
+                };
+                if let Err(e) = self.sink.event(&event) {
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+}
+
+impl Engine {
+    /// Send every change applied transactions make to client funds to `sink` from now on (see
+    /// `events`). Errors from the sink are returned by `finish_events`.
+    pub fn with_events<S: EventSink + 'static>(mut self, sink: S) -> Self {
+        self.events = Some(EventLog {
+            sink: Box::new(sink),
+            error: None,
+        });
+        self
+    }
+
+    /// Finish the event stream, returning the first error the sink returned, if any. Does nothing
+    /// if the engine isn't sending events.
+    pub fn finish_events(&mut self) -> io::Result<()> {
+        match self.events.as_mut() {
+            Some(events) => match events.error.take() {
+                Some(e) => Err(e),
+                None => events.sink.finish(),
+            },
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+/// Fees charged on deposits and withdrawals.
+///
+/// Fees are configured per transaction type in a TOML file, as a flat amount and/or a percentage of
+/// the transaction's amount:
+///
+/// ```toml
+/// [deposit]
+/// percent = 1.5
+///
+/// [withdrawal]
+/// flat = "0.50"
+/// percent = 0.25
+/// ```
+///
+/// A fee is taken from the client's available funds once its transaction has been applied, and a
+/// transaction whose fee can't be covered is rejected (as insufficient funds). Fees aren't
+/// refunded when a transaction is disputed, and disputes only ever hold the transaction's amount.
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::money::{Money, Precision};
+use crate::TransactionType;
+
+/// Fees for each transaction type which has an amount.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeSchedule {
+    #[serde(default)]
+    pub deposit: Fee,
+    #[serde(default)]
+    pub withdrawal: Fee,
+}
+
+/// The fee for a single transaction type.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fee {
+    /// Charged on every transaction.
+    #[serde(default)]
+    pub flat: Money,
+    /// Percentage of the transaction's amount (e.g. `1.5` is 1.5%).
+    #[serde(default)]
+    pub percent: Decimal,
+}
+
+impl Default for Fee {
+    fn default() -> Self {
+        Fee {
+            flat: Money::ZERO,
+            percent: Decimal::ZERO,
+        }
+    }
+}
+
+impl Fee {
+    /// The fee for a transaction of `amount`, rounded to `precision`.
+    pub fn charge(&self, amount: Money, precision: &Precision) -> Money {
+        let amount: Decimal = amount.into();
+        let flat: Decimal = self.flat.into();
+
+        precision.round(flat + amount * (self.percent / Decimal::ONE_HUNDRED))
+    }
+}
+
+impl FeeSchedule {
+    /// Parse a fee schedule from TOML. Fees can't be negative, and percentages can't be over 100.
+    pub fn from_toml(s: &str) -> Result<Self, Box<dyn Error>> {
+        let fees: FeeSchedule = toml::from_str(s)?;
+        for (name, fee) in [("deposit", &fees.deposit), ("withdrawal", &fees.withdrawal)] {
+            if fee.flat.is_sign_negative() || fee.percent.is_sign_negative() {
+                return Err(format!("{} fees can't be negative", name).into());
+            }
+            if fee.percent > Decimal::ONE_HUNDRED {
+                return Err(format!("{} fee percentage can't be over 100", name).into());
+            }
+        }
+
+        Ok(fees)
+    }
+
+    /// The fee for a transaction of some type and amount, rounded to `precision`. Only deposits
+    /// and withdrawals have fees.
+    pub fn fee(&self, r#type: TransactionType, amount: Money, precision: &Precision) -> Money {
+        match r#type {
+            TransactionType::Deposit => self.deposit.charge(amount, precision),
+            TransactionType::Withdrawal => self.withdrawal.charge(amount, precision),
+            _ => Money::ZERO,
+        }
+    }
+}
+
+/// Read a fee schedule from a TOML file.
+pub fn read_file(path: &Path) -> Result<FeeSchedule, Box<dyn Error>> {
+    FeeSchedule::from_toml(&fs::read_to_string(path)?)
+}
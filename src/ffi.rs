@@ -0,0 +1,198 @@
+/// A C API for embedding the engine in services written in other languages (requires the `ffi`
+/// feature).
+///
+/// A host creates an engine with `engine_new`, feeds it one CSV row at a time with
+/// `engine_apply_csv_line`, reads client balances back as JSON with `engine_export_balances_json`,
+/// and destroys it with `engine_free`. Rows have no header, so their columns are
+/// `type,client,tx,amount` (optionally followed by `terminal`, `currency`, and `to_currency`), as
+/// with `--no-header`. The engine uses the default policies.
+///
+/// The header, `include/payment_engine.h`, is committed, and building with the feature generates it
+/// again with cbindgen (see `build.rs`) for the tests to check it's current. An engine isn't thread safe: a host calling it from several threads
+/// has to serialize the calls itself. Panics are caught at the boundary rather than unwinding into
+/// the host.
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::error::TransactionError;
+use crate::input::{InputFormat, Schema};
+use crate::output::{JsonBalanceWriter, SortBy};
+use crate::{read_transactions_with_schema, write_balances, Engine, Transaction};
+
+/// An engine owned by the host, which only sees it through a pointer.
+pub struct PaymentEngine {
+    engine: Engine,
+    /// Why the last row wasn't applied, if it wasn't.
+    last_error: Option<CString>,
+}
+
+/// The outcome of applying a row.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineStatus {
+    /// The transaction was applied.
+    Ok = 0,
+    /// The transaction was well-formed, but had no effect (e.g. insufficient funds).
+    Rejected = 1,
+    /// The row couldn't be parsed as a single transaction, or wasn't UTF-8.
+    InvalidInput = 2,
+    /// The engine can't go on (e.g. a transaction broke a balance invariant). It keeps the state it
+    /// had, and can still be exported.
+    Fatal = 3,
+    /// A pointer argument was null.
+    NullPointer = 4,
+}
+
+/// Create an engine with the default policies. It must be destroyed with `engine_free`.
+#[no_mangle]
+pub extern "C" fn engine_new() -> *mut PaymentEngine {
+    Box::into_raw(Box::new(PaymentEngine {
+        engine: Engine::new(),
+        last_error: None,
+    }))
+}
+
+/// Apply a single CSV row (without a trailing newline) to `engine`. Unless the transaction was
+/// applied, the reason is kept for `engine_last_error`.
+///
+/// # Safety
+///
+/// `engine` must come from `engine_new` and not have been freed, and `line` must be a
+/// NUL-terminated string. Neither is retained after the call.
+#[no_mangle]
+pub unsafe extern "C" fn engine_apply_csv_line(
+    engine: *mut PaymentEngine,
+    line: *const c_char,
+) -> EngineStatus {
+    if engine.is_null() || line.is_null() {
+        return EngineStatus::NullPointer;
+    }
+    let engine = &mut *engine;
+    let line = CStr::from_ptr(line);
+
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| apply_line(&mut engine.engine, line)));
+    let (status, error) = match outcome {
+        Ok(Ok(())) => (EngineStatus::Ok, None),
+        Ok(Err((status, error))) => (status, Some(error)),
+        Err(_) => (EngineStatus::Fatal, Some("the engine panicked".to_string())),
+    };
+    engine.last_error = error.and_then(|error| CString::new(error).ok());
+
+    status
+}
+
+/// Parse `line` as a single transaction and apply it, returning why it wasn't applied otherwise.
+fn apply_line(engine: &mut Engine, line: &CStr) -> Result<(), (EngineStatus, String)> {
+    let invalid = |message: String| (EngineStatus::InvalidInput, message);
+    let line = line
+        .to_str()
+        .map_err(|e| invalid(format!("row isn't UTF-8: {}", e)))?;
+    let mut transactions = read_transactions_with_schema(
+        io::Cursor::new(line.as_bytes().to_vec()),
+        InputFormat::Csv,
+        &Schema::positional(),
+    );
+    let tx = match (transactions.next(), transactions.next()) {
+        (Some(Ok(tx)), None) => tx,
+        (Some(Err(e)), _) => return Err(invalid(e.to_string())),
+        (None, _) => return Err(invalid("row is empty".to_string())),
+        (Some(Ok(_)), Some(_)) => return Err(invalid("row holds more than one line".to_string())),
+    };
+
+    let result = engine.apply(&tx);
+    // Only engines holding transactions for locked accounts release any, and these don't, but a
+    // fatal rejection among them still has to be reported.
+    let released = engine.take_released_rejects();
+    if let Some((tx, e)) = released.into_iter().find(|(_, e)| engine.is_fatal(e)) {
+        return Err(fatal(&tx, &e));
+    }
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) if engine.is_fatal(&e) => Err(fatal(&tx, &e)),
+        Err(e) => Err((EngineStatus::Rejected, e.to_string())),
+    }
+}
+
+fn fatal(tx: &Transaction, e: &TransactionError) -> (EngineStatus, String) {
+    (
+        EngineStatus::Fatal,
+        format!(
+            "transaction {} for client {}: {}",
+            tx.tx_id, tx.client_id, e
+        ),
+    )
+}
+
+/// Why the last row given to `engine` wasn't applied, or null if it was. The string belongs to the
+/// engine, and is only valid until the next call to `engine_apply_csv_line` or `engine_free`.
+///
+/// # Safety
+///
+/// `engine` must come from `engine_new` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn engine_last_error(engine: *const PaymentEngine) -> *const c_char {
+    match engine
+        .as_ref()
+        .and_then(|engine| engine.last_error.as_ref())
+    {
+        Some(error) => error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Every client's balances as a JSON array (like `--output-format json`), in ascending order of
+/// client ID, or null if they can't be exported. The string must be freed with
+/// `engine_free_string`.
+///
+/// # Safety
+///
+/// `engine` must come from `engine_new` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn engine_export_balances_json(engine: *const PaymentEngine) -> *mut c_char {
+    let engine = match engine.as_ref() {
+        Some(engine) => engine,
+        None => return ptr::null_mut(),
+    };
+
+    let exported = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut json = Vec::new();
+        write_balances(
+            &mut JsonBalanceWriter::array(&mut json),
+            engine.engine.client_states(),
+            SortBy::Client,
+        )
+        .ok()?;
+        CString::new(json).ok()
+    }));
+    match exported {
+        Ok(Some(json)) => json.into_raw(),
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Free a string returned by `engine_export_balances_json`. Null is ignored.
+///
+/// # Safety
+///
+/// `string` must come from `engine_export_balances_json`, and not have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn engine_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Destroy an engine. Null is ignored.
+///
+/// # Safety
+///
+/// `engine` must come from `engine_new`, and not have been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn engine_free(engine: *mut PaymentEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
@@ -0,0 +1,126 @@
+/// A reader which misbehaves the way slow or unreliable sources (pipes, sockets) do, for testing
+/// that transactions are read correctly however their bytes arrive.
+///
+/// Faults are chosen from a seed, so a failing run can be repeated exactly. Every fault is off by
+/// default:
+///
+/// - short reads return fewer bytes than were asked for (as few as one)
+/// - interruptions fail a read with `ErrorKind::Interrupted` before returning any data
+/// - EOFs return `Ok(0)` at the end of a line, before the wrapped reader is exhausted. Reading again
+///   continues with the next line, like a pipe whose writer is between lines
+/// - delays sleep before a read, like a source waiting on the network
+use std::io::{self, Read};
+use std::thread;
+use std::time::Duration;
+
+use crate::synthetic::SplitMix64;
+
+/// A reader which injects faults into reads from another reader.
+pub struct FlakyReader<R> {
+    inner: R,
+    rng: SplitMix64,
+    /// Most bytes returned by a single read, if reads are short.
+    max_read: Option<usize>,
+    interrupt_rate: f64,
+    eof_rate: f64,
+    delay_rate: f64,
+    delay: Duration,
+    /// Whether the next read returns an EOF before any more data.
+    eof_pending: bool,
+    /// Bytes read from `inner` which were held back by an EOF.
+    pending: Vec<u8>,
+    /// Bytes returned so far.
+    bytes_read: u64,
+}
+
+impl<R: Read> FlakyReader<R> {
+    /// A reader which reads from `inner` without faults, until some are configured.
+    pub fn new(inner: R, seed: u64) -> Self {
+        FlakyReader {
+            inner,
+            rng: SplitMix64(seed),
+            max_read: None,
+            interrupt_rate: 0.0,
+            eof_rate: 0.0,
+            delay_rate: 0.0,
+            delay: Duration::ZERO,
+            eof_pending: false,
+            pending: Vec::new(),
+            bytes_read: 0,
+        }
+    }
+
+    /// Return at most `max_read` bytes (at least 1) from each read, and often fewer.
+    pub fn with_short_reads(mut self, max_read: usize) -> Self {
+        self.max_read = Some(max_read.max(1));
+        self
+    }
+
+    /// Fail a fraction of reads with `ErrorKind::Interrupted`.
+    pub fn with_interruptions(mut self, rate: f64) -> Self {
+        self.interrupt_rate = rate;
+        self
+    }
+
+    /// End a fraction of reads at the end of a line (if they include one), followed by an EOF.
+    pub fn with_eofs(mut self, rate: f64) -> Self {
+        self.eof_rate = rate;
+        self
+    }
+
+    /// Sleep for `delay` before a fraction of reads.
+    pub fn with_delays(mut self, rate: f64, delay: Duration) -> Self {
+        self.delay_rate = rate;
+        self.delay = delay;
+        self
+    }
+
+    /// Bytes returned so far (including any before an early EOF).
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+}
+
+impl<R: Read> Read for FlakyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.eof_pending {
+            self.eof_pending = false;
+            return Ok(0);
+        }
+        if self.rng.chance(self.delay_rate) {
+            thread::sleep(self.delay);
+        }
+        if self.rng.chance(self.interrupt_rate) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "injected interruption",
+            ));
+        }
+
+        let len = match self.max_read {
+            Some(max_read) => buf.len().min(1 + self.rng.below(max_read as u64) as usize),
+            None => buf.len(),
+        };
+        let mut read = if self.pending.is_empty() {
+            self.inner.read(&mut buf[..len])?
+        } else {
+            let read = len.min(self.pending.len());
+            buf[..read].copy_from_slice(&self.pending[..read]);
+            self.pending.drain(..read);
+            read
+        };
+
+        if self.rng.chance(self.eof_rate) {
+            if let Some(end) = buf[..read].iter().position(|&b| b == b'\n') {
+                // Anything after the end of the line is returned after the EOF.
+                self.pending
+                    .splice(0..0, buf[end + 1..read].iter().copied());
+                read = end + 1;
+                self.eof_pending = true;
+            }
+        }
+        self.bytes_read += read as u64;
+
+        Ok(read)
+    }
+}
@@ -0,0 +1,601 @@
+/// Transaction handlers, which work out what each type of transaction does, and the registry of
+/// custom transaction types embedders can add (e.g. `fee`, `interest`, or `adjustment`) without
+/// changing the engine.
+///
+/// A handler looks at a transaction and its client's state, and returns what applying it changes
+/// (an `Outcome`): the postings which move the client's funds (see `posting`), and whether the
+/// account is locked or unlocked. It doesn't change anything itself. The engine applies the
+/// outcome, so every transaction goes through the same overflow checks, versioning, history,
+/// replication, and events, whichever handler it had.
+///
+/// The built-in types (deposits, withdrawals, disputes, resolves, and chargebacks, as well as
+/// conversions and unlocks) have default handlers, which can't be replaced. Rows of any other type
+/// with a valid name (see `TypeName`) are read as `TransactionType::Custom`, and a handler for the
+/// type is given to each engine which should apply it with `Engine::with_handler`, which keeps
+/// the names of the types it has handlers for. Rows of a type an engine has no handler for are
+/// handled by its `UnknownTypePolicy`.
+///
+/// ```text
+/// struct Interest;
+///
+/// impl TransactionHandler for Interest {
+///     fn apply(&self, tx: &Transaction, _: &HandlerContext) -> Result<Outcome, TransactionError> {
+///         let amount = tx.validated_amount()?;
+///         let credit = Posting::new(Account::External, Account::Available, tx.currency, amount);
+///         Ok(Outcome::new(vec![credit]))
+///     }
+/// }
+///
+/// let engine = Engine::new().with_handler(TypeName::new("interest")?, Interest);
+/// ```
+///
+/// Custom transactions don't have IDs of their own as far as the engine is concerned: they can't
+/// be disputed, and aren't deduplicated or skipped as backfilled.
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::currency::{Balance, Currency};
+use crate::error::TransactionError;
+use crate::money::Money;
+use crate::posting::{Account, Posting};
+use crate::store::{DisputableKind, DisputableTx};
+use crate::{
+    fee, storage_error, ClientState, DisputeState, Engine, RedisputePolicy, Transaction,
+    TransactionType, WithdrawalDisputePolicy,
+};
+
+/// Longest name a custom transaction type can have.
+pub const MAX_TYPE_NAME_LEN: usize = 32;
+
+/// The name of a custom transaction type. Names are kept inline (rather than registered anywhere),
+/// so transaction types stay `Copy`, and each engine only knows the types it has handlers for.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TypeName {
+    /// The name, padded with zeros.
+    bytes: [u8; MAX_TYPE_NAME_LEN],
+    len: u8,
+}
+
+impl TypeName {
+    /// A custom transaction type's name. Names are ASCII letters, digits, `_`, and `-`, and can't
+    /// be those of built-in types (or `unknown`).
+    pub fn new(name: &str) -> Result<Self, String> {
+        let valid = !name.is_empty()
+            && name.len() <= MAX_TYPE_NAME_LEN
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+        if !valid {
+            return Err(format!("invalid transaction type name '{}'", name));
+        }
+        if TransactionType::built_in(name).is_some() || name == "unknown" {
+            return Err(format!("'{}' is a built-in transaction type", name));
+        }
+
+        let mut bytes = [0; MAX_TYPE_NAME_LEN];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(TypeName {
+            bytes,
+            len: name.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..usize::from(self.len)]).expect("type names are ASCII")
+    }
+}
+
+impl FromStr for TypeName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TypeName::new(s)
+    }
+}
+
+impl fmt::Display for TypeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for TypeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// What applying a transaction changes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Outcome {
+    /// Movements of the client's funds.
+    pub postings: Vec<Posting>,
+    /// Lock (or unlock) the client's account.
+    pub lock: Option<bool>,
+    /// Record the transaction so it can be disputed, for this amount (deposits and withdrawals).
+    disputable: Option<Money>,
+    /// Move a transaction the client disputed on in the dispute lifecycle.
+    dispute: Option<(u32, DisputeState)>,
+}
+
+impl Outcome {
+    pub fn new(postings: Vec<Posting>) -> Self {
+        Outcome {
+            postings,
+            ..Default::default()
+        }
+    }
+
+    /// Lock the client's account too.
+    pub fn locking(mut self) -> Self {
+        self.lock = Some(true);
+        self
+    }
+}
+
+/// What a handler can see while working out a transaction's outcome.
+pub struct HandlerContext<'a> {
+    engine: &'a Engine,
+    state: &'a ClientState,
+}
+
+impl HandlerContext<'_> {
+    /// The state of the transaction's client, before it's applied.
+    pub fn client(&self) -> &ClientState {
+        self.state
+    }
+
+    /// The client's funds in some currency, before the transaction is applied.
+    pub fn balance(&self, currency: Currency) -> Balance {
+        self.state.balance(currency)
+    }
+
+    /// How far below zero the client's available funds may go (zero without credit limits).
+    pub fn credit_limit(&self) -> Money {
+        self.engine
+            .credit_limits
+            .as_ref()
+            .map_or(Money::ZERO, |limits| limits.limit(self.state.client_id))
+    }
+
+    /// The transaction a dispute, resolve, or chargeback references.
+    fn disputed(&self, tx: &Transaction) -> Result<DisputableTx, TransactionError> {
+        // Specification states that "if the transaction specified by the dispute doesn't exist
+        // you can ignore it". Assumption: A `Dispute` can only reference a transaction which has
+        // already occurred, and since transactions in CSV are in order they occurred, we can skip
+        // disputes against transactions we haven't seen yet.
+        let disputed_tx = self
+            .engine
+            .disputable_transactions
+            .get(tx.tx_id)
+            .map_err(|e| storage_error(tx, e))?
+            .ok_or(TransactionError::UnknownTx {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            })?;
+
+        Ok(disputed_tx)
+    }
+
+    /// Check a resolve or chargeback references a transaction in the same currency, which is under
+    /// dispute.
+    fn check_disputed(&self, tx: &Transaction) -> Result<DisputableTx, TransactionError> {
+        let disputed_tx = self.disputed(tx)?;
+        check_currency(tx, &disputed_tx)?;
+        if self.state.dispute_state(tx.tx_id) != DisputeState::Disputed {
+            return Err(TransactionError::NotDisputed {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            });
+        }
+
+        Ok(disputed_tx)
+    }
+}
+
+/// Works out what a type of transaction does.
+pub trait TransactionHandler: Send {
+    /// What applying `tx` changes, or why it can't be applied (in which case nothing changes).
+    /// Handlers for custom types can reject transactions with `TransactionError::Rejected`.
+    fn apply(
+        &self,
+        tx: &Transaction,
+        context: &HandlerContext<'_>,
+    ) -> Result<Outcome, TransactionError>;
+}
+
+/// The handlers for custom transaction types an engine applies, by the types' names.
+#[derive(Default)]
+pub(crate) struct HandlerRegistry {
+    custom: HashMap<TypeName, Box<dyn TransactionHandler>>,
+}
+
+impl HandlerRegistry {
+    pub(crate) fn insert(&mut self, r#type: TypeName, handler: Box<dyn TransactionHandler>) {
+        self.custom.insert(r#type, handler);
+    }
+
+    /// The handler for a type of transaction, if the engine can apply it.
+    pub(crate) fn get(&self, r#type: TransactionType) -> Option<&dyn TransactionHandler> {
+        Some(match r#type {
+            TransactionType::Deposit => &Deposit,
+            TransactionType::Withdrawal => &Withdrawal,
+            TransactionType::Dispute => &Dispute,
+            TransactionType::Resolve => &Resolve,
+            TransactionType::Chargeback => &Chargeback,
+            TransactionType::Convert => &Convert,
+            TransactionType::Unlock => &Unlock,
+            TransactionType::Custom(name) => return self.custom.get(&name).map(Box::as_ref),
+            TransactionType::Unknown => return None,
+        })
+    }
+
+    /// The type a transaction is counted as (e.g. by metrics): custom types the engine has no
+    /// handler for are unknown to it.
+    pub(crate) fn resolve(&self, r#type: TransactionType) -> TransactionType {
+        match r#type {
+            TransactionType::Custom(name) if !self.custom.contains_key(&name) => {
+                TransactionType::Unknown
+            }
+            r#type => r#type,
+        }
+    }
+}
+
+impl Engine {
+    /// Apply transactions of a custom type with `handler` (see `handler`).
+    pub fn with_handler<H: TransactionHandler + 'static>(
+        mut self,
+        r#type: TypeName,
+        handler: H,
+    ) -> Self {
+        self.handlers.insert(r#type, Box::new(handler));
+        self
+    }
+
+    /// Work out the outcome of `tx` with the handler for its type, for a client which is being
+    /// tracked.
+    pub(crate) fn outcome(
+        &self,
+        handler: &dyn TransactionHandler,
+        tx: &Transaction,
+    ) -> Result<Outcome, TransactionError> {
+        let context = HandlerContext {
+            engine: self,
+            state: &self.client_states[&tx.client_id],
+        };
+
+        handler.apply(tx, &context)
+    }
+
+    /// Record a transaction's outcome, other than its postings, once they've been applied.
+    pub(crate) fn record_outcome(
+        &mut self,
+        tx: &Transaction,
+        outcome: &Outcome,
+    ) -> Result<(), TransactionError> {
+        if let Some(amount) = outcome.disputable {
+            self.disputable_transactions
+                .insert(tx.tx_id, tx.disputable(amount, self.dispute_clock))
+                .map_err(|e| storage_error(tx, e))?;
+        }
+        if let Some((tx_id, dispute_state)) = outcome.dispute {
+            if let Some(state) = self.client_states.get_mut(&tx.client_id) {
+                state.disputes.insert(tx_id, dispute_state);
+            }
+            // A transaction's later disputes might be from another client.
+            if dispute_state != DisputeState::Disputed {
+                self.settled_disputes.insert(tx_id, dispute_state);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The posting of a fee, unless there isn't one.
+fn fee_posting(currency: Currency, fee: Money) -> Option<Posting> {
+    (!fee.is_zero()).then(|| Posting::new(Account::Available, Account::Fees, currency, fee))
+}
+
+/// Check a dispute, resolve, or chargeback is in the same currency as the transaction it
+/// references.
+fn check_currency(tx: &Transaction, disputed_tx: &DisputableTx) -> Result<(), TransactionError> {
+    if disputed_tx.currency != tx.currency {
+        return Err(TransactionError::CurrencyMismatch {
+            client_id: tx.client_id,
+            tx_id: tx.tx_id,
+        });
+    }
+
+    Ok(())
+}
+
+struct Deposit;
+
+impl TransactionHandler for Deposit {
+    fn apply(
+        &self,
+        tx: &Transaction,
+        context: &HandlerContext<'_>,
+    ) -> Result<Outcome, TransactionError> {
+        let engine = context.engine;
+        let amount = tx.validated_amount()?;
+        let fee = fee(&engine.fees, tx, amount, &engine.precision);
+
+        if context.balance(tx.currency).available + amount < fee {
+            return Err(TransactionError::InsufficientFunds {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            });
+        }
+        let mut postings = vec![Posting::new(
+            Account::External,
+            Account::Available,
+            tx.currency,
+            amount,
+        )];
+        postings.extend(fee_posting(tx.currency, fee));
+
+        Ok(Outcome {
+            disputable: Some(amount),
+            ..Outcome::new(postings)
+        })
+    }
+}
+
+struct Withdrawal;
+
+impl TransactionHandler for Withdrawal {
+    fn apply(
+        &self,
+        tx: &Transaction,
+        context: &HandlerContext<'_>,
+    ) -> Result<Outcome, TransactionError> {
+        let engine = context.engine;
+        let amount = tx.validated_amount()?;
+        let fee = fee(&engine.fees, tx, amount, &engine.precision);
+
+        if context.balance(tx.currency).available + context.credit_limit() < amount + fee {
+            return Err(TransactionError::InsufficientFunds {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            });
+        }
+        let mut postings = vec![Posting::new(
+            Account::Available,
+            Account::External,
+            tx.currency,
+            amount,
+        )];
+        postings.extend(fee_posting(tx.currency, fee));
+
+        Ok(Outcome {
+            disputable: Some(amount),
+            ..Outcome::new(postings)
+        })
+    }
+}
+
+struct Convert;
+
+impl TransactionHandler for Convert {
+    fn apply(
+        &self,
+        tx: &Transaction,
+        context: &HandlerContext<'_>,
+    ) -> Result<Outcome, TransactionError> {
+        let engine = context.engine;
+        let amount = tx.validated_amount()?;
+        let converted = engine
+            .rates
+            .as_ref()
+            .and_then(|rates| rates.convert(amount, tx.currency, tx.to_currency, &engine.precision))
+            .ok_or(TransactionError::UnknownRate {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            })?;
+
+        if context.balance(tx.currency).available < amount {
+            return Err(TransactionError::InsufficientFunds {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            });
+        }
+        if converted.is_zero() {
+            return Err(TransactionError::ZeroAmount {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            });
+        }
+        if converted.exceeds_max() {
+            return Err(TransactionError::Overflow {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            });
+        }
+
+        Ok(Outcome::new(vec![
+            Posting::new(Account::Available, Account::External, tx.currency, amount),
+            Posting::new(
+                Account::External,
+                Account::Available,
+                tx.to_currency,
+                converted,
+            ),
+        ]))
+    }
+}
+
+struct Dispute;
+
+impl TransactionHandler for Dispute {
+    fn apply(
+        &self,
+        tx: &Transaction,
+        context: &HandlerContext<'_>,
+    ) -> Result<Outcome, TransactionError> {
+        let engine = context.engine;
+        let state = context.state;
+        let ref_tx = tx.tx_id;
+        let disputed_tx = context.disputed(tx)?;
+        if let Some(window) = engine.dispute_window {
+            if engine.dispute_clock.saturating_sub(disputed_tx.at) > window.span() {
+                return Err(TransactionError::DisputeExpired {
+                    client_id: tx.client_id,
+                    tx_id: tx.tx_id,
+                });
+            }
+        }
+        check_currency(tx, &disputed_tx)?;
+
+        // Assumptions: we don't have to consider the client ID, and differentiate between
+        // disputes on the same tx ID by different clients. If this was the case then transactions
+        // would probably indicate source/destination clients.
+        //
+        // All disputes are valid as long as the tx ID has already occurred, and no dispute is
+        // already outstanding against some tx ID for this client.
+        //
+        // This implies that the client ID in the dispute should match the client ID in the
+        // disputed transaction, but since it isn't in the spec no check is made here. If we did
+        // want to enforce this, we could store a collection of `&Transaction` for each client
+        // (i.e. `disputable_transactions` would be per-client)
+        if disputed_tx.kind == DisputableKind::Withdrawal
+            && engine.withdrawal_dispute_policy == WithdrawalDisputePolicy::Ignore
+        {
+            return Err(TransactionError::WithdrawalDisputeIgnored {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            });
+        }
+        if state.dispute_state(ref_tx) == DisputeState::Disputed {
+            return Err(TransactionError::AlreadyDisputed {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            });
+        }
+        // A transaction's earlier disputes might have been from another client.
+        match engine.settled_disputes.get(&ref_tx) {
+            Some(DisputeState::ChargedBack) => {
+                return Err(TransactionError::AlreadyChargedBack {
+                    client_id: tx.client_id,
+                    tx_id: tx.tx_id,
+                })
+            }
+            Some(DisputeState::Resolved) if engine.redispute_policy == RedisputePolicy::Once => {
+                return Err(TransactionError::AlreadyResolved {
+                    client_id: tx.client_id,
+                    tx_id: tx.tx_id,
+                })
+            }
+            _ => {}
+        }
+        if matches!(engine.max_open_disputes, Some(max) if state.open_disputes().count() >= max) {
+            return Err(TransactionError::TooManyDisputes {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            });
+        }
+
+        let from = match disputed_tx.kind {
+            // The deposited funds can't be used until the dispute is settled.
+            DisputableKind::Deposit => Account::Available,
+            // The withdrawn funds are provisionally credited back to the client, but can't be used
+            // until the dispute is settled.
+            DisputableKind::Withdrawal => Account::External,
+        };
+
+        Ok(Outcome {
+            dispute: Some((ref_tx, DisputeState::Disputed)),
+            ..Outcome::new(vec![Posting::new(
+                from,
+                Account::Held,
+                tx.currency,
+                disputed_tx.amount,
+            )])
+        })
+    }
+}
+
+struct Resolve;
+
+impl TransactionHandler for Resolve {
+    fn apply(
+        &self,
+        tx: &Transaction,
+        context: &HandlerContext<'_>,
+    ) -> Result<Outcome, TransactionError> {
+        // See assumptions for `Dispute` above.
+        let disputed_tx = context.check_disputed(tx)?;
+        let to = match disputed_tx.kind {
+            // The deposit stands, so held funds become available again.
+            DisputableKind::Deposit => Account::Available,
+            // The withdrawal stands, so the provisional credit is removed.
+            DisputableKind::Withdrawal => Account::External,
+        };
+
+        Ok(Outcome {
+            dispute: Some((tx.tx_id, DisputeState::Resolved)),
+            ..Outcome::new(vec![Posting::new(
+                Account::Held,
+                to,
+                tx.currency,
+                disputed_tx.amount,
+            )])
+        })
+    }
+}
+
+struct Chargeback;
+
+impl TransactionHandler for Chargeback {
+    fn apply(
+        &self,
+        tx: &Transaction,
+        context: &HandlerContext<'_>,
+    ) -> Result<Outcome, TransactionError> {
+        // See assumptions for `Dispute` above.
+        let disputed_tx = context.check_disputed(tx)?;
+        let to = match disputed_tx.kind {
+            // The deposit is reversed, so held funds are removed.
+            DisputableKind::Deposit => Account::External,
+            // The withdrawal is reversed, so the provisional credit becomes available.
+            DisputableKind::Withdrawal => Account::Available,
+        };
+
+        Ok(Outcome {
+            dispute: Some((tx.tx_id, DisputeState::ChargedBack)),
+            ..Outcome::new(vec![Posting::new(
+                Account::Held,
+                to,
+                tx.currency,
+                disputed_tx.amount,
+            )])
+            .locking()
+        })
+    }
+}
+
+struct Unlock;
+
+impl TransactionHandler for Unlock {
+    fn apply(
+        &self,
+        tx: &Transaction,
+        context: &HandlerContext<'_>,
+    ) -> Result<Outcome, TransactionError> {
+        if !context.state.locked {
+            return Err(TransactionError::NotLocked {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            });
+        }
+
+        // Unlocking doesn't touch funds, so it doesn't start tracking its currency either.
+        Ok(Outcome {
+            lock: Some(false),
+            ..Default::default()
+        })
+    }
+}
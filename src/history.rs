@@ -0,0 +1,211 @@
+/// Past client states, for answering "what was the balance as of ..." queries.
+///
+/// Every change to a client's state is kept along with when it was applied, so a client can be
+/// looked up as of one of its versions (see `ClientState::version`, which balance exports include)
+/// or as of a point in time. The transaction which led to each state is kept with it, so a client's
+/// ledger (every transaction applied to it, in order) can be listed for support investigations.
+/// History is kept in memory for as long as the engine lives, and grows with every applied
+/// transaction.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{ClientState, Transaction};
+
+/// A point in a client's history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    /// After the client's state reached this version.
+    Version(u64),
+    /// At this time, in milliseconds since the Unix epoch.
+    Time(u64),
+}
+
+impl FromStr for AsOf {
+    type Err = String;
+
+    /// A version (a plain number), or an RFC 3339 timestamp (e.g. `2022-03-01T12:00:00Z`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+            return s
+                .parse()
+                .map(AsOf::Version)
+                .map_err(|_| format!("invalid version '{}'", s));
+        }
+
+        parse_timestamp(s).map(AsOf::Time).ok_or_else(|| {
+            format!(
+                "invalid as_of '{}', expected a version or an RFC 3339 timestamp",
+                s
+            )
+        })
+    }
+}
+
+/// A client's state, when it was reached, and the transaction which led to it (if it wasn't
+/// already the client's state when history started being kept).
+#[derive(Debug)]
+struct Version {
+    at: u64,
+    state: ClientState,
+    transaction: Option<Transaction>,
+}
+
+/// A transaction applied to a client, and the state it left the client in.
+#[derive(Debug, Clone, Copy)]
+pub struct LedgerEntry<'a> {
+    pub transaction: &'a Transaction,
+    pub state: &'a ClientState,
+}
+
+/// Every state each client has been in, oldest first.
+#[derive(Debug, Default)]
+pub struct StateHistory {
+    clients: HashMap<u16, Vec<Version>>,
+    /// Time of the latest change, so times never go backwards if the clock does.
+    latest: u64,
+}
+
+impl StateHistory {
+    /// Record a client's current state.
+    pub(crate) fn record(&mut self, state: &ClientState) {
+        self.record_at(state, now());
+    }
+
+    /// Record the state a transaction which was just applied left its client in.
+    pub(crate) fn record_applied(&mut self, state: &ClientState, tx: &Transaction) {
+        self.push(state, now(), Some(tx.clone()));
+    }
+
+    /// Record a client's state as of `at` (milliseconds since the Unix epoch).
+    pub(crate) fn record_at(&mut self, state: &ClientState, at: u64) {
+        self.push(state, at, None);
+    }
+
+    fn push(&mut self, state: &ClientState, at: u64, transaction: Option<Transaction>) {
+        self.latest = self.latest.max(at);
+        self.clients
+            .entry(state.client_id)
+            .or_default()
+            .push(Version {
+                at: self.latest,
+                state: state.without_disputes(),
+                transaction,
+            });
+    }
+
+    /// The client's state as of some point, or `None` if the client had no state yet. Transactions
+    /// under dispute aren't kept, so the state won't list any.
+    pub fn get(&self, client_id: u16, as_of: AsOf) -> Option<&ClientState> {
+        let versions = self.clients.get(&client_id)?;
+        let count = match as_of {
+            AsOf::Version(version) => {
+                versions.partition_point(|past| past.state.version <= version)
+            }
+            AsOf::Time(at) => versions.partition_point(|past| past.at <= at),
+        };
+
+        count.checked_sub(1).map(|last| &versions[last].state)
+    }
+
+    /// The transactions applied to a client since history started being kept, oldest first.
+    pub fn ledger(&self, client_id: u16) -> impl Iterator<Item = LedgerEntry<'_>> {
+        let versions = self.clients.get(&client_id).map_or(&[][..], Vec::as_slice);
+        versions.iter().filter_map(|version| {
+            version.transaction.as_ref().map(|transaction| LedgerEntry {
+                transaction,
+                state: &version.state,
+            })
+        })
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Milliseconds since the Unix epoch of an RFC 3339 timestamp like `2022-03-01T12:00:00.5+01:00`.
+pub(crate) fn parse_timestamp(s: &str) -> Option<u64> {
+    let number = |s: &str| -> Option<i64> {
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        s.parse().ok()
+    };
+
+    let (date, time) = s.split_once(['T', 't', ' '])?;
+    let mut date = date.splitn(3, '-');
+    let (year, month, day) = (
+        number(date.next()?)?,
+        number(date.next()?)?,
+        number(date.next()?)?,
+    );
+
+    let (time, offset) = match time.strip_suffix(['Z', 'z']) {
+        Some(time) => (time, 0),
+        None => {
+            let sign_at = time.rfind(['+', '-'])?;
+            let (hours, minutes) = time[sign_at + 1..].split_once(':')?;
+            let (hours, minutes) = (number(hours)?, number(minutes)?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset = hours * 60 + minutes;
+            let sign = if time[sign_at..].starts_with('-') {
+                -1
+            } else {
+                1
+            };
+            (&time[..sign_at], sign * offset * 60_000)
+        }
+    };
+    let (time, fraction) = match time.split_once('.') {
+        Some((time, fraction)) => (time, Some(fraction)),
+        None => (time, None),
+    };
+    let mut time = time.splitn(3, ':');
+    let (hour, minute, second) = (
+        number(time.next()?)?,
+        number(time.next()?)?,
+        number(time.next()?)?,
+    );
+    let millis = match fraction {
+        Some(fraction) => {
+            if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let digits: String = fraction.chars().chain("00".chars()).take(3).collect();
+            number(&digits)?
+        }
+        None => 0,
+    };
+
+    if !(0..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let seconds = ((days * 24 + hour) * 60 + minute) * 60 + second;
+    u64::try_from(seconds * 1000 + millis - offset).ok()
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146_097 + day_of_era - 719_468
+}
@@ -0,0 +1,236 @@
+/// HTTP API mode (requires the `http` feature), for using the engine as a small ledger service.
+///
+/// ```text
+/// POST /transactions    apply a transaction (a JSON object with the NDJSON input fields)
+/// GET  /clients/{id}    the state of a single client, or with `?as_of=<version|timestamp>` its
+///                       state at some point in the past (if the engine keeps history)
+/// GET  /balances        the states of every client, ordered by client ID
+/// GET  /metrics         Prometheus metrics (see `metrics`)
+/// ```
+///
+/// Responses other than metrics are JSON. A rejected transaction gets `422` with the reason (see
+/// `TransactionError::reason`), and a malformed one gets `400`. As with `server`, fatal rejections
+/// are reported to the client without stopping the server.
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde::Serialize;
+
+use crate::history::AsOf;
+use crate::input::{parse_line, InputFormat};
+use crate::metrics;
+use crate::output::SortBy;
+use crate::{ClientState, Engine};
+
+/// Number of threads handling requests.
+const HTTP_WORKER_THREADS: usize = 4;
+
+/// Body of the response to `POST /transactions`.
+#[derive(Debug, Serialize)]
+struct SubmitResponse {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// Body of any error response which isn't about a submitted transaction.
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Serve the API on `addr` forever.
+pub fn serve<A: ToSocketAddrs>(addr: A, engine: Engine) -> io::Result<()> {
+    serve_shared(addr, Arc::new(Mutex::new(engine)))
+}
+
+/// Like `serve`, for an engine which is also used elsewhere (e.g. for replication).
+pub fn serve_shared<A: ToSocketAddrs>(addr: A, engine: Arc<Mutex<Engine>>) -> io::Result<()> {
+    let server = Arc::new(tiny_http::Server::http(addr).map_err(io::Error::other)?);
+    if let Some(addr) = server.server_addr().to_ip() {
+        tracing::info!("listening on http://{}", addr);
+    }
+
+    let workers: Vec<_> = (0..HTTP_WORKER_THREADS)
+        .map(|_| {
+            let server = server.clone();
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let mut body = String::new();
+                    let (status, body) = match request.as_reader().read_to_string(&mut body) {
+                        Ok(_) => handle(&engine, request.method().as_str(), request.url(), &body),
+                        Err(e) => error(400, e.to_string()),
+                    };
+                    let content_type =
+                        content_type(request.method().as_str(), request.url(), status);
+                    let response = tiny_http::Response::from_string(body)
+                        .with_status_code(status)
+                        .with_header(
+                            format!("Content-Type: {}", content_type)
+                                .parse::<tiny_http::Header>()
+                                .expect("header is valid"),
+                        );
+                    if let Err(e) = request.respond(response) {
+                        tracing::error!("error writing response: {}", e);
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    Ok(())
+}
+
+/// Handle a single request, returning the status code and JSON body of the response.
+pub fn handle(engine: &Mutex<Engine>, method: &str, url: &str, body: &str) -> (u16, String) {
+    let path = url.split('?').next().unwrap_or(url);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (method, segments.as_slice()) {
+        ("POST", ["transactions"]) => submit(engine, body),
+        ("GET", ["clients", id]) => match id.parse::<u16>() {
+            Ok(client_id) => {
+                let engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+                match query_param(url, "as_of") {
+                    Some(as_of) => client_as_of(&engine, client_id, &as_of),
+                    None => match engine.client_states().get(&client_id) {
+                        Some(state) => json(200, state),
+                        None => error(404, format!("unknown client {}", client_id)),
+                    },
+                }
+            }
+            Err(_) => error(400, format!("invalid client ID '{}'", id)),
+        },
+        ("GET", ["balances"]) => {
+            let engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+            let mut states: Vec<&ClientState> = engine.client_states().values().collect();
+            SortBy::Client.sort(&mut states);
+            json(200, &states)
+        }
+        ("GET", ["metrics"]) => {
+            let engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+            match metrics::render(&engine) {
+                Some(text) => (200, text),
+                None => error(404, "the server isn't keeping metrics".to_string()),
+            }
+        }
+        (_, ["transactions"]) | (_, ["clients", _]) | (_, ["balances"]) | (_, ["metrics"]) => {
+            error(405, format!("method {} not allowed", method))
+        }
+        _ => error(404, format!("no such endpoint {}", path)),
+    }
+}
+
+/// Content type of the response to a request, given its status.
+fn content_type(method: &str, url: &str, status: u16) -> &'static str {
+    match (method, url.split('?').next(), status) {
+        ("GET", Some("/metrics"), 200) => "text/plain; version=0.0.4",
+        _ => "application/json",
+    }
+}
+
+fn client_as_of(engine: &Engine, client_id: u16, as_of: &str) -> (u16, String) {
+    let point = match as_of.parse::<AsOf>() {
+        Ok(point) => point,
+        Err(e) => return error(400, e),
+    };
+    let history = match engine.history() {
+        Some(history) => history,
+        None => return error(400, "the server isn't keeping history".to_string()),
+    };
+
+    match history.get(client_id, point) {
+        Some(state) => json(200, state),
+        None => error(
+            404,
+            format!("client {} had no state as of {}", client_id, as_of),
+        ),
+    }
+}
+
+/// The (percent-decoded) value of a query string parameter.
+fn query_param(url: &str, name: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+    let value = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))?;
+
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, escaped) {
+            (b'%', Some(escaped)) => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+fn submit(engine: &Mutex<Engine>, body: &str) -> (u16, String) {
+    let tx = match parse_line(body.trim(), InputFormat::Ndjson) {
+        Ok(tx) => tx,
+        Err(e) => return error(400, e.to_string()),
+    };
+
+    let mut engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+    let result = engine.apply(&tx);
+    // Responses are only for the transaction submitted, so held transactions are reported here.
+    for (tx, e) in engine.take_released_rejects() {
+        tracing::warn!("held transaction {} rejected after unlock: {}", tx.tx_id, e);
+    }
+    match result {
+        Ok(()) => json(
+            200,
+            &SubmitResponse {
+                status: "ok",
+                reason: None,
+                message: None,
+            },
+        ),
+        Err(e) => json(
+            422,
+            &SubmitResponse {
+                status: if engine.is_fatal(&e) {
+                    "error"
+                } else {
+                    "rejected"
+                },
+                reason: Some(e.reason()),
+                message: Some(e.to_string()),
+            },
+        ),
+    }
+}
+
+fn json<T: Serialize + ?Sized>(status: u16, body: &T) -> (u16, String) {
+    match serde_json::to_string(body) {
+        Ok(body) => (status, body),
+        Err(e) => error(500, e.to_string()),
+    }
+}
+
+fn error(status: u16, message: String) -> (u16, String) {
+    let body = serde_json::to_string(&ErrorResponse { error: message })
+        .expect("error responses are serializable");
+
+    (status, body)
+}
@@ -0,0 +1,774 @@
+/// Reading transactions from the supported input formats. Every format produces the same stream of
+/// `Transaction`s, so they all share the same processing core.
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::currency::Currency;
+use crate::decompress::{self, Compression};
+use crate::error::ParseError;
+use crate::merge;
+use crate::money::Money;
+use crate::output::client_hash;
+use crate::{Transaction, TransactionType};
+
+/// Path which stands for stdin.
+pub const STDIN_PATH: &str = "-";
+
+/// Open the transaction log at `path`, or stdin if the path is `STDIN_PATH`. Compressed logs are
+/// decompressed as they're read (see `decompress`).
+pub fn open_input(path: &str) -> io::Result<Box<dyn io::Read>> {
+    if path == STDIN_PATH {
+        Ok(Box::new(io::stdin()))
+    } else if let Some(compression) = Compression::of(path) {
+        Ok(Box::new(decompress::open(path, compression)?))
+    } else {
+        Ok(Box::new(File::open(path)?))
+    }
+}
+
+/// Expand a path whose file name is a pattern (`*` matching any run of characters, and `?` any one)
+/// into the paths of every matching file, in order of name. Other paths are returned as they are.
+pub fn expand_path(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let path = Path::new(path);
+    let pattern = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.contains(['*', '?']) => name,
+        _ => return Ok(vec![path.to_string_lossy().into_owned()]),
+    };
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if matches!(name.to_str(), Some(name) if glob_match(pattern, name))
+            && entry.path().is_file()
+        {
+            paths.push(path.with_file_name(name).to_string_lossy().into_owned());
+        }
+    }
+    if paths.is_empty() {
+        return Err(format!("no files match {}", path.display()).into());
+    }
+    paths.sort_unstable();
+
+    Ok(paths)
+}
+
+/// Whether `name` matches `pattern` (see `expand_path`).
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where to resume after the last `*` if the rest of the pattern stops matching.
+    let mut backtrack = None;
+    let (mut p, mut n) = (0, 0);
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// A reader which retries reads that were interrupted (e.g. by a signal) instead of failing. The
+/// CSV reader treats any error (including `ErrorKind::Interrupted`) as the end of its input.
+pub(crate) struct RetryInterrupted<R>(pub(crate) R);
+
+impl<R: io::Read> io::Read for RetryInterrupted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+/// A stream of transactions in any input format.
+pub type TransactionStream = Box<dyn Iterator<Item = Result<Transaction, Box<dyn Error>>>>;
+
+/// Format of the transaction log.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// CSV with a `type,client,tx,amount` header.
+    #[default]
+    Csv,
+    /// Newline delimited JSON, one transaction object per line with the same field names as the
+    /// CSV header (e.g. `{"type":"deposit","client":1,"tx":1,"amount":"1.5"}`).
+    Ndjson,
+    /// Parquet, with a column for each field named as in the CSV header. Only available with the
+    /// `parquet` feature (see `parquet`).
+    Parquet,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "ndjson" => Ok(InputFormat::Ndjson),
+            "parquet" => Ok(InputFormat::Parquet),
+            _ => Err(format!(
+                "unknown input format '{}', expected csv, ndjson, or parquet",
+                s
+            )),
+        }
+    }
+}
+
+/// Transactions read from CSV, along with the line each one started on.
+pub fn csv_transactions<R>(
+    mut reader: csv::Reader<R>,
+) -> impl Iterator<Item = Result<Transaction, Box<dyn Error>>>
+where
+    R: io::Read,
+{
+    let mut headers = None;
+    let mut record = csv::StringRecord::new();
+
+    std::iter::from_fn(move || {
+        if headers.is_none() {
+            match reader.headers() {
+                Ok(row) => headers = Some(row.clone()),
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+
+        match reader.read_record(&mut record) {
+            Ok(true) => Some(
+                record
+                    .deserialize::<Transaction>(headers.as_ref())
+                    .map(|mut tx| {
+                        tx.line = record.position().map(csv::Position::line);
+                        tx
+                    })
+                    .map_err(|e| csv_parse_error(record.as_byte_record(), &e).into()),
+            ),
+            Ok(false) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    })
+}
+
+/// Transactions read from CSV, along with the line each one started on. Rows are parsed directly
+/// from their bytes, which is much faster than `csv_transactions`. Rows which can't be parsed
+/// exactly the same way (e.g. amounts with more than `FAST_AMOUNT_DIGITS` digits, or any row which
+/// fails to parse) fall back to `csv_transactions`' parsing, so both give the same results.
+///
+/// `reader` must trim fields (`Trim::All`).
+pub fn fast_csv_transactions<R>(
+    reader: csv::Reader<R>,
+) -> impl Iterator<Item = Result<Transaction, Box<dyn Error>>>
+where
+    R: io::Read,
+{
+    fast_csv_transactions_with_headers(reader, None)
+}
+
+/// Transactions read from CSV like `fast_csv_transactions`, with the given `headers` rather than
+/// the reader's (for a reader without any, see `Schema::positional`).
+pub(crate) fn fast_csv_transactions_with_headers<R>(
+    mut reader: csv::Reader<R>,
+    mut headers: Option<csv::StringRecord>,
+) -> impl Iterator<Item = Result<Transaction, Box<dyn Error>>>
+where
+    R: io::Read,
+{
+    let mut columns = headers.as_ref().and_then(Columns::new);
+    let mut record = csv::ByteRecord::new();
+
+    std::iter::from_fn(move || {
+        if headers.is_none() {
+            match reader.headers() {
+                Ok(row) => {
+                    columns = Columns::new(row);
+                    headers = Some(row.clone());
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+
+        match reader.read_byte_record(&mut record) {
+            Ok(true) => {
+                let tx = match columns.as_ref().and_then(|columns| columns.parse(&record)) {
+                    Some(tx) => Ok(tx),
+                    None => deserialize_byte_record(&record, headers.as_ref()),
+                };
+                Some(tx.map(|mut tx| {
+                    tx.line = record.position().map(csv::Position::line);
+                    tx
+                }))
+            }
+            Ok(false) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    })
+}
+
+/// Most digits in an amount parsed by `fast_csv_transactions`. Serde parses amounts with a decimal
+/// point as an `f64` first, which only preserves up to 15 significant digits.
+const FAST_AMOUNT_DIGITS: usize = 15;
+
+/// Positions of the columns `fast_csv_transactions` reads.
+struct Columns {
+    r#type: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    terminal: Option<usize>,
+    currency: Option<usize>,
+    to_currency: Option<usize>,
+    timestamp: Option<usize>,
+    /// Fewest fields a row can have. Serde needs a field for every column except the optional ones
+    /// (even columns it ignores).
+    required: usize,
+}
+
+impl Columns {
+    /// Column positions, if every required column is present exactly once.
+    fn new(headers: &csv::StringRecord) -> Option<Self> {
+        let position = |name| {
+            let mut matches = headers.iter().enumerate().filter(|&(_, h)| h == name);
+            match (matches.next(), matches.next()) {
+                (Some((i, _)), None) => Ok(Some(i)),
+                (None, _) => Ok(None),
+                (Some(_), Some(_)) => Err(()),
+            }
+        };
+
+        let required = headers
+            .iter()
+            .enumerate()
+            .filter(|&(_, h)| {
+                !matches!(
+                    h,
+                    "amount" | "terminal" | "currency" | "to_currency" | "timestamp"
+                )
+            })
+            .last()
+            .map_or(0, |(last, _)| last + 1);
+
+        Some(Columns {
+            r#type: position("type").ok()??,
+            client: position("client").ok()??,
+            tx: position("tx").ok()??,
+            amount: position("amount").ok()?,
+            terminal: position("terminal").ok()?,
+            currency: position("currency").ok()?,
+            to_currency: position("to_currency").ok()?,
+            timestamp: position("timestamp").ok()?,
+            required,
+        })
+    }
+
+    /// The transaction in `record`, if it's simple enough to parse without serde.
+    fn parse(&self, record: &csv::ByteRecord) -> Option<Transaction> {
+        if record.len() < self.required {
+            return None;
+        }
+
+        // Missing optional fields and empty fields are both `None`, as they are with serde.
+        let optional = |column: Option<usize>| {
+            column
+                .and_then(|column| record.get(column))
+                .filter(|field| !field.is_empty())
+        };
+
+        let r#type = match record.get(self.r#type)? {
+            b"deposit" => TransactionType::Deposit,
+            b"withdrawal" => TransactionType::Withdrawal,
+            b"dispute" => TransactionType::Dispute,
+            b"resolve" => TransactionType::Resolve,
+            b"chargeback" => TransactionType::Chargeback,
+            b"convert" => TransactionType::Convert,
+            b"unlock" => TransactionType::Unlock,
+            _ => return None,
+        };
+        let amount = match optional(self.amount) {
+            Some(field) => Some(parse_amount(field)?),
+            None => None,
+        };
+        let terminal = match optional(self.terminal) {
+            // Serde trims Unicode whitespace, which the byte record hasn't been trimmed of.
+            Some(field) if field.is_ascii() => Some(String::from_utf8(field.to_vec()).ok()?),
+            Some(_) => return None,
+            None => None,
+        };
+        let currency = |column| match optional(column) {
+            Some(field) => Currency::parse(field),
+            None => Some(Currency::IMPLICIT),
+        };
+
+        Some(Transaction {
+            r#type,
+            client_id: parse_integer(record.get(self.client)?)?,
+            tx_id: parse_integer(record.get(self.tx)?)?,
+            amount: amount.map(Money::new),
+            raw_amount: amount,
+            line: None,
+            terminal,
+            currency: currency(self.currency)?,
+            to_currency: currency(self.to_currency)?,
+            timestamp: match optional(self.timestamp) {
+                Some(field) => Some(merge::parse(std::str::from_utf8(field).ok()?.trim())?),
+                None => None,
+            },
+        })
+    }
+}
+
+/// An unsigned integer made of only ASCII digits.
+fn parse_integer<T: TryFrom<u64>>(field: &[u8]) -> Option<T> {
+    if field.is_empty() || field.len() > 19 {
+        return None;
+    }
+
+    let mut n: u64 = 0;
+    for &b in field {
+        if !b.is_ascii_digit() {
+            return None;
+        }
+        n = n * 10 + u64::from(b - b'0');
+    }
+
+    T::try_from(n).ok()
+}
+
+/// An amount like `-12.3456`, with digits on both sides of the decimal point (if any), as written.
+fn parse_amount(field: &[u8]) -> Option<Decimal> {
+    let digits = field.strip_prefix(b"-").unwrap_or(field);
+    let (whole, fraction) = match digits.iter().position(|&b| b == b'.') {
+        Some(point) => (&digits[..point], Some(&digits[point + 1..])),
+        None => (digits, None),
+    };
+
+    let all_digits = |part: &[u8]| !part.is_empty() && part.iter().all(u8::is_ascii_digit);
+    if !all_digits(whole) || !fraction.is_none_or(all_digits) {
+        return None;
+    }
+    if whole.len() + fraction.map_or(0, <[u8]>::len) > FAST_AMOUNT_DIGITS {
+        return None;
+    }
+
+    // Only ASCII digits, a sign, and a point, so this is valid UTF-8.
+    std::str::from_utf8(field).ok()?.parse::<Decimal>().ok()
+}
+
+/// A transaction deserialized from a byte record the same way `csv_transactions` would.
+fn deserialize_byte_record(
+    record: &csv::ByteRecord,
+    headers: Option<&csv::StringRecord>,
+) -> Result<Transaction, Box<dyn Error>> {
+    let mut string_record = csv::StringRecord::from_byte_record(record.clone()).map_err(|e| {
+        parse_error(
+            record,
+            format!("invalid UTF-8 in transaction: {}", e.utf8_error()),
+        )
+    })?;
+    string_record.trim();
+
+    Ok(string_record
+        .deserialize(headers)
+        .map_err(|e| csv_parse_error(record, &e))?)
+}
+
+/// The error for a CSV record which couldn't be deserialized.
+fn csv_parse_error(record: &csv::ByteRecord, e: &csv::Error) -> ParseError {
+    // The error's own position is the record's, which the `ParseError` has already.
+    let message = match e.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+        _ => e.to_string(),
+    };
+
+    parse_error(record, message)
+}
+
+fn parse_error(record: &csv::ByteRecord, message: String) -> ParseError {
+    let fields: Vec<_> = record.iter().map(String::from_utf8_lossy).collect();
+
+    ParseError {
+        path: None,
+        line: record.position().map(csv::Position::line),
+        byte: record.position().map(csv::Position::byte),
+        record: fields.join(","),
+        message,
+    }
+}
+
+/// Parse a single transaction from one line of CSV (without a header, and in
+/// `type,client,tx,amount[,terminal[,currency[,to_currency]]]` order) or NDJSON.
+pub fn parse_line(line: &str, format: InputFormat) -> Result<Transaction, Box<dyn Error>> {
+    match format {
+        InputFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .trim(csv::Trim::All)
+                .flexible(true)
+                .from_reader(line.as_bytes());
+            let headers = Schema::positional_headers();
+            let mut record = csv::StringRecord::new();
+            if !reader.read_record(&mut record)? {
+                return Err("expected a transaction".into());
+            }
+
+            Ok(record.deserialize(Some(&headers))?)
+        }
+        InputFormat::Ndjson => Ok(serde_json::from_str(line)?),
+        InputFormat::Parquet => Err("parquet can't be read a line at a time".into()),
+    }
+}
+
+/// Transactions read from Parquet (see `parquet`), or an error without the `parquet` feature.
+#[allow(unused_variables)]
+pub fn parquet_transactions<R>(reader: R) -> TransactionStream
+where
+    R: io::Read + 'static,
+{
+    #[cfg(feature = "parquet")]
+    return crate::parquet::parquet_transactions(reader);
+    #[cfg(not(feature = "parquet"))]
+    Box::new(std::iter::once(Err(
+        "parquet input requires the parquet feature".into(),
+    )))
+}
+
+/// Transactions read from NDJSON. Blank lines are skipped, and parse errors are `ParseError`s.
+pub fn ndjson_transactions<R>(
+    mut reader: R,
+) -> impl Iterator<Item = Result<Transaction, Box<dyn Error>>>
+where
+    R: io::BufRead,
+{
+    let mut line = String::new();
+    let mut number = 0;
+    let mut byte = 0;
+
+    std::iter::from_fn(move || loop {
+        line.clear();
+        let start = byte;
+        match reader.read_line(&mut line) {
+            Ok(0) => return None,
+            Ok(read) => byte += read as u64,
+            Err(e) => return Some(Err(e.into())),
+        }
+        number += 1;
+
+        let text = line.trim_end_matches(['\n', '\r']);
+        if text.trim().is_empty() {
+            continue;
+        }
+        return Some(match serde_json::from_str::<Transaction>(text) {
+            Ok(mut tx) => {
+                tx.line = Some(number);
+                Ok(tx)
+            }
+            Err(e) => Err(ParseError {
+                path: None,
+                line: Some(number),
+                byte: Some(start),
+                record: text.to_string(),
+                message: e.to_string(),
+            }
+            .into()),
+        });
+    })
+}
+
+/// Drop rows which couldn't be parsed (`ParseError`s) from `transactions`, passing each to
+/// `on_skip`. Any other error (e.g. failing to read the log) is kept, and still stops processing.
+pub fn skip_bad_rows<I, F>(
+    transactions: I,
+    mut on_skip: F,
+) -> impl Iterator<Item = Result<Transaction, Box<dyn Error>>>
+where
+    I: IntoIterator<Item = Result<Transaction, Box<dyn Error>>>,
+    F: FnMut(&ParseError),
+{
+    transactions.into_iter().filter(move |result| {
+        match result.as_ref().map_err(|e| e.downcast_ref::<ParseError>()) {
+            Err(Some(e)) => {
+                on_skip(e);
+                false
+            }
+            _ => true,
+        }
+    })
+}
+
+/// A deterministic sample of clients, given as a percentage with up to two decimal places (e.g.
+/// `1%` or `0.25%`). Sampled clients are chosen by hashing the client ID, so the same clients are
+/// sampled on every run, and every transaction for a sampled client is included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// Hundredths of a percent of clients to include.
+    basis_points: u32,
+}
+
+impl Sample {
+    /// Whether transactions for some client are part of the sample.
+    pub fn includes(&self, client_id: u16) -> bool {
+        client_hash(client_id) % 10_000 < self.basis_points
+    }
+
+    /// Fraction of clients in the sample, for scaling sampled counts up to the full batch.
+    pub fn fraction(&self) -> f64 {
+        f64::from(self.basis_points) / 10_000.0
+    }
+
+    /// Only the transactions in `transactions` which belong to sampled clients. Errors are kept,
+    /// since there's no way to tell which client they belong to.
+    pub fn filter(self, transactions: TransactionStream) -> TransactionStream {
+        Box::new(transactions.filter(move |result| match result {
+            Ok(tx) => self.includes(tx.client_id),
+            Err(_) => true,
+        }))
+    }
+}
+
+/// A set of clients to process, ignoring everyone else (e.g. to investigate a few clients in a huge
+/// log). Every transaction for an included client is kept.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClientFilter {
+    clients: HashSet<u16>,
+}
+
+impl ClientFilter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn insert(&mut self, client_id: u16) {
+        self.clients.insert(client_id);
+    }
+
+    pub fn includes(&self, client_id: u16) -> bool {
+        self.clients.contains(&client_id)
+    }
+
+    /// Only the transactions in `transactions` which belong to included clients. Errors are kept,
+    /// since there's no way to tell which client they belong to.
+    pub fn filter(self, transactions: TransactionStream) -> TransactionStream {
+        Box::new(transactions.filter(move |result| match result {
+            Ok(tx) => self.includes(tx.client_id),
+            Err(_) => true,
+        }))
+    }
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:02}%",
+            self.basis_points / 100,
+            self.basis_points % 100
+        )
+    }
+}
+
+impl FromStr for Sample {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid sample '{}', expected a percentage between 0.01% and 100%",
+                s
+            )
+        };
+
+        let percent: Decimal = s.trim_end_matches('%').parse().map_err(|_| invalid())?;
+        let basis_points = (percent * Decimal::ONE_HUNDRED)
+            .to_u32()
+            .filter(|basis_points| (1..=10_000).contains(basis_points))
+            .ok_or_else(invalid)?;
+        if Decimal::from(basis_points) != percent * Decimal::ONE_HUNDRED {
+            return Err(invalid());
+        }
+
+        Ok(Sample { basis_points })
+    }
+}
+
+/// Fields of a transaction, as the columns of a CSV log are named by default. Logs without a
+/// header (and single lines, see `parse_line`) have the first `POSITIONAL_FIELDS` in this order.
+const FIELDS: [&str; 8] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "terminal",
+    "currency",
+    "to_currency",
+    "timestamp",
+];
+
+/// How many of `FIELDS` a log without a header can have, in order.
+const POSITIONAL_FIELDS: usize = 7;
+
+/// Which columns of a CSV log hold which fields of a transaction, for logs whose columns aren't
+/// named after the fields (e.g. `txn_type` rather than `type`). Given as `field=column` pairs (e.g.
+/// `type=txn_type,client=cust_id`), or a `[schema]` table of them in a config file. Fields which
+/// aren't mapped are read from the columns named after them, and any other columns are ignored.
+///
+/// A log without a header (see `Schema::positional`) has its fields in a fixed order instead, and
+/// a log's fields can be separated by something other than commas (see `Schema::with_delimiter`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "BTreeMap<String, String>")]
+pub struct Schema {
+    /// The column each mapped field is read from.
+    columns: BTreeMap<String, String>,
+    /// The log has no header, so its first row is a transaction.
+    positional: bool,
+    delimiter: u8,
+}
+
+impl Default for Schema {
+    fn default() -> Self {
+        Schema {
+            columns: BTreeMap::new(),
+            positional: false,
+            delimiter: b',',
+        }
+    }
+}
+
+impl Schema {
+    /// The schema of a log without a header, whose columns are `type,client,tx,amount` (optionally
+    /// followed by `terminal`, `currency`, and `to_currency`).
+    pub fn positional() -> Self {
+        Schema {
+            positional: true,
+            ..Default::default()
+        }
+    }
+
+    /// Fields separated by `delimiter` (e.g. `b'\t'` or `b';'`) rather than commas.
+    pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+
+    /// Whether the log is comma separated, with a header naming every field's column.
+    pub fn is_default(&self) -> bool {
+        *self == Schema::default()
+    }
+
+    /// Whether any field is read from a column which isn't named after it.
+    pub fn maps_columns(&self) -> bool {
+        !self.columns.is_empty()
+    }
+
+    /// Whether the log has no header (see `Schema::positional`).
+    pub fn is_positional(&self) -> bool {
+        self.positional
+    }
+
+    /// The header of a log without one.
+    pub fn positional_headers() -> csv::StringRecord {
+        csv::StringRecord::from(&FIELDS[..POSITIONAL_FIELDS])
+    }
+
+    /// `headers` with each mapped column renamed to its field. A column named after a mapped field
+    /// is read from elsewhere, so it's given no name (and ignored).
+    pub fn rename(&self, headers: &csv::StringRecord) -> csv::StringRecord {
+        headers
+            .iter()
+            .map(
+                |header| match self.columns.iter().find(|(_, column)| *column == header) {
+                    Some((field, _)) => field.as_str(),
+                    None if self.columns.contains_key(header) => "",
+                    None => header,
+                },
+            )
+            .collect()
+    }
+}
+
+impl TryFrom<BTreeMap<String, String>> for Schema {
+    type Error = String;
+
+    fn try_from(columns: BTreeMap<String, String>) -> Result<Self, Self::Error> {
+        if let Some(field) = columns
+            .keys()
+            .find(|field| !FIELDS.contains(&field.as_str()))
+        {
+            return Err(format!(
+                "unknown field '{}' in schema, expected {}",
+                field,
+                FIELDS.join(", ")
+            ));
+        }
+        let mut read = HashSet::new();
+        if let Some(column) = columns.values().find(|column| !read.insert(*column)) {
+            return Err(format!("column '{}' is mapped to several fields", column));
+        }
+
+        Ok(Schema {
+            columns,
+            ..Default::default()
+        })
+    }
+}
+
+impl FromStr for Schema {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut columns = BTreeMap::new();
+        for pair in s.split(',') {
+            let (field, column) = match pair.split_once('=') {
+                Some((field, column)) if !column.trim().is_empty() => (field.trim(), column.trim()),
+                _ => {
+                    return Err(format!(
+                        "invalid schema '{}', expected field=column pairs (e.g. type=txn_type)",
+                        s
+                    ))
+                }
+            };
+            if columns
+                .insert(field.to_string(), column.to_string())
+                .is_some()
+            {
+                return Err(format!("field '{}' is mapped more than once", field));
+            }
+        }
+
+        Schema::try_from(columns)
+    }
+}
@@ -0,0 +1,59 @@
+/// Stopping a batch run cleanly on Ctrl-C (SIGINT).
+///
+/// The first interrupt only raises a flag, which the transaction stream checks before each row, so
+/// reading stops between transactions and everything applied so far can still be reported: a
+/// partial summary, and (with `--interrupt-snapshot`) a snapshot of the engine state to triage or
+/// resume from. Balances aren't exported, as they'd pass for the outcome of the whole log. A second
+/// interrupt exits at once, for a run which is stuck waiting for input.
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::input::TransactionStream;
+
+/// Exit code after a second interrupt, as a shell reports a process killed by SIGINT.
+const EXIT_CODE: i32 = 130;
+
+/// Whether the run has been interrupted.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    /// Handle Ctrl-C for the rest of the process, raising the returned flag on the first interrupt
+    /// and exiting on the second.
+    pub fn install() -> Result<Self, ctrlc::Error> {
+        let interrupt = Interrupt::default();
+        let flag = interrupt.0.clone();
+        ctrlc::set_handler(move || {
+            if flag.swap(true, Ordering::SeqCst) {
+                std::process::exit(EXIT_CODE);
+            }
+        })?;
+
+        Ok(interrupt)
+    }
+
+    /// Raise the flag, as an interrupt would.
+    pub fn raise(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the run was interrupted.
+    pub fn is_raised(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Stop reading `transactions` once `interrupt` is raised, without reading another row.
+pub fn until_interrupted(
+    mut transactions: TransactionStream,
+    interrupt: &Interrupt,
+) -> TransactionStream {
+    let interrupt = interrupt.clone();
+    Box::new(std::iter::from_fn(move || {
+        if interrupt.is_raised() {
+            None
+        } else {
+            transactions.next()
+        }
+    }))
+}
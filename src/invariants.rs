@@ -0,0 +1,101 @@
+/// Balance invariants, checked after every applied transaction (see `Engine::with_invariant_checks`).
+///
+/// A client's funds in every currency must add up (`total == available + held`), and held funds
+/// can't be negative. Available funds can legitimately go below zero (a chargeback of a deposit
+/// which was already withdrawn, or a withdrawal within a credit limit), so that's only checked on
+/// request. A transaction which breaks an invariant is a bug in the engine rather than in the
+/// input, so it stops processing, naming the transaction. Debug builds check the basic invariants
+/// with assertions whether or not they were asked to, so tests fail where a balance first breaks.
+use std::str::FromStr;
+
+use crate::currency::Currency;
+use crate::error::TransactionError;
+use crate::money::Money;
+use crate::{ClientState, Engine, Transaction};
+
+/// Which invariants are checked.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum InvariantCheck {
+    /// Funds add up, and held funds aren't negative.
+    #[default]
+    Balances,
+    /// As `Balances`, and available funds aren't negative either.
+    NonNegative,
+}
+
+impl FromStr for InvariantCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "balances" => Ok(InvariantCheck::Balances),
+            "non-negative" => Ok(InvariantCheck::NonNegative),
+            _ => Err(format!(
+                "unknown invariant check '{}', expected balances or non-negative",
+                s
+            )),
+        }
+    }
+}
+
+/// Check a client's funds in every currency, describing the first invariant which doesn't hold.
+pub fn check(state: &ClientState, check: InvariantCheck) -> Result<(), String> {
+    let currencies = std::iter::once(Currency::IMPLICIT).chain(state.currencies.keys().copied());
+    for currency in currencies {
+        let balance = state.balance(currency);
+        let suffix = if currency.is_implicit() {
+            String::new()
+        } else {
+            format!(" in {}", currency)
+        };
+
+        if balance.total != balance.available + balance.held {
+            return Err(format!(
+                "total {} isn't available {} plus held {}{}",
+                balance.total, balance.available, balance.held, suffix
+            ));
+        }
+        if balance.held < Money::ZERO {
+            return Err(format!("held {} is negative{}", balance.held, suffix));
+        }
+        if check == InvariantCheck::NonNegative && balance.available < Money::ZERO {
+            return Err(format!(
+                "available {} is negative{}",
+                balance.available, suffix
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+impl Engine {
+    /// Check the invariants of the client `tx` was just applied to, if the engine checks them.
+    pub(crate) fn check_invariants(&self, tx: &Transaction) -> Result<(), TransactionError> {
+        let state = match self.client_states.get(&tx.client_id) {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+
+        match self.invariant_check {
+            Some(invariants) => {
+                check(state, invariants).map_err(|message| TransactionError::InvariantViolated {
+                    client_id: tx.client_id,
+                    tx_id: tx.tx_id,
+                    message,
+                })
+            }
+            None => {
+                if cfg!(debug_assertions) {
+                    if let Err(message) = check(state, InvariantCheck::Balances) {
+                        panic!(
+                            "transaction {} for client {} broke a balance invariant: {}",
+                            tx.tx_id, tx.client_id, message
+                        );
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
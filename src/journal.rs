@@ -0,0 +1,260 @@
+/// Write-ahead journal of transactions, for recovering from a crash part way through a run.
+///
+/// Every transaction given to the engine is appended to the journal as a line of JSON, along with
+/// the line of the input it came from, and the reason it was rejected (if it was). Rejected
+/// transactions still move dispute windows along, so they're replayed too, and have to be rejected
+/// for the same reason again. After a crash, replaying the journal brings a fresh engine back to
+/// the same state, and the input can be resumed after the last journaled line.
+///
+/// How often the journal is synced to disk is configurable (see `JournalSync`). Entries which
+/// weren't synced can be lost in a crash, in which case the transactions they recorded are applied
+/// again from the input when resuming. An entry only partly written when the process died is
+/// discarded.
+///
+/// A journal starts with a hash of the engine state its run started from (e.g. a restored
+/// snapshot), and is only replayed into an engine in that same state. Replaying it on top of any
+/// other state (a different snapshot, or one written after the journaled run) would silently
+/// diverge from the interrupted run, so it's refused instead.
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use rust_decimal::Decimal;
+
+use crate::currency::Currency;
+use crate::digest::to_hex;
+use crate::error::TransactionError;
+use crate::money::Money;
+use crate::{Engine, Transaction, TransactionType};
+
+/// When the journal is synced to disk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum JournalSync {
+    /// After every entry. Nothing applied is lost in a crash, but every transaction waits on disk.
+    #[default]
+    Always,
+    /// After every `n` entries, so up to `n - 1` entries can be lost.
+    Every(u64),
+    /// Only when the run finishes. Entries are buffered in the meantime, so a crash can lose any
+    /// number of them.
+    Never,
+}
+
+impl FromStr for JournalSync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(JournalSync::Always),
+            "never" => Ok(JournalSync::Never),
+            _ => match s.parse() {
+                Ok(0) | Err(_) => Err(format!(
+                    "unknown journal sync '{}', expected always, never, or a number of entries",
+                    s
+                )),
+                Ok(1) => Ok(JournalSync::Always),
+                Ok(n) => Ok(JournalSync::Every(n)),
+            },
+        }
+    }
+}
+
+/// First line of a journal.
+#[derive(Serialize, Deserialize)]
+struct Start {
+    /// Hash of the engine state the journaled run started from (see `state_hash`).
+    start: String,
+}
+
+/// A single journaled transaction.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// Line of the input the transaction was read from.
+    line: Option<u64>,
+    r#type: TransactionType,
+    client: u16,
+    tx: u32,
+    /// The amount as it was written, so it's rounded (or rejected as inexact) the same way again.
+    amount: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Currency::is_implicit")]
+    currency: Currency,
+    #[serde(default, skip_serializing_if = "Currency::is_implicit")]
+    to_currency: Currency,
+    /// The transaction's timestamp, so replay sees the same times (e.g. for dispute windows).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    /// Why the engine rejected the transaction, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rejected: Option<String>,
+}
+
+/// Appends transactions to a journal file.
+pub struct JournalWriter {
+    writer: io::BufWriter<File>,
+    sync: JournalSync,
+    /// Entries written since the journal was last synced.
+    unsynced: u64,
+}
+
+impl JournalWriter {
+    /// Start a new journal at `path` for a run starting from `engine`'s current state, replacing
+    /// any existing journal.
+    pub fn create(path: &Path, sync: JournalSync, engine: &Engine) -> Result<Self, Box<dyn Error>> {
+        let mut journal = Self::new(File::create(path)?, sync);
+        let start = Start {
+            start: state_hash(engine)?,
+        };
+        serde_json::to_writer(&mut journal.writer, &start)?;
+        journal.writer.write_all(b"\n")?;
+        journal.sync()?;
+
+        Ok(journal)
+    }
+
+    /// Continue an existing journal (e.g. after replaying it), creating it if it doesn't exist. An
+    /// entry left partly written by a crash is removed first.
+    pub fn append(path: &Path, sync: JournalSync) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        let complete = contents
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(0, |end| end + 1);
+        file.set_len(complete as u64)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(Self::new(file, sync))
+    }
+
+    fn new(file: File, sync: JournalSync) -> Self {
+        JournalWriter {
+            writer: io::BufWriter::new(file),
+            sync,
+            unsynced: 0,
+        }
+    }
+
+    /// Record a transaction which the engine applied, or rejected with `rejected`.
+    pub fn record(
+        &mut self,
+        tx: &Transaction,
+        rejected: Option<&TransactionError>,
+    ) -> io::Result<()> {
+        let entry = Entry {
+            line: tx.line,
+            r#type: tx.r#type,
+            client: tx.client_id,
+            tx: tx.tx_id,
+            amount: tx.raw_amount.or_else(|| tx.amount.map(Decimal::from)),
+            currency: tx.currency,
+            to_currency: tx.to_currency,
+            timestamp: tx.timestamp,
+            rejected: rejected.map(|e| e.reason().to_string()),
+        };
+        serde_json::to_writer(&mut self.writer, &entry)?;
+        self.writer.write_all(b"\n")?;
+
+        self.unsynced += 1;
+        match self.sync {
+            JournalSync::Always => self.sync(),
+            JournalSync::Every(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sync every entry written so far to disk. Called once processing has finished.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.unsynced = 0;
+
+        Ok(())
+    }
+}
+
+/// Apply every transaction in the journal at `path` to `engine`, returning the last input line
+/// which was journaled (if any). Replay has to start from the same state and use the same policies
+/// as the run which wrote the journal, so a different starting state, or any transaction which
+/// isn't applied (or rejected) on replay as it was in the journaled run, is an error.
+pub fn replay(engine: &mut Engine, path: &Path) -> Result<Option<u64>, Box<dyn Error>> {
+    let mut last_line = None;
+
+    for (index, line) in io::BufReader::new(File::open(path)?)
+        .split(b'\n')
+        .enumerate()
+    {
+        let line = line?;
+        if index == 0 {
+            // Journals written before starting states were recorded go straight to entries.
+            if let Ok(Start { start }) = serde_json::from_slice(&line) {
+                if start != state_hash(engine)? {
+                    return Err("journal was started from a different engine state (e.g. \
+                                another snapshot) than it's being replayed into"
+                        .into());
+                }
+                continue;
+            }
+        }
+        let entry: Entry = match serde_json::from_slice(&line) {
+            Ok(entry) => entry,
+            // The last entry may have been cut short by the crash being recovered from.
+            Err(e) if e.is_eof() => break,
+            Err(e) => return Err(format!("journal line {}: {}", index + 1, e).into()),
+        };
+
+        let tx = Transaction {
+            r#type: entry.r#type,
+            client_id: entry.client,
+            tx_id: entry.tx,
+            amount: entry.amount.map(Money::new),
+            raw_amount: entry.amount,
+            line: entry.line,
+            terminal: None,
+            currency: entry.currency,
+            to_currency: entry.to_currency,
+            timestamp: entry.timestamp,
+        };
+        match (engine.apply(&tx), entry.rejected) {
+            (Ok(()), None) => {}
+            (Err(e), Some(reason)) if e.reason() == reason => {}
+            (Ok(()), Some(reason)) => {
+                return Err(format!(
+                    "journal line {} was rejected ({}), but is applied on replay",
+                    index + 1,
+                    reason
+                )
+                .into())
+            }
+            (Err(e), _) => {
+                return Err(format!("journal line {} can't be replayed: {}", index + 1, e).into())
+            }
+        }
+        // Held transactions were rejected when they were released by the journaled run too.
+        engine.take_released_rejects();
+
+        last_line = last_line.max(entry.line);
+    }
+
+    Ok(last_line)
+}
+
+/// SHA-256 hash (as hex) of an engine's state, which is the same for any two engines with the same
+/// client states and disputable transactions.
+pub fn state_hash(engine: &Engine) -> Result<String, Box<dyn Error>> {
+    let mut hasher = Sha256::new();
+    engine.write_snapshot(&mut hasher)?;
+
+    Ok(to_hex(&hasher.finalize()))
+}
@@ -0,0 +1,155 @@
+/// Consuming transactions from a Kafka topic (with the `kafka` feature), for applying a live stream
+/// continuously (`payment-engine consume`).
+///
+/// Each message holds one or more transactions, a line each (CSV in
+/// `type,client,tx,amount[,terminal,...]` order without a header, or NDJSON, as for `serve`), which
+/// are applied in order. Rejected transactions are logged and don't stop the consumer, just as they
+/// don't stop a batch run.
+///
+/// A message's offset is only stored once every transaction in it has been applied, and stored
+/// offsets are only committed once a snapshot of the engine state covering them has been written
+/// (every `snapshot_interval`, and when the consumer is stopped with Ctrl-C). A consumer started
+/// again restores that snapshot and carries on from the committed offsets, so no transaction is
+/// lost or applied twice. The exception is a consumer which stopped between writing a snapshot and
+/// committing, which applies the messages since the previous commit again (`--dedup` skips them).
+///
+/// A message which can't be applied (a line which can't be parsed, without `--skip-bad-rows`, or a
+/// fatal rejection, e.g. in strict mode) stops the consumer without a snapshot or a commit, so it's
+/// consumed again (after everything since the last snapshot) once the consumer is restarted.
+///
+/// The engine needs to see every transaction for a client, so the consumer group should have one
+/// member, or the topic be partitioned by client with each member keeping its own snapshot.
+use std::error::Error;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+use rdkafka::Message;
+use tracing::{info, warn};
+
+use crate::cli::ConsumeOptions;
+use crate::input::{parse_line, InputFormat};
+use crate::interrupt::Interrupt;
+use crate::output::{AtomicFile, BalanceWriter, SortBy};
+use crate::{snapshot, write_balances, Engine};
+
+/// How long to wait for a message before checking whether to snapshot or stop.
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Consume transactions from `options.topic` into `engine` until `interrupt` is raised, or a
+/// message can't be applied.
+pub fn consume(
+    engine: &mut Engine,
+    options: &ConsumeOptions,
+    interrupt: &Interrupt,
+) -> Result<(), Box<dyn Error>> {
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &options.brokers)
+        .set("group.id", &options.group)
+        .set("auto.offset.reset", "earliest")
+        // Offsets are stored once a message has been applied, and committed with a snapshot.
+        .set("enable.auto.commit", "false")
+        .set("enable.auto.offset.store", "false")
+        .create()?;
+    consumer.subscribe(&[&options.topic])?;
+    info!("consuming {} from {}", options.topic, options.brokers);
+
+    let mut last_snapshot = Instant::now();
+    let mut uncommitted = false;
+    while !interrupt.is_raised() {
+        match consumer.poll(POLL_TIMEOUT) {
+            Some(Ok(message)) => {
+                let payload = message.payload().unwrap_or_default();
+                apply_message(engine, payload, options).map_err(|e| {
+                    format!(
+                        "couldn't apply the message at offset {} of partition {}: {}",
+                        message.offset(),
+                        message.partition(),
+                        e
+                    )
+                })?;
+                consumer.store_offset_from_message(&message)?;
+                uncommitted = true;
+            }
+            // The client reconnects by itself, so errors (e.g. a broker going down) are passing.
+            Some(Err(e)) => warn!("couldn't consume from {}: {}", options.topic, e),
+            None => {}
+        }
+
+        if last_snapshot.elapsed() >= options.snapshot_interval {
+            if uncommitted {
+                checkpoint(engine, options)?;
+                consumer.commit_consumer_state(CommitMode::Sync)?;
+                uncommitted = false;
+            }
+            last_snapshot = Instant::now();
+        }
+    }
+
+    if uncommitted {
+        checkpoint(engine, options)?;
+        consumer.commit_consumer_state(CommitMode::Sync)?;
+    }
+
+    Ok(())
+}
+
+/// Apply every transaction in a message, in order. Blank lines and a leading CSV header are
+/// ignored. Returns the number of transactions applied (or rejected).
+pub fn apply_message(
+    engine: &mut Engine,
+    payload: &[u8],
+    options: &ConsumeOptions,
+) -> Result<usize, Box<dyn Error>> {
+    let format = options.options.input_format;
+    let payload = std::str::from_utf8(payload)?;
+    let mut applied = 0;
+
+    for (index, line) in payload.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (index == 0 && format == InputFormat::Csv && line.starts_with("type"))
+        {
+            continue;
+        }
+
+        let tx = match parse_line(line, format) {
+            Ok(tx) => tx,
+            Err(e) if options.options.skip_bad_rows => {
+                warn!("skipping '{}' which can't be parsed: {}", line, e);
+                continue;
+            }
+            Err(e) => return Err(format!("'{}' can't be parsed: {}", line, e).into()),
+        };
+        match engine.apply(&tx) {
+            Ok(()) => {}
+            Err(e) if engine.is_fatal(&e) => return Err(e.into()),
+            Err(e) => warn!("transaction {} rejected: {}", tx.tx_id, e),
+        }
+        for (tx, e) in engine.take_released_rejects() {
+            warn!("held transaction {} rejected after unlock: {}", tx.tx_id, e);
+        }
+        applied += 1;
+    }
+
+    Ok(applied)
+}
+
+/// Write a snapshot of the engine state, and the balances (if they're exported).
+pub fn checkpoint(engine: &Engine, options: &ConsumeOptions) -> Result<(), Box<dyn Error>> {
+    snapshot::write_file(engine, Path::new(&options.snapshot_path))?;
+
+    if let Some(path) = &options.output_path {
+        let mut file = AtomicFile::create(Path::new(path))?;
+        let mut writer = io::BufWriter::new(&mut file);
+        let mut sink = BalanceWriter::new(&mut writer, &options.options.output_dialect);
+        write_balances(&mut sink, engine.client_states(), SortBy::Client)?;
+        drop(sink);
+        writer.flush()?;
+        drop(writer);
+        file.commit()?;
+    }
+
+    Ok(())
+}
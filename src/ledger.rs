@@ -0,0 +1,279 @@
+/// Keeping the engine's state in a SQLite ledger (`--db`), so it lasts across runs and disputable
+/// transactions don't have to fit in memory (requires the `sqlite` feature).
+///
+/// Every disputable transaction is stored in the ledger's `disputable` table rather than in memory,
+/// and looked up there when it's disputed. Client states stay in memory while the log is applied
+/// (client IDs are 16 bits, so there are at most 65536 of them), and are stored when the run is
+/// committed: the rest of the engine's state goes in the `state` table as a snapshot without its
+/// transactions (see `snapshot`), and each client's balances go in the `clients` table, a row per
+/// currency, for anything which wants to query them.
+///
+/// A run happens in a single SQLite transaction, which `Engine::commit_ledger` commits once the
+/// whole log has been applied. A run which fails or is interrupted leaves the ledger as it was, so
+/// it can simply be run again. The next run against the same ledger picks up where the last one
+/// left off.
+use std::collections::HashSet;
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use rust_decimal::Decimal;
+
+use crate::currency::Currency;
+use crate::money::Money;
+use crate::store::{DisputableKind, DisputableTx};
+use crate::Engine;
+
+/// Version of the ledger's schema written by this build.
+pub const LEDGER_VERSION: u32 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS disputable (
+        tx INTEGER PRIMARY KEY,
+        client INTEGER NOT NULL,
+        type TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        currency TEXT NOT NULL,
+        at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS disputable_at ON disputable (at);
+    CREATE TABLE IF NOT EXISTS clients (
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        total TEXT NOT NULL,
+        locked INTEGER NOT NULL,
+        version INTEGER NOT NULL,
+        PRIMARY KEY (client, currency)
+    );
+    CREATE TABLE IF NOT EXISTS state (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        snapshot TEXT NOT NULL
+    );
+";
+
+/// An open ledger, with a SQLite transaction in progress.
+pub(crate) struct Ledger {
+    connection: Connection,
+}
+
+impl Ledger {
+    /// Open the ledger at `path`, creating it if it doesn't exist, and start a transaction.
+    fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let connection = Connection::open(path)?;
+        let version: u32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        match version {
+            0 => {
+                connection.execute_batch(SCHEMA)?;
+                connection.pragma_update(None, "user_version", LEDGER_VERSION)?;
+            }
+            LEDGER_VERSION => {}
+            _ => {
+                return Err(format!(
+                    "unsupported ledger version {}, expected {}",
+                    version, LEDGER_VERSION
+                )
+                .into())
+            }
+        }
+        connection.execute_batch("BEGIN")?;
+
+        Ok(Ledger { connection })
+    }
+
+    /// The engine's state when the ledger was last committed, if it ever was.
+    fn state(&self) -> rusqlite::Result<Option<String>> {
+        self.connection
+            .query_row("SELECT snapshot FROM state WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()
+    }
+
+    pub(crate) fn get(&self, tx_id: u32) -> io::Result<Option<DisputableTx>> {
+        self.connection
+            .prepare_cached(&format!("{} WHERE tx = ?1", SELECT))
+            .and_then(|mut statement| statement.query_row([tx_id], read_row).optional())
+            .map_err(io_error)?
+            .map(decode)
+            .transpose()
+            .map(|tx| tx.map(|(_, tx)| tx))
+    }
+
+    pub(crate) fn insert(&self, tx_id: u32, tx: DisputableTx) -> io::Result<()> {
+        let r#type = match tx.kind {
+            DisputableKind::Deposit => "deposit",
+            DisputableKind::Withdrawal => "withdrawal",
+        };
+        self.connection
+            .prepare_cached(
+                "INSERT OR REPLACE INTO disputable (tx, client, type, amount, currency, at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )
+            .and_then(|mut statement| {
+                statement.execute(params![
+                    tx_id,
+                    tx.client_id,
+                    r#type,
+                    tx.amount.to_string(),
+                    tx.currency.to_string(),
+                    tx.at as i64
+                ])
+            })
+            .map_err(io_error)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn stored_count(&self) -> io::Result<usize> {
+        self.connection
+            .query_row("SELECT COUNT(*) FROM disputable", [], |row| row.get(0))
+            .map_err(io_error)
+    }
+
+    pub(crate) fn transactions(&self) -> io::Result<Vec<(u32, DisputableTx)>> {
+        let rows: Vec<Row> = self
+            .connection
+            .prepare(SELECT)
+            .and_then(|mut statement| statement.query_map([], read_row)?.collect())
+            .map_err(io_error)?;
+
+        rows.into_iter().map(decode).collect()
+    }
+
+    /// Forget transactions applied before `cutoff`, other than those in `keep`.
+    pub(crate) fn evict(&self, cutoff: u64, keep: &HashSet<u32>) -> io::Result<()> {
+        // `keep` is passed as a JSON array, so eviction is a single statement however many
+        // transactions are kept.
+        let keep = serde_json::to_string(keep).map_err(io::Error::from)?;
+        self.connection
+            .prepare_cached(
+                "DELETE FROM disputable
+                 WHERE at < ?1 AND tx NOT IN (SELECT value FROM json_each(?2))",
+            )
+            .and_then(|mut statement| statement.execute(params![cutoff as i64, keep]))
+            .map_err(io_error)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn clear(&self) -> io::Result<()> {
+        self.connection
+            .execute("DELETE FROM disputable", [])
+            .map_err(io_error)?;
+
+        Ok(())
+    }
+
+    /// Store `engine`'s state and client balances, commit everything since the last commit, and
+    /// start the next transaction.
+    fn commit(&self, engine: &Engine, state: &str) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO state (id, snapshot) VALUES (0, ?1)",
+            [state],
+        )?;
+        self.connection.execute("DELETE FROM clients", [])?;
+        let mut insert = self.connection.prepare_cached(
+            "INSERT INTO clients (client, currency, available, held, total, locked, version)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        for row in engine
+            .client_states
+            .values()
+            .flat_map(|state| state.currency_rows())
+        {
+            insert.execute(params![
+                row.client_id,
+                row.currency.unwrap_or_default().to_string(),
+                row.available.to_string(),
+                row.held.to_string(),
+                row.total.to_string(),
+                row.locked,
+                row.version as i64
+            ])?;
+        }
+
+        self.connection.execute_batch("COMMIT; BEGIN")
+    }
+}
+
+/// Columns of a stored transaction, in the order `read_row` expects them.
+const SELECT: &str = "SELECT tx, client, type, amount, currency, at FROM disputable";
+
+/// A stored transaction as it's read, before its columns are parsed.
+type Row = (u32, u16, String, String, String, i64);
+
+fn read_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Row> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+    ))
+}
+
+fn decode(
+    (tx_id, client_id, r#type, amount, currency, at): Row,
+) -> io::Result<(u32, DisputableTx)> {
+    let tx = DisputableTx {
+        kind: match r#type.as_str() {
+            "deposit" => DisputableKind::Deposit,
+            "withdrawal" => DisputableKind::Withdrawal,
+            _ => return Err(invalid(tx_id, "type")),
+        },
+        client_id,
+        amount: Decimal::from_str(&amount)
+            .map(Money::new)
+            .map_err(|_| invalid(tx_id, "amount"))?,
+        currency: Currency::from_str(&currency).map_err(|_| invalid(tx_id, "currency"))?,
+        at: at as u64,
+    };
+
+    Ok((tx_id, tx))
+}
+
+fn io_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn invalid(tx_id: u32, column: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("ledger has an invalid {} for transaction {}", column, tx_id),
+    )
+}
+
+impl Engine {
+    /// Keep the engine's state in the SQLite ledger at `path` (see the module docs), creating it if
+    /// it doesn't exist, or restoring the state it was last committed with. Policies are kept, as
+    /// with `restore_snapshot`, so they should be set first.
+    pub fn with_ledger(mut self, path: &Path) -> Result<Self, Box<dyn Error>> {
+        let ledger = Ledger::open(path)?;
+        let state = ledger.state()?;
+        self.disputable_transactions.ledger = Some(ledger);
+
+        match state {
+            Some(state) => self.restore_state(state.as_bytes(), false),
+            None => Ok(self),
+        }
+    }
+
+    /// Commit the engine's state to its ledger, if it has one. Nothing applied since the last
+    /// commit is kept in the ledger until then.
+    pub fn commit_ledger(&self) -> Result<(), Box<dyn Error>> {
+        let ledger = match &self.disputable_transactions.ledger {
+            Some(ledger) => ledger,
+            None => return Ok(()),
+        };
+        let mut state = Vec::new();
+        self.write_state(&mut state, false)?;
+        ledger.commit(self, std::str::from_utf8(&state)?)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1848 @@
+/// A toy parser/processer for transaction data, as might be used for an ATM.
+///
+/// The engine is usable as a library (see `Engine`), and the `payment-engine` binary wraps it in a
+/// command line interface.
+///
+/// John Ferguson, 2022
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::io::{self, BufRead};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+use csv::{ReaderBuilder, Trim};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub mod cli;
+pub mod clock;
+pub mod compact;
+pub mod config;
+pub mod currency;
+pub mod decompress;
+pub mod dedup;
+pub mod digest;
+#[cfg(feature = "tokio")]
+pub mod embedded;
+pub mod error;
+pub mod events;
+pub mod fees;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod flaky;
+pub mod handler;
+pub mod history;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod input;
+pub mod interrupt;
+pub mod invariants;
+pub mod journal;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "sqlite")]
+pub mod ledger;
+pub mod limits;
+pub mod logging;
+pub mod merge;
+pub mod metrics;
+pub mod money;
+pub mod output;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod policy_report;
+pub mod posting;
+pub mod progress;
+pub mod proof;
+pub mod rates;
+pub mod reorder;
+pub mod replication;
+pub mod server;
+pub mod snapshot;
+pub mod store;
+#[cfg(feature = "tokio")]
+pub mod stream;
+pub mod summary;
+pub mod synthetic;
+pub mod terminal;
+#[cfg(feature = "test-util")]
+pub mod test_util;
+#[cfg(test)]
+mod tests;
+
+use clock::{Clock, TransactionClock};
+use currency::{Balance, Currency};
+use dedup::ProcessedSet;
+use error::{ParseError, TransactionError};
+use events::EventLog;
+use fees::FeeSchedule;
+use handler::{HandlerRegistry, TypeName};
+use history::{LedgerEntry, StateHistory};
+use input::{
+    fast_csv_transactions, fast_csv_transactions_with_headers, ndjson_transactions, open_input,
+    InputFormat, RetryInterrupted, Schema, TransactionStream,
+};
+use invariants::InvariantCheck;
+use limits::CreditLimits;
+use metrics::Metrics;
+use money::{Money, Precision};
+use output::{shard_for_client, BalanceSink, SortBy};
+use posting::Posting;
+use rates::RateTable;
+use replication::Replication;
+use store::{DisputableKind, DisputableStore, DisputableTx};
+
+/// Maximum size of CSV reader buffer. Useful for larger datasets.
+const CSV_READER_BUFFER_SIZE_IN_BYTES: usize = 1024;
+/// Number of transactions sent to a worker at a time when processing in parallel.
+const PARALLEL_CHUNK_SIZE: usize = 1024;
+/// Number of chunks which can be waiting for each worker before reading blocks.
+const PARALLEL_CHANNEL_CAPACITY: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionType {
+    /// Credit to a client's account. Increases available and total funds.
+    Deposit,
+    /// Debit to the client's account. Decreases the available and total funds. Does not apply when
+    /// the client lacks the funds for the transaction.
+    Withdrawal,
+    /// Claim that some transaction was erroneous. Decreases available funds, and increases held
+    /// funds. Has no associated amount, and references an amount in another transaction (if it
+    /// exists).
+    Dispute,
+    /// Resolution to a Dispute. Held funds decrease by amount of disputed transaction, available
+    /// funds increase by amount of disputed transaction.
+    Resolve,
+    /// Resolution to a Dispute. Held funds decrease by disputed amount, and client's account is
+    /// frozen/locked.
+    Chargeback,
+    /// Exchange of some of the client's funds in one currency for another, at the engine's rate.
+    /// Decreases available funds in `currency` by the amount, and increases available funds in
+    /// `to_currency` by the converted amount. Can't be disputed.
+    Convert,
+    /// Administrative reinstatement of a locked account, clearing its `locked` flag. Has no
+    /// associated amount, and doesn't change any funds. Only applied by engines which allow admin
+    /// ops, as the specification has no way to unlock an account.
+    Unlock,
+    /// Any other type with a valid name, for embedders' own types (see `handler`). Applied by
+    /// engines with a handler for it, and handled like an unknown type by others.
+    Custom(TypeName),
+    /// Any type whose name isn't valid (or `unknown`). Never applied, and handled according to the
+    /// engine's `UnknownTypePolicy`.
+    Unknown,
+}
+
+impl TransactionType {
+    /// The type's name, as it's written in transaction logs.
+    pub fn name(&self) -> &str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Convert => "convert",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Custom(name) => name.as_str(),
+            TransactionType::Unknown => "unknown",
+        }
+    }
+
+    /// The built-in type with this name, if there is one.
+    pub(crate) fn built_in(name: &str) -> Option<Self> {
+        Some(match name {
+            "deposit" => TransactionType::Deposit,
+            "withdrawal" => TransactionType::Withdrawal,
+            "dispute" => TransactionType::Dispute,
+            "resolve" => TransactionType::Resolve,
+            "chargeback" => TransactionType::Chargeback,
+            "convert" => TransactionType::Convert,
+            "unlock" => TransactionType::Unlock,
+            _ => return None,
+        })
+    }
+}
+
+impl Serialize for TransactionType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Ok(TransactionType::built_in(&name)
+            .or_else(|| TypeName::new(&name).ok().map(TransactionType::Custom))
+            .unwrap_or(TransactionType::Unknown))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "TransactionRecord")]
+pub struct Transaction {
+    pub r#type: TransactionType,
+    pub client_id: u16,
+    pub tx_id: u32,
+    /// Transaction amount, rounded to 4 decimal places when parsed.
+    pub amount: Option<Money>,
+    /// The amount as it was written, before it was rounded, if it was read. Engines with another
+    /// precision round this (once) rather than `amount`, and reject it if it can't be kept exactly
+    /// (see `Engine::with_exact_amounts`).
+    pub raw_amount: Option<Decimal>,
+    /// Line of the input the transaction was read from (if known), for reporting errors.
+    pub line: Option<u64>,
+    /// Terminal (or session) the transaction was submitted from, if the input says.
+    pub terminal: Option<String>,
+    /// Currency of the transaction. Transactions which don't say are in the implicit currency.
+    pub currency: Currency,
+    /// Currency a `convert` exchanges funds into. Unused by other transactions.
+    pub to_currency: Currency,
+    /// When the transaction happened (milliseconds since the Unix epoch) or its sequence number,
+    /// if the input says. Only used to merge several inputs (see `merge`).
+    pub timestamp: Option<u64>,
+}
+
+/// A transaction as it's read, with its amount as written.
+#[derive(Deserialize)]
+struct TransactionRecord {
+    r#type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<Decimal>,
+    #[serde(default)]
+    terminal: Option<String>,
+    #[serde(default, deserialize_with = "currency::deserialize_optional")]
+    currency: Currency,
+    #[serde(default, deserialize_with = "currency::deserialize_optional")]
+    to_currency: Currency,
+    #[serde(default, deserialize_with = "merge::deserialize_timestamp")]
+    timestamp: Option<u64>,
+}
+
+impl From<TransactionRecord> for Transaction {
+    fn from(record: TransactionRecord) -> Self {
+        Transaction {
+            r#type: record.r#type,
+            client_id: record.client,
+            tx_id: record.tx,
+            amount: record.amount.map(Money::new),
+            raw_amount: record.amount,
+            line: None,
+            terminal: record.terminal,
+            currency: record.currency,
+            to_currency: record.to_currency,
+            timestamp: record.timestamp,
+        }
+    }
+}
+
+/// What a transaction does, with only the parts its type uses. Built from a `Transaction` by
+/// `Transaction::kind`, which checks them, so a deposit always has a valid amount and a dispute
+/// never has one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionKind {
+    Deposit {
+        amount: Money,
+    },
+    Withdrawal {
+        amount: Money,
+    },
+    Convert {
+        amount: Money,
+        to_currency: Currency,
+    },
+    /// Disputes, resolves, and chargebacks reference the transaction with their own ID.
+    Dispute {
+        ref_tx: u32,
+    },
+    Resolve {
+        ref_tx: u32,
+    },
+    Chargeback {
+        ref_tx: u32,
+    },
+    Unlock,
+    Custom(TypeName),
+    Unknown,
+}
+
+impl Transaction {
+    /// What the transaction does. Deposits, withdrawals, and conversions must have an amount which
+    /// is positive after rounding. Amounts given for any other type are ignored.
+    pub fn kind(&self) -> Result<TransactionKind, TransactionError> {
+        Ok(match self.r#type {
+            TransactionType::Deposit => TransactionKind::Deposit {
+                amount: self.validated_amount()?,
+            },
+            TransactionType::Withdrawal => TransactionKind::Withdrawal {
+                amount: self.validated_amount()?,
+            },
+            TransactionType::Convert => TransactionKind::Convert {
+                amount: self.validated_amount()?,
+                to_currency: self.to_currency,
+            },
+            TransactionType::Dispute => TransactionKind::Dispute { ref_tx: self.tx_id },
+            TransactionType::Resolve => TransactionKind::Resolve { ref_tx: self.tx_id },
+            TransactionType::Chargeback => TransactionKind::Chargeback { ref_tx: self.tx_id },
+            TransactionType::Unlock => TransactionKind::Unlock,
+            TransactionType::Custom(name) => TransactionKind::Custom(name),
+            TransactionType::Unknown => TransactionKind::Unknown,
+        })
+    }
+
+    /// A span for applying the transaction (and reporting its rejection), with the client,
+    /// transaction ID, and line.
+    pub fn span(&self) -> tracing::Span {
+        tracing::debug_span!(
+            "tx",
+            client = self.client_id,
+            tx = self.tx_id,
+            line = self.line
+        )
+    }
+
+    /// Whether the transaction's ID is its own, rather than a reference to another transaction.
+    /// Only deposits, withdrawals, and conversions have IDs of their own.
+    fn has_own_id(&self) -> bool {
+        matches!(
+            self.r#type,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Convert
+        )
+    }
+
+    /// The amount of a deposit, withdrawal, or conversion (see `kind`), or of a custom transaction
+    /// which has to have one.
+    pub fn validated_amount(&self) -> Result<Money, TransactionError> {
+        let amount = self.amount.ok_or(TransactionError::MissingAmount {
+            client_id: self.client_id,
+            tx_id: self.tx_id,
+        })?;
+
+        if amount.is_zero() {
+            Err(TransactionError::ZeroAmount {
+                client_id: self.client_id,
+                tx_id: self.tx_id,
+            })
+        } else if amount.is_sign_negative() {
+            Err(TransactionError::NegativeAmount {
+                client_id: self.client_id,
+                tx_id: self.tx_id,
+            })
+        } else if amount.exceeds_max() {
+            Err(TransactionError::Overflow {
+                client_id: self.client_id,
+                tx_id: self.tx_id,
+            })
+        } else {
+            Ok(amount)
+        }
+    }
+
+    /// The parts of this deposit or withdrawal kept in case it's disputed later.
+    fn disputable(&self, amount: Money, at: u64) -> DisputableTx {
+        DisputableTx {
+            // Only deposits and withdrawals are disputable.
+            kind: DisputableKind::of(self.r#type).unwrap_or(DisputableKind::Withdrawal),
+            client_id: self.client_id,
+            amount,
+            currency: self.currency,
+            at,
+        }
+    }
+}
+
+/// Where a deposit or withdrawal is in the dispute lifecycle. Transactions start out undisputed,
+/// and each dispute ends in a resolve (after which the transaction can be disputed again) or a
+/// chargeback (after which it can't).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeState {
+    #[default]
+    Undisputed,
+    /// Under dispute, with its funds held until it's resolved or charged back.
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ClientState {
+    /// This needs to be included for serialization
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    /// Currency of the funds, only set on rows exported by `currency_rows` (the other funds fields
+    /// are then for this currency).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    /// Funds in the implicit currency.
+    #[serde(deserialize_with = "money::deserialize_exact")]
+    pub available: Money,
+    #[serde(deserialize_with = "money::deserialize_exact")]
+    pub held: Money,
+    #[serde(deserialize_with = "money::deserialize_exact")]
+    pub total: Money,
+    pub locked: bool,
+    /// Number of transactions applied to the account. Increases with every change, so downstream
+    /// systems can order updates and detect stale reads. Missing from exports written before
+    /// versions were tracked.
+    #[serde(default)]
+    pub version: u64,
+    /// Fees charged to the client so far. Only tracked (and exported) when the engine charges fees.
+    #[serde(
+        default,
+        deserialize_with = "money::deserialize_exact_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub fees_collected: Option<Money>,
+    /// Latest timestamp of any transaction for the client. Only tracked (and exported) when the
+    /// engine tracks activity.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_activity: Option<u64>,
+    /// Funds in currencies other than the implicit one. Never part of CSV (which has a row per
+    /// currency instead, see `currency_rows`), but included in JSON.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub currencies: BTreeMap<Currency, Balance>,
+    /// Dispute state of each of the client's transactions which have ever been disputed.
+    #[serde(skip)]
+    disputes: HashMap<u32, DisputeState>,
+    /// Highest ID of the client's deposits, withdrawals, and conversions so far (applied or not).
+    #[serde(skip)]
+    high_water: Option<u32>,
+}
+
+impl Default for ClientState {
+    fn default() -> Self {
+        ClientState {
+            client_id: Default::default(),
+            currency: None,
+            available: Money::ZERO,
+            held: Money::ZERO,
+            total: Money::ZERO,
+            locked: false,
+            version: 0,
+            fees_collected: None,
+            last_activity: None,
+            currencies: BTreeMap::new(),
+            disputes: HashMap::new(),
+            high_water: None,
+        }
+    }
+}
+
+impl ClientState {
+    fn new(client_id: u16) -> Self {
+        ClientState {
+            client_id,
+            ..Default::default()
+        }
+    }
+
+    /// The client's funds in some currency (nothing if it hasn't transacted in that currency).
+    pub fn balance(&self, currency: Currency) -> Balance {
+        if currency.is_implicit() {
+            Balance {
+                available: self.available,
+                held: self.held,
+                total: self.total,
+                fees_collected: self.fees_collected,
+            }
+        } else {
+            self.currencies
+                .get(&currency)
+                .copied()
+                .unwrap_or_else(|| Balance::new(self.fees_collected.is_some()))
+        }
+    }
+
+    /// Replace the client's funds in some currency, updating the total.
+    fn set_balance(&mut self, currency: Currency, mut balance: Balance) {
+        // Serde doesn't allow serialized fields to be computed by combining other fields, so the
+        // total is stored explicitly.
+        balance.total = balance.available + balance.held;
+        if currency.is_implicit() {
+            self.available = balance.available;
+            self.held = balance.held;
+            self.total = balance.total;
+            self.fees_collected = balance.fees_collected;
+        } else {
+            self.currencies.insert(currency, balance);
+        }
+    }
+
+    /// Where one of the client's transactions is in the dispute lifecycle.
+    pub fn dispute_state(&self, tx_id: u32) -> DisputeState {
+        self.disputes.get(&tx_id).copied().unwrap_or_default()
+    }
+
+    /// IDs of the client's transactions which are under dispute, in no particular order.
+    pub fn open_disputes(&self) -> impl Iterator<Item = u32> + '_ {
+        self.disputes_in(DisputeState::Disputed)
+    }
+
+    /// IDs of the client's transactions in some dispute state (other than undisputed), in no
+    /// particular order.
+    pub(crate) fn disputes_in(&self, state: DisputeState) -> impl Iterator<Item = u32> + '_ {
+        self.disputes
+            .iter()
+            .filter(move |(_, &s)| s == state)
+            .map(|(&tx_id, _)| tx_id)
+    }
+
+    /// A copy of the client's state without the transactions under dispute.
+    pub(crate) fn without_disputes(&self) -> ClientState {
+        ClientState {
+            client_id: self.client_id,
+            currency: self.currency,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            version: self.version,
+            fees_collected: self.fees_collected,
+            last_activity: self.last_activity,
+            currencies: self.currencies.clone(),
+            disputes: HashMap::new(),
+            high_water: self.high_water,
+        }
+    }
+
+    /// The client's state split into one row per currency, with `currency` set on every row. The
+    /// implicit currency is left out for clients which have only used other currencies.
+    pub fn currency_rows(&self) -> Vec<ClientState> {
+        let row = |currency: Currency| {
+            let balance = self.balance(currency);
+            ClientState {
+                client_id: self.client_id,
+                currency: Some(currency),
+                available: balance.available,
+                held: balance.held,
+                total: balance.total,
+                locked: self.locked,
+                version: self.version,
+                fees_collected: balance.fees_collected,
+                last_activity: self.last_activity,
+                ..Default::default()
+            }
+        };
+
+        let implicit = self.balance(Currency::IMPLICIT);
+        let mut rows = Vec::with_capacity(self.currencies.len() + 1);
+        if self.currencies.is_empty() || implicit != Balance::new(self.fees_collected.is_some()) {
+            rows.push(row(Currency::IMPLICIT));
+        }
+        rows.extend(self.currencies.keys().map(|&currency| row(currency)));
+
+        rows
+    }
+}
+
+/// What to do when a deposit or withdrawal reuses the ID of an earlier deposit or withdrawal.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateTxPolicy {
+    /// The duplicate has no effect, and is reported like any other rejected transaction.
+    #[default]
+    Reject,
+    /// The duplicate has no effect, and isn't reported.
+    Ignore,
+    /// Processing stops. Duplicate IDs almost always indicate corrupted input.
+    ErrorOut,
+}
+
+impl FromStr for DuplicateTxPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(DuplicateTxPolicy::Reject),
+            "ignore" => Ok(DuplicateTxPolicy::Ignore),
+            "error-out" => Ok(DuplicateTxPolicy::ErrorOut),
+            _ => Err(format!(
+                "unknown duplicate tx policy '{}', expected reject, ignore, or error-out",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with transactions of an unknown type.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownTypePolicy {
+    /// The transaction is reported like any other rejected transaction (so it's kept in the
+    /// rejects log for later review).
+    Reject,
+    /// The transaction is dropped, and isn't reported.
+    Ignore,
+    /// Processing stops. An unknown type may mean the input is from a newer or different system.
+    #[default]
+    ErrorOut,
+}
+
+impl FromStr for UnknownTypePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(UnknownTypePolicy::Reject),
+            "ignore" => Ok(UnknownTypePolicy::Ignore),
+            "error-out" => Ok(UnknownTypePolicy::ErrorOut),
+            _ => Err(format!(
+                "invalid unknown type policy '{}', expected reject, ignore, or error-out",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with transactions for a locked account.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LockedAccountPolicy {
+    /// The transaction has no effect, and is reported like any other rejected transaction.
+    #[default]
+    Reject,
+    /// The transaction has no effect, and isn't reported.
+    Ignore,
+    /// The transaction is held until the account is unlocked (see `TransactionType::Unlock`), then
+    /// applied along with every other transaction held for the account, in the order they arrived.
+    Queue,
+}
+
+impl FromStr for LockedAccountPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(LockedAccountPolicy::Reject),
+            "ignore" => Ok(LockedAccountPolicy::Ignore),
+            "queue" => Ok(LockedAccountPolicy::Queue),
+            _ => Err(format!(
+                "unknown locked account policy '{}', expected reject, ignore, or queue",
+                s
+            )),
+        }
+    }
+}
+
+/// How disputes against withdrawals are handled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WithdrawalDisputePolicy {
+    /// Disputes move funds in the reverse direction of a deposit dispute. The withdrawn amount is
+    /// credited to held funds while under dispute. A resolve removes the credit (the withdrawal
+    /// stands), and a chargeback makes it available (the withdrawal is reversed) and locks the
+    /// account.
+    #[default]
+    Reverse,
+    /// Disputes against withdrawals have no effect, and are reported like any other rejected
+    /// transaction.
+    Ignore,
+}
+
+impl FromStr for WithdrawalDisputePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reverse" => Ok(WithdrawalDisputePolicy::Reverse),
+            "ignore" => Ok(WithdrawalDisputePolicy::Ignore),
+            _ => Err(format!(
+                "unknown withdrawal dispute policy '{}', expected reverse or ignore",
+                s
+            )),
+        }
+    }
+}
+
+/// How long after a deposit or withdrawal it can still be disputed. Disputes from outside the
+/// window are rejected, and transactions which can no longer be disputed are eventually forgotten
+/// (after which disputes against them are rejected as unknown), bounding the memory they use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeWindow {
+    /// Disputes must arrive within this many transactions (of any kind) after the transaction.
+    Transactions(u64),
+    /// Disputes must arrive within this long (in milliseconds) after the transaction, by the
+    /// engine's clock (see `clock`).
+    Duration(u64),
+}
+
+impl DisputeWindow {
+    /// Size of the window, in transactions or milliseconds.
+    fn span(self) -> u64 {
+        match self {
+            DisputeWindow::Transactions(span) | DisputeWindow::Duration(span) => span,
+        }
+    }
+}
+
+impl FromStr for DisputeWindow {
+    type Err = String;
+
+    /// A number of transactions (e.g. `10000`), or a duration with a unit of `ms`, `s`, `m`, `h`,
+    /// or `d` (e.g. `120d`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || {
+            format!(
+                "invalid dispute window '{}', expected a number of transactions or a duration \
+                 like 120d",
+                s
+            )
+        };
+
+        let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let count = match digits.parse::<u64>() {
+            Ok(0) | Err(_) => return Err(error()),
+            Ok(count) => count,
+        };
+        let millis = match &s[digits.len()..] {
+            "" => return Ok(DisputeWindow::Transactions(count)),
+            "ms" => 1,
+            "s" => 1_000,
+            "m" => 60_000,
+            "h" => 3_600_000,
+            "d" => 86_400_000,
+            _ => return Err(error()),
+        };
+        count
+            .checked_mul(millis)
+            .map(DisputeWindow::Duration)
+            .ok_or_else(error)
+    }
+}
+
+/// Whether a transaction can be disputed again once a dispute of it is resolved. A transaction which
+/// was charged back can never be disputed again.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RedisputePolicy {
+    /// A resolved transaction can be disputed again, any number of times.
+    #[default]
+    Repeatable,
+    /// Each transaction can only be disputed once. Disputes of a resolved transaction are reported
+    /// like any other rejected transaction.
+    Once,
+}
+
+impl FromStr for RedisputePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "repeatable" => Ok(RedisputePolicy::Repeatable),
+            "once" => Ok(RedisputePolicy::Once),
+            _ => Err(format!(
+                "unknown redispute policy '{}', expected repeatable or once",
+                s
+            )),
+        }
+    }
+}
+
+/// Processes transactions one at a time, and keeps track of client account states.
+#[derive(Default)]
+pub struct Engine {
+    /// Keep track of client states as transactions are processed.
+    client_states: HashMap<u16, ClientState>,
+    /// Keep track of disputable transactions in case they are referenced by later transactions.
+    /// Only transactions with an amount can be disputed.
+    disputable_transactions: DisputableStore,
+    /// How to handle deposits/withdrawals which reuse an earlier transaction ID.
+    duplicate_tx_policy: DuplicateTxPolicy,
+    /// How to handle disputes against withdrawals.
+    withdrawal_dispute_policy: WithdrawalDisputePolicy,
+    /// Whether resolved transactions can be disputed again.
+    redispute_policy: RedisputePolicy,
+    /// How the latest dispute of each transaction was settled (resolved or charged back), whichever
+    /// client disputed it. Clients only know about their own disputes.
+    settled_disputes: HashMap<u32, DisputeState>,
+    /// How to handle transactions of an unknown type.
+    unknown_type_policy: UnknownTypePolicy,
+    /// How to handle transactions for locked accounts.
+    locked_account_policy: LockedAccountPolicy,
+    /// Transactions held for each locked account, in the order they arrived.
+    queued: HashMap<u16, Vec<Transaction>>,
+    /// Held transactions which were rejected when their account was unlocked, until they're taken
+    /// by `take_released_rejects`.
+    released_rejects: Vec<(Transaction, TransactionError)>,
+    /// Stop processing at the first rejected transaction.
+    strict: bool,
+    /// Apply administrative transactions (`unlock`), rather than rejecting them.
+    allow_admin_ops: bool,
+    /// Fees charged on deposits and withdrawals, if any.
+    fees: Option<FeeSchedule>,
+    /// Every past client state, if it's being kept.
+    history: Option<StateHistory>,
+    /// Where changes to client funds are sent, if anywhere.
+    events: Option<EventLog>,
+    /// Handlers for custom transaction types (see `handler`).
+    handlers: HandlerRegistry,
+    /// Exchange rates for conversions, if any.
+    rates: Option<RateTable>,
+    /// How far withdrawals may take each client's available funds below zero, if at all.
+    credit_limits: Option<CreditLimits>,
+    /// How long transactions can be disputed for, if not forever.
+    dispute_window: Option<DisputeWindow>,
+    /// Transactions seen (or the latest time seen) so far, if there's a dispute window.
+    dispute_clock: u64,
+    /// When transactions which can no longer be disputed are next evicted.
+    next_eviction: u64,
+    /// Most disputes each client may have open at once, if limited.
+    max_open_disputes: Option<usize>,
+    /// Time transactions arrive at, for time-based policies (transaction timestamps if not set).
+    clock: Option<Box<dyn Clock>>,
+    /// Track when each client was last active.
+    track_activity: bool,
+    /// Skip deposits, withdrawals, and conversions already reflected in the restored snapshot.
+    skip_backfilled: bool,
+    /// Each client's high-water mark in the restored snapshot, if one was restored.
+    snapshot_high_water: HashMap<u16, u32>,
+    /// Transactions skipped because the restored snapshot already reflected them.
+    backfilled: u64,
+    /// Deposits, withdrawals, and conversions processed so far, if replays are being skipped.
+    processed: Option<ProcessedSet>,
+    /// Transactions skipped because they'd already been processed.
+    replayed: u64,
+    /// Changes published to followers, if the engine is replicating.
+    replication: Option<Replication>,
+    /// Counters and timings of the transactions applied, if they're being kept.
+    metrics: Option<Metrics>,
+    /// Balance invariants checked after every applied transaction, if any.
+    invariant_check: Option<InvariantCheck>,
+    /// Decimal places amounts, fees, and converted amounts are rounded to, and how.
+    precision: Precision,
+    /// Reject amounts with more decimal places than `precision` keeps, rather than rounding them.
+    exact_amounts: bool,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_duplicate_tx_policy(mut self, policy: DuplicateTxPolicy) -> Self {
+        self.duplicate_tx_policy = policy;
+        self
+    }
+
+    pub fn with_withdrawal_dispute_policy(mut self, policy: WithdrawalDisputePolicy) -> Self {
+        self.withdrawal_dispute_policy = policy;
+        self
+    }
+
+    pub fn with_redispute_policy(mut self, policy: RedisputePolicy) -> Self {
+        self.redispute_policy = policy;
+        self
+    }
+
+    pub fn with_unknown_type_policy(mut self, policy: UnknownTypePolicy) -> Self {
+        self.unknown_type_policy = policy;
+        self
+    }
+
+    pub fn with_locked_account_policy(mut self, policy: LockedAccountPolicy) -> Self {
+        self.locked_account_policy = policy;
+        self
+    }
+
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Apply administrative transactions (`unlock`). Without this they're rejected, so standard
+    /// runs follow the specification.
+    pub fn with_admin_ops(mut self, allow_admin_ops: bool) -> Self {
+        self.allow_admin_ops = allow_admin_ops;
+        self
+    }
+
+    /// Charge fees on deposits and withdrawals. Client states track the fees collected from them.
+    pub fn with_fees(mut self, fees: FeeSchedule) -> Self {
+        self.fees = Some(fees);
+        self
+    }
+
+    /// Allow conversions between currencies at these rates. Without rates, every conversion is
+    /// rejected.
+    pub fn with_rates(mut self, rates: RateTable) -> Self {
+        self.rates = Some(rates);
+        self
+    }
+
+    /// Only allow disputes within `window` of the disputed transaction, forgetting transactions
+    /// once they can no longer be disputed.
+    pub fn with_dispute_window(mut self, window: DisputeWindow) -> Self {
+        self.dispute_window = Some(window);
+        self
+    }
+
+    /// Reject disputes from clients who already have `max` disputes open, so one account can't
+    /// tie up the engine (and its funds) with an unbounded number of them.
+    pub fn with_max_open_disputes(mut self, max: usize) -> Self {
+        self.max_open_disputes = Some(max);
+        self
+    }
+
+    /// Track the latest timestamp of any transaction (applied or not) for each client, exported as
+    /// `last_activity`.
+    pub fn with_activity_tracking(mut self, track_activity: bool) -> Self {
+        self.track_activity = track_activity;
+        self
+    }
+
+    /// Skip deposits, withdrawals, and conversions which a restored snapshot already reflects, i.e.
+    /// those with an ID no higher than the highest one the snapshot saw for the client. Allows
+    /// history which overlaps a snapshot to be replayed onto it, as long as each client's
+    /// transaction IDs increase. Disputes, resolves, and chargebacks can't be told apart this way,
+    /// so they're still applied.
+    pub fn with_skip_backfilled(mut self, skip_backfilled: bool) -> Self {
+        self.skip_backfilled = skip_backfilled;
+        self
+    }
+
+    /// Start from some client states (e.g. an earlier run's balance export, read by
+    /// `read_balances`) rather than none, so only the transactions since then need to be applied.
+    /// Balances don't say which transactions are under dispute, so held funds stay held (nothing
+    /// can resolve or charge them back), and earlier transactions can't be disputed; a snapshot
+    /// carries those over where they're needed. Fees collected are only kept by engines which charge
+    /// fees, so this should be called after `with_fees`.
+    pub fn with_initial_balances(mut self, balances: HashMap<u16, ClientState>) -> Self {
+        let fees_collected =
+            |fees: Option<Money>| self.fees.as_ref().map(|_| fees.unwrap_or(Money::ZERO));
+        self.client_states = balances
+            .into_iter()
+            .map(|(client_id, mut state)| {
+                state.currency = None;
+                state.fees_collected = fees_collected(state.fees_collected);
+                for balance in state.currencies.values_mut() {
+                    balance.fees_collected = fees_collected(balance.fees_collected);
+                }
+                (client_id, state)
+            })
+            .collect();
+        self
+    }
+
+    /// Number of transactions skipped because a restored snapshot already reflected them.
+    pub fn backfilled_count(&self) -> u64 {
+        self.backfilled
+    }
+
+    /// Remember every deposit, withdrawal, and conversion processed (applied or not), by client and
+    /// transaction ID, and skip any which arrive again. Replaying input which overlaps what's
+    /// already been processed (e.g. after a partial failure) then applies each transaction once.
+    /// The set is kept in snapshots, so replays can span batches too. A client reusing one of its
+    /// own transaction IDs is taken to be a replay, rather than being rejected as a duplicate.
+    pub fn with_dedup(mut self) -> Self {
+        self.processed = Some(ProcessedSet::new());
+        self
+    }
+
+    /// The deposits, withdrawals, and conversions processed so far, if the engine skips replays.
+    pub fn processed(&self) -> Option<&ProcessedSet> {
+        self.processed.as_ref()
+    }
+
+    /// Number of transactions skipped because they'd already been processed.
+    pub fn replayed_count(&self) -> u64 {
+        self.replayed
+    }
+
+    /// Take the time transactions arrive at from `clock`, rather than their timestamps.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Allow withdrawals to take clients' available funds below zero, down to their credit limits.
+    pub fn with_credit_limits(mut self, limits: CreditLimits) -> Self {
+        self.credit_limits = Some(limits);
+        self
+    }
+
+    /// Keep every state each client goes through from now on (see `history`), starting with the
+    /// clients' current states.
+    pub fn with_history(mut self) -> Self {
+        let mut history = StateHistory::default();
+        for state in self.client_states.values() {
+            history.record(state);
+        }
+        self.history = Some(history);
+        self
+    }
+
+    /// Past client states, if the engine keeps them.
+    pub fn history(&self) -> Option<&StateHistory> {
+        self.history.as_ref()
+    }
+
+    /// The transactions applied to a client (since history started being kept), oldest first,
+    /// each with the state it left the client in. `None` if the engine isn't keeping history.
+    pub fn client_history(&self, client_id: u16) -> Option<Vec<LedgerEntry<'_>>> {
+        self.history
+            .as_ref()
+            .map(|history| history.ledger(client_id).collect())
+    }
+
+    /// Count and time the transactions applied from now on (see `metrics`).
+    pub fn with_metrics(mut self) -> Self {
+        self.metrics = Some(Metrics::default());
+        self
+    }
+
+    /// Metrics of the transactions applied, if the engine keeps them.
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+
+    /// Check balance invariants after every applied transaction, stopping at the first which breaks
+    /// one (see `invariants`).
+    pub fn with_invariant_checks(mut self, check: InvariantCheck) -> Self {
+        self.invariant_check = Some(check);
+        self
+    }
+
+    /// Round amounts (and fees, and converted amounts) to some precision rather than
+    /// `DECIMAL_PLACES` with banker's rounding. Amounts are rounded as they were written, so
+    /// they're only rounded once. An amount which rounds to zero is rejected.
+    pub fn with_precision(mut self, precision: Precision) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Reject deposits, withdrawals, and conversions with more decimal places than the engine's
+    /// precision keeps (as `TooManyDecimalPlaces`) rather than rounding them. Amounts are checked as
+    /// they were written, before they were rounded to `DECIMAL_PLACES` when read, so e.g. `1.00005`
+    /// is rejected at the default precision too (trailing zeros don't count).
+    pub fn with_exact_amounts(mut self, exact_amounts: bool) -> Self {
+        self.exact_amounts = exact_amounts;
+        self
+    }
+
+    /// Keep roughly `bytes` of disputable transactions in memory, spilling older transactions to a
+    /// temporary file (see `store`). Client states aren't limited.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.disputable_transactions = DisputableStore::with_memory_limit(bytes);
+        self
+    }
+
+    /// Whether some rejected transaction should stop processing entirely, rather than just being
+    /// reported.
+    pub fn is_fatal(&self, e: &TransactionError) -> bool {
+        if self.strict {
+            return true;
+        }
+
+        match e {
+            TransactionError::DuplicateTxId { .. } => {
+                self.duplicate_tx_policy == DuplicateTxPolicy::ErrorOut
+            }
+            TransactionError::UnknownType { .. } => {
+                self.unknown_type_policy == UnknownTypePolicy::ErrorOut
+            }
+            TransactionError::InvariantViolated { .. } | TransactionError::Storage { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Apply a single transaction to the client states. Transactions which can't be applied leave
+    /// all states unchanged, and the reason is returned. (The exception is a transaction which
+    /// breaks a balance invariant, which has been applied, but is reported as fatal.)
+    pub fn apply(&mut self, tx: &Transaction) -> Result<(), TransactionError> {
+        tracing::debug!(
+            "applying {:?} transaction {} for client {}",
+            tx.r#type,
+            tx.tx_id,
+            tx.client_id
+        );
+        // Replays are skipped as they arrive, so transactions held for a locked account aren't
+        // taken for replays when they're released.
+        if let Some(processed) = self.processed.as_mut() {
+            if tx.has_own_id() && !processed.insert(tx.client_id, tx.tx_id) {
+                self.replayed += 1;
+                return Ok(());
+            }
+        }
+        let started = self.metrics.is_some().then(Instant::now);
+        // Amounts were rounded to the default precision when they were read, so they're rounded
+        // (and checked) as they were written, rather than rounded twice.
+        let rounded;
+        let tx = match tx.amount {
+            Some(amount) => {
+                let raw_amount = tx.raw_amount.unwrap_or_else(|| amount.into());
+                let amount_rounded = self.precision.round(raw_amount);
+                if self.exact_amounts && amount_rounded != raw_amount {
+                    return Err(TransactionError::TooManyDecimalPlaces {
+                        client_id: tx.client_id,
+                        tx_id: tx.tx_id,
+                    });
+                }
+                if self.precision == Precision::default() {
+                    tx
+                } else {
+                    rounded = Transaction {
+                        amount: Some(amount_rounded),
+                        ..tx.clone()
+                    };
+                    &rounded
+                }
+            }
+            None => tx,
+        };
+        let result = self
+            .apply_transaction(tx)
+            .and_then(|()| self.check_invariants(tx));
+        if let (Some(metrics), Some(started)) = (self.metrics.as_mut(), started) {
+            metrics.record(self.handlers.resolve(tx.r#type), started.elapsed(), &result);
+        }
+
+        result
+    }
+
+    fn apply_transaction(&mut self, tx: &Transaction) -> Result<(), TransactionError> {
+        self.advance_dispute_clock(tx)?;
+
+        // Transactions of a type the engine can't apply don't say anything about their client, so
+        // they aren't tracked.
+        let handler = match self.handlers.get(tx.r#type) {
+            Some(handler) => handler,
+            None => {
+                return match self.unknown_type_policy {
+                    UnknownTypePolicy::Ignore => Ok(()),
+                    UnknownTypePolicy::Reject | UnknownTypePolicy::ErrorOut => {
+                        Err(TransactionError::UnknownType {
+                            client_id: tx.client_id,
+                            tx_id: tx.tx_id,
+                        })
+                    }
+                };
+            }
+        };
+
+        let has_own_id = tx.has_own_id();
+        if self.skip_backfilled
+            && has_own_id
+            && self
+                .snapshot_high_water
+                .get(&tx.client_id)
+                .is_some_and(|&high_water| tx.tx_id <= high_water)
+        {
+            self.backfilled += 1;
+            return Ok(());
+        }
+
+        // All clients referenced by any transaction get tracked.
+        let charges_fees = self.fees.is_some();
+        let track_activity = self.track_activity;
+        let state: &mut ClientState =
+            self.client_states
+                .entry(tx.client_id)
+                .or_insert_with(|| ClientState {
+                    fees_collected: charges_fees.then_some(Money::ZERO),
+                    ..ClientState::new(tx.client_id)
+                });
+        if has_own_id {
+            state.high_water = state.high_water.max(Some(tx.tx_id));
+        }
+        if track_activity {
+            state.last_activity = state.last_activity.max(Some(tx.timestamp.unwrap_or(0)));
+        }
+
+        if tx.r#type == TransactionType::Unlock && !self.allow_admin_ops {
+            return Err(TransactionError::AdminOpsDisabled {
+                client_id: tx.client_id,
+                tx_id: tx.tx_id,
+            });
+        }
+
+        // Transactions only get applied if the client's account isn't locked/frozen, other than
+        // the one unlocking it.
+        if state.locked && tx.r#type != TransactionType::Unlock {
+            return match self.locked_account_policy {
+                LockedAccountPolicy::Reject => Err(TransactionError::AccountLocked {
+                    client_id: tx.client_id,
+                    tx_id: tx.tx_id,
+                }),
+                LockedAccountPolicy::Ignore => Ok(()),
+                LockedAccountPolicy::Queue => {
+                    self.queued
+                        .entry(tx.client_id)
+                        .or_default()
+                        .push(tx.clone());
+                    Ok(())
+                }
+            };
+        }
+
+        // Deposits and withdrawals are recorded by ID so they can be disputed, a repeated ID would
+        // make any later dispute ambiguous.
+        if matches!(
+            tx.r#type,
+            TransactionType::Deposit | TransactionType::Withdrawal
+        ) && self
+            .disputable_transactions
+            .contains(tx.tx_id)
+            .map_err(|e| storage_error(tx, e))?
+        {
+            return match self.duplicate_tx_policy {
+                DuplicateTxPolicy::Ignore => Ok(()),
+                DuplicateTxPolicy::Reject | DuplicateTxPolicy::ErrorOut => {
+                    Err(TransactionError::DuplicateTxId {
+                        client_id: tx.client_id,
+                        tx_id: tx.tx_id,
+                    })
+                }
+            };
+        }
+
+        // Funds only change by postings (see `posting`), which are checked before anything else
+        // changes, so a transaction which can't be posted leaves everything as it was.
+        let outcome = self.outcome(handler, tx)?;
+        let balances = post_within_max(&self.client_states[&tx.client_id], &outcome.postings, tx)?;
+        self.record_outcome(tx, &outcome)?;
+
+        let state = self
+            .client_states
+            .get_mut(&tx.client_id)
+            .expect("client is tracked");
+        for (currency, balance) in balances {
+            state.set_balance(currency, balance);
+        }
+        if let Some(locked) = outcome.lock {
+            state.locked = locked;
+        }
+        state.version += 1;
+        if let Some(events) = self.events.as_mut() {
+            events.record(tx, &outcome.postings, state);
+        }
+        if let Some(history) = self.history.as_mut() {
+            history.record_applied(state, tx);
+        }
+        if let Some(replication) = self.replication.as_mut() {
+            replication.publish(state);
+        }
+
+        if outcome.lock == Some(false) {
+            self.release_queued(tx.client_id);
+        }
+
+        Ok(())
+    }
+
+    /// Move the dispute window clock on to `tx`, evicting transactions which can no longer be
+    /// disputed every so often (once per window, so each eviction only sees about two windows of
+    /// transactions).
+    fn advance_dispute_clock(&mut self, tx: &Transaction) -> Result<(), TransactionError> {
+        let window = match self.dispute_window {
+            Some(window) => window,
+            None => return Ok(()),
+        };
+        match window {
+            DisputeWindow::Transactions(_) => self.dispute_clock += 1,
+            DisputeWindow::Duration(_) => {
+                let clock = self
+                    .clock
+                    .get_or_insert_with(|| Box::new(TransactionClock::default()));
+                self.dispute_clock = self.dispute_clock.max(clock.now(tx));
+            }
+        }
+
+        if self.dispute_clock >= self.next_eviction {
+            // Transactions under dispute can still be resolved or charged back.
+            let disputed: HashSet<u32> = self
+                .client_states
+                .values()
+                .flat_map(|state| state.open_disputes())
+                .collect();
+            self.disputable_transactions
+                .evict(self.dispute_clock.saturating_sub(window.span()), &disputed)
+                .map_err(|e| storage_error(tx, e))?;
+            self.next_eviction = self.dispute_clock.saturating_add(window.span());
+        }
+
+        Ok(())
+    }
+
+    /// Apply the transactions held while a client's account was locked, now it's been unlocked.
+    /// Any which lock the account again hold the rest until the next unlock.
+    fn release_queued(&mut self, client_id: u16) {
+        for tx in self.queued.remove(&client_id).unwrap_or_default() {
+            // Held transactions were counted when they arrived, so only their rejections are.
+            if let Err(e) = self.apply_transaction(&tx) {
+                if let Some(metrics) = self.metrics.as_mut() {
+                    metrics.reject(&e);
+                }
+                self.released_rejects.push((tx, e));
+            }
+        }
+    }
+
+    /// Held transactions (see `LockedAccountPolicy::Queue`) which were rejected when their account
+    /// was unlocked, since this was last called.
+    pub fn take_released_rejects(&mut self) -> Vec<(Transaction, TransactionError)> {
+        std::mem::take(&mut self.released_rejects)
+    }
+
+    /// Number of transactions held for locked accounts.
+    pub fn queued_count(&self) -> usize {
+        self.queued.values().map(Vec::len).sum()
+    }
+
+    /// Every transaction under dispute, with its ID, ordered by client and then transaction ID.
+    pub fn open_disputes(&self) -> io::Result<Vec<(u32, DisputableTx)>> {
+        let mut open = Vec::new();
+        for state in self.client_states.values() {
+            for tx_id in state.open_disputes() {
+                // Transactions under dispute are never evicted, but a restored snapshot might not
+                // have them all.
+                if let Some(tx) = self.disputable_transactions.get(tx_id)? {
+                    open.push((tx_id, tx));
+                }
+            }
+        }
+        open.sort_unstable_by_key(|&(tx_id, tx)| (tx.client_id, tx_id));
+
+        Ok(open)
+    }
+
+    /// Stop tracking some client, returning its current state.
+    pub fn take_client_state(&mut self, client_id: u16) -> Option<ClientState> {
+        self.client_states.remove(&client_id)
+    }
+
+    /// Current client account states.
+    pub fn client_states(&self) -> &HashMap<u16, ClientState> {
+        &self.client_states
+    }
+
+    /// Consume the engine, returning the final client account states.
+    pub fn into_client_states(self) -> HashMap<u16, ClientState> {
+        self.client_states
+    }
+}
+
+/// Apply a stream of transactions using `engine`, and return a map of client account states.
+///
+/// Malformed transactions abort processing. Transactions which are well-formed but can't be applied
+/// are passed to `on_reject` along with the reason they were rejected, and processing continues
+/// unless `on_reject` returns an error, or the engine considers the rejection fatal.
+pub fn process_transactions<I, F>(
+    mut engine: Engine,
+    transactions: I,
+    on_reject: F,
+) -> Result<HashMap<u16, ClientState>, Box<dyn Error>>
+where
+    I: IntoIterator<Item = Result<Transaction, Box<dyn Error>>>,
+    F: FnMut(&Transaction, &TransactionError) -> Result<(), Box<dyn Error>>,
+{
+    apply_transactions(&mut engine, transactions, on_reject)?;
+
+    Ok(engine.into_client_states())
+}
+
+/// Apply a stream of transactions to `engine`, handling malformed and rejected transactions the
+/// same way as `process_transactions`. The engine keeps its state afterwards (e.g. so it can be
+/// snapshotted).
+pub fn apply_transactions<I, F>(
+    engine: &mut Engine,
+    transactions: I,
+    on_reject: F,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = Result<Transaction, Box<dyn Error>>>,
+    F: FnMut(&Transaction, &TransactionError) -> Result<(), Box<dyn Error>>,
+{
+    apply_transactions_with(engine, transactions, on_reject, |_, _| Ok(()))
+}
+
+/// Apply a stream of transactions to `engine` like `apply_transactions`, also passing every
+/// transaction to `on_apply` (e.g. to journal it) with the error it was rejected with, if it was,
+/// before the next one is read.
+pub fn apply_transactions_with<I, F, A>(
+    engine: &mut Engine,
+    transactions: I,
+    mut on_reject: F,
+    mut on_apply: A,
+) -> Result<(), Box<dyn Error>>
+where
+    I: IntoIterator<Item = Result<Transaction, Box<dyn Error>>>,
+    F: FnMut(&Transaction, &TransactionError) -> Result<(), Box<dyn Error>>,
+    A: FnMut(&Transaction, Option<&TransactionError>) -> Result<(), Box<dyn Error>>,
+{
+    for result in transactions {
+        let tx = result?;
+        let _span = tx.span().entered();
+
+        match engine.apply(&tx) {
+            Ok(()) => on_apply(&tx, None)?,
+            Err(e) if engine.is_fatal(&e) => return Err(fatal_error(&tx, e)),
+            Err(e) => {
+                on_apply(&tx, Some(&e))?;
+                on_reject(&tx, &e)?;
+            }
+        }
+        report_released(engine, &mut on_reject)?;
+    }
+
+    Ok(())
+}
+
+/// Pass any held transactions which were rejected once their account was unlocked to `on_reject`,
+/// unless the rejection stops processing.
+fn report_released<F>(engine: &mut Engine, on_reject: &mut F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(&Transaction, &TransactionError) -> Result<(), Box<dyn Error>>,
+{
+    for (tx, e) in engine.take_released_rejects() {
+        if engine.is_fatal(&e) {
+            return Err(fatal_error(&tx, e));
+        }
+        on_reject(&tx, &e)?;
+    }
+
+    Ok(())
+}
+
+/// The fee for a deposit or withdrawal of `amount`, if fees are charged.
+fn fee(
+    fees: &Option<FeeSchedule>,
+    tx: &Transaction,
+    amount: Money,
+    precision: &Precision,
+) -> Money {
+    fees.as_ref()
+        .map_or(Money::ZERO, |fees| fees.fee(tx.r#type, amount, precision))
+}
+
+/// The client's funds once `postings` have been applied (see `posting::posted`), unless any would
+/// be beyond `Money::MAX`. Amounts and balances are at most `Money::MAX`, so funds can't overflow,
+/// but they'd grow beyond it without this.
+fn post_within_max(
+    state: &ClientState,
+    postings: &[Posting],
+    tx: &Transaction,
+) -> Result<BTreeMap<Currency, Balance>, TransactionError> {
+    let balances = posting::posted(state, postings);
+    if balances.values().any(exceeds_max) {
+        return Err(TransactionError::Overflow {
+            client_id: tx.client_id,
+            tx_id: tx.tx_id,
+        });
+    }
+
+    Ok(balances)
+}
+
+/// Whether any of a balance's funds are beyond `Money::MAX`.
+fn exceeds_max(balance: &Balance) -> bool {
+    balance.available.exceeds_max()
+        || balance.held.exceeds_max()
+        || (balance.available + balance.held).exceeds_max()
+}
+
+/// The error for a transaction which couldn't be handled because stored transactions couldn't be
+/// accessed.
+fn storage_error(tx: &Transaction, e: io::Error) -> TransactionError {
+    TransactionError::Storage {
+        client_id: tx.client_id,
+        tx_id: tx.tx_id,
+        message: e.to_string(),
+    }
+}
+
+/// The error for a rejected transaction which stops processing, including the line it came from.
+fn fatal_error(tx: &Transaction, e: TransactionError) -> Box<dyn Error> {
+    match tx.line {
+        Some(line) => format!("line {}: {}", line, e).into(),
+        None => e.into(),
+    }
+}
+
+/// Something a worker in `process_parallel` reports back to the thread reading transactions.
+enum WorkerEvent {
+    Rejected(Transaction, TransactionError),
+    Fatal(Transaction, TransactionError),
+}
+
+/// Apply a stream of transactions using `threads` workers, each with its own engine from
+/// `new_engine` and owning a disjoint set of clients (see `shard_for_client`). Transactions are
+/// read on the calling thread and sent to workers in chunks of `PARALLEL_CHUNK_SIZE`.
+///
+/// Every transaction for some client is applied by the same worker in input order, so client
+/// states match `process_transactions` as long as transaction IDs are unique and disputes only
+/// reference the disputing client's own transactions. Rejected transactions are passed to
+/// `on_reject` on the calling thread, but (unlike `process_transactions`) rejections for different
+/// clients can be reported out of input order.
+pub fn process_parallel<I, E, F>(
+    new_engine: E,
+    threads: usize,
+    transactions: I,
+    mut on_reject: F,
+) -> Result<HashMap<u16, ClientState>, Box<dyn Error>>
+where
+    I: IntoIterator<Item = Result<Transaction, Box<dyn Error>>>,
+    E: Fn() -> Engine,
+    F: FnMut(&Transaction, &TransactionError) -> Result<(), Box<dyn Error>>,
+{
+    let (event_sender, events) = mpsc::channel();
+    let mut senders = Vec::with_capacity(threads);
+    let mut workers = Vec::with_capacity(threads);
+    for _ in 0..threads {
+        let (sender, chunks) = mpsc::sync_channel::<Vec<Transaction>>(PARALLEL_CHANNEL_CAPACITY);
+        let event_sender = event_sender.clone();
+        let mut engine = new_engine();
+        // Workers log within the caller's span (e.g. the run), as the calling thread does.
+        let span = tracing::Span::current();
+        workers.push(thread::spawn(move || {
+            let _span = span.entered();
+            for tx in chunks.into_iter().flatten() {
+                let result = tx
+                    .span()
+                    .in_scope(|| engine.apply(&tx).err())
+                    .map(|e| (tx, e));
+                for (tx, e) in result.into_iter().chain(engine.take_released_rejects()) {
+                    if engine.is_fatal(&e) {
+                        let _ = event_sender.send(WorkerEvent::Fatal(tx, e));
+                        return None;
+                    }
+                    let _ = event_sender.send(WorkerEvent::Rejected(tx, e));
+                }
+            }
+
+            Some(engine.into_client_states())
+        }));
+        senders.push(sender);
+    }
+    drop(event_sender);
+
+    let mut handle_event = |event| match event {
+        WorkerEvent::Rejected(tx, e) => on_reject(&tx, &e),
+        WorkerEvent::Fatal(tx, e) => Err(fatal_error(&tx, e)),
+    };
+
+    let mut chunks = vec![Vec::with_capacity(PARALLEL_CHUNK_SIZE); threads];
+    for result in transactions {
+        let tx = result?;
+
+        let shard = shard_for_client(tx.client_id, threads);
+        chunks[shard].push(tx);
+        if chunks[shard].len() == PARALLEL_CHUNK_SIZE {
+            let chunk =
+                std::mem::replace(&mut chunks[shard], Vec::with_capacity(PARALLEL_CHUNK_SIZE));
+            // A worker only hangs up after a fatal error, which is reported as an event.
+            let _ = senders[shard].send(chunk);
+        }
+
+        for event in events.try_iter() {
+            handle_event(event)?;
+        }
+    }
+    for (sender, chunk) in senders.into_iter().zip(chunks) {
+        let _ = sender.send(chunk);
+    }
+
+    // Workers finish once every chunk has been sent and the channels are closed.
+    for event in events {
+        handle_event(event)?;
+    }
+
+    let mut client_states = HashMap::new();
+    for worker in workers {
+        match worker.join() {
+            Ok(Some(states)) => client_states.extend(states),
+            Ok(None) => unreachable!("fatal errors are reported as events"),
+            Err(_) => return Err("worker thread panicked".into()),
+        }
+    }
+
+    Ok(client_states)
+}
+
+/// Apply a stream of transactions using `engine` in two passes, writing each client's final state
+/// to `sink` as soon as the last transaction referencing that client has been applied. Only the
+/// states of clients with transactions still to come are held in memory.
+///
+/// `first_pass` and `second_pass` must yield the same transactions. The first pass only finds the
+/// last transaction referencing each client, so malformed transactions abort processing before
+/// anything is written. Rejected transactions are handled the same way as `process_transactions`,
+/// but a fatal rejection can leave partial output in `sink`.
+pub fn process_two_pass<I1, I2, F>(
+    mut engine: Engine,
+    first_pass: I1,
+    second_pass: I2,
+    mut on_reject: F,
+    sink: &mut dyn BalanceSink,
+) -> Result<(), Box<dyn Error>>
+where
+    I1: IntoIterator<Item = Result<Transaction, Box<dyn Error>>>,
+    I2: IntoIterator<Item = Result<Transaction, Box<dyn Error>>>,
+    F: FnMut(&Transaction, &TransactionError) -> Result<(), Box<dyn Error>>,
+{
+    let mut last_rows = HashMap::<u16, usize>::new();
+    let mut by_currency = false;
+    for (row, result) in first_pass.into_iter().enumerate() {
+        let tx = result?;
+        last_rows.insert(tx.client_id, row);
+        by_currency |= !tx.currency.is_implicit();
+    }
+
+    for (row, result) in second_pass.into_iter().enumerate() {
+        let tx = result?;
+        let _span = tx.span().entered();
+
+        if let Err(e) = engine.apply(&tx) {
+            if engine.is_fatal(&e) {
+                return Err(fatal_error(&tx, e));
+            }
+            on_reject(&tx, &e)?;
+        }
+        report_released(&mut engine, &mut on_reject)?;
+
+        // No further transactions reference this client, so its state is final.
+        if last_rows.get(&tx.client_id) == Some(&row) {
+            if let Some(state) = engine.take_client_state(tx.client_id) {
+                write_state(sink, &state, by_currency)?;
+            }
+        }
+    }
+
+    sink.finish()
+}
+
+/// Write client account states to some sink, in the given order.
+pub fn write_balances(
+    sink: &mut dyn BalanceSink,
+    states: &HashMap<u16, ClientState>,
+    sort_by: SortBy,
+) -> Result<(), Box<dyn Error>> {
+    let by_currency = states.values().any(|state| !state.currencies.is_empty());
+    let mut states: Vec<&ClientState> = states.values().collect();
+    sort_by.sort(&mut states);
+
+    for state in states {
+        write_state(sink, state, by_currency)?;
+    }
+
+    sink.finish()
+}
+
+/// Write a client's state to `sink`, as a row per currency if any client used currencies other
+/// than the implicit one (so every row has the same columns).
+fn write_state(
+    sink: &mut dyn BalanceSink,
+    state: &ClientState,
+    by_currency: bool,
+) -> Result<(), Box<dyn Error>> {
+    if by_currency {
+        for row in state.currency_rows() {
+            sink.write(&row)?;
+        }
+        Ok(())
+    } else {
+        sink.write(state)
+    }
+}
+
+/// Read a balance export (CSV in the default dialect, JSON, or NDJSON, as written by
+/// `write_balances`) back into client states. The format is detected from the first character of
+/// the export. Rows for each currency a client holds are combined into a single state. Exports
+/// don't say which transactions are under dispute, so restored states have none (use a snapshot to
+/// resume processing instead).
+pub fn read_balances(path: &str) -> Result<HashMap<u16, ClientState>, Box<dyn Error>> {
+    let mut reader = io::BufReader::new(open_input(path)?);
+    let first = reader
+        .fill_buf()?
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .copied();
+
+    let states: Vec<ClientState> = match first {
+        Some(b'[') => serde_json::from_reader(reader)?,
+        Some(b'{') => serde_json::Deserializer::from_reader(reader)
+            .into_iter()
+            .collect::<Result<_, _>>()?,
+        _ => ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(reader)
+            .deserialize()
+            .collect::<Result<_, _>>()?,
+    };
+
+    let mut balances: HashMap<u16, ClientState> = HashMap::with_capacity(states.len());
+    let mut rows = HashSet::with_capacity(states.len());
+    for row in states {
+        let currency = row.currency.unwrap_or(Currency::IMPLICIT);
+        if !rows.insert((row.client_id, currency)) {
+            return Err(format!(
+                "client {} appears more than once in the balance export",
+                row.client_id
+            )
+            .into());
+        }
+
+        let state = balances
+            .entry(row.client_id)
+            .or_insert_with(|| ClientState {
+                locked: row.locked,
+                version: row.version,
+                fees_collected: row.fees_collected.map(|_| Money::ZERO),
+                last_activity: row.last_activity,
+                ..ClientState::new(row.client_id)
+            });
+        if currency.is_implicit() {
+            state.available = row.available;
+            state.held = row.held;
+            state.total = row.total;
+            state.fees_collected = row.fees_collected;
+            state.currencies.extend(row.currencies);
+        } else {
+            state
+                .currencies
+                .insert(currency, row.balance(Currency::IMPLICIT));
+        }
+    }
+
+    Ok(balances)
+}
+
+/// Open a transaction log for reading.
+pub fn open_transactions(
+    path: &str,
+    format: InputFormat,
+) -> Result<TransactionStream, Box<dyn Error>> {
+    Ok(read_transactions(open_input(path)?, format))
+}
+
+/// Open several transaction logs to be read one after another, as if they were a single log. Each
+/// log is read with its own header (with its columns mapped by `schema`), and errors say which log
+/// they came from.
+pub fn open_transaction_logs(
+    paths: &[String],
+    format: InputFormat,
+    schema: &Schema,
+) -> Result<TransactionStream, Box<dyn Error>> {
+    open_transaction_logs_with(paths, format, schema, |reader| reader)
+}
+
+/// Open several transaction logs like `open_transaction_logs`, passing each log's reader through
+/// `wrap` before it's read (e.g. to count the bytes read, see `progress`).
+pub fn open_transaction_logs_with<W>(
+    paths: &[String],
+    format: InputFormat,
+    schema: &Schema,
+    mut wrap: W,
+) -> Result<TransactionStream, Box<dyn Error>>
+where
+    W: FnMut(Box<dyn io::Read>) -> Box<dyn io::Read>,
+{
+    let mut open = |path: &str| -> Result<TransactionStream, Box<dyn Error>> {
+        Ok(read_transactions_with_schema(
+            wrap(open_input(path)?),
+            format,
+            schema,
+        ))
+    };
+    if let [path] = paths {
+        return open(path);
+    }
+
+    let logs = paths
+        .iter()
+        .map(|path| {
+            let path = path.clone();
+            let transactions = open(&path).map_err(|e| format!("couldn't read {}: {}", path, e))?;
+            Ok(transactions.map(move |result| {
+                result.map_err(|e| -> Box<dyn Error> {
+                    // Rows which can't be parsed stay `ParseError`s, so they can still be skipped.
+                    match e.downcast::<ParseError>() {
+                        Ok(mut e) => {
+                            e.path = Some(path.clone());
+                            e
+                        }
+                        Err(e) => format!("{}: {}", path, e).into(),
+                    }
+                })
+            }))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+    Ok(Box::new(logs.into_iter().flatten()))
+}
+
+/// What `process_bytes` made of a transaction log.
+#[derive(Debug)]
+pub struct ProcessedBytes {
+    pub client_states: HashMap<u16, ClientState>,
+    /// Every transaction which was rejected, and why, in the order they were read.
+    pub rejected: Vec<(Transaction, TransactionError)>,
+}
+
+/// Apply a CSV transaction log held in memory using an engine with the default policies. This is
+/// the entry point for fuzzing (see `fuzz/`), so it never panics, whatever `input` holds: input
+/// which can't be parsed (or a rejection which stops processing) is returned as an error, and
+/// transactions which are rejected are listed with the reason.
+pub fn process_bytes(input: &[u8]) -> Result<ProcessedBytes, Box<dyn Error>> {
+    let mut rejected = Vec::new();
+    let client_states = process_transactions(
+        Engine::new(),
+        read_transactions(io::Cursor::new(input.to_vec()), InputFormat::Csv),
+        |tx, e| {
+            rejected.push((tx.clone(), e.clone()));
+            Ok(())
+        },
+    )?;
+
+    Ok(ProcessedBytes {
+        client_states,
+        rejected,
+    })
+}
+
+/// Read a transaction log from any reader. Reads interrupted before any data arrives are retried.
+pub fn read_transactions<R>(reader: R, format: InputFormat) -> TransactionStream
+where
+    R: io::Read + 'static,
+{
+    read_transactions_with_schema(reader, format, &Schema::default())
+}
+
+/// Read a transaction log from any reader like `read_transactions`, with the columns of a CSV log
+/// mapped to transaction fields by `schema`.
+pub fn read_transactions_with_schema<R>(
+    reader: R,
+    format: InputFormat,
+    schema: &Schema,
+) -> TransactionStream
+where
+    R: io::Read + 'static,
+{
+    let reader = RetryInterrupted(reader);
+    match format {
+        InputFormat::Csv => {
+            let mut reader = ReaderBuilder::new()
+                .has_headers(!schema.is_positional())
+                .delimiter(schema.delimiter())
+                // Avoid using too much memory
+                .buffer_capacity(CSV_READER_BUFFER_SIZE_IN_BYTES)
+                // Accept whitespace
+                .trim(Trim::All)
+                // Parsing is flexible, i.e. TransactionType::{Dispute, Resolve, Chargeback} may
+                // not have an amount; any amounts will be ignored)
+                .flexible(true)
+                .from_reader(reader);
+            if schema.is_positional() {
+                let headers = Schema::positional_headers();
+                return Box::new(fast_csv_transactions_with_headers(reader, Some(headers)));
+            }
+            // A header which can't be read is reported when the first transaction is read.
+            if schema.maps_columns() {
+                if let Ok(headers) = reader.headers() {
+                    let headers = schema.rename(headers);
+                    reader.set_headers(headers);
+                }
+            }
+
+            Box::new(fast_csv_transactions(reader))
+        }
+        InputFormat::Ndjson => Box::new(ndjson_transactions(io::BufReader::new(reader))),
+        InputFormat::Parquet => input::parquet_transactions(reader),
+    }
+}
@@ -0,0 +1,94 @@
+/// Credit limits, letting withdrawals overdraw a client's available funds.
+///
+/// Limits are read from CSV with a row per client, giving how far below zero the client's available
+/// funds may go:
+///
+/// ```text
+/// client,limit
+/// 1,500.00
+/// 7,25
+/// ```
+///
+/// Clients without a row get the default limit (zero unless one is given, so they can't overdraw).
+/// Only withdrawals (and their fees) can overdraw. Disputes and chargebacks can still leave a
+/// client further below zero, as they can without a limit.
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::str::FromStr;
+
+use csv::{ReaderBuilder, Trim};
+use serde::Deserialize;
+
+use crate::input::open_input;
+use crate::money::{self, Money};
+
+#[derive(Deserialize)]
+struct LimitRecord {
+    client: u16,
+    #[serde(deserialize_with = "money::deserialize_exact")]
+    limit: Money,
+}
+
+/// How far each client's available funds may go below zero.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CreditLimits {
+    /// Limit for clients without one of their own.
+    default: Money,
+    clients: HashMap<u16, Money>,
+}
+
+impl CreditLimits {
+    /// The same limit for every client.
+    pub fn new(default: Money) -> Self {
+        CreditLimits {
+            default,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Read per-client limits from CSV, on top of `default`. Limits can't be negative, and each
+    /// client can only be listed once.
+    pub fn from_reader<R: io::Read>(reader: R, default: Money) -> Result<Self, Box<dyn Error>> {
+        let mut clients = HashMap::new();
+        for record in ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(reader)
+            .deserialize()
+        {
+            let LimitRecord { client, limit } = record?;
+            if limit.is_sign_negative() {
+                return Err(format!("credit limit for client {} is negative", client).into());
+            }
+            if clients.insert(client, limit).is_some() {
+                return Err(format!("client {} has more than one credit limit", client).into());
+            }
+        }
+
+        Ok(CreditLimits { default, clients })
+    }
+
+    /// How far the client's available funds may go below zero.
+    pub fn limit(&self, client_id: u16) -> Money {
+        self.clients
+            .get(&client_id)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Read per-client limits from a CSV file, on top of `default`.
+pub fn read_file(path: &str, default: Money) -> Result<CreditLimits, Box<dyn Error>> {
+    CreditLimits::from_reader(open_input(path)?, default)
+}
+
+/// Parse a credit limit given on the command line.
+pub fn parse_limit(s: &str) -> Result<Money, String> {
+    match rust_decimal::Decimal::from_str(s) {
+        Ok(limit) if !limit.is_sign_negative() => Ok(Money::new(limit)),
+        _ => Err(format!(
+            "invalid credit limit '{}', expected a non-negative amount",
+            s
+        )),
+    }
+}
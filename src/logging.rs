@@ -0,0 +1,128 @@
+/// Logging to stderr, for the `tracing` spans and events the engine and the binary emit.
+///
+/// The binary installs a `Logger` at startup, configured by `--log-level` and `--log-format`. Runs
+/// are quiet by default: only errors are written, and rejected transactions (warnings) and the
+/// summary at the end of a run (info) need a lower level. Each run is a `run` span, and with
+/// `debug` each transaction applied is a `tx` span (with its client, transaction ID, and line)
+/// with an event of its own.
+///
+/// Plain text (the default) writes just each message, one per line, as the binary always has.
+/// JSON writes one object per line, with the time, level, target, and message, followed by the
+/// event's fields (e.g. the client and transaction ID of a rejected transaction) and the spans it
+/// happened in, for ingestion by log pipelines. Library users can install any other `tracing`
+/// subscriber instead.
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::{SubscriberInitExt, TryInitError};
+
+/// How log events are written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Each message on a line of its own.
+    #[default]
+    Text,
+    /// A JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+/// Parse a `--log-level` (`off`, `error`, `warn`, `info`, `debug`, or `trace`).
+pub fn parse_level(s: &str) -> Result<LevelFilter, String> {
+    s.parse().map_err(|_| {
+        format!(
+            "unknown log level '{}', expected off, error, warn, info, debug, or trace",
+            s
+        )
+    })
+}
+
+/// Writes log events at or above some level to stderr.
+#[derive(Debug)]
+pub struct Logger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+impl Logger {
+    pub fn new(level: LevelFilter, format: LogFormat) -> Self {
+        Logger { level, format }
+    }
+
+    /// Install the logger for the rest of the process.
+    pub fn install(self) -> Result<(), TryInitError> {
+        self.subscriber(io::stderr).try_init()
+    }
+
+    /// A subscriber writing events to `writer` (rather than stderr), e.g. for tests.
+    pub fn subscriber<W>(&self, writer: W) -> Box<dyn Subscriber + Send + Sync>
+    where
+        W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+    {
+        let builder = tracing_subscriber::fmt()
+            .with_max_level(self.level)
+            .with_writer(writer)
+            .with_ansi(false);
+        match self.format {
+            LogFormat::Text => Box::new(builder.event_format(Message).finish()),
+            LogFormat::Json => Box::new(
+                builder
+                    .json()
+                    .flatten_event(true)
+                    .with_current_span(false)
+                    .with_span_list(true)
+                    .finish(),
+            ),
+        }
+    }
+}
+
+/// Formats an event as just its message.
+struct Message;
+
+impl<S, N> FormatEvent<S, N> for Message
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut message = MessageField(&mut writer, Ok(()));
+        event.record(&mut message);
+        message.1?;
+        writeln!(writer)
+    }
+}
+
+/// Writes an event's `message` field, ignoring the others.
+struct MessageField<'a, 'w>(&'a mut Writer<'w>, fmt::Result);
+
+impl Visit for MessageField<'_, '_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.1 = write!(self.0, "{:?}", value);
+        }
+    }
+}
@@ -1,257 +1,1177 @@
-/// A toy parser/processer for transaction data, as might be used for an ATM.
+/// Command line interface for the payment engine.
 ///
 /// John Ferguson, 2022
-use rust_decimal::prelude::*;
-use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::env;
 use std::error::Error;
-use std::{env, io};
-
-use csv::{ReaderBuilder, Trim};
-use serde::{Deserialize, Serialize};
-
-#[cfg(test)]
-mod tests;
-
-/// Maximum size of CSV reader buffer. Useful for larger datasets.
-const CSV_READER_BUFFER_SIZE_IN_BYTES: usize = 1024;
-
-/// How many decimal places to handle for transaction amounts.
-const TX_AMOUNT_DECIMAL_PLACES: u32 = 4;
-
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "lowercase")]
-enum TransactionType {
-    /// Credit to a client's account. Increases available and total funds.
-    Deposit,
-    /// Debit to the client's account. Decreases the available and total funds. Does not apply when
-    /// the client lacks the funds for the transaction.
-    Withdrawal,
-    /// Claim that some transaction was erroneous. Decreases available funds, and increases held
-    /// funds. Has no associated amount, and references an amount in another transaction (if it
-    /// exists).
-    Dispute,
-    /// Resolution to a Dispute. Held funds decrease by amount of disputed transaction, available
-    /// funds increase by amount of disputed transaction.
-    Resolve,
-    /// Resolution to a Dispute. Held funds decrease by disputed amount, and client's account is
-    /// frozen/locked.
-    Chargeback,
+use std::fs::File;
+use std::io::{self, IsTerminal};
+use std::net::TcpListener;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "kafka")]
+use payment_engine::cli::ConsumeOptions;
+use payment_engine::cli::{
+    self, Command, CompactOptions, HistoryOptions, Options, ServeOptions, VerifyDigestOptions,
+};
+use payment_engine::clock::{ClockKind, SystemClock};
+use payment_engine::compact;
+use payment_engine::config::EngineConfig;
+use payment_engine::digest::{self, AuditDigest, HashingWriter, WriterHash};
+use payment_engine::error::TransactionError;
+use payment_engine::events::EventWriter;
+use payment_engine::fees;
+#[cfg(feature = "http")]
+use payment_engine::http;
+use payment_engine::input::{self, TransactionStream, STDIN_PATH};
+use payment_engine::interrupt::{until_interrupted, Interrupt};
+use payment_engine::journal::{self, JournalWriter};
+#[cfg(feature = "kafka")]
+use payment_engine::kafka;
+use payment_engine::limits::{self, CreditLimits};
+use payment_engine::logging::Logger;
+use payment_engine::merge;
+use payment_engine::metrics;
+use payment_engine::money::Money;
+use payment_engine::output::{
+    self, AtomicFile, BalanceSink, BalanceWriter, DiffWriter, JsonBalanceWriter, OutputFormat,
+    RejectWriter, ShardedBalanceWriter, Tee,
+};
+#[cfg(feature = "parquet")]
+use payment_engine::parquet::ParquetBalanceWriter;
+use payment_engine::policy_report::PolicyReport;
+use payment_engine::progress::{self, CountingReader, Progress};
+use payment_engine::proof::{self, BalanceProof, BalanceProofs};
+use payment_engine::rates;
+use payment_engine::reorder;
+use payment_engine::replication;
+use payment_engine::server;
+use payment_engine::snapshot;
+use payment_engine::summary::Summary;
+use payment_engine::terminal::TerminalStats;
+use payment_engine::{
+    apply_transactions, apply_transactions_with, open_transaction_logs, open_transaction_logs_with,
+    process_parallel, process_transactions, process_two_pass, read_balances, write_balances,
+    Engine, Transaction,
+};
+use tracing::{error, error_span, info, warn};
+
+/// Sign a digest of every artifact written by this run.
+fn write_digest(
+    options: &Options,
+    digest_path: &Path,
+    key: &[u8],
+    stdout_hash: Option<WriterHash>,
+) -> Result<(), Box<dyn Error>> {
+    let mut artifacts = Vec::new();
+    match (options.output_shards, &options.output_path) {
+        (Some(shards), _) => {
+            let dir = Path::new(options.output_dir.as_deref().unwrap_or("."));
+            for path in output::shard_paths(dir, shards) {
+                artifacts.push((path.display().to_string(), digest::hash_file(&path)?));
+            }
+        }
+        (None, Some(path)) => artifacts.push((path.clone(), digest::hash_file(Path::new(path))?)),
+        (None, None) => {
+            let hash = stdout_hash.ok_or("balances written to stdout weren't hashed")?;
+            artifacts.push((digest::STDOUT_ARTIFACT.to_string(), hash.finish()));
+        }
+    }
+    if let Some(rejects_path) = &options.rejects_path {
+        artifacts.push((
+            rejects_path.clone(),
+            digest::hash_file(Path::new(rejects_path))?,
+        ));
+    }
+    if let Some(events_path) = options
+        .events_path
+        .as_ref()
+        .filter(|&path| path != STDIN_PATH)
+    {
+        artifacts.push((
+            events_path.clone(),
+            digest::hash_file(Path::new(events_path))?,
+        ));
+    }
+
+    let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    AuditDigest { created, artifacts }.write(digest_path, key)?;
+
+    Ok(())
 }
 
-#[derive(Debug, Deserialize)]
-struct Transaction {
-    r#type: TransactionType,
-    #[serde(rename = "client")]
-    client_id: u16,
-    #[serde(rename = "tx")]
-    tx_id: u32,
-    /// Transaction amount, will be rounded to 4 decimal places before handling.
-    amount: Option<Decimal>,
+/// Check a digest written by an earlier run against the archived artifacts.
+fn verify_digest(options: &VerifyDigestOptions) -> Result<(), Box<dyn Error>> {
+    let key = digest::key_from_env()?;
+    let digest = AuditDigest::read(Path::new(&options.digest_path), &key)?;
+
+    let mismatched = digest.verify(|name| {
+        if name == digest::STDOUT_ARTIFACT {
+            options.balances_path.clone()
+        } else {
+            None
+        }
+    })?;
+    if !mismatched.is_empty() {
+        return Err(format!("artifacts don't match digest: {}", mismatched.join(", ")).into());
+    }
+
+    info!(
+        "digest verified, {} artifact(s) unaltered",
+        digest.artifacts.len()
+    );
+
+    Ok(())
 }
 
-// TODO: Wrap `Decimal` in a newtype and implement `serde::Serialize` so that decimal place
-// handling requires less effort.
-#[derive(Debug, Serialize)]
-struct ClientState {
-    /// This needs to be included for serialization
-    #[serde(rename = "client")]
-    client_id: u16,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
-    locked: bool,
-    #[serde(skip)]
-    disputed_tx_ids: HashSet<u32>,
+/// Check a single client's balance proof against a published root.
+fn verify_proof(root: &str, proof_path: &str) -> Result<(), Box<dyn Error>> {
+    let proof: BalanceProof = serde_json::from_str(&std::fs::read_to_string(proof_path)?)?;
+    if !proof.verify(root) {
+        return Err(format!(
+            "balance {} for client {} is not included in root {}",
+            proof.total, proof.client, root
+        )
+        .into());
+    }
+
+    info!(
+        "balance {} for client {} is included in root {}",
+        proof.total, proof.client, root
+    );
+
+    Ok(())
 }
 
-impl Default for ClientState {
-    fn default() -> Self {
-        ClientState {
-            client_id: Default::default(),
-            available: Decimal::new(0, 0),
-            held: Decimal::new(0, 0),
-            total: Decimal::new(0, 0),
-            locked: false,
-            disputed_tx_ids: Default::default(),
+fn main() {
+    // Usage errors (and --help) are reported by clap, before there's a logger.
+    let (log_options, command) = cli::parse(env::args().skip(1)).unwrap_or_else(|e| e.exit());
+    if let Err(e) = Logger::new(log_options.level, log_options.format).install() {
+        eprintln!("couldn't install the logger: {}", e);
+    }
+    // The run's span is at the highest level, so every event logged is in it.
+    let _run = error_span!("run", command = command.name()).entered();
+
+    match command {
+        Command::Process(options) => process(options),
+        Command::Validate(options) => validate(options),
+        Command::VerifyDigest(options) => {
+            if let Err(e) = verify_digest(&options) {
+                error!("{}", e);
+                std::process::exit(-1);
+            }
+        }
+        Command::Serve(options) => {
+            let engine = shared_engine(&options);
+            let result = TcpListener::bind(&options.listen_addr).and_then(|listener| {
+                info!("listening on {}", listener.local_addr()?);
+                server::serve_shared(listener, engine, options.options.input_format)
+            });
+            if let Err(e) = result {
+                error!("server error: {}", e);
+                std::process::exit(-1);
+            }
+        }
+        #[cfg(feature = "http")]
+        Command::ServeHttp(options) => {
+            if let Err(e) = http::serve_shared(&options.listen_addr, shared_engine(&options)) {
+                error!("server error: {}", e);
+                std::process::exit(-1);
+            }
+        }
+        #[cfg(not(feature = "http"))]
+        Command::ServeHttp(_) => unreachable!("serve-http isn't parsed without the http feature"),
+        Command::Follow(options) => {
+            let engine = Arc::new(Mutex::new(Engine::new()));
+            let follower = engine.clone();
+            let leader_addr = options.leader_addr.clone();
+            thread::spawn(move || replication::follow_forever(leader_addr, &follower));
+
+            let result = TcpListener::bind(&options.listen_addr).and_then(|listener| {
+                info!("serving reads on {}", listener.local_addr()?);
+                replication::serve_reads(listener, engine)
+            });
+            if let Err(e) = result {
+                error!("server error: {}", e);
+                std::process::exit(-1);
+            }
+        }
+        Command::Compact(options) => {
+            if let Err(e) = compact(&options) {
+                error!("couldn't compact transactions: {}", e);
+                std::process::exit(-1);
+            }
+        }
+        Command::PolicyReport(options) => {
+            let result = PolicyReport::run(options.arguments.clone(), || {
+                configured_engine(&options.options)
+            })
+            .and_then(|report| report.write_json(io::stdout().lock()));
+            if let Err(e) = result {
+                error!("couldn't write policy report: {}", e);
+                std::process::exit(-1);
+            }
+        }
+        Command::History(options) => {
+            if let Err(e) = history(&options) {
+                error!("couldn't list client history: {:?}", e);
+                std::process::exit(-1);
+            }
+        }
+        #[cfg(feature = "kafka")]
+        Command::Consume(options) => {
+            if let Err(e) = consume(&options) {
+                error!("couldn't consume transactions: {}", e);
+                std::process::exit(-1);
+            }
+        }
+        #[cfg(not(feature = "kafka"))]
+        Command::Consume(_) => unreachable!("consume isn't parsed without the kafka feature"),
+        Command::Gen(generator) => {
+            if let Err(e) = generator.write_csv(io::stdout()) {
+                error!("error writing transactions: {}", e);
+                std::process::exit(-1);
+            }
+        }
+        Command::VerifyProof { root, proof_path } => {
+            if let Err(e) = verify_proof(&root, &proof_path) {
+                error!("{}", e);
+                std::process::exit(-1);
+            }
         }
     }
+
+    std::process::exit(0);
 }
 
-impl ClientState {
-    fn new(client_id: u16) -> Self {
-        ClientState {
-            client_id,
-            ..Default::default()
+/// Paths of the transaction logs, with any patterns expanded. The log is stdin when no path is
+/// given (or the path is `-`).
+fn input_paths(options: &Options) -> Vec<String> {
+    let mut paths = Vec::new();
+    let csv_path = options.csv_path.as_deref().unwrap_or(STDIN_PATH);
+    for path in std::iter::once(csv_path).chain(options.more_paths.iter().map(String::as_str)) {
+        match input::expand_path(path) {
+            Ok(expanded) => paths.extend(expanded),
+            Err(e) => {
+                error!("couldn't read CSV: {}", e);
+                std::process::exit(-1);
+            }
         }
     }
+    if let Some(path) = paths
+        .iter()
+        .find(|path| path.as_str() != STDIN_PATH && !Path::new(path).exists())
+    {
+        error!("couldn't read CSV: {}", path);
+        std::process::exit(-1);
+    }
+    // A pattern can match several logs, which journals can't tell apart (see `Options`).
+    if paths.len() > 1 && (options.journal_path.is_some() || options.recover_path.is_some()) {
+        error!("journals can't be used with several logs");
+        std::process::exit(-1);
+    }
+
+    paths
 }
 
-/// Get all the transactions in some readable CSV data and return a map of client account states.
-fn process_csv<R>(mut reader: csv::Reader<R>) -> Result<HashMap<u16, ClientState>, Box<dyn Error>>
-where
-    R: std::io::Read,
-{
-    // Keep track of client states as transactions are processed.
-    let mut client_states = HashMap::<u16, ClientState>::new();
-
-    // Keep track of disputable transactions in case they are referenced by later transactions.
-    // Only transactions with an amount can be disputed.
-    //let mut disputable_transactions = HashMap::<u32, &Transaction>::new();
-    let mut disputable_transactions = HashMap::<u32, Transaction>::new();
-
-    for result in reader.deserialize() {
-        let tx: Transaction = result?;
-
-        // All clients referenced by any transaction get tracked.
-        let state: &mut ClientState = client_states
-            .entry(tx.client_id)
-            .or_insert_with(|| ClientState::new(tx.client_id));
-
-        // Transactions only get applied if the client's account isn't locked/frozen.
-        if !state.locked {
-            let tx_amount = tx
-                .amount
-                .unwrap_or_default()
-                .round_dp(TX_AMOUNT_DECIMAL_PLACES);
-
-            match tx.r#type {
-                TransactionType::Deposit => {
-                    state.available += tx_amount;
-
-                    disputable_transactions.insert(tx.tx_id, tx);
-                }
-                TransactionType::Withdrawal => {
-                    if state.available >= tx_amount {
-                        state.available -= tx_amount;
-                    }
+/// Open the transaction logs (read in sequence, or merged or checked to be in order by timestamp,
+/// or reordered by ID), keeping only the requested (`--client`) and sampled clients, if any. Rows
+/// and bytes read are counted in `progress`, if it's being shown.
+fn open_input_transactions(
+    paths: &[String],
+    options: &Options,
+    skipped: Option<&Rc<Cell<u64>>>,
+    progress: Option<&Rc<RefCell<Progress>>>,
+) -> Result<TransactionStream, Box<dyn Error>> {
+    let open = |paths: &[String]| -> Result<TransactionStream, Box<dyn Error>> {
+        let transactions = match progress {
+            Some(progress) => {
+                let bytes_read = progress.borrow().bytes_read();
+                let transactions = open_transaction_logs_with(
+                    paths,
+                    options.input_format,
+                    &options.schema,
+                    |reader| Box::new(CountingReader::new(reader, bytes_read.clone())),
+                )?;
+                let progress = progress.clone();
+                Box::new(transactions.inspect(move |_| progress.borrow_mut().observe()))
+            }
+            None => open_transaction_logs(paths, options.input_format, &options.schema)?,
+        };
+        Ok(skip_bad_rows(transactions, options, skipped))
+    };
+    let transactions = if let Some(window) = options.reorder_window {
+        reorder::reorder_by_id(open(paths)?, window)
+    } else if !options.chronological {
+        open(paths)?
+    } else {
+        let sources = (paths.iter())
+            .chain(&options.merge_paths)
+            .map(|path| open(std::slice::from_ref(path)))
+            .collect::<Result<Vec<_>, _>>()?;
+        merge::merge_by_timestamp(sources, options.lateness)
+    };
 
-                    disputable_transactions.insert(tx.tx_id, tx);
+    let transactions = match &options.clients {
+        Some(clients) => clients.clone().filter(transactions),
+        None => transactions,
+    };
+    Ok(match options.sample {
+        Some(sample) => sample.filter(transactions),
+        None => transactions,
+    })
+}
+
+/// Progress through the logs at `paths` (and any merged in), if it's to be shown (`--progress`)
+/// and stderr is a terminal, so it doesn't clutter logs.
+fn start_progress(paths: &[String], options: &Options) -> Option<Rc<RefCell<Progress>>> {
+    if !options.progress || !io::stderr().is_terminal() {
+        return None;
+    }
+    let merged = options.merge_paths.iter().filter(|_| options.chronological);
+    let total_bytes = progress::total_size(paths.iter().chain(merged));
+    Some(Rc::new(RefCell::new(Progress::new(total_bytes))))
+}
+
+/// Drop rows which can't be parsed from `transactions` if asked to (`--skip-bad-rows`), logging
+/// each, and counting them in `skipped`. Without a count, rows are skipped quietly (e.g. on the
+/// first of two passes over a log, as the second reports them).
+fn skip_bad_rows(
+    transactions: TransactionStream,
+    options: &Options,
+    skipped: Option<&Rc<Cell<u64>>>,
+) -> TransactionStream {
+    if !options.skip_bad_rows {
+        return transactions;
+    }
+
+    let skipped = skipped.cloned();
+    Box::new(input::skip_bad_rows(transactions, move |e| {
+        if let Some(skipped) = &skipped {
+            warn!(line = e.line, "skipped a row which can't be parsed: {}", e);
+            skipped.set(skipped.get() + 1);
+        }
+    }))
+}
+
+/// Count every transaction read from `transactions` against its terminal, if terminals are being
+/// tracked.
+fn observe_terminals(
+    transactions: TransactionStream,
+    terminals: &Option<Rc<RefCell<TerminalStats>>>,
+) -> TransactionStream {
+    match terminals {
+        Some(terminals) => {
+            let terminals = terminals.clone();
+            Box::new(transactions.inspect(move |result| {
+                if let Ok(tx) = result {
+                    terminals.borrow_mut().observe(tx);
                 }
-                TransactionType::Dispute => {
-                    // Specification states that "if the transaction specified by the dispute
-                    // doesn't exist you can ignore it". Assumption: A `Dispute` can only reference
-                    // a transaction which has already occurred, and since transactions in CSV are
-                    // in order they occurred, we can skip disputes against transactions we haven't
-                    // seen yet.
-                    if let Some(disputed_tx) = disputable_transactions.get(&tx.tx_id) {
-                        // Assumptions: we don't have to consider the client ID, and differentiate
-                        // between disputes on the same tx ID by different clients. If this was
-                        // the case then transactions would probably indicate source/destination
-                        // clients.
-                        //
-                        // All disputes are valid as long as the tx ID has already occurred, and no
-                        // dispute is already outstanding against some tx ID for this client.
-                        //
-                        // This implies that the client ID in the dispute should match the client
-                        // ID in the disputed transaction, but since it isn't in the spec no check
-                        // is made here. If we did want to enforce this, we could store a
-                        // collection of `&Transaction` for each client (i.e.
-                        // `disputable_transactions` would be per-client)
-
-                        if !state.disputed_tx_ids.contains(&tx.tx_id) {
-                            let disputed_amount = disputed_tx
-                                .amount
-                                .unwrap()
-                                .round_dp(TX_AMOUNT_DECIMAL_PLACES);
-                            state.available -= disputed_amount;
-                            state.held += disputed_amount;
-
-                            state.disputed_tx_ids.insert(tx.tx_id);
-                        }
-                    }
+            }))
+        }
+        None => transactions,
+    }
+}
+
+/// Count every transaction read from `transactions` in the summary, if one was requested.
+fn observe_summary(
+    transactions: TransactionStream,
+    summary: &Option<Rc<RefCell<Summary>>>,
+) -> TransactionStream {
+    match summary {
+        Some(summary) => {
+            let summary = summary.clone();
+            Box::new(transactions.inspect(move |result| {
+                if let Ok(tx) = result {
+                    summary.borrow_mut().observe(tx);
                 }
-                TransactionType::Resolve => {
-                    // See assumptions for `TransactionType::Dispute` above.
-                    if let Some(disputed_tx) = disputable_transactions.get(&tx.tx_id) {
-                        if state.disputed_tx_ids.contains(&tx.tx_id) {
-                            state.disputed_tx_ids.remove(&tx.tx_id);
-
-                            let disputed_amount = disputed_tx
-                                .amount
-                                .unwrap()
-                                .round_dp(TX_AMOUNT_DECIMAL_PLACES);
-                            state.available += disputed_amount;
-                            state.held -= disputed_amount;
-                        }
-                    }
+            }))
+        }
+        None => transactions,
+    }
+}
+
+/// The log of rejected transactions, if one was requested.
+fn create_rejects_log(options: &Options) -> Option<RejectWriter<File>> {
+    match options.rejects_path.as_ref().map(File::create) {
+        Some(Ok(file)) => Some(RejectWriter::new(file, &options.output_dialect)),
+        Some(Err(e)) => {
+            error!("couldn't create rejects log: {}", e);
+            std::process::exit(-1);
+        }
+        None => None,
+    }
+}
+
+/// The journal of applied transactions, if one was requested. A journal being recovered from is
+/// continued rather than replaced.
+fn open_journal(options: &Options, engine: &Engine) -> Option<JournalWriter> {
+    let path = options.journal_path.as_ref()?;
+    let journal = if options.recover_path.as_ref() == Some(path) {
+        JournalWriter::append(Path::new(path), options.journal_sync).map_err(Box::from)
+    } else {
+        JournalWriter::create(Path::new(path), options.journal_sync, engine)
+    };
+
+    match journal {
+        Ok(journal) => Some(journal),
+        Err(e) => {
+            error!("couldn't open journal: {}", e);
+            std::process::exit(-1);
+        }
+    }
+}
+
+/// Replay the journal of an interrupted run (if recovering from one), and drop the transactions
+/// it covers from the input.
+fn recover(
+    engine: &mut Engine,
+    transactions: TransactionStream,
+    options: &Options,
+) -> TransactionStream {
+    let last_line = match options.recover_path.as_ref() {
+        Some(path) => match journal::replay(engine, Path::new(path)) {
+            Ok(last_line) => last_line,
+            Err(e) => {
+                error!("couldn't recover from journal: {}", e);
+                std::process::exit(-1);
+            }
+        },
+        None => return transactions,
+    };
+
+    match last_line {
+        Some(last_line) => {
+            info!("recovered journal up to line {}", last_line);
+            Box::new(transactions.filter(move |result| {
+                !matches!(result, Ok(tx) if tx.line.is_some_and(|line| line <= last_line))
+            }))
+        }
+        None => transactions,
+    }
+}
+
+/// An engine with the requested policies and fees, resumed from a snapshot or ledger if one was
+/// given.
+fn configured_engine(options: &Options) -> Engine {
+    let mut engine = engine_config(options)
+        .build()
+        .with_skip_backfilled(options.skip_backfilled)
+        .with_activity_tracking(options.chronological);
+    if options.clock == ClockKind::System {
+        engine = engine.with_clock(SystemClock);
+    }
+    if let Some(path) = &options.initial_balances_path {
+        match read_balances(path) {
+            Ok(mut balances) => {
+                if let Some(clients) = &options.clients {
+                    balances.retain(|&client_id, _| clients.includes(client_id));
                 }
-                TransactionType::Chargeback => {
-                    // See assumptions for `TransactionType::Dispute` above.
-                    if let Some(disputed_tx) = disputable_transactions.get(&tx.tx_id) {
-                        if state.disputed_tx_ids.contains(&tx.tx_id) {
-                            state.disputed_tx_ids.remove(&tx.tx_id);
-
-                            let disputed_amount = disputed_tx
-                                .amount
-                                .unwrap()
-                                .round_dp(TX_AMOUNT_DECIMAL_PLACES);
-                            state.held -= disputed_amount;
-                            state.locked = true;
-                        }
-                    }
+                let held = balances
+                    .values()
+                    .filter(|state| {
+                        state.held != Money::ZERO
+                            || state
+                                .currencies
+                                .values()
+                                .any(|balance| balance.held != Money::ZERO)
+                    })
+                    .count();
+                if held > 0 {
+                    warn!(
+                        "{} clients start with held funds, which can't be released by the transactions \
+                         being applied",
+                        held
+                    );
                 }
+                engine = engine.with_initial_balances(balances);
             }
+            Err(e) => {
+                error!("couldn't read initial balances: {}", e);
+                std::process::exit(-1);
+            }
+        }
+    }
 
-            // Update the client's total (serde doesn't allow serialized fields to be computed by
-            // combining other fields so we store it explicitly).
-            state.total = state.available + state.held;
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = &options.db_path {
+        engine = match engine.with_ledger(Path::new(path)) {
+            Ok(engine) => engine,
+            Err(e) => {
+                error!("couldn't open ledger: {}", e);
+                std::process::exit(-1);
+            }
+        };
+    }
+
+    match &options.snapshot_in {
+        Some(path) => match snapshot::read_file(engine, Path::new(path)) {
+            Ok(engine) => engine,
+            Err(e) => {
+                error!("couldn't restore snapshot: {}", e);
+                std::process::exit(-1);
+            }
+        },
+        None => engine,
+    }
+}
+
+/// The engine configuration given by the options (and any config file they were read with).
+fn engine_config(options: &Options) -> EngineConfig {
+    let mut config = EngineConfig::new()
+        .with_duplicate_tx_policy(options.duplicate_tx_policy)
+        .with_withdrawal_dispute_policy(options.withdrawal_dispute_policy)
+        .with_redispute_policy(options.redispute_policy)
+        .with_unknown_type_policy(options.unknown_type_policy)
+        .with_locked_account_policy(options.locked_account_policy)
+        .with_strict(options.strict)
+        .with_admin_ops(options.allow_admin_ops)
+        .with_precision(options.precision);
+    if options.dedup {
+        config = config.with_dedup();
+    }
+    if let Some(window) = options.dispute_window {
+        config = config.with_dispute_window(window);
+    }
+    if let Some(max) = options.max_open_disputes {
+        config = config.with_max_open_disputes(max);
+    }
+    if let Some(check) = options.invariant_check {
+        config = config.with_invariant_checks(check);
+    }
+    // Each worker thread has its own engine, so they share the memory limit.
+    if let Some(max_memory) = options.max_memory {
+        config = config.with_memory_limit(max_memory / options.threads.unwrap_or(1));
+    }
+    if let Some(path) = &options.fees_path {
+        match fees::read_file(Path::new(path)) {
+            Ok(fees) => config = config.with_fees(fees),
+            Err(e) => {
+                error!("couldn't read fee schedule: {}", e);
+                std::process::exit(-1);
+            }
+        }
+    }
+    if let Some(path) = &options.rates_path {
+        match rates::read_file(path) {
+            Ok(rates) => config = config.with_rates(rates),
+            Err(e) => {
+                error!("couldn't read exchange rates: {}", e);
+                std::process::exit(-1);
+            }
+        }
+    }
+    if options.limits_path.is_some() || options.credit_limit.is_some() {
+        let default = options.credit_limit.unwrap_or(Money::ZERO);
+        let limits = match &options.limits_path {
+            Some(path) => limits::read_file(path, default),
+            None => Ok(CreditLimits::new(default)),
+        };
+        match limits {
+            Ok(limits) => config = config.with_credit_limits(limits),
+            Err(e) => {
+                error!("couldn't read credit limits: {}", e);
+                std::process::exit(-1);
+            }
         }
     }
 
-    Ok(client_states)
+    config
 }
 
-/// Print client account states to stdout.
-fn print_balances(states: &HashMap<u16, ClientState>) -> Result<(), Box<dyn Error>> {
-    let mut writer = csv::Writer::from_writer(io::stdout());
+/// The engine for `serve` or `serve-http`, shared with a replication listener if `--replicate` was
+/// given.
+fn shared_engine(options: &ServeOptions) -> Arc<Mutex<Engine>> {
+    let mut engine = configured_engine(&options.options);
+    if options.history {
+        engine = engine.with_history();
+    }
+    if options.replicate_addr.is_some() {
+        engine = engine.with_replication();
+    }
+    let engine = Arc::new(Mutex::new(engine.with_metrics()));
 
-    for state in states.values() {
-        writer.serialize(state)?;
+    if let Some(addr) = &options.metrics_addr {
+        match TcpListener::bind(addr) {
+            Ok(listener) => {
+                if let Ok(addr) = listener.local_addr() {
+                    info!("serving metrics on http://{}", addr);
+                }
+                metrics::spawn_metrics_server(listener, engine.clone());
+            }
+            Err(e) => {
+                error!("couldn't listen for metrics: {}", e);
+                std::process::exit(-1);
+            }
+        }
+    }
+
+    if let Some(addr) = &options.replicate_addr {
+        match TcpListener::bind(addr) {
+            Ok(listener) => {
+                if let Ok(addr) = listener.local_addr() {
+                    info!("replicating on {}", addr);
+                }
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    if let Err(e) = replication::serve(listener, engine) {
+                        error!("replication error: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("couldn't listen for followers: {}", e);
+                std::process::exit(-1);
+            }
+        }
     }
-    writer.flush()?;
+
+    engine
+}
+
+/// Apply the transaction log, and write a compacted log with the same outcome.
+fn compact(options: &CompactOptions) -> Result<(), Box<dyn Error>> {
+    let mut engine = configured_engine(&options.options);
+    let mut transactions = 0;
+    apply_transactions(
+        &mut engine,
+        open_input_transactions(&input_paths(&options.options), &options.options, None, None)?
+            .inspect(|_| transactions += 1),
+        |_, _| Ok(()),
+    )?;
+
+    let rows = compact::write_file(&engine, Path::new(&options.output_path))?;
+    info!(
+        "compacted {} transactions into {} (verified)",
+        transactions, rows
+    );
 
     Ok(())
 }
 
-fn main() {
-    // Ensure user provided a file path as argument to the program.
-    if let Some(csv_path) = env::args().nth(1) {
-        // Ensure the path provided is a file which exists.
-        if std::path::Path::new(&csv_path).exists() {
-            // When run from the command line, we parse a CSV file at the given path.
-            let reader: csv::Reader<std::fs::File> = ReaderBuilder::new()
-                // Avoid using too much memory
-                .buffer_capacity(CSV_READER_BUFFER_SIZE_IN_BYTES)
-                // Accept whitespace
-                .trim(Trim::All)
-                // Parsing is flexible, i.e. TransactionType::{Dispute, Resolve, Chargeback} may not have an
-                // amount; any amounts will be ignored)
-                .flexible(true)
-                // Reading CSV from some path
-                .from_path(csv_path)
-                .unwrap();
-
-            // Process the transaction log and export client balances.
-            match process_csv(reader) {
-                Ok(client_states) => {
-                    if let Err(e) = print_balances(&client_states) {
-                        eprintln!("error writing client account states: {:?}", e);
-                        std::process::exit(-1);
-                    }
+/// Apply transactions consumed from Kafka until interrupted, starting from the snapshot the
+/// consumer keeps (if it's been written before).
+#[cfg(feature = "kafka")]
+fn consume(options: &ConsumeOptions) -> Result<(), Box<dyn Error>> {
+    let mut engine = configured_engine(&options.options);
+    let path = Path::new(&options.snapshot_path);
+    if path.exists() {
+        engine = snapshot::read_file(engine, path)?;
+        info!("resuming from {}", options.snapshot_path);
+    }
+    let interrupt = Interrupt::install().unwrap_or_else(|e| {
+        warn!(
+            "couldn't handle Ctrl-C, interrupting will stop the consumer at once: {}",
+            e
+        );
+        Interrupt::default()
+    });
+
+    kafka::consume(&mut engine, options, &interrupt)
+}
+
+/// Apply the transaction log keeping history, and write one client's ledger. Rejected
+/// transactions for the client are logged, so the ledger can be read alongside them.
+fn history(options: &HistoryOptions) -> Result<(), Box<dyn Error>> {
+    let client_id = options.client_id;
+    let mut engine = configured_engine(&options.options).with_history();
+    apply_transactions(
+        &mut engine,
+        open_input_transactions(
+            &input_paths(&options.options),
+            &options.options,
+            Some(&Rc::default()),
+            None,
+        )?,
+        |tx, e| {
+            if tx.client_id == client_id {
+                warn!(
+                    client = tx.client_id,
+                    tx = tx.tx_id,
+                    line = tx.line,
+                    reason = e.reason(),
+                    "rejected transaction: {}",
+                    e
+                );
+            }
+            Ok(())
+        },
+    )?;
+
+    let ledger = engine.client_history(client_id).unwrap_or_default();
+    if ledger.is_empty() {
+        info!("no transactions were applied to client {}", client_id);
+    }
+    output::write_ledger(io::stdout().lock(), ledger, &options.options.output_dialect)
+}
+
+/// Apply the transaction log, and export client balances.
+fn process(options: Options) {
+    let paths = input_paths(&options);
+
+    // Fail before doing any work if the digest can't be signed.
+    let digest_key = match options.digest_path.as_ref().map(|_| digest::key_from_env()) {
+        Some(Ok(key)) => Some(key),
+        Some(Err(e)) => {
+            error!("{}, aborting", e);
+            std::process::exit(-1);
+        }
+        None => None,
+    };
+    let proof_key = match options
+        .balance_proofs_dir
+        .as_ref()
+        .map(|_| proof::key_from_env())
+    {
+        Some(Ok(key)) => Some(key),
+        Some(Err(e)) => {
+            error!("{}, aborting", e);
+            std::process::exit(-1);
+        }
+        None => None,
+    };
+    let previous_balances = match options.diff_path.as_deref().map(read_balances) {
+        Some(Ok(balances)) => Some(balances),
+        Some(Err(e)) => {
+            error!("couldn't read balances to diff against: {}, aborting", e);
+            std::process::exit(-1);
+        }
+        None => None,
+    };
+
+    // Rejected transactions are logged to a file if requested, otherwise they're reported on
+    // stderr.
+    let mut rejects = create_rejects_log(&options);
+    let mut rejected = 0;
+    let terminals = options
+        .terminal_report_path
+        .as_ref()
+        .map(|_| Rc::new(RefCell::new(TerminalStats::default())));
+    let summary = options
+        .summary
+        .then(|| Rc::new(RefCell::new(Summary::new())));
+    let skipped = Rc::new(Cell::new(0));
+    let progress = start_progress(&paths, &options);
+    let interrupt = match Interrupt::install() {
+        Ok(interrupt) => interrupt,
+        Err(e) => {
+            warn!(
+                "couldn't handle Ctrl-C, interrupting will stop the run at once: {}",
+                e
+            );
+            Interrupt::default()
+        }
+    };
+    // Clients whose disputes were rejected for having too many open, and how many were.
+    let mut capped_disputes: BTreeMap<u16, u64> = BTreeMap::new();
+    let on_reject = |tx: &Transaction, e: &TransactionError| {
+        rejected += 1;
+        if let Some(terminals) = &terminals {
+            terminals.borrow_mut().reject(tx);
+        }
+        if let Some(summary) = &summary {
+            summary.borrow_mut().reject(tx, e);
+        }
+        if let TransactionError::TooManyDisputes { client_id, .. } = e {
+            let count = capped_disputes.entry(*client_id).or_default();
+            if *count == 0 {
+                let max = options.max_open_disputes.unwrap_or_default();
+                warn!(
+                    client = client_id,
+                    max,
+                    "alert: client {} has {} disputes open, further disputes are rejected",
+                    client_id,
+                    max
+                );
+            }
+            *count += 1;
+        }
+        match rejects.as_mut() {
+            Some(rejects) => rejects.write(tx, e),
+            None => {
+                warn!(
+                    client = tx.client_id,
+                    tx = tx.tx_id,
+                    line = tx.line,
+                    reason = e.reason(),
+                    "rejected transaction: {}",
+                    e
+                );
+                Ok(())
+            }
+        }
+    };
+
+    // Client balances go to stdout (or a file, renamed into place once they're all written),
+    // unless they're split into several (CSV) files, or events go to stdout instead. Stdout can't
+    // be read back later, so it's hashed as it's written if a digest is required.
+    let mut stdout_hash = None;
+    let output_file = options.output_path.as_ref().map(|path| {
+        AtomicFile::create(Path::new(path)).unwrap_or_else(|e| {
+            error!("couldn't create {}: {}", path, e);
+            std::process::exit(-1);
+        })
+    });
+    let stdout: Box<dyn io::Write> = match &output_file {
+        Some(file) => match file.try_clone() {
+            Ok(file) => Box::new(io::BufWriter::new(file)),
+            Err(e) => {
+                error!("couldn't write {}: {}", file.temp_path().display(), e);
+                exit_discarding(&output_file);
+            }
+        },
+        None if options.events_replace_balances() => Box::new(io::sink()),
+        None if digest_key.is_some() => {
+            let (writer, hash) = HashingWriter::new(io::stdout());
+            stdout_hash = Some(hash);
+            Box::new(writer)
+        }
+        None => Box::new(io::stdout()),
+    };
+    let sink: Box<dyn BalanceSink> = match options.output_shards {
+        Some(shards) => match ShardedBalanceWriter::create(
+            Path::new(options.output_dir.as_deref().unwrap_or(".")),
+            &options.output_dialect,
+            shards,
+        ) {
+            Ok(writer) => Box::new(writer),
+            Err(e) => {
+                error!("couldn't create sharded output: {}", e);
+                exit_discarding(&output_file);
+            }
+        },
+        None => match options.output_format {
+            OutputFormat::Csv => match previous_balances {
+                Some(previous) => {
+                    Box::new(DiffWriter::new(stdout, &options.output_dialect, previous))
                 }
+                None => Box::new(BalanceWriter::new(stdout, &options.output_dialect)),
+            },
+            OutputFormat::Json => Box::new(JsonBalanceWriter::array(stdout)),
+            OutputFormat::Ndjson => Box::new(JsonBalanceWriter::ndjson(stdout)),
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet => Box::new(ParquetBalanceWriter::new(stdout)),
+            #[cfg(not(feature = "parquet"))]
+            OutputFormat::Parquet => {
+                unreachable!("parquet isn't parsed without the parquet feature")
+            }
+        },
+    };
+
+    // Balance proofs need every client's total, so they see every state written to the export.
+    let mut proofs = proof_key.map(BalanceProofs::new);
+    let mut sink: Box<dyn BalanceSink + '_> = match proofs.as_mut() {
+        Some(proofs) => Box::new(Tee(sink, proofs)),
+        None => sink,
+    };
+
+    // Process the transaction log and export client balances.
+    let mut engine = configured_engine(&options);
+    if options.metrics_path.is_some() {
+        engine = engine.with_metrics();
+    }
+    if let Some(path) = &options.events_path {
+        let writer: Box<dyn io::Write + Send> = if path == STDIN_PATH {
+            Box::new(io::stdout())
+        } else {
+            match File::create(path) {
+                Ok(file) => Box::new(file),
                 Err(e) => {
-                    eprintln!("error handling transaction data: {:?}", e);
-                    std::process::exit(-1);
+                    error!("couldn't create {}: {}", path, e);
+                    exit_discarding(&output_file);
                 }
             }
-        } else {
-            eprintln!("couldn't read CSV: {}", csv_path);
-            std::process::exit(-1);
+        };
+        engine = engine.with_events(EventWriter::new(io::BufWriter::new(writer)));
+    }
+    let mut journal = open_journal(&options, &engine);
+    let result = if options.two_pass {
+        match (
+            open_input_transactions(&paths, &options, None, None),
+            open_input_transactions(&paths, &options, Some(&skipped), progress.as_ref()),
+        ) {
+            (Ok(first_pass), Ok(second_pass)) => process_two_pass(
+                engine,
+                until_interrupted(first_pass, &interrupt),
+                observe_terminals(until_interrupted(second_pass, &interrupt), &terminals),
+                on_reject,
+                sink.as_mut(),
+            )
+            // Balances are streamed straight to the sink, so the clients aren't counted.
+            .map(|()| None),
+            (Err(e), _) | (_, Err(e)) => Err(e),
         }
     } else {
-        eprintln!("expected path to CSV as first argument, aborting");
+        let sort_by = options.sort_by.unwrap_or_default();
+        open_input_transactions(&paths, &options, Some(&skipped), progress.as_ref())
+            .map(|transactions| until_interrupted(transactions, &interrupt))
+            .map(|transactions| recover(&mut engine, transactions, &options))
+            .map(|transactions| observe_terminals(transactions, &terminals))
+            .map(|transactions| observe_summary(transactions, &summary))
+            .and_then(|transactions| match options.threads {
+                Some(threads) => process_parallel(
+                    || configured_engine(&options),
+                    threads,
+                    transactions,
+                    on_reject,
+                ),
+                None => {
+                    apply_transactions_with(
+                        &mut engine,
+                        transactions,
+                        on_reject,
+                        |tx, rejected| match journal.as_mut() {
+                            Some(journal) => Ok(journal.record(tx, rejected)?),
+                            None => Ok(()),
+                        },
+                    )?;
+                    if let Some(journal) = journal.as_mut() {
+                        journal.sync()?;
+                    }
+                    engine.finish_events()?;
+                    if engine.backfilled_count() > 0 {
+                        info!(
+                            "skipped {} transactions already reflected in the snapshot",
+                            engine.backfilled_count()
+                        );
+                    }
+                    if engine.replayed_count() > 0 {
+                        info!(
+                            "skipped {} transactions which were already processed",
+                            engine.replayed_count()
+                        );
+                    }
+                    // An interrupted run's state is only snapshot separately, if at all, so it
+                    // can't be mistaken for the state after the whole log.
+                    if interrupt.is_raised() {
+                        if let Some(path) = &options.interrupt_snapshot {
+                            snapshot::write_file(&engine, Path::new(path))?;
+                            info!("wrote the state so far to {}", path);
+                        }
+                        return Ok(engine.into_client_states());
+                    }
+                    // Only snapshot (or commit) state from a run which applied the whole log.
+                    #[cfg(feature = "sqlite")]
+                    engine.commit_ledger()?;
+                    if let Some(path) = &options.snapshot_out {
+                        snapshot::write_file(&engine, Path::new(path))?;
+                    }
+                    if let (Some(path), Some(metrics)) = (&options.metrics_path, engine.metrics()) {
+                        metrics.write(&engine, File::create(path)?)?;
+                    }
+                    if let Some(path) = &options.open_disputes_path {
+                        output::write_open_disputes(File::create(path)?, &engine.open_disputes()?)?;
+                    }
+                    Ok(engine.into_client_states())
+                }
+            })
+            .map(|client_states| {
+                // Balances are only exported from a run which applied the whole log.
+                let written = if interrupt.is_raised() {
+                    Ok(())
+                } else {
+                    write_balances(sink.as_mut(), &client_states, sort_by)
+                };
+                if let Err(e) = written {
+                    error!("error writing client account states: {:?}", e);
+                    exit_discarding(&output_file);
+                }
+                let locked = client_states.values().filter(|state| state.locked).count();
+                Some((client_states.len(), locked))
+            })
+    };
+    if let Some(progress) = &progress {
+        progress.borrow_mut().finish();
+    }
+    if let Some(Err(e)) = rejects.as_mut().map(RejectWriter::flush) {
+        error!("error writing rejects log: {:?}", e);
+        exit_discarding(&output_file);
+    }
+    let counts = match result {
+        Ok(counts) => counts,
+        Err(e) => {
+            error!("error handling transaction data: {:?}", e);
+            exit_discarding(&output_file);
+        }
+    };
+    drop(sink);
+    if interrupt.is_raised() {
+        // An unfinished `--output` file is removed, like any failed run's.
+        if let (Some(summary), Some((_, locked))) = (&summary, counts) {
+            let mut summary = summary.borrow_mut();
+            summary.interrupt();
+            write_summary(&options, &mut summary, skipped.get(), locked);
+        }
+        error!(
+            rejected,
+            "interrupted, balances weren't written ({} rejected transactions so far)", rejected
+        );
+        exit_discarding(&output_file);
+    }
+    if let (Some(path), Some(file)) = (&options.output_path, output_file) {
+        if let Err(e) = file.commit() {
+            error!("error writing {}: {:?}", path, e);
+            std::process::exit(-1);
+        }
+    }
+
+    if let (Some(dir), Some(proofs)) = (&options.balance_proofs_dir, &proofs) {
+        if let Err(e) = proofs.export(Path::new(dir)) {
+            error!("error writing balance proofs: {:?}", e);
+            std::process::exit(-1);
+        }
+    }
+
+    if let (Some(path), Some(terminals)) = (&options.terminal_report_path, &terminals) {
+        let terminals = terminals.borrow();
+        if let Err(e) = File::create(path)
+            .map_err(Box::from)
+            .and_then(|file| terminals.write_report(file, &options.output_dialect))
+        {
+            error!("error writing terminal report: {:?}", e);
+            std::process::exit(-1);
+        }
+        for terminal in terminals.flagged() {
+            warn!(
+                "terminal {} has an anomalous reject or dispute rate",
+                terminal
+            );
+        }
+    }
+
+    for (client_id, count) in &capped_disputes {
+        info!(
+            "client {} had {} dispute(s) rejected for having too many open",
+            client_id, count
+        );
+    }
+
+    if skipped.get() > 0 {
+        info!("skipped {} row(s) which can't be parsed", skipped.get());
+    }
+
+    if let (Some(summary), Some((_, locked))) = (&summary, counts) {
+        write_summary(&options, &mut summary.borrow_mut(), skipped.get(), locked);
+    }
+
+    // A sample is only useful for estimates, so scale its results up to the full batch.
+    if let Some(sample) = options.sample {
+        info!(
+            "processed a {} sample of clients: {} rejected transactions (about {:.0} in the full \
+             batch)",
+            sample,
+            rejected,
+            rejected as f64 / sample.fraction()
+        );
+    }
+
+    if let (Some(digest_path), Some(key)) = (&options.digest_path, &digest_key) {
+        if let Err(e) = write_digest(&options, Path::new(digest_path), key, stdout_hash) {
+            error!("error writing digest: {:?}", e);
+            std::process::exit(-1);
+        }
+    }
+
+    match counts {
+        Some((clients, _)) => info!(
+            clients,
+            rejected, "processed {} clients with {} rejected transactions", clients, rejected
+        ),
+        None => info!(
+            rejected,
+            "processed the log with {} rejected transactions", rejected
+        ),
+    }
+}
+
+/// Exit with an error, removing the unfinished `--output` file first (as exiting skips dropping
+/// it).
+fn exit_discarding(output_file: &Option<AtomicFile>) -> ! {
+    if let Some(file) = output_file {
+        file.discard();
+    }
+    std::process::exit(-1)
+}
+
+/// Write the summary (to `--summary-file`, or stderr), counting the rows which were skipped.
+fn write_summary(options: &Options, summary: &mut Summary, skipped: u64, locked: usize) {
+    summary.skip_rows(skipped);
+    let result = match &options.summary_path {
+        Some(path) => File::create(path).and_then(|file| summary.write(file, locked)),
+        None => summary.write(io::stderr().lock(), locked),
+    };
+    if let Err(e) = result {
+        error!("error writing summary: {:?}", e);
         std::process::exit(-1);
     }
+}
 
-    std::process::exit(0);
+/// Apply the transaction log without exporting anything, only reporting rejected transactions
+/// (including amounts with more decimal places than the ledger keeps, and unknown types) and rows
+/// which can't be parsed, with their lines. Every row is checked unless `--strict` stops at the
+/// first problem. Exits with an error if the log can't be read, or any transaction was rejected
+/// (or row skipped).
+fn validate(options: Options) {
+    let paths = input_paths(&options);
+
+    let mut rejects = create_rejects_log(&options);
+    let mut rejected = 0;
+    let on_reject = |tx: &Transaction, e: &TransactionError| {
+        rejected += 1;
+        match rejects.as_mut() {
+            Some(rejects) => rejects.write(tx, e),
+            None => {
+                let at = tx
+                    .line
+                    .map_or_else(String::new, |line| format!("line {}: ", line));
+                warn!(
+                    client = tx.client_id,
+                    tx = tx.tx_id,
+                    line = tx.line,
+                    reason = e.reason(),
+                    "{}rejected transaction: {}",
+                    at,
+                    e
+                );
+                Ok(())
+            }
+        }
+    };
+
+    let skipped = Rc::new(Cell::new(0));
+    let progress = start_progress(&paths, &options);
+    let result = open_input_transactions(&paths, &options, Some(&skipped), progress.as_ref())
+        .and_then(|transactions| {
+            let engine = configured_engine(&options).with_exact_amounts(true);
+            process_transactions(engine, transactions, on_reject)
+        });
+    if let Some(progress) = &progress {
+        progress.borrow_mut().finish();
+    }
+    if let Some(Err(e)) = rejects.as_mut().map(RejectWriter::flush) {
+        error!("error writing rejects log: {:?}", e);
+        std::process::exit(-1);
+    }
+    match result {
+        Ok(client_states) => {
+            info!(
+                "{} clients, {} rejected transactions, {} skipped rows",
+                client_states.len(),
+                rejected,
+                skipped.get()
+            );
+            if rejected > 0 || skipped.get() > 0 {
+                std::process::exit(-1);
+            }
+        }
+        Err(e) => {
+            error!("error handling transaction data: {:?}", e);
+            std::process::exit(-1);
+        }
+    }
 }
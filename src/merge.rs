@@ -0,0 +1,181 @@
+/// Merging of several transaction logs into one, ordered by timestamp.
+///
+/// Transactions which arrive on separate feeds (e.g. deposits on one, disputes on another) each
+/// need a `timestamp` column: an RFC 3339 timestamp (e.g. `2022-03-01T12:00:00.250Z`), or a plain
+/// number such as a sequence number shared by every feed. Transactions are applied in timestamp
+/// order, with ties going to the feed listed first.
+///
+/// Each feed is expected to be mostly in order. A transaction is only passed on once every other
+/// feed has reached `lateness` past it, so a transaction arriving up to `lateness` behind the
+/// latest one on its own feed still takes its place in the merged order. One arriving any later
+/// than that can't be, and is an error.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::error::Error;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+
+use crate::history::parse_timestamp;
+use crate::input::TransactionStream;
+use crate::Transaction;
+
+/// A transaction waiting to be merged, ordered by timestamp, then feed, then arrival.
+struct Pending {
+    timestamp: u64,
+    source: usize,
+    arrival: u64,
+    tx: Transaction,
+}
+
+impl Pending {
+    fn key(&self) -> (u64, usize, u64) {
+        (self.timestamp, self.source, self.arrival)
+    }
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// A feed being merged.
+struct Source {
+    transactions: TransactionStream,
+    /// Latest timestamp read from the feed so far.
+    latest: Option<u64>,
+    done: bool,
+}
+
+/// Merge `sources` into a single stream in timestamp order (see the module docs). Errors reading
+/// any source are passed on as soon as they're read.
+pub fn merge_by_timestamp(sources: Vec<TransactionStream>, lateness: u64) -> TransactionStream {
+    let mut sources: Vec<Source> = sources
+        .into_iter()
+        .map(|transactions| Source {
+            transactions,
+            latest: None,
+            done: false,
+        })
+        .collect();
+    let mut pending: BinaryHeap<Reverse<Pending>> = BinaryHeap::new();
+    let mut arrivals = 0;
+    let mut merged_up_to = None;
+
+    Box::new(std::iter::from_fn(move || loop {
+        // The feed which is furthest behind decides what can be passed on.
+        let behind = sources
+            .iter()
+            .enumerate()
+            .filter(|(_, source)| !source.done)
+            .min_by_key(|(_, source)| source.latest)
+            .map(|(index, source)| (index, source.latest));
+
+        if let Some(Reverse(next)) = pending.peek() {
+            let ready = match behind {
+                None => true,
+                Some((_, latest)) => {
+                    latest.is_some_and(|latest| latest >= next.timestamp.saturating_add(lateness))
+                }
+            };
+            if ready {
+                let Reverse(next) = pending.pop()?;
+                merged_up_to = Some(next.timestamp);
+                return Some(Ok(next.tx));
+            }
+        }
+
+        let (index, _) = behind?;
+        let source = &mut sources[index];
+        let tx = match source.transactions.next() {
+            Some(Ok(tx)) => tx,
+            Some(Err(e)) => return Some(Err(e)),
+            None => {
+                source.done = true;
+                continue;
+            }
+        };
+
+        let timestamp = match tx.timestamp {
+            Some(timestamp) => timestamp,
+            None => return Some(Err(late_or_missing(index, &tx, "has no timestamp"))),
+        };
+        if merged_up_to.is_some_and(|merged| timestamp < merged) {
+            return Some(Err(late_or_missing(
+                index,
+                &tx,
+                "arrived later than the lateness allows",
+            )));
+        }
+        source.latest = source.latest.max(Some(timestamp));
+        arrivals += 1;
+        pending.push(Reverse(Pending {
+            timestamp,
+            source: index,
+            arrival: arrivals,
+            tx,
+        }));
+    }))
+}
+
+/// The error for a transaction which can't be merged.
+fn late_or_missing(source: usize, tx: &Transaction, problem: &str) -> Box<dyn Error> {
+    let line = tx
+        .line
+        .map_or(String::new(), |line| format!(" (line {})", line));
+
+    format!(
+        "transaction {} from input {}{} {}",
+        tx.tx_id,
+        source + 1,
+        line,
+        problem
+    )
+    .into()
+}
+
+/// A timestamp as written in an input.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTimestamp {
+    Number(u64),
+    Text(String),
+}
+
+/// Parse a timestamp column: a plain number, or an RFC 3339 timestamp (as milliseconds since the
+/// Unix epoch). Missing and empty timestamps are `None`.
+pub fn deserialize_timestamp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u64>, D::Error> {
+    match Option::<RawTimestamp>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(RawTimestamp::Number(number)) => Ok(Some(number)),
+        Some(RawTimestamp::Text(text)) => parse(text.trim())
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("invalid timestamp '{}'", text))),
+    }
+}
+
+/// A plain number, or an RFC 3339 timestamp as milliseconds since the Unix epoch.
+pub(crate) fn parse(s: &str) -> Option<u64> {
+    if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        parse_timestamp(s)
+    }
+}
@@ -0,0 +1,250 @@
+/// Prometheus metrics for an engine (see `Engine::with_metrics`).
+///
+/// An engine with metrics counts the transactions it's given by type, and its rejections by
+/// reason, and times each transaction it applies. Gauges (clients, locked accounts, held
+/// transactions, and the size of the disputable transaction store) are read from the engine when
+/// the metrics are written, in the Prometheus text format. Long-lived modes serve them over HTTP
+/// (`serve_metrics`, or `GET /metrics` with `serve-http`), and batch runs can write them at exit.
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::TransactionError;
+use crate::{Engine, TransactionType};
+
+/// Upper bounds (in seconds) of the buckets of the latency histogram.
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.000_001,
+    0.000_002_5,
+    0.000_005,
+    0.000_01,
+    0.000_025,
+    0.000_05,
+    0.000_1,
+    0.000_5,
+    0.001,
+    0.01,
+];
+
+/// Every transaction type, as it's labelled. Custom types (see `handler`) are counted together.
+pub(crate) const TYPES: [&str; 9] = [
+    "deposit",
+    "withdrawal",
+    "dispute",
+    "resolve",
+    "chargeback",
+    "convert",
+    "unlock",
+    "custom",
+    "unknown",
+];
+
+/// Index of a transaction type's label in `TYPES`.
+pub(crate) fn type_index(r#type: TransactionType) -> usize {
+    match r#type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Convert => 5,
+        TransactionType::Unlock => 6,
+        TransactionType::Custom(_) => 7,
+        TransactionType::Unknown => 8,
+    }
+}
+
+/// Counters and the latency histogram, kept up to date by the engine.
+#[derive(Debug, Default, Clone)]
+pub struct Metrics {
+    /// Transactions given to the engine, in the order of `TYPES`.
+    transactions: [u64; TYPES.len()],
+    /// Rejected transactions (including held transactions rejected once their account was
+    /// unlocked), by reason.
+    rejects: BTreeMap<&'static str, u64>,
+    /// Transactions in each bucket of `LATENCY_BUCKETS` (not cumulative), and any slower.
+    latency_buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    latency_sum: Duration,
+}
+
+impl Metrics {
+    /// Record a transaction the engine was given, how long it took, and its outcome.
+    pub(crate) fn record(
+        &mut self,
+        r#type: TransactionType,
+        elapsed: Duration,
+        result: &Result<(), TransactionError>,
+    ) {
+        self.transactions[type_index(r#type)] += 1;
+
+        let seconds = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket] += 1;
+        self.latency_sum += elapsed;
+
+        if let Err(e) = result {
+            self.reject(e);
+        }
+    }
+
+    /// Record a rejection which wasn't the outcome of a transaction the engine was just given.
+    pub(crate) fn reject(&mut self, e: &TransactionError) {
+        *self.rejects.entry(e.reason()).or_default() += 1;
+    }
+
+    /// Number of transactions of some type the engine has been given (of any custom type, for a
+    /// custom type).
+    pub fn transactions(&self, r#type: TransactionType) -> u64 {
+        self.transactions[type_index(r#type)]
+    }
+
+    /// Number of transactions rejected for some reason (see `TransactionError::reason`).
+    pub fn rejects(&self, reason: &str) -> u64 {
+        self.rejects.get(reason).copied().unwrap_or(0)
+    }
+
+    /// Write the metrics, and gauges read from `engine`, in the Prometheus text format.
+    pub fn write<W: Write>(&self, engine: &Engine, mut writer: W) -> io::Result<()> {
+        header(
+            &mut writer,
+            "transactions_total",
+            "counter",
+            "Transactions processed, by type.",
+        )?;
+        for (name, count) in TYPES.iter().zip(&self.transactions) {
+            writeln!(
+                writer,
+                "payment_engine_transactions_total{{type=\"{}\"}} {}",
+                name, count
+            )?;
+        }
+
+        header(
+            &mut writer,
+            "rejects_total",
+            "counter",
+            "Rejected transactions, by reason.",
+        )?;
+        for (reason, count) in &self.rejects {
+            writeln!(
+                writer,
+                "payment_engine_rejects_total{{reason=\"{}\"}} {}",
+                reason, count
+            )?;
+        }
+
+        let states = engine.client_states();
+        let locked = states.values().filter(|state| state.locked).count();
+        let gauges = [
+            ("clients", "Clients seen.", states.len()),
+            ("locked_accounts", "Locked client accounts.", locked),
+            (
+                "held_transactions",
+                "Transactions held for locked accounts.",
+                engine.queued_count(),
+            ),
+            (
+                "disputable_transactions",
+                "Transactions kept in case they're disputed (in memory, spilled, or in a ledger).",
+                engine.disputable_transactions.stored_count()?,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            header(&mut writer, name, "gauge", help)?;
+            writeln!(writer, "payment_engine_{} {}", name, value)?;
+        }
+
+        header(
+            &mut writer,
+            "apply_seconds",
+            "histogram",
+            "Time taken to apply each transaction.",
+        )?;
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&self.latency_buckets) {
+            cumulative += count;
+            writeln!(
+                writer,
+                "payment_engine_apply_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            )?;
+        }
+        cumulative += self.latency_buckets[LATENCY_BUCKETS.len()];
+        writeln!(
+            writer,
+            "payment_engine_apply_seconds_bucket{{le=\"+Inf\"}} {}",
+            cumulative
+        )?;
+        writeln!(
+            writer,
+            "payment_engine_apply_seconds_sum {}",
+            self.latency_sum.as_secs_f64()
+        )?;
+        writeln!(writer, "payment_engine_apply_seconds_count {}", cumulative)?;
+
+        Ok(())
+    }
+}
+
+fn header<W: Write>(writer: &mut W, name: &str, kind: &str, help: &str) -> io::Result<()> {
+    writeln!(writer, "# HELP payment_engine_{} {}", name, help)?;
+    writeln!(writer, "# TYPE payment_engine_{} {}", name, kind)
+}
+
+/// The engine's metrics in the Prometheus text format, or `None` if it isn't keeping any.
+pub fn render(engine: &Engine) -> Option<String> {
+    let mut text = Vec::new();
+    engine
+        .metrics()?
+        .write(engine, &mut text)
+        .expect("writing to memory can't fail");
+
+    Some(String::from_utf8(text).expect("metrics are UTF-8"))
+}
+
+/// Answer every HTTP request on `listener` with the engine's metrics, forever. For modes without
+/// an HTTP server of their own (e.g. `serve`), so only the request line and headers are read, and
+/// the path isn't checked.
+pub fn serve_metrics(listener: TcpListener, engine: &Mutex<Engine>) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        let reader = BufReader::new(stream.try_clone()?);
+        for line in reader.lines() {
+            if line?.is_empty() {
+                break;
+            }
+        }
+
+        let body = {
+            let engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+            render(&engine).unwrap_or_default()
+        };
+        let result = write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        if let Err(e) = result {
+            tracing::error!("error writing metrics: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Serve metrics on `listener` from a background thread (see `serve_metrics`).
+pub fn spawn_metrics_server(listener: TcpListener, engine: Arc<Mutex<Engine>>) {
+    thread::spawn(move || {
+        if let Err(e) = serve_metrics(listener, &engine) {
+            tracing::error!("metrics server failed: {}", e);
+        }
+    });
+}
@@ -0,0 +1,224 @@
+/// Monetary amounts.
+///
+/// All amounts handled by the engine are `Money`, so decimal place handling lives here rather than
+/// being sprinkled through transaction processing.
+///
+/// Amounts are read with `DECIMAL_PLACES` decimal places. An engine can be configured to round
+/// them (and fees, and converted amounts) to fewer places with some other rounding strategy (see
+/// `Precision`), for ledgers which e.g. require two decimal places rounded half up.
+use std::fmt;
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::str::FromStr;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// How many decimal places to handle for amounts.
+pub const DECIMAL_PLACES: u32 = 4;
+
+/// An amount of money with at most `DECIMAL_PLACES` decimal places.
+///
+/// Amounts are rounded when they're parsed (using "banker's rounding" rules), and always formatted
+/// with exactly `DECIMAL_PLACES` decimal places (e.g. `1.5000`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(Decimal);
+
+impl Money {
+    pub const ZERO: Money = Money(Decimal::ZERO);
+
+    /// The largest amount, and the largest balance in either direction, the engine handles
+    /// (10^20). Far beyond any real balance, but small enough that adding a few never overflows.
+    pub const MAX: Money = Money(Decimal::from_parts(0x6310_0000, 0x6bc7_5e2d, 0x5, false, 0));
+
+    /// An amount rounded to `DECIMAL_PLACES`.
+    pub fn new(amount: Decimal) -> Self {
+        Money(amount.round_dp(DECIMAL_PLACES))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    pub fn is_sign_negative(&self) -> bool {
+        self.0.is_sign_negative() && !self.0.is_zero()
+    }
+
+    /// Whether the amount is beyond `MAX` in either direction.
+    pub fn exceeds_max(&self) -> bool {
+        self.0.abs() > Money::MAX.0
+    }
+}
+
+/// How amounts are rounded to fewer decimal places.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// To the nearest, with midpoints rounded to the even neighbour (e.g. `0.125` to `0.12`).
+    #[default]
+    Bankers,
+    /// To the nearest, with midpoints rounded away from zero (e.g. `0.125` to `0.13`).
+    HalfUp,
+    /// Towards zero (e.g. `0.129` to `0.12`).
+    Truncate,
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bankers" => Ok(Rounding::Bankers),
+            "half-up" => Ok(Rounding::HalfUp),
+            "truncate" => Ok(Rounding::Truncate),
+            _ => Err(format!(
+                "unknown rounding '{}', expected bankers, half-up, or truncate",
+                s
+            )),
+        }
+    }
+}
+
+/// Decimal places amounts are rounded to, and how.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Precision {
+    decimal_places: u32,
+    rounding: Rounding,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Precision {
+            decimal_places: DECIMAL_PLACES,
+            rounding: Rounding::Bankers,
+        }
+    }
+}
+
+impl Precision {
+    /// Round to `decimal_places`, which can't be more than amounts are read with
+    /// (`DECIMAL_PLACES`).
+    pub fn new(decimal_places: u32, rounding: Rounding) -> Result<Self, String> {
+        if decimal_places > DECIMAL_PLACES {
+            return Err(format!(
+                "amounts can't have more than {} decimal places",
+                DECIMAL_PLACES
+            ));
+        }
+
+        Ok(Precision {
+            decimal_places,
+            rounding,
+        })
+    }
+
+    pub fn decimal_places(&self) -> u32 {
+        self.decimal_places
+    }
+
+    pub fn rounding(&self) -> Rounding {
+        self.rounding
+    }
+
+    /// An amount rounded to this precision.
+    pub fn round(&self, amount: Decimal) -> Money {
+        let strategy = match self.rounding {
+            Rounding::Bankers => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+        };
+
+        Money(amount.round_dp_with_strategy(self.decimal_places, strategy))
+    }
+}
+
+impl From<Decimal> for Money {
+    fn from(amount: Decimal) -> Self {
+        Money::new(amount)
+    }
+}
+
+impl From<Money> for Decimal {
+    fn from(amount: Money) -> Self {
+        amount.0
+    }
+}
+
+/// Allows comparison against plain decimals (e.g. `dec!(1.5)`), mostly for convenience in tests.
+impl PartialEq<Decimal> for Money {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.0 == *other
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.*}", DECIMAL_PLACES as usize, self.0)
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        Money(self.0 - other.0)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        self.0 -= other.0;
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        <Decimal as Deserialize>::deserialize(deserializer).map(Money::new)
+    }
+}
+
+/// Deserialize an amount which must be a string, for `#[serde(deserialize_with)]`. Amounts are
+/// otherwise parsed as floats from CSV first, which loses precision on large amounts.
+pub fn deserialize_exact<'de, D>(deserializer: D) -> Result<Money, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let amount = String::deserialize(deserializer)?;
+
+    Decimal::from_str(&amount)
+        .map(Money::new)
+        .map_err(|_| D::Error::custom(format!("invalid amount '{}'", amount)))
+}
+
+/// Like `deserialize_exact`, for optional amounts.
+pub fn deserialize_exact_option<'de, D>(deserializer: D) -> Result<Option<Money>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_exact(deserializer).map(Some)
+}
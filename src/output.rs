@@ -0,0 +1,835 @@
+/// Writing client account states, and the log of rejected transactions.
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use csv::{QuoteStyle, Terminator, WriterBuilder};
+use serde::Serialize;
+
+use crate::currency::{Balance, Currency};
+use crate::error::TransactionError;
+use crate::history::LedgerEntry;
+use crate::money::Money;
+use crate::store::DisputableTx;
+use crate::{ClientState, Transaction, TransactionType};
+
+/// CSV dialect used when exporting client account states. Defaults match the `csv` crate (comma
+/// delimited, quoting only where necessary, LF line endings).
+#[derive(Debug, Clone, Copy)]
+pub struct OutputDialect {
+    pub delimiter: u8,
+    pub quote_style: QuoteStyle,
+    pub line_ending: LineEnding,
+}
+
+impl Default for OutputDialect {
+    fn default() -> Self {
+        OutputDialect {
+            delimiter: b',',
+            quote_style: QuoteStyle::Necessary,
+            line_ending: LineEnding::Lf,
+        }
+    }
+}
+
+impl OutputDialect {
+    /// A `csv::WriterBuilder` configured for this dialect.
+    pub fn writer_builder(&self) -> WriterBuilder {
+        let mut builder = WriterBuilder::new();
+        builder
+            .delimiter(self.delimiter)
+            .quote_style(self.quote_style)
+            .terminator(match self.line_ending {
+                LineEnding::Lf => Terminator::Any(b'\n'),
+                LineEnding::CrLf => Terminator::CRLF,
+            });
+
+        builder
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    CrLf,
+}
+
+impl FromStr for LineEnding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lf" => Ok(LineEnding::Lf),
+            "crlf" => Ok(LineEnding::CrLf),
+            _ => Err(format!("unknown line ending '{}', expected lf or crlf", s)),
+        }
+    }
+}
+
+/// Parse a quoting policy by name.
+pub fn parse_quote_style(s: &str) -> Result<QuoteStyle, String> {
+    match s {
+        "always" => Ok(QuoteStyle::Always),
+        "necessary" => Ok(QuoteStyle::Necessary),
+        "non-numeric" => Ok(QuoteStyle::NonNumeric),
+        "never" => Ok(QuoteStyle::Never),
+        _ => Err(format!(
+            "unknown quote style '{}', expected always, necessary, non-numeric, or never",
+            s
+        )),
+    }
+}
+
+/// Parse a single byte delimiter. `tab` and `\t` are accepted for tab separated output.
+pub fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(format!(
+            "invalid delimiter '{}', expected a single ASCII character",
+            s
+        )),
+    }
+}
+
+/// Which of `shards` output files the state for some client belongs in.
+pub fn shard_for_client(client_id: u16, shards: usize) -> usize {
+    client_hash(client_id) as usize % shards
+}
+
+/// 32-bit FNV-1a over the client ID. Used wherever clients are partitioned, since it's stable
+/// between runs and platforms (unlike `std`'s randomly seeded hasher).
+pub fn client_hash(client_id: u16) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in client_id.to_le_bytes().iter() {
+        hash ^= u32::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+
+    hash
+}
+
+/// A row in the shard manifest.
+#[derive(Debug, Serialize)]
+struct ManifestRecord {
+    shard: usize,
+    path: String,
+    rows: usize,
+}
+
+/// Somewhere client account states can be written to, one at a time. States can be written as
+/// soon as they're final, so the full set of states never needs to be held in memory at once.
+pub trait BalanceSink {
+    /// Write the state of a single client.
+    fn write(&mut self, state: &ClientState) -> Result<(), Box<dyn Error>>;
+
+    /// Called once all states have been written.
+    fn finish(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+/// Writes every client state to two sinks, e.g. the balance export and something which needs to
+/// see every balance as it's written.
+pub struct Tee<A: BalanceSink, B: BalanceSink>(pub A, pub B);
+
+impl<A: BalanceSink, B: BalanceSink> BalanceSink for Tee<A, B> {
+    fn write(&mut self, state: &ClientState) -> Result<(), Box<dyn Error>> {
+        self.0.write(state)?;
+        self.1.write(state)
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.0.finish()?;
+        self.1.finish()
+    }
+}
+
+impl<S: BalanceSink + ?Sized> BalanceSink for Box<S> {
+    fn write(&mut self, state: &ClientState) -> Result<(), Box<dyn Error>> {
+        (**self).write(state)
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        (**self).finish()
+    }
+}
+
+impl<S: BalanceSink + ?Sized> BalanceSink for &mut S {
+    fn write(&mut self, state: &ClientState) -> Result<(), Box<dyn Error>> {
+        (**self).write(state)
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        (**self).finish()
+    }
+}
+
+/// Order of client states in the balance export. Ties are always broken by client ID so output is
+/// deterministic.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    /// Ascending client ID.
+    #[default]
+    Client,
+    /// Descending total funds (largest accounts first).
+    Total,
+    /// Locked accounts first.
+    Locked,
+}
+
+impl SortBy {
+    /// Sort client states into this order.
+    pub fn sort(&self, states: &mut [&ClientState]) {
+        match self {
+            SortBy::Client => states.sort_by_key(|state| state.client_id),
+            SortBy::Total => states.sort_by(|a, b| {
+                b.total
+                    .cmp(&a.total)
+                    .then_with(|| a.client_id.cmp(&b.client_id))
+            }),
+            SortBy::Locked => {
+                states.sort_by_key(|state| (std::cmp::Reverse(state.locked), state.client_id))
+            }
+        }
+    }
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(SortBy::Client),
+            "total" => Ok(SortBy::Total),
+            "locked" => Ok(SortBy::Locked),
+            _ => Err(format!(
+                "unknown sort order '{}', expected client, total, or locked",
+                s
+            )),
+        }
+    }
+}
+
+/// Writes client account states as a single CSV stream.
+pub struct BalanceWriter<W: io::Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: io::Write> BalanceWriter<W> {
+    pub fn new(writer: W, dialect: &OutputDialect) -> Self {
+        BalanceWriter {
+            writer: dialect.writer_builder().from_writer(writer),
+        }
+    }
+}
+
+impl<W: io::Write> BalanceSink for BalanceWriter<W> {
+    fn write(&mut self, state: &ClientState) -> Result<(), Box<dyn Error>> {
+        self.writer.serialize(state)?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// A row of a balance diff: a client's funds (in one currency) now, and how much each changed.
+#[derive(Debug, Serialize)]
+struct DiffRecord {
+    client: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    available: Money,
+    held: Money,
+    total: Money,
+    locked: bool,
+    version: u64,
+    available_delta: Money,
+    held_delta: Money,
+    total_delta: Money,
+}
+
+/// Writes only the client states which differ from a previous balance export (read by
+/// `read_balances`), as CSV with the change in each of the funds. A client's funds or lock changing
+/// counts, as does a client which is new. Rows have the client's current version (like the balance
+/// export), but versions aren't compared, so a client whose transactions cancel out isn't written. Clients (or currencies) in the previous export which
+/// aren't written are diffed when the diff finishes as having no funds, so every row's funds less
+/// its deltas are the funds previously exported.
+pub struct DiffWriter<W: io::Write> {
+    writer: csv::Writer<W>,
+    previous: HashMap<u16, ClientState>,
+    /// Whether each client written so far is locked, and its version.
+    clients: HashMap<u16, (bool, u64)>,
+    /// Clients and currencies written so far (changed or not).
+    written: HashSet<(u16, Currency)>,
+    /// Whether rows have a currency column, once the first state has been written.
+    by_currency: Option<bool>,
+    rows: usize,
+}
+
+impl<W: io::Write> DiffWriter<W> {
+    pub fn new(writer: W, dialect: &OutputDialect, previous: HashMap<u16, ClientState>) -> Self {
+        DiffWriter {
+            writer: dialect.writer_builder().from_writer(writer),
+            previous,
+            clients: HashMap::new(),
+            written: HashSet::new(),
+            by_currency: None,
+            rows: 0,
+        }
+    }
+
+    /// Write a row if a client's funds in some currency (or its lock) differ from the previous
+    /// export's.
+    fn diff(
+        &mut self,
+        client_id: u16,
+        currency: Currency,
+        balance: Balance,
+        (locked, version): (bool, u64),
+    ) -> Result<(), Box<dyn Error>> {
+        let (previous, previously_locked) = match self.previous.get(&client_id) {
+            Some(state) => (state.balance(currency), state.locked),
+            None => (Balance::new(false), false),
+        };
+        let funds = |balance: &Balance| (balance.available, balance.held, balance.total);
+        if self.previous.contains_key(&client_id)
+            && funds(&balance) == funds(&previous)
+            && locked == previously_locked
+        {
+            return Ok(());
+        }
+
+        self.writer.serialize(DiffRecord {
+            client: client_id,
+            currency: self.by_currency.unwrap_or_default().then_some(currency),
+            available: balance.available,
+            held: balance.held,
+            total: balance.total,
+            locked,
+            version,
+            available_delta: balance.available - previous.available,
+            held_delta: balance.held - previous.held,
+            total_delta: balance.total - previous.total,
+        })?;
+        self.rows += 1;
+
+        Ok(())
+    }
+}
+
+impl<W: io::Write> BalanceSink for DiffWriter<W> {
+    fn write(&mut self, state: &ClientState) -> Result<(), Box<dyn Error>> {
+        self.by_currency.get_or_insert(state.currency.is_some());
+        let currency = state.currency.unwrap_or(Currency::IMPLICIT);
+        self.clients
+            .insert(state.client_id, (state.locked, state.version));
+        self.written.insert((state.client_id, currency));
+        self.diff(
+            state.client_id,
+            currency,
+            state.balance(currency),
+            (state.locked, state.version),
+        )
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        let previous = &self.previous;
+        let by_currency = *self
+            .by_currency
+            .get_or_insert_with(|| previous.values().any(|state| !state.currencies.is_empty()));
+        let mut gone: Vec<(u16, Currency)> = previous
+            .values()
+            .flat_map(|state| match by_currency {
+                true => state.currency_rows(),
+                false => vec![ClientState::new(state.client_id)],
+            })
+            .map(|row| (row.client_id, row.currency.unwrap_or(Currency::IMPLICIT)))
+            .filter(|row| !self.written.contains(row))
+            .collect();
+        gone.sort_unstable();
+        for (client_id, currency) in gone {
+            // Clients which are no longer exported at all aren't locked either, and have no version.
+            let client = self.clients.get(&client_id).copied().unwrap_or_default();
+            self.diff(client_id, currency, Balance::new(false), client)?;
+        }
+
+        // The header is only written with the first row, but an empty diff should still have one.
+        if self.rows == 0 {
+            let mut header = vec!["client"];
+            if by_currency {
+                header.push("currency");
+            }
+            header.extend([
+                "available",
+                "held",
+                "total",
+                "locked",
+                "version",
+                "available_delta",
+                "held_delta",
+                "total_delta",
+            ]);
+            self.writer.write_record(&header)?;
+        }
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Format of the balance export.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// CSV in the configured `OutputDialect`.
+    #[default]
+    Csv,
+    /// A single JSON array of client states.
+    Json,
+    /// Newline delimited JSON, one client state per line.
+    Ndjson,
+    /// Parquet, with the same columns as CSV. Only available with the `parquet` feature.
+    Parquet,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "parquet" => Ok(OutputFormat::Parquet),
+            _ => Err(format!(
+                "unknown output format '{}', expected csv, json, ndjson, or parquet",
+                s
+            )),
+        }
+    }
+}
+
+/// Writes client account states as JSON objects with the same fields as the CSV export. Amounts are
+/// written as strings so no precision is lost by JSON parsers which use floating point numbers.
+///
+/// States are written as they arrive, so the JSON array form is still streamed rather than being
+/// built in memory.
+pub struct JsonBalanceWriter<W: io::Write> {
+    writer: W,
+    ndjson: bool,
+    rows: usize,
+}
+
+impl<W: io::Write> JsonBalanceWriter<W> {
+    /// Write states as elements of a single JSON array.
+    pub fn array(writer: W) -> Self {
+        JsonBalanceWriter {
+            writer,
+            ndjson: false,
+            rows: 0,
+        }
+    }
+
+    /// Write states as newline delimited JSON.
+    pub fn ndjson(writer: W) -> Self {
+        JsonBalanceWriter {
+            writer,
+            ndjson: true,
+            rows: 0,
+        }
+    }
+}
+
+impl<W: io::Write> BalanceSink for JsonBalanceWriter<W> {
+    fn write(&mut self, state: &ClientState) -> Result<(), Box<dyn Error>> {
+        if !self.ndjson {
+            self.writer
+                .write_all(if self.rows == 0 { b"[\n" } else { b",\n" })?;
+        }
+        serde_json::to_writer(&mut self.writer, state)?;
+        if self.ndjson {
+            self.writer.write_all(b"\n")?;
+        }
+        self.rows += 1;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        if !self.ndjson {
+            self.writer
+                .write_all(if self.rows == 0 { b"[]\n" } else { b"\n]\n" })?;
+        }
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Writes client account states into several files in some directory, partitioned by client ID
+/// (see `shard_for_client`). Shards are named `balances-NNNN.csv`, and when finished a
+/// `manifest.csv` lists every shard along with the number of rows it contains. Empty shards are
+/// still written (with a header) so the set of files is predictable.
+pub struct ShardedBalanceWriter {
+    dir: PathBuf,
+    dialect: OutputDialect,
+    writers: Vec<csv::Writer<File>>,
+    rows: Vec<usize>,
+    /// Columns of every shard, fixed by the first state written.
+    header: Option<Vec<&'static str>>,
+}
+
+impl ShardedBalanceWriter {
+    pub fn create(
+        dir: &Path,
+        dialect: &OutputDialect,
+        shards: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        std::fs::create_dir_all(dir)?;
+
+        let mut writers = Vec::with_capacity(shards);
+        for shard in 0..shards {
+            // Headers are written explicitly, since `serialize` would only write them for shards
+            // which have at least one row.
+            let writer = dialect
+                .writer_builder()
+                .has_headers(false)
+                .from_path(dir.join(shard_file_name(shard)))?;
+            writers.push(writer);
+        }
+
+        Ok(ShardedBalanceWriter {
+            dir: dir.to_path_buf(),
+            dialect: *dialect,
+            writers,
+            rows: vec![0; shards],
+            header: None,
+        })
+    }
+}
+
+impl BalanceSink for ShardedBalanceWriter {
+    fn write(&mut self, state: &ClientState) -> Result<(), Box<dyn Error>> {
+        let shard = shard_for_client(state.client_id, self.writers.len());
+        let header = self.header.get_or_insert_with(|| header(state));
+        if self.rows[shard] == 0 {
+            self.writers[shard].write_record(header.iter())?;
+        }
+        self.writers[shard].serialize(state)?;
+        self.rows[shard] += 1;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut manifest = self
+            .dialect
+            .writer_builder()
+            .from_path(self.dir.join("manifest.csv"))?;
+        for (shard, writer) in self.writers.iter_mut().enumerate() {
+            if self.rows[shard] == 0 {
+                let header = match &self.header {
+                    Some(header) => header.clone(),
+                    None => header(&ClientState::default()),
+                };
+                writer.write_record(header)?;
+            }
+            writer.flush()?;
+            manifest.serialize(ManifestRecord {
+                shard,
+                path: shard_file_name(shard),
+                rows: self.rows[shard],
+            })?;
+        }
+        manifest.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Columns of a balance export starting with `state`. Currencies, fees collected, and last activity
+/// are only included if the state has them.
+pub(crate) fn header(state: &ClientState) -> Vec<&'static str> {
+    let mut header = vec!["client"];
+    if state.currency.is_some() {
+        header.push("currency");
+    }
+    header.extend(["available", "held", "total", "locked", "version"]);
+    if state.fees_collected.is_some() {
+        header.push("fees_collected");
+    }
+    if state.last_activity.is_some() {
+        header.push("last_activity");
+    }
+
+    header
+}
+
+/// Paths of every file written by `ShardedBalanceWriter`, i.e. each shard followed by the manifest.
+pub fn shard_paths(dir: &Path, shards: usize) -> Vec<PathBuf> {
+    (0..shards)
+        .map(|shard| dir.join(shard_file_name(shard)))
+        .chain(std::iter::once(dir.join("manifest.csv")))
+        .collect()
+}
+
+fn shard_file_name(shard: usize) -> String {
+    format!("balances-{:04}.csv", shard)
+}
+
+/// A file written to a temporary file next to `path`, and only renamed to `path` once it's complete
+/// (see `commit`), so downstream jobs never pick up a file from a run which failed partway. Each
+/// temporary file is unique (`.<name>.<pid>-<n>.partial`), so concurrent writers don't interfere,
+/// and it's removed if the file is dropped without being committed (e.g. on any error).
+#[derive(Debug)]
+pub struct AtomicFile {
+    file: File,
+    temp_path: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a file", path.display()),
+            )
+        })?;
+        loop {
+            let mut temp_name = OsString::from(".");
+            temp_name.push(name);
+            temp_name.push(format!(
+                ".{}-{}.partial",
+                std::process::id(),
+                CREATED.fetch_add(1, Ordering::Relaxed)
+            ));
+            let temp_path = path.with_file_name(temp_name);
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp_path)
+            {
+                Ok(file) => {
+                    return Ok(AtomicFile {
+                        file,
+                        temp_path,
+                        path: path.to_path_buf(),
+                        committed: false,
+                    })
+                }
+                // Left behind by a process which was killed, and had the same ID.
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Where the file is written until it's committed.
+    pub fn temp_path(&self) -> &Path {
+        &self.temp_path
+    }
+
+    /// Another handle to the file being written, e.g. for a writer which has to own one.
+    pub fn try_clone(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// Sync the file to disk and rename it into place, then sync the directory so the rename
+    /// survives a crash too.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.temp_path, &self.path)?;
+        self.committed = true;
+        sync_parent(&self.path)
+    }
+
+    /// Remove the temporary file, for callers about to exit without dropping it.
+    pub fn discard(&self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+impl io::Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        self.discard();
+    }
+}
+
+/// Sync the directory `path` is in, so entries renamed into it are durable.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened (and don't need syncing) elsewhere.
+#[cfg(not(unix))]
+fn sync_parent(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// A row in the rejected transaction log. The original transaction is reproduced as it was read,
+/// followed by the reason it was rejected.
+#[derive(Debug, Serialize)]
+struct RejectRecord {
+    r#type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<Money>,
+    reason: &'static str,
+}
+
+/// Writes every transaction rejected by the engine as CSV, so that output can be reconciled with
+/// upstream systems.
+pub struct RejectWriter<W: io::Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: io::Write> RejectWriter<W> {
+    pub fn new(writer: W, dialect: &OutputDialect) -> Self {
+        RejectWriter {
+            writer: dialect.writer_builder().from_writer(writer),
+        }
+    }
+
+    /// Record a rejected transaction along with the reason it was rejected.
+    pub fn write(&mut self, tx: &Transaction, e: &TransactionError) -> Result<(), Box<dyn Error>> {
+        self.writer.serialize(RejectRecord {
+            r#type: tx.r#type,
+            client: tx.client_id,
+            tx: tx.tx_id,
+            amount: tx.amount,
+            reason: e.reason(),
+        })?;
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A row of a client's ledger: a transaction applied to the client, and the client's funds (in the
+/// transaction's currency) and version once it was applied.
+#[derive(Debug, Serialize)]
+struct LedgerRecord {
+    version: u64,
+    r#type: TransactionType,
+    tx: u32,
+    amount: Option<Money>,
+    available: Money,
+    held: Money,
+    total: Money,
+    locked: bool,
+}
+
+/// Write a client's ledger (see `Engine::client_history`) as CSV, oldest transaction first.
+pub fn write_ledger<'a, W, I>(
+    writer: W,
+    entries: I,
+    dialect: &OutputDialect,
+) -> Result<(), Box<dyn Error>>
+where
+    W: io::Write,
+    I: IntoIterator<Item = LedgerEntry<'a>>,
+{
+    let mut writer = dialect.writer_builder().from_writer(writer);
+    let mut rows = 0;
+    for LedgerEntry { transaction, state } in entries {
+        let balance = state.balance(transaction.currency);
+        writer.serialize(LedgerRecord {
+            version: state.version,
+            r#type: transaction.r#type,
+            tx: transaction.tx_id,
+            amount: transaction.amount,
+            available: balance.available,
+            held: balance.held,
+            total: balance.total,
+            locked: state.locked,
+        })?;
+        rows += 1;
+    }
+    // The header is only written with the first row, but an empty ledger should still have one.
+    if rows == 0 {
+        writer.write_record([
+            "version",
+            "type",
+            "tx",
+            "amount",
+            "available",
+            "held",
+            "total",
+            "locked",
+        ])?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// A transaction under dispute.
+#[derive(Debug, Serialize)]
+struct OpenDisputeRecord {
+    client: u16,
+    tx: u32,
+    r#type: TransactionType,
+    amount: Money,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+}
+
+/// Write the transactions under dispute (see `Engine::open_disputes`) as CSV. Currencies are only
+/// written if some disputed transaction isn't in the implicit currency.
+pub fn write_open_disputes<W: io::Write>(
+    writer: W,
+    disputes: &[(u32, DisputableTx)],
+) -> Result<(), Box<dyn Error>> {
+    let by_currency = disputes.iter().any(|(_, tx)| !tx.currency.is_implicit());
+    let mut writer = csv::Writer::from_writer(writer);
+    for &(tx_id, tx) in disputes {
+        writer.serialize(OpenDisputeRecord {
+            client: tx.client_id,
+            tx: tx_id,
+            r#type: tx.r#type(),
+            amount: tx.amount,
+            currency: by_currency.then_some(tx.currency),
+        })?;
+    }
+    // The header is only written with the first row, but an empty report should still have one.
+    if disputes.is_empty() {
+        writer.write_record(["client", "tx", "type", "amount"])?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
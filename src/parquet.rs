@@ -0,0 +1,320 @@
+/// Parquet transaction logs and balance exports (with the `parquet` feature), for data lakes which
+/// export and load Parquet rather than CSV.
+///
+/// A transaction log has a column for each transaction field, named as in the CSV header (`type`,
+/// `client`, `tx`, `amount`, and optionally `terminal`, `currency`, `to_currency`, and
+/// `timestamp`). Columns may be of any type Arrow can cast to a string (e.g. amounts as decimals,
+/// floats, or strings), and each row is then parsed like a CSV row, so amounts are rounded and
+/// fields are checked just the same. Errors give the row number in place of a line. Parquet keeps
+/// its metadata at the end of the file, so a log is read into memory before its rows are decoded,
+/// a batch at a time.
+///
+/// Balances are written with the same columns as the CSV export, with amounts as decimals with
+/// `DECIMAL_PLACES` decimal places. The export is encoded in memory (it has at most a row per
+/// client and currency) and written out when it's finished.
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+
+use ::parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use ::parquet::arrow::ArrowWriter;
+use arrow_array::builder::{
+    BooleanBuilder, Decimal128Builder, StringBuilder, UInt16Builder, UInt64Builder,
+};
+use arrow_array::{Array, ArrayRef, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema};
+use rust_decimal::Decimal;
+
+use crate::error::ParseError;
+use crate::input::TransactionStream;
+use crate::money::{Money, DECIMAL_PLACES};
+use crate::output::{header, BalanceSink};
+use crate::{ClientState, Transaction};
+
+/// Rows decoded (or encoded) at a time.
+const BATCH_SIZE: usize = 8192;
+
+/// Digits in exported amounts, the most a 128-bit decimal holds.
+const AMOUNT_PRECISION: u8 = 38;
+
+/// Transactions read from a Parquet transaction log, along with their row numbers.
+pub fn parquet_transactions<R>(mut reader: R) -> TransactionStream
+where
+    R: io::Read,
+{
+    let mut bytes = Vec::new();
+    let batches = match reader
+        .read_to_end(&mut bytes)
+        .map_err(Box::from)
+        .and_then(|_| {
+            ParquetRecordBatchReaderBuilder::try_new(bytes::Bytes::from(bytes))?
+                .with_batch_size(BATCH_SIZE)
+                .build()
+                .map_err(Box::from)
+        }) {
+        Ok(batches) => batches,
+        Err(e) => return Box::new(std::iter::once(Err(e))),
+    };
+
+    Box::new(Rows {
+        batches,
+        batch: None,
+        next: 0,
+        row: 0,
+    })
+}
+
+/// Rows of a Parquet log, parsed into transactions as they're reached.
+struct Rows {
+    batches: ParquetRecordBatchReader,
+    /// The batch being read, with its column names and every column cast to strings.
+    batch: Option<(csv::StringRecord, Vec<StringArray>)>,
+    /// Index of the next row in the batch.
+    next: usize,
+    /// Rows read so far, across every batch.
+    row: u64,
+}
+
+impl Rows {
+    /// Move on to the next batch, returning whether there is one.
+    fn next_batch(&mut self) -> Result<bool, Box<dyn Error>> {
+        let batch = match self.batches.next() {
+            Some(batch) => batch?,
+            None => return Ok(false),
+        };
+        let schema = batch.schema();
+        let names = schema.fields().iter().map(|field| field.name().as_str());
+        let columns = (batch.columns().iter())
+            .map(|column| {
+                let column = arrow_cast::cast(column, &DataType::Utf8)?;
+                Ok(column
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+                    .expect("columns are cast to strings")
+                    .clone())
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        self.batch = Some((names.collect(), columns));
+        self.next = 0;
+
+        Ok(true)
+    }
+}
+
+impl Iterator for Rows {
+    type Item = Result<Transaction, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match &self.batch {
+                Some((_, columns)) if columns.first().map_or(0, Array::len) > self.next => break,
+                _ => match self.next_batch() {
+                    Ok(true) => continue,
+                    Ok(false) => return None,
+                    Err(e) => return Some(Err(e)),
+                },
+            }
+        }
+        let (headers, columns) = self.batch.as_ref().expect("a batch with rows left");
+        let index = self.next;
+        self.next += 1;
+        self.row += 1;
+
+        // Nulls are empty fields, just as in CSV.
+        let record: csv::StringRecord = (columns.iter())
+            .map(|column| match column.is_null(index) {
+                true => "",
+                false => column.value(index),
+            })
+            .collect();
+        let result = record
+            .deserialize::<Transaction>(Some(headers))
+            .map(|tx| Transaction {
+                line: Some(self.row),
+                ..tx
+            })
+            .map_err(|e| -> Box<dyn Error> {
+                let message = match e.kind() {
+                    csv::ErrorKind::Deserialize { err, .. } => err.to_string(),
+                    _ => e.to_string(),
+                };
+                Box::new(ParseError {
+                    path: None,
+                    line: Some(self.row),
+                    byte: None,
+                    record: record.iter().collect::<Vec<_>>().join(","),
+                    message,
+                })
+            });
+
+        Some(result)
+    }
+}
+
+/// Writes client account states as a Parquet file, with the columns of the CSV export (fixed by
+/// the first state written).
+pub struct ParquetBalanceWriter<W: io::Write> {
+    writer: W,
+    columns: Option<Columns>,
+    parquet: Option<ArrowWriter<Vec<u8>>>,
+}
+
+impl<W: io::Write> ParquetBalanceWriter<W> {
+    pub fn new(writer: W) -> Self {
+        ParquetBalanceWriter {
+            writer,
+            columns: None,
+            parquet: None,
+        }
+    }
+
+    /// Encode the rows buffered so far.
+    fn flush_rows(&mut self) -> Result<(), Box<dyn Error>> {
+        let columns = self
+            .columns
+            .as_mut()
+            .expect("columns are set by the first row");
+        let batch = columns.finish()?;
+        let parquet = match &mut self.parquet {
+            Some(parquet) => parquet,
+            None => self
+                .parquet
+                .insert(ArrowWriter::try_new(Vec::new(), batch.schema(), None)?),
+        };
+        parquet.write(&batch)?;
+
+        Ok(())
+    }
+}
+
+impl<W: io::Write> BalanceSink for ParquetBalanceWriter<W> {
+    fn write(&mut self, state: &ClientState) -> Result<(), Box<dyn Error>> {
+        let columns = self
+            .columns
+            .get_or_insert_with(|| Columns::new(header(state)));
+        columns.append(state);
+        if columns.rows == BATCH_SIZE {
+            self.flush_rows()?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        // An export without any clients still has the columns a client would have.
+        let columns = self
+            .columns
+            .get_or_insert_with(|| Columns::new(header(&ClientState::default())));
+        if columns.rows > 0 || self.parquet.is_none() {
+            self.flush_rows()?;
+        }
+        if let Some(parquet) = self.parquet.take() {
+            self.writer.write_all(&parquet.into_inner()?)?;
+        }
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Columns of the balance export, built a batch at a time.
+struct Columns {
+    header: Vec<&'static str>,
+    rows: usize,
+    client: UInt16Builder,
+    currency: StringBuilder,
+    available: Decimal128Builder,
+    held: Decimal128Builder,
+    total: Decimal128Builder,
+    locked: BooleanBuilder,
+    version: UInt64Builder,
+    fees_collected: Decimal128Builder,
+    last_activity: UInt64Builder,
+}
+
+impl Columns {
+    fn new(header: Vec<&'static str>) -> Self {
+        Columns {
+            header,
+            rows: 0,
+            client: UInt16Builder::new(),
+            currency: StringBuilder::new(),
+            available: Decimal128Builder::new(),
+            held: Decimal128Builder::new(),
+            total: Decimal128Builder::new(),
+            locked: BooleanBuilder::new(),
+            version: UInt64Builder::new(),
+            fees_collected: Decimal128Builder::new(),
+            last_activity: UInt64Builder::new(),
+        }
+    }
+
+    fn append(&mut self, state: &ClientState) {
+        self.client.append_value(state.client_id);
+        self.currency
+            .append_option(state.currency.map(|currency| currency.to_string()));
+        self.available.append_value(mantissa(state.available));
+        self.held.append_value(mantissa(state.held));
+        self.total.append_value(mantissa(state.total));
+        self.locked.append_value(state.locked);
+        self.version.append_value(state.version);
+        self.fees_collected
+            .append_option(state.fees_collected.map(mantissa));
+        self.last_activity.append_option(state.last_activity);
+        self.rows += 1;
+    }
+
+    /// The rows appended since the last batch, as a batch with the export's columns.
+    fn finish(&mut self) -> Result<RecordBatch, Box<dyn Error>> {
+        let amount = DataType::Decimal128(AMOUNT_PRECISION, DECIMAL_PLACES as i8);
+        let mut fields = Vec::with_capacity(self.header.len());
+        let mut arrays: Vec<ArrayRef> = Vec::with_capacity(self.header.len());
+        for &name in &self.header {
+            let (data_type, nullable, array): (_, _, ArrayRef) = match name {
+                "client" => (DataType::UInt16, false, Arc::new(self.client.finish())),
+                "currency" => (DataType::Utf8, false, Arc::new(self.currency.finish())),
+                "available" => (
+                    amount.clone(),
+                    false,
+                    Arc::new(finish(&mut self.available)?),
+                ),
+                "held" => (amount.clone(), false, Arc::new(finish(&mut self.held)?)),
+                "total" => (amount.clone(), false, Arc::new(finish(&mut self.total)?)),
+                "locked" => (DataType::Boolean, false, Arc::new(self.locked.finish())),
+                "version" => (DataType::UInt64, false, Arc::new(self.version.finish())),
+                "fees_collected" => (
+                    amount.clone(),
+                    true,
+                    Arc::new(finish(&mut self.fees_collected)?),
+                ),
+                "last_activity" => (
+                    DataType::UInt64,
+                    true,
+                    Arc::new(self.last_activity.finish()),
+                ),
+                _ => unreachable!("{} isn't a balance column", name),
+            };
+            fields.push(Field::new(name, data_type, nullable));
+            arrays.push(array);
+        }
+        // Columns which aren't exported are still built, so they're dropped here.
+        *self = Columns::new(std::mem::take(&mut self.header));
+
+        Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)?)
+    }
+}
+
+/// The amounts appended to a builder, as decimals with `DECIMAL_PLACES` decimal places.
+fn finish(builder: &mut Decimal128Builder) -> Result<arrow_array::Decimal128Array, Box<dyn Error>> {
+    Ok(builder
+        .finish()
+        .with_precision_and_scale(AMOUNT_PRECISION, DECIMAL_PLACES as i8)?)
+}
+
+/// An amount as an integer number of units of its last decimal place.
+fn mantissa(amount: Money) -> i128 {
+    let mut amount = Decimal::from(amount);
+    amount.rescale(DECIMAL_PLACES);
+
+    amount.mantissa()
+}
@@ -0,0 +1,259 @@
+/// Reports of how a policy configuration handles a canonical set of scenarios, for sign-off.
+///
+/// Each scenario is a short transaction log exercising one area of policy (disputes, locked
+/// accounts, duplicate IDs, ...). A report runs every scenario on a fresh engine with the
+/// configuration being signed off, and lists what happened to each transaction (applied, held for
+/// a locked account, rejected and why, or stopped processing), followed by each client's final
+/// state. Reports only depend on the configuration and the build (whose version they record), so
+/// a configuration change can be reviewed by diffing its report against the current one.
+use std::error::Error;
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::input::InputFormat;
+use crate::{read_transactions, ClientState, Engine, Transaction, TransactionType};
+
+/// A transaction log exercising one area of policy.
+pub struct Scenario {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// The log, as CSV.
+    pub log: &'static str,
+}
+
+/// Every scenario a report covers, in the order they're reported.
+pub const SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "deposits_and_withdrawals",
+        description: "withdrawals up to and beyond the available funds",
+        log: "type, client, tx, amount
+              deposit, 1, 1, 10.0
+              withdrawal, 1, 2, 4.0
+              withdrawal, 1, 3, 7.0
+              withdrawal, 2, 4, 1.0",
+    },
+    Scenario {
+        name: "amounts",
+        description: "missing, negative, zero, and over-precise amounts",
+        log: "type, client, tx, amount
+              deposit, 1, 1,
+              deposit, 1, 2, -5.0
+              deposit, 1, 3, 0.00001
+              deposit, 1, 4, 1.23456",
+    },
+    Scenario {
+        name: "dispute_resolved",
+        description: "a deposit disputed and then resolved",
+        log: "type, client, tx, amount
+              deposit, 1, 1, 10.0
+              dispute, 1, 1,
+              withdrawal, 1, 2, 5.0
+              resolve, 1, 1,
+              withdrawal, 1, 3, 5.0",
+    },
+    Scenario {
+        name: "dispute_charged_back",
+        description: "a deposit charged back, and later activity on the locked account",
+        log: "type, client, tx, amount
+              deposit, 1, 1, 10.0
+              deposit, 1, 2, 5.0
+              dispute, 1, 1,
+              chargeback, 1, 1,
+              deposit, 1, 3, 1.0
+              withdrawal, 1, 4, 1.0
+              dispute, 1, 2,",
+    },
+    Scenario {
+        name: "spent_deposit_charged_back",
+        description: "a deposit charged back after being withdrawn",
+        log: "type, client, tx, amount
+              deposit, 1, 1, 10.0
+              withdrawal, 1, 2, 8.0
+              dispute, 1, 1,
+              chargeback, 1, 1",
+    },
+    Scenario {
+        name: "withdrawal_disputed",
+        description: "withdrawals disputed, then resolved or charged back",
+        log: "type, client, tx, amount
+              deposit, 1, 1, 10.0
+              withdrawal, 1, 2, 4.0
+              withdrawal, 1, 3, 1.0
+              dispute, 1, 3,
+              resolve, 1, 3,
+              dispute, 1, 2,
+              chargeback, 1, 2",
+    },
+    Scenario {
+        name: "invalid_references",
+        description: "disputes, resolves, and chargebacks which don't apply",
+        log: "type, client, tx, amount
+              deposit, 1, 1, 10.0
+              dispute, 1, 9,
+              resolve, 1, 1,
+              chargeback, 1, 1,
+              dispute, 1, 1,
+              dispute, 1, 1,
+              dispute, 2, 5,",
+    },
+    Scenario {
+        name: "redisputes",
+        description: "a deposit disputed again after being resolved, and after being charged back",
+        log: "type, client, tx, amount
+              deposit, 1, 1, 10.0
+              dispute, 1, 1,
+              resolve, 1, 1,
+              dispute, 1, 1,
+              resolve, 1, 1,
+              deposit, 2, 2, 5.0
+              dispute, 2, 2,
+              chargeback, 2, 2,
+              dispute, 1, 2,",
+    },
+    Scenario {
+        name: "duplicate_ids",
+        description: "deposits and withdrawals reusing an earlier transaction's ID",
+        log: "type, client, tx, amount
+              deposit, 1, 1, 10.0
+              deposit, 1, 1, 10.0
+              withdrawal, 2, 1, 1.0",
+    },
+    Scenario {
+        name: "unlock",
+        description: "an admin unlock of a charged back account",
+        log: "type, client, tx, amount
+              deposit, 1, 1, 10.0
+              dispute, 1, 1,
+              chargeback, 1, 1,
+              deposit, 1, 2, 3.0
+              unlock, 1, 3,
+              deposit, 1, 4, 2.0",
+    },
+    Scenario {
+        name: "unknown_type",
+        description: "a transaction of a type the engine doesn't know",
+        log: "type, client, tx, amount
+              deposit, 1, 1, 10.0
+              refund, 1, 2, 5.0
+              withdrawal, 1, 3, 5.0",
+    },
+];
+
+/// The outcomes of every scenario under one configuration.
+#[derive(Serialize)]
+pub struct PolicyReport {
+    /// Version of the engine which produced the report.
+    pub engine_version: &'static str,
+    /// The configuration, as given on the command line.
+    pub configuration: Vec<String>,
+    pub scenarios: Vec<ScenarioReport>,
+}
+
+#[derive(Serialize)]
+pub struct ScenarioReport {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub outcomes: Vec<Outcome>,
+    /// Every client's state once the scenario has run, by client ID.
+    pub clients: Vec<ClientState>,
+}
+
+/// What happened to a single transaction.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct Outcome {
+    pub line: Option<u64>,
+    pub r#type: TransactionType,
+    pub client: u16,
+    pub tx: u32,
+    /// `applied`, `held` (for a locked account), `rejected`, or `error` (processing stopped).
+    pub outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+}
+
+impl Outcome {
+    fn new(tx: &Transaction, outcome: &'static str, reason: Option<&'static str>) -> Self {
+        Outcome {
+            line: tx.line,
+            r#type: tx.r#type,
+            client: tx.client_id,
+            tx: tx.tx_id,
+            outcome,
+            reason,
+        }
+    }
+}
+
+impl PolicyReport {
+    /// Run every scenario on a fresh engine from `engine`, recording `configuration` as the
+    /// configuration the engines were built with.
+    pub fn run<F>(configuration: Vec<String>, engine: F) -> Result<Self, Box<dyn Error>>
+    where
+        F: Fn() -> Engine,
+    {
+        let scenarios = SCENARIOS
+            .iter()
+            .map(|scenario| run_scenario(scenario, engine()))
+            .collect::<Result<_, _>>()?;
+
+        Ok(PolicyReport {
+            engine_version: env!("CARGO_PKG_VERSION"),
+            configuration,
+            scenarios,
+        })
+    }
+
+    /// Write the report as pretty-printed JSON, so reports diff line by line.
+    pub fn write_json<W: Write>(&self, mut writer: W) -> Result<(), Box<dyn Error>> {
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)?;
+
+        Ok(())
+    }
+}
+
+fn run_scenario(scenario: &Scenario, mut engine: Engine) -> Result<ScenarioReport, Box<dyn Error>> {
+    let mut outcomes = Vec::new();
+    for tx in read_transactions(scenario.log.as_bytes(), InputFormat::Csv) {
+        let tx = tx.map_err(|e| format!("scenario {} is malformed: {}", scenario.name, e))?;
+        let queued = engine.queued_count();
+        let result = engine.apply(&tx);
+
+        let stopped = match result {
+            Ok(()) if engine.queued_count() > queued => {
+                outcomes.push(Outcome::new(&tx, "held", None));
+                false
+            }
+            Ok(()) => {
+                outcomes.push(Outcome::new(&tx, "applied", None));
+                false
+            }
+            Err(e) if engine.is_fatal(&e) => {
+                outcomes.push(Outcome::new(&tx, "error", Some(e.reason())));
+                true
+            }
+            Err(e) => {
+                outcomes.push(Outcome::new(&tx, "rejected", Some(e.reason())));
+                false
+            }
+        };
+        // Transactions held for an account are applied once it's unlocked.
+        for (tx, e) in engine.take_released_rejects() {
+            outcomes.push(Outcome::new(&tx, "rejected", Some(e.reason())));
+        }
+        if stopped {
+            break;
+        }
+    }
+
+    let mut clients: Vec<ClientState> = engine.into_client_states().into_values().collect();
+    clients.sort_unstable_by_key(|state| state.client_id);
+
+    Ok(ScenarioReport {
+        name: scenario.name,
+        description: scenario.description,
+        outcomes,
+        clients,
+    })
+}
@@ -0,0 +1,124 @@
+/// Double-entry postings, which are how transactions change client funds.
+///
+/// Every change a transaction makes to funds is a posting, moving an amount in some currency from
+/// one account to another, so no transaction can create or destroy money: whatever one account
+/// gains, another loses. A client has an `Available` and a `Held` account in each currency (its
+/// total is their sum), fees charged to it move to `Fees` (its `fees_collected`), and funds entering
+/// or leaving the engine move from or to `External`, which stands for the rest of the world.
+///
+/// ```text
+/// deposit      External -> Available (amount), then Available -> Fees (fee, if any)
+/// withdrawal   Available -> External (amount), then Available -> Fees (fee, if any)
+/// dispute      Available -> Held for a deposit, External -> Held for a withdrawal
+/// resolve      Held -> Available for a deposit, Held -> External for a withdrawal
+/// chargeback   Held -> External for a deposit, Held -> Available for a withdrawal
+/// convert      Available -> External (amount), then External -> Available (converted amount, in
+///              `to_currency`)
+/// ```
+///
+/// So a client's funds in some currency only ever change by the net of its postings, and in every
+/// currency, `External` has always lost exactly what the clients' funds and fees add up to.
+/// Balance change events (see `events`) are reconstructed from the postings of each transaction.
+use std::collections::BTreeMap;
+
+use crate::currency::{Balance, Currency};
+use crate::money::Money;
+use crate::ClientState;
+
+/// An account funds can be posted to or from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Account {
+    /// A client's funds which can be withdrawn.
+    Available,
+    /// A client's funds under dispute.
+    Held,
+    /// Fees charged to a client.
+    Fees,
+    /// Anywhere outside the engine.
+    External,
+}
+
+/// A movement of funds in a single currency from one account to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Posting {
+    pub from: Account,
+    pub to: Account,
+    pub currency: Currency,
+    pub amount: Money,
+}
+
+impl Posting {
+    pub fn new(from: Account, to: Account, currency: Currency, amount: Money) -> Self {
+        Posting {
+            from,
+            to,
+            currency,
+            amount,
+        }
+    }
+}
+
+/// How much `postings` change an account's balance in some currency.
+pub fn net(postings: &[Posting], account: Account, currency: Currency) -> Money {
+    let mut net = Money::ZERO;
+    for posting in postings
+        .iter()
+        .filter(|posting| posting.currency == currency)
+    {
+        if posting.to == account {
+            net += posting.amount;
+        }
+        if posting.from == account {
+            net -= posting.amount;
+        }
+    }
+
+    net
+}
+
+/// The currencies `postings` are in, in the order they first appear.
+pub fn currencies(postings: &[Posting]) -> Vec<Currency> {
+    let mut currencies = Vec::new();
+    for posting in postings {
+        if !currencies.contains(&posting.currency) {
+            currencies.push(posting.currency);
+        }
+    }
+
+    currencies
+}
+
+impl Balance {
+    /// Apply a posting's change to this balance (which should be in the posting's currency).
+    fn post(&mut self, posting: &Posting) {
+        self.adjust(posting.from, Money::ZERO - posting.amount);
+        self.adjust(posting.to, posting.amount);
+        self.total = self.available + self.held;
+    }
+
+    fn adjust(&mut self, account: Account, amount: Money) {
+        match account {
+            Account::Available => self.available += amount,
+            Account::Held => self.held += amount,
+            Account::Fees => {
+                if let Some(fees_collected) = self.fees_collected.as_mut() {
+                    *fees_collected += amount;
+                }
+            }
+            Account::External => {}
+        }
+    }
+}
+
+/// The client's funds in each currency `postings` are in, once they've been applied.
+pub(crate) fn posted(state: &ClientState, postings: &[Posting]) -> BTreeMap<Currency, Balance> {
+    let mut balances = BTreeMap::new();
+    for posting in postings {
+        balances
+            .entry(posting.currency)
+            .or_insert_with(|| state.balance(posting.currency))
+            .post(posting);
+    }
+
+    balances
+}
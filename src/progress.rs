@@ -0,0 +1,159 @@
+/// Progress through a large transaction log (`--progress`), shown on a terminal as it's read.
+///
+/// Rows are counted as they're read, and bytes as they're read from the log files (through a
+/// `CountingReader`), so when the logs' total size is known, the share read so far and the time
+/// left can be estimated from it. The size of stdin isn't known, and neither is how large a
+/// compressed log is once it's decompressed, so only rows, bytes, and throughput are shown for
+/// them. The status line is redrawn in place on stderr a few times a second.
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::decompress::Compression;
+use crate::input::STDIN_PATH;
+
+/// How often the status line is redrawn.
+const INTERVAL: Duration = Duration::from_millis(250);
+/// Rows read between checks of the time, so reading a row stays cheap.
+const CHECK_EVERY: u64 = 4096;
+
+/// A reader which counts the bytes read through it.
+pub struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R> CountingReader<R> {
+    /// Count the bytes read from `inner` in `count` (which can be shared by several readers).
+    pub fn new(inner: R, count: Rc<Cell<u64>>) -> Self {
+        CountingReader { inner, count }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.set(self.count.get() + read as u64);
+        Ok(read)
+    }
+}
+
+/// Rows and bytes read so far, and when the status line was last drawn.
+#[derive(Debug)]
+pub struct Progress {
+    started: Instant,
+    drawn: Instant,
+    rows: u64,
+    bytes: Rc<Cell<u64>>,
+    /// Size of the logs being read, if it's known.
+    total_bytes: Option<u64>,
+}
+
+impl Progress {
+    /// Track progress through logs of `total_bytes` in all (if known), timing the run from now.
+    pub fn new(total_bytes: Option<u64>) -> Self {
+        let now = Instant::now();
+        Progress {
+            started: now,
+            drawn: now,
+            rows: 0,
+            bytes: Rc::new(Cell::new(0)),
+            total_bytes,
+        }
+    }
+
+    /// The count of bytes read, for `CountingReader`s to add to.
+    pub fn bytes_read(&self) -> Rc<Cell<u64>> {
+        self.bytes.clone()
+    }
+
+    /// Record a row which was read, redrawing the status line if it's due.
+    pub fn observe(&mut self) {
+        self.rows += 1;
+        if self.rows.is_multiple_of(CHECK_EVERY) && self.drawn.elapsed() >= INTERVAL {
+            self.draw();
+        }
+    }
+
+    /// Draw the final status line, leaving it in place.
+    pub fn finish(&mut self) {
+        self.draw();
+        eprintln!();
+    }
+
+    fn draw(&mut self) {
+        self.drawn = Instant::now();
+        let mut stderr = io::stderr().lock();
+        // There's nowhere to report a failure to write to stderr, and progress is only a nicety.
+        let _ = write!(stderr, "\r\x1b[K{}", self.status(self.started.elapsed()));
+        let _ = stderr.flush();
+    }
+
+    /// The status line after `elapsed`: rows and bytes read (out of the total, if known), the
+    /// throughput, and an estimate of the time left.
+    pub fn status(&self, elapsed: Duration) -> String {
+        let seconds = elapsed.as_secs_f64().max(1e-6);
+        let bytes = self.bytes.get();
+        let mut status = format!("{} rows, {}", self.rows, format_bytes(bytes));
+        if let Some(total) = self.total_bytes {
+            let share = if total == 0 {
+                1.0
+            } else {
+                bytes as f64 / total as f64
+            };
+            status += &format!(" of {} ({:.1}%)", format_bytes(total), share * 100.0);
+        }
+        status += &format!(", {:.0} rows/s", self.rows as f64 / seconds);
+        if let Some(total) = self.total_bytes {
+            status += &match bytes {
+                0 => ", ETA unknown".to_string(),
+                _ => {
+                    let left = total.saturating_sub(bytes) as f64 / (bytes as f64 / seconds);
+                    format!(", ETA {}", format_duration(Duration::from_secs_f64(left)))
+                }
+            };
+        }
+
+        status
+    }
+}
+
+/// Total size of the logs at `paths`, unless one is stdin or compressed (or can't be read), when
+/// it isn't known how much will be read.
+pub fn total_size<'a>(paths: impl IntoIterator<Item = &'a String>) -> Option<u64> {
+    paths.into_iter().try_fold(0, |total, path| {
+        if path == STDIN_PATH || Compression::of(path).is_some() {
+            return None;
+        }
+        Some(total + fs::metadata(path).ok()?.len())
+    })
+}
+
+/// A number of bytes in the largest unit which keeps it at least 1 (e.g. `1.5G`), in powers of
+/// 1024 like `--max-memory`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
+
+/// A duration to the second, in its two largest units (e.g. `1h05m` or `3m20s`).
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, seconds) => format!("{}s", seconds),
+        (0, minutes, seconds) => format!("{}m{:02}s", minutes, seconds),
+        (hours, minutes, _) => format!("{}h{:02}m", hours, minutes),
+    }
+}
@@ -0,0 +1,237 @@
+/// Merkle tree proofs of client balances.
+///
+/// After processing, a Merkle tree is built over every client's total (ordered by client ID), and
+/// the root can be published. Each client gets a proof of their own balance, which is enough to
+/// recompute the root without seeing any other client's balance.
+///
+/// Leaves are `SHA-256(0x00 || salt || client || total)`, where `client` is the big-endian client
+/// ID and `total` is the total formatted with 4 decimal places. Each client's salt is derived from
+/// a key taken from the environment (HMAC-SHA256 of the client ID), so the hashes of other clients
+/// in a proof can't be brute forced. Interior nodes are `SHA-256(0x01 || left || right)`, and a
+/// node without a sibling is carried up to the next level unchanged.
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::digest::{from_hex, to_hex};
+use crate::money::Money;
+use crate::output::BalanceSink;
+use crate::ClientState;
+
+/// Environment variable holding the key used to derive per-client salts.
+pub const KEY_ENV_VAR: &str = "PAYMENT_ENGINE_PROOF_KEY";
+
+/// Read the salt key from the environment.
+pub fn key_from_env() -> Result<Vec<u8>, String> {
+    match std::env::var(KEY_ENV_VAR) {
+        Ok(key) if !key.is_empty() => Ok(key.into_bytes()),
+        _ => Err(format!(
+            "{} must be set to export balance proofs",
+            KEY_ENV_VAR
+        )),
+    }
+}
+
+/// Which side of the path a sibling hash is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// One step from a leaf towards the root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub side: Side,
+    /// Hex encoded hash of the sibling node.
+    pub hash: String,
+}
+
+/// Proof that some client's total is included in a tree with a given root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceProof {
+    pub client: u16,
+    pub total: Money,
+    /// Hex encoded salt for this client's leaf.
+    pub salt: String,
+    /// Sibling hashes from the leaf up to (but excluding) the root.
+    pub path: Vec<ProofStep>,
+}
+
+impl BalanceProof {
+    /// Whether this proof leads to `root` (hex encoded).
+    pub fn verify(&self, root: &str) -> bool {
+        let salt = match from_hex(&self.salt) {
+            Some(salt) => salt,
+            None => return false,
+        };
+
+        let mut hash = leaf_hash(&salt, self.client, self.total);
+        for step in &self.path {
+            let sibling = match from_hex(&step.hash).and_then(|hash| hash_from_slice(&hash)) {
+                Some(sibling) => sibling,
+                None => return false,
+            };
+            hash = match step.side {
+                Side::Left => node_hash(&sibling, &hash),
+                Side::Right => node_hash(&hash, &sibling),
+            };
+        }
+
+        to_hex(&hash) == root.to_ascii_lowercase()
+    }
+}
+
+/// Collects client totals as they're written, and builds the tree once all clients are known.
+pub struct BalanceProofs {
+    key: Vec<u8>,
+    totals: Vec<(u16, Money)>,
+}
+
+impl BalanceProofs {
+    pub fn new(key: Vec<u8>) -> Self {
+        BalanceProofs {
+            key,
+            totals: Vec::new(),
+        }
+    }
+
+    /// The root of the tree (hex encoded), and a proof for every client (ordered by client ID).
+    pub fn build(&self) -> (String, Vec<BalanceProof>) {
+        let mut totals = self.totals.clone();
+        totals.sort_by_key(|(client, _)| *client);
+
+        let salts: Vec<[u8; 32]> = totals
+            .iter()
+            .map(|(client, _)| salt(&self.key, *client))
+            .collect();
+        let mut levels = vec![totals
+            .iter()
+            .zip(&salts)
+            .map(|((client, total), salt)| leaf_hash(salt, *client, *total))
+            .collect::<Vec<_>>()];
+        while levels[levels.len() - 1].len() > 1 {
+            let next = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!("chunks have one or two nodes"),
+                })
+                .collect();
+            levels.push(next);
+        }
+        let root = match levels[levels.len() - 1].first() {
+            Some(root) => *root,
+            None => Sha256::digest(b"").into(),
+        };
+
+        let proofs = totals
+            .iter()
+            .zip(&salts)
+            .enumerate()
+            .map(|(leaf, ((client, total), salt))| {
+                let mut index = leaf;
+                let mut path = Vec::new();
+                for level in &levels[..levels.len() - 1] {
+                    let sibling = index ^ 1;
+                    if let Some(hash) = level.get(sibling) {
+                        path.push(ProofStep {
+                            side: if sibling < index {
+                                Side::Left
+                            } else {
+                                Side::Right
+                            },
+                            hash: to_hex(hash),
+                        });
+                    }
+                    index /= 2;
+                }
+
+                BalanceProof {
+                    client: *client,
+                    total: *total,
+                    salt: to_hex(salt),
+                    path,
+                }
+            })
+            .collect();
+
+        (to_hex(&root), proofs)
+    }
+
+    /// Write the root to `root.txt`, and every client's proof to `proofs.ndjson` (one JSON object
+    /// per line) in `dir`.
+    pub fn export(&self, dir: &Path) -> Result<(), Box<dyn Error>> {
+        let (root, proofs) = self.build();
+
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join("root.txt"), format!("{}\n", root))?;
+        let mut writer = io::BufWriter::new(File::create(dir.join("proofs.ndjson"))?);
+        for proof in &proofs {
+            serde_json::to_writer(&mut writer, proof)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+impl BalanceSink for BalanceProofs {
+    fn write(&mut self, state: &ClientState) -> Result<(), Box<dyn Error>> {
+        if state.currency.is_some() {
+            return Err("balance proofs can't be built for multiple currencies".into());
+        }
+        self.totals.push((state.client_id, state.total));
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+fn salt(key: &[u8], client: u16) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&client.to_be_bytes());
+
+    mac.finalize().into_bytes().into()
+}
+
+fn leaf_hash(salt: &[u8], client: u16, total: Money) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0x00]);
+    hasher.update(salt);
+    hasher.update(client.to_be_bytes());
+    hasher.update(total.to_string().as_bytes());
+
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0x01]);
+    hasher.update(left);
+    hasher.update(right);
+
+    hasher.finalize().into()
+}
+
+fn hash_from_slice(bytes: &[u8]) -> Option<[u8; 32]> {
+    if bytes.len() != 32 {
+        return None;
+    }
+
+    let mut hash = [0; 32];
+    hash.copy_from_slice(bytes);
+
+    Some(hash)
+}
@@ -0,0 +1,102 @@
+/// Exchange rates for `convert` transactions.
+///
+/// Rates are read from CSV with a row per currency pair, giving how much of `to` one unit of `from`
+/// buys:
+///
+/// ```text
+/// from,to,rate
+/// USD,EUR,0.92
+/// EUR,USD,1.087
+/// ```
+///
+/// Only the pairs listed can be converted (a rate isn't inverted to convert the other way), and
+/// converted amounts are rounded like any other amount (see `Precision`).
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::str::FromStr;
+
+use csv::{ReaderBuilder, Trim};
+use rust_decimal::Decimal;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+
+use crate::currency::Currency;
+use crate::input::open_input;
+use crate::money::{Money, Precision};
+
+#[derive(Deserialize)]
+struct RateRecord {
+    from: Currency,
+    to: Currency,
+    #[serde(deserialize_with = "deserialize_rate")]
+    rate: Decimal,
+}
+
+/// Exchange rates by currency pair.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RateTable {
+    rates: HashMap<(Currency, Currency), Decimal>,
+}
+
+impl RateTable {
+    /// Read a rate table from CSV. Rates must be positive, between two different currencies
+    /// (neither of which is the implicit currency), and listed once per pair.
+    pub fn from_reader<R: io::Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut rates = HashMap::new();
+        for record in ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(reader)
+            .deserialize()
+        {
+            let RateRecord { from, to, rate } = record?;
+            if from.is_implicit() || to.is_implicit() {
+                return Err("rates need a currency on both sides".into());
+            }
+            if from == to {
+                return Err(format!("rate from {} to itself", from).into());
+            }
+            if rate.is_sign_negative() || rate.is_zero() {
+                return Err(format!("rate from {} to {} must be positive", from, to).into());
+            }
+            if rates.insert((from, to), rate).is_some() {
+                return Err(
+                    format!("rate from {} to {} is listed more than once", from, to).into(),
+                );
+            }
+        }
+
+        Ok(RateTable { rates })
+    }
+
+    /// `amount` of `from` converted into `to` and rounded to `precision`, if there's a rate for the
+    /// pair (and the converted amount doesn't overflow).
+    pub fn convert(
+        &self,
+        amount: Money,
+        from: Currency,
+        to: Currency,
+        precision: &Precision,
+    ) -> Option<Money> {
+        let rate = self.rates.get(&(from, to))?;
+
+        Decimal::from(amount)
+            .checked_mul(*rate)
+            .map(|amount| precision.round(amount))
+    }
+}
+
+/// Parse a rate as an exact decimal. Rates aren't amounts, so they aren't rounded.
+fn deserialize_rate<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let rate = String::deserialize(deserializer)?;
+
+    Decimal::from_str(&rate).map_err(|_| D::Error::custom(format!("invalid rate '{}'", rate)))
+}
+
+/// Read a rate table from a CSV file.
+pub fn read_file(path: &str) -> Result<RateTable, Box<dyn Error>> {
+    RateTable::from_reader(open_input(path)?)
+}
@@ -0,0 +1,77 @@
+/// Reordering of transaction logs which are mostly, but not quite, in transaction ID order.
+///
+/// Some feeds deliver transactions slightly out of order, so a dispute can arrive before the
+/// deposit it references and be dropped as unknown. Buffering a window of transactions and
+/// releasing the one with the lowest ID each time the window is full puts them back in order, as
+/// long as none arrives more than a window late. Disputes, resolves, and chargebacks share the ID
+/// of the transaction they reference, so they're released after it (and resolves and chargebacks
+/// after their dispute).
+///
+/// Transactions arriving later than the window allows are passed on as they are, so they're applied
+/// out of order just as they would have been without a window. Logs with timestamps can be
+/// reordered by timestamp instead (see `merge`).
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::input::TransactionStream;
+use crate::{Transaction, TransactionType};
+
+/// A buffered transaction, ordered by ID, then by type (see the module docs), then arrival.
+struct Buffered {
+    key: (u32, u8, u64),
+    tx: Transaction,
+}
+
+impl PartialEq for Buffered {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Buffered {}
+
+impl PartialOrd for Buffered {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Buffered {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// Where a transaction goes among transactions with the same ID.
+fn rank(r#type: TransactionType) -> u8 {
+    match r#type {
+        TransactionType::Dispute => 1,
+        TransactionType::Resolve | TransactionType::Chargeback => 2,
+        _ => 0,
+    }
+}
+
+/// Reorder `transactions` by ID, holding up to `window` of them at a time (see the module docs).
+/// Errors are passed on as soon as they're read.
+pub fn reorder_by_id(mut transactions: TransactionStream, window: usize) -> TransactionStream {
+    let mut buffer: BinaryHeap<Reverse<Buffered>> = BinaryHeap::with_capacity(window + 1);
+    let mut arrivals = 0;
+
+    Box::new(std::iter::from_fn(move || loop {
+        if buffer.len() > window {
+            return buffer.pop().map(|Reverse(buffered)| Ok(buffered.tx));
+        }
+
+        match transactions.next() {
+            Some(Ok(tx)) => {
+                arrivals += 1;
+                buffer.push(Reverse(Buffered {
+                    key: (tx.tx_id, rank(tx.r#type), arrivals),
+                    tx,
+                }));
+            }
+            Some(Err(e)) => return Some(Err(e)),
+            None => return buffer.pop().map(|Reverse(buffered)| Ok(buffered.tx)),
+        }
+    }))
+}
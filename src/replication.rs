@@ -0,0 +1,251 @@
+/// Replication of client states to warm standby engines (followers).
+///
+/// A leader started with `--replicate <addr>` streams every change to its client states to any
+/// follower which connects to `addr`, as newline delimited JSON. A follower first gets every
+/// client's current state, then a delta for each transaction the leader applies, each numbered so
+/// a missed delta is noticed:
+///
+/// ```text
+/// {"kind":"snapshot","seq":41,"clients":[{"client":1,"available":"10.0000",...}, ...]}
+/// {"kind":"delta","seq":42,"client":{"client":1,"available":"12.5000",...}}
+/// ```
+///
+/// Followers (`payment-engine follow`) only serve reads, so they can take over reads as soon as the
+/// leader fails, from the last state they received. Only client states are replicated, not
+/// transactions under dispute, so a follower can't take over applying transactions. A follower
+/// which loses its leader (or falls out of sequence) reconnects, and starts again from a snapshot.
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::output::{BalanceWriter, OutputDialect, SortBy};
+use crate::{write_balances, ClientState, Engine};
+
+/// How long a follower waits before reconnecting to its leader.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A line of the replication stream.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+enum Message {
+    /// Every client's state as of `seq`. Always the first message a follower gets.
+    Snapshot { seq: u64, clients: Vec<ClientState> },
+    /// A single client's state once the change numbered `seq` was applied.
+    Delta { seq: u64, client: ClientState },
+}
+
+/// Changes published to followers.
+#[derive(Default)]
+pub(crate) struct Replication {
+    /// Number of changes published so far.
+    seq: u64,
+    /// Lines still to be sent to each follower.
+    pub(crate) followers: Vec<mpsc::Sender<Arc<str>>>,
+}
+
+impl Replication {
+    /// Publish a client's new state to every follower, forgetting any which have disconnected.
+    pub(crate) fn publish(&mut self, state: &ClientState) {
+        self.seq += 1;
+        if self.followers.is_empty() {
+            return;
+        }
+
+        let message = Message::Delta {
+            seq: self.seq,
+            client: state.without_disputes(),
+        };
+        let line: Arc<str> = match serde_json::to_string(&message) {
+            Ok(line) => line.into(),
+            Err(e) => {
+                tracing::error!("couldn't serialize replication delta: {}", e);
+                return;
+            }
+        };
+        self.followers
+            .retain(|follower| follower.send(line.clone()).is_ok());
+    }
+}
+
+/// A newly subscribed follower's snapshot line, and where its delta lines arrive.
+struct Subscription {
+    snapshot: String,
+    deltas: mpsc::Receiver<Arc<str>>,
+}
+
+impl Engine {
+    /// Publish every change to client states from now on, for followers (see `replication`).
+    pub fn with_replication(mut self) -> Self {
+        self.replication = Some(Replication::default());
+        self
+    }
+
+    /// Start following the engine: a snapshot line with every client's current state, and the
+    /// delta lines for every later change. `None` if the engine isn't replicating.
+    fn subscribe(&mut self) -> Option<Result<Subscription, Box<dyn Error>>> {
+        let replication = self.replication.as_mut()?;
+
+        let mut clients: Vec<ClientState> = self
+            .client_states
+            .values()
+            .map(ClientState::without_disputes)
+            .collect();
+        clients.sort_unstable_by_key(|state| state.client_id);
+        let snapshot = Message::Snapshot {
+            seq: replication.seq,
+            clients,
+        };
+        let snapshot = match serde_json::to_string(&snapshot) {
+            Ok(snapshot) => snapshot,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        let (sender, deltas) = mpsc::channel();
+        replication.followers.push(sender);
+
+        Some(Ok(Subscription { snapshot, deltas }))
+    }
+}
+
+/// Stream changes to every follower which connects to `listener`, forever.
+pub fn serve(listener: TcpListener, engine: Arc<Mutex<Engine>>) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let engine = engine.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = stream_to_follower(stream, &engine) {
+                tracing::warn!("follower {:?} disconnected: {}", peer, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Send the snapshot and then every delta to a single follower.
+pub fn stream_to_follower<W: Write>(
+    mut writer: W,
+    engine: &Mutex<Engine>,
+) -> Result<(), Box<dyn Error>> {
+    // The snapshot is taken and the follower subscribed under one lock, so no delta is missed.
+    let subscription = engine.lock().unwrap_or_else(|e| e.into_inner()).subscribe();
+    let Subscription { snapshot, deltas } =
+        subscription.ok_or("the engine isn't replicating")??;
+
+    writeln!(writer, "{}", snapshot)?;
+    writer.flush()?;
+    for delta in deltas {
+        writeln!(writer, "{}", delta)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Apply a leader's replication stream to `engine`, until the stream ends. Returns an error if the
+/// stream is out of sequence, in which case the follower should reconnect.
+pub fn follow<R: BufRead>(reader: R, engine: &Mutex<Engine>) -> Result<(), Box<dyn Error>> {
+    let mut last_seq = None;
+
+    for line in reader.lines() {
+        let line = line?;
+        match serde_json::from_str(&line)? {
+            Message::Snapshot { seq, clients } => {
+                let mut engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+                engine.client_states = clients
+                    .into_iter()
+                    .map(|state| (state.client_id, state))
+                    .collect();
+                last_seq = Some(seq);
+            }
+            Message::Delta { seq, client } => {
+                let expected =
+                    last_seq.ok_or("replication stream didn't start with a snapshot")? + 1;
+                if seq != expected {
+                    return Err(format!(
+                        "replication stream skipped from {} to {}",
+                        expected - 1,
+                        seq
+                    )
+                    .into());
+                }
+                let mut engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+                engine.client_states.insert(client.client_id, client);
+                last_seq = Some(seq);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Follow the leader at `addr` forever, reconnecting whenever the stream ends or fails.
+pub fn follow_forever<A: ToSocketAddrs>(addr: A, engine: &Mutex<Engine>) {
+    loop {
+        let result = TcpStream::connect(&addr)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|stream| follow(BufReader::new(stream), engine));
+        match result {
+            Ok(()) => tracing::warn!("leader closed the replication stream, reconnecting"),
+            Err(e) => tracing::warn!("replication failed ({}), reconnecting", e),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+/// Serve reads from a follower's engine on `listener` forever. Connections use the `server`
+/// protocol, but only `BALANCES` is accepted, and every transaction gets an error.
+pub fn serve_reads(listener: TcpListener, engine: Arc<Mutex<Engine>>) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let engine = engine.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            let result = stream
+                .try_clone()
+                .and_then(|reader| handle_read_connection(BufReader::new(reader), stream, &engine));
+            if let Err(e) = result {
+                tracing::warn!("connection from {:?} failed: {}", peer, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Respond to every line sent over a single connection to a follower.
+pub fn handle_read_connection<R, W>(
+    reader: R,
+    mut writer: W,
+    engine: &Mutex<Engine>,
+) -> io::Result<()>
+where
+    R: BufRead,
+    W: Write,
+{
+    for line in reader.lines() {
+        let line = line?;
+        match line.trim() {
+            "" => continue,
+            "BALANCES" => {
+                let engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+                let mut sink = BalanceWriter::new(&mut writer, &OutputDialect::default());
+                write_balances(&mut sink, engine.client_states(), SortBy::Client)
+                    .map_err(|e| io::Error::other(e.to_string()))?;
+                drop(sink);
+                writer.write_all(b"\n")?;
+            }
+            _ => writer.write_all(b"ERROR followers only serve reads\n")?,
+        }
+        writer.flush()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+/// TCP server mode, for applying live streams of transactions.
+///
+/// Every connection sends transactions one per line (CSV in `type,client,tx,amount[,terminal]`
+/// order without a header, or NDJSON), and they're all applied to one shared engine in the order
+/// they arrive. Each line gets a single line response:
+///
+/// ```text
+/// OK                      the transaction was applied
+/// REJECTED <reason>       the transaction had no effect (see `TransactionError::reason`)
+/// ERROR <message>         the line couldn't be parsed, or the rejection would have been fatal
+/// ```
+///
+/// A `BALANCES` line instead responds with the current balances as CSV (ordered by client ID),
+/// followed by an empty line. Blank lines and a leading CSV header are ignored.
+///
+/// Fatal rejections (e.g. in strict mode) can't stop a server shared by other connections, so
+/// they're reported as errors to the connection that sent them, and processing continues.
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::input::{parse_line, InputFormat};
+use crate::output::{BalanceWriter, OutputDialect, SortBy};
+use crate::{write_balances, Engine};
+
+/// Accept connections on `listener` forever, handling each on its own thread.
+pub fn serve(listener: TcpListener, engine: Engine, format: InputFormat) -> io::Result<()> {
+    serve_shared(listener, Arc::new(Mutex::new(engine)), format)
+}
+
+/// Like `serve`, for an engine which is also used elsewhere (e.g. for replication).
+pub fn serve_shared(
+    listener: TcpListener,
+    engine: Arc<Mutex<Engine>>,
+    format: InputFormat,
+) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let engine = engine.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = handle_tcp_connection(stream, &engine, format) {
+                tracing::warn!("connection from {:?} failed: {}", peer, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle_tcp_connection(
+    stream: TcpStream,
+    engine: &Mutex<Engine>,
+    format: InputFormat,
+) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+
+    handle_connection(reader, stream, engine, format)
+}
+
+/// Apply every transaction sent over a single connection, responding to each line.
+pub fn handle_connection<R, W>(
+    reader: R,
+    mut writer: W,
+    engine: &Mutex<Engine>,
+    format: InputFormat,
+) -> io::Result<()>
+where
+    R: BufRead,
+    W: Write,
+{
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || (index == 0 && format == InputFormat::Csv && line.starts_with("type"))
+        {
+            continue;
+        }
+
+        if line == "BALANCES" {
+            let engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+            let mut sink = BalanceWriter::new(&mut writer, &OutputDialect::default());
+            write_balances(&mut sink, engine.client_states(), SortBy::Client)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            drop(sink);
+            writer.write_all(b"\n")?;
+            writer.flush()?;
+            continue;
+        }
+
+        let response = match parse_line(line, format) {
+            Ok(tx) => {
+                let mut engine = engine.lock().unwrap_or_else(|e| e.into_inner());
+                let response = match engine.apply(&tx) {
+                    Ok(()) => "OK".to_string(),
+                    Err(e) if engine.is_fatal(&e) => format!("ERROR {}", e),
+                    Err(e) => format!("REJECTED {}", e.reason()),
+                };
+                // Responses are only for the line sent, so held transactions are reported here.
+                for (tx, e) in engine.take_released_rejects() {
+                    tracing::warn!("held transaction {} rejected after unlock: {}", tx.tx_id, e);
+                }
+                response
+            }
+            Err(e) => format!("ERROR {}", e),
+        };
+        writeln!(writer, "{}", response)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,415 @@
+/// Persistent engine state, so a later batch can pick up where an earlier one left off.
+///
+/// A snapshot holds every client's state (including which of its transactions are under dispute, or
+/// were resolved or charged back), every transaction which could still be disputed, every
+/// transaction held for a locked account, and (for engines which skip replays) every transaction
+/// processed. Policies and fees aren't part of a snapshot, they're taken from the engine a snapshot
+/// is restored into.
+///
+/// Snapshots are JSON, ordered by client and transaction ID so snapshots of the same state are
+/// identical. Each snapshot records a format version, and snapshots of any other version are
+/// refused rather than misread.
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::currency::{Balance, Currency};
+use crate::dedup::ProcessedSet;
+use crate::money::Money;
+use crate::output::AtomicFile;
+use crate::store::{DisputableKind, DisputableTx};
+use crate::{ClientState, DisputeState, Engine, Transaction, TransactionKind, TransactionType};
+
+/// Version of the snapshot format written by this build.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    clients: Vec<ClientSnapshot>,
+    transactions: Vec<TransactionSnapshot>,
+    /// Transactions held for locked accounts, by client in the order they arrived.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    queued: Vec<QueuedSnapshot>,
+    /// The engine's dispute window clock (zero without a dispute window).
+    #[serde(default, skip_serializing_if = "is_zero")]
+    dispute_clock: u64,
+    /// Transactions processed by each client, if the engine skips replays (see
+    /// `Engine::with_dedup`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    processed: Option<Vec<ProcessedSnapshot>>,
+}
+
+/// A client's processed transaction IDs, as runs of consecutive IDs (first and last, inclusive).
+#[derive(Serialize, Deserialize)]
+struct ProcessedSnapshot {
+    client: u16,
+    ranges: Vec<(u32, u32)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ClientSnapshot {
+    client: u16,
+    available: Money,
+    held: Money,
+    total: Money,
+    locked: bool,
+    /// Missing from snapshots written before versions were tracked.
+    #[serde(default)]
+    version: u64,
+    /// Fees charged to the client so far (zero if the engine didn't charge fees).
+    #[serde(default)]
+    fees_collected: Money,
+    /// Latest timestamp of any transaction for the client, if the engine tracked activity.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_activity: Option<u64>,
+    /// Funds in currencies other than the implicit one (the fields above).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    currencies: Vec<CurrencySnapshot>,
+    /// IDs of the client's transactions which are under dispute.
+    disputed: Vec<u32>,
+    /// IDs of the client's transactions whose disputes were resolved (missing from snapshots
+    /// written before dispute states were tracked, like those of `charged_back`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    resolved: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    charged_back: Vec<u32>,
+    /// Highest ID of the client's deposits, withdrawals, and conversions (missing from snapshots
+    /// written before it was tracked).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    high_water: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CurrencySnapshot {
+    currency: Currency,
+    available: Money,
+    held: Money,
+    total: Money,
+    #[serde(default)]
+    fees_collected: Money,
+}
+
+/// A disputable transaction. Only deposits and withdrawals (which always have an amount) are
+/// recorded as disputable.
+#[derive(Serialize, Deserialize)]
+struct TransactionSnapshot {
+    r#type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Money,
+    #[serde(default, skip_serializing_if = "Currency::is_implicit")]
+    currency: Currency,
+    /// When the transaction was applied, on the dispute window clock.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    at: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// A transaction held until its client's account is unlocked.
+#[derive(Serialize, Deserialize)]
+struct QueuedSnapshot {
+    r#type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<Money>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    line: Option<u64>,
+    #[serde(default, skip_serializing_if = "Currency::is_implicit")]
+    currency: Currency,
+    #[serde(default, skip_serializing_if = "Currency::is_implicit")]
+    to_currency: Currency,
+}
+
+impl Engine {
+    /// Write the engine's state (client states and disputable transactions) as a snapshot.
+    pub fn write_snapshot<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        self.write_state(writer, true)
+    }
+
+    /// Write the engine's state as a snapshot, leaving out the disputable transactions unless
+    /// `with_transactions` (e.g. as a ledger keeps them itself, see `ledger`).
+    pub(crate) fn write_state<W: Write>(
+        &self,
+        writer: W,
+        with_transactions: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut clients: Vec<ClientSnapshot> = self
+            .client_states
+            .values()
+            .map(|state| {
+                let sorted = |dispute_state| {
+                    let mut tx_ids: Vec<u32> = state.disputes_in(dispute_state).collect();
+                    tx_ids.sort_unstable();
+                    tx_ids
+                };
+                ClientSnapshot {
+                    client: state.client_id,
+                    available: state.available,
+                    held: state.held,
+                    total: state.total,
+                    locked: state.locked,
+                    version: state.version,
+                    fees_collected: state.fees_collected.unwrap_or(Money::ZERO),
+                    last_activity: state.last_activity,
+                    currencies: state
+                        .currencies
+                        .iter()
+                        .map(|(&currency, balance)| CurrencySnapshot {
+                            currency,
+                            available: balance.available,
+                            held: balance.held,
+                            total: balance.total,
+                            fees_collected: balance.fees_collected.unwrap_or(Money::ZERO),
+                        })
+                        .collect(),
+                    disputed: sorted(DisputeState::Disputed),
+                    resolved: sorted(DisputeState::Resolved),
+                    charged_back: sorted(DisputeState::ChargedBack),
+                    high_water: state.high_water,
+                }
+            })
+            .collect();
+        clients.sort_unstable_by_key(|client| client.client);
+
+        let stored = if with_transactions {
+            self.disputable_transactions.transactions()?
+        } else {
+            Vec::new()
+        };
+        let mut transactions: Vec<TransactionSnapshot> = stored
+            .into_iter()
+            .map(|(tx_id, tx)| TransactionSnapshot {
+                r#type: tx.r#type(),
+                client: tx.client_id,
+                tx: tx_id,
+                amount: tx.amount,
+                currency: tx.currency,
+                at: tx.at,
+            })
+            .collect();
+        transactions.sort_unstable_by_key(|tx| tx.tx);
+
+        let mut queued_clients: Vec<u16> = self.queued.keys().copied().collect();
+        queued_clients.sort_unstable();
+        let queued = queued_clients
+            .iter()
+            .flat_map(|client| &self.queued[client])
+            .map(|tx| QueuedSnapshot {
+                r#type: tx.r#type,
+                client: tx.client_id,
+                tx: tx.tx_id,
+                amount: tx.amount,
+                line: tx.line,
+                currency: tx.currency,
+                to_currency: tx.to_currency,
+            })
+            .collect();
+
+        let processed = self.processed.as_ref().map(|processed| {
+            let mut clients: Vec<ProcessedSnapshot> = processed
+                .clients()
+                .map(|(client, ids)| ProcessedSnapshot {
+                    client,
+                    ranges: ids.ranges(),
+                })
+                .collect();
+            clients.sort_unstable_by_key(|client| client.client);
+            clients
+        });
+
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            clients,
+            transactions,
+            queued,
+            dispute_clock: self.dispute_clock,
+            processed,
+        };
+        serde_json::to_writer(writer, &snapshot)?;
+
+        Ok(())
+    }
+
+    /// Replace the engine's state with a snapshot written by `write_snapshot`, keeping its
+    /// policies.
+    pub fn restore_snapshot<R: Read>(self, reader: R) -> Result<Self, Box<dyn Error>> {
+        self.restore_state(reader, true)
+    }
+
+    /// Replace the engine's state with a snapshot written by `write_state`. Unless
+    /// `with_transactions`, the engine's disputable transactions are kept rather than replaced.
+    pub(crate) fn restore_state<R: Read>(
+        mut self,
+        reader: R,
+        with_transactions: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let snapshot: Snapshot = serde_json::from_reader(reader)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "unsupported snapshot version {}, expected {}",
+                snapshot.version, SNAPSHOT_VERSION
+            )
+            .into());
+        }
+
+        self.client_states = snapshot
+            .clients
+            .into_iter()
+            .map(|client| {
+                let fees_collected = |fees| self.fees.as_ref().map(|_| fees);
+                let state = ClientState {
+                    client_id: client.client,
+                    currency: None,
+                    available: client.available,
+                    held: client.held,
+                    total: client.total,
+                    locked: client.locked,
+                    version: client.version,
+                    fees_collected: fees_collected(client.fees_collected),
+                    last_activity: self
+                        .track_activity
+                        .then(|| client.last_activity.unwrap_or(0)),
+                    currencies: client
+                        .currencies
+                        .into_iter()
+                        .map(|currency| {
+                            let balance = Balance {
+                                available: currency.available,
+                                held: currency.held,
+                                total: currency.total,
+                                fees_collected: fees_collected(currency.fees_collected),
+                            };
+                            (currency.currency, balance)
+                        })
+                        .collect(),
+                    disputes: client
+                        .disputed
+                        .into_iter()
+                        .map(|tx_id| (tx_id, DisputeState::Disputed))
+                        .chain(
+                            client
+                                .resolved
+                                .into_iter()
+                                .map(|tx_id| (tx_id, DisputeState::Resolved)),
+                        )
+                        .chain(
+                            client
+                                .charged_back
+                                .into_iter()
+                                .map(|tx_id| (tx_id, DisputeState::ChargedBack)),
+                        )
+                        .collect(),
+                    high_water: client.high_water,
+                };
+                (client.client, state)
+            })
+            .collect();
+        if with_transactions {
+            self.disputable_transactions.clear()?;
+        }
+        for tx in snapshot.transactions {
+            // Disputes rely on every disputable transaction being a deposit or withdrawal with a
+            // valid amount, as the engine only ever records.
+            let recorded = Transaction {
+                r#type: tx.r#type,
+                client_id: tx.client,
+                tx_id: tx.tx,
+                amount: Some(tx.amount),
+                raw_amount: None,
+                line: None,
+                terminal: None,
+                currency: tx.currency,
+                to_currency: Currency::IMPLICIT,
+                timestamp: None,
+            };
+            let (kind, amount) = match recorded.kind() {
+                Ok(TransactionKind::Deposit { amount }) => (DisputableKind::Deposit, amount),
+                Ok(TransactionKind::Withdrawal { amount }) => (DisputableKind::Withdrawal, amount),
+                _ => {
+                    return Err(
+                        format!("snapshot has an invalid disputable transaction {}", tx.tx).into(),
+                    )
+                }
+            };
+            let disputable = DisputableTx {
+                kind,
+                client_id: tx.client,
+                amount,
+                currency: tx.currency,
+                at: tx.at,
+            };
+            self.disputable_transactions.insert(tx.tx, disputable)?;
+        }
+        self.snapshot_high_water = self
+            .client_states
+            .values()
+            .filter_map(|state| Some((state.client_id, state.high_water?)))
+            .collect();
+        // Charged back transactions can't be disputed again, so theirs is the latest settlement.
+        self.settled_disputes.clear();
+        for state in self.client_states.values() {
+            for tx_id in state.disputes_in(DisputeState::Resolved) {
+                self.settled_disputes
+                    .entry(tx_id)
+                    .or_insert(DisputeState::Resolved);
+            }
+            for tx_id in state.disputes_in(DisputeState::ChargedBack) {
+                self.settled_disputes
+                    .insert(tx_id, DisputeState::ChargedBack);
+            }
+        }
+        self.dispute_clock = snapshot.dispute_clock;
+        // Snapshots from engines which didn't skip replays start an empty set.
+        if self.processed.is_some() {
+            let mut processed = ProcessedSet::new();
+            for client in snapshot.processed.unwrap_or_default() {
+                processed.insert_ranges(client.client, &client.ranges);
+            }
+            self.processed = Some(processed);
+        }
+        self.queued.clear();
+        for tx in snapshot.queued {
+            self.queued.entry(tx.client).or_default().push(Transaction {
+                r#type: tx.r#type,
+                client_id: tx.client,
+                tx_id: tx.tx,
+                amount: tx.amount,
+                raw_amount: None,
+                line: tx.line,
+                terminal: None,
+                currency: tx.currency,
+                to_currency: tx.to_currency,
+                timestamp: None,
+            });
+        }
+
+        Ok(self)
+    }
+}
+
+/// Write a snapshot of `engine` to `path`. The snapshot is written next to `path` and renamed into
+/// place (see `AtomicFile`), so an interrupted run never leaves a partial snapshot behind (and the
+/// snapshot being replaced can be the one the run started from).
+pub fn write_file(engine: &Engine, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut file = AtomicFile::create(path)?;
+    let mut writer = io::BufWriter::new(&mut file);
+    engine.write_snapshot(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+
+    file.commit()?;
+
+    Ok(())
+}
+
+/// Restore a snapshot from `path` into `engine`.
+pub fn read_file(engine: Engine, path: &Path) -> Result<Engine, Box<dyn Error>> {
+    engine.restore_snapshot(io::BufReader::new(File::open(path)?))
+}
@@ -0,0 +1,414 @@
+/// Storage for transactions which can still be disputed.
+///
+/// Only the parts of a deposit or withdrawal needed to handle a dispute are stored (see
+/// `DisputableTx`), keyed by transaction ID. Without a memory limit every deposit and withdrawal
+/// is kept in memory. With a limit,
+/// transactions are held in two in-memory generations. Once the newer generation fills up, the
+/// older one is spilled to a temporary file as a run of fixed-size records sorted by ID, so recent
+/// transactions (the ones most likely to be disputed) stay in memory.
+///
+/// Lookups check memory first, then each spilled run whose ID range could contain the transaction.
+/// A run only keeps the first ID of each block of `BLOCK_RECORDS` records in memory, so finding a
+/// spilled transaction reads a single block. Runs don't overlap when transaction IDs increase
+/// through the input, but with IDs in no particular order, a lookup may read a block from every
+/// run.
+///
+/// With a dispute window, transactions which can no longer be disputed are evicted from memory, and
+/// spilled runs are dropped once none of their transactions can be disputed.
+///
+/// Engines keeping their state in a SQLite ledger (`--db`) store every transaction there instead,
+/// with no memory limit (see `ledger`).
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use rust_decimal::Decimal;
+
+use crate::currency::Currency;
+use crate::money::Money;
+use crate::TransactionType;
+
+/// Size of a spilled transaction: ID, client ID, type, amount, currency, and when it was applied.
+const RECORD_BYTES: usize = 4 + 2 + 1 + 16 + 3 + 8;
+/// Number of spilled records read at a time when looking a transaction up.
+const BLOCK_RECORDS: usize = 256;
+/// Rough memory used by each transaction held in memory (the map entry, and the map's control
+/// byte).
+const ENTRY_BYTES: usize = mem::size_of::<(u32, DisputableTx)>() + 1;
+
+/// Number of spill files created by this process, so each one gets a unique name.
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether a disputable transaction is a deposit or a withdrawal. Kept in a byte, rather than as a
+/// whole `TransactionType`, since every disputable transaction is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputableKind {
+    Deposit,
+    Withdrawal,
+}
+
+impl DisputableKind {
+    /// The kind of a transaction of some type, if it's disputable.
+    pub fn of(r#type: TransactionType) -> Option<Self> {
+        match r#type {
+            TransactionType::Deposit => Some(DisputableKind::Deposit),
+            TransactionType::Withdrawal => Some(DisputableKind::Withdrawal),
+            _ => None,
+        }
+    }
+}
+
+impl From<DisputableKind> for TransactionType {
+    fn from(kind: DisputableKind) -> Self {
+        match kind {
+            DisputableKind::Deposit => TransactionType::Deposit,
+            DisputableKind::Withdrawal => TransactionType::Withdrawal,
+        }
+    }
+}
+
+/// A deposit or withdrawal which can be disputed. Amounts have already been validated, so they're
+/// always present.
+#[derive(Debug, Clone, Copy)]
+pub struct DisputableTx {
+    pub kind: DisputableKind,
+    pub client_id: u16,
+    pub amount: Money,
+    pub currency: Currency,
+    /// When the transaction was applied, on the engine's dispute window clock (zero without a
+    /// dispute window).
+    pub at: u64,
+}
+
+impl DisputableTx {
+    /// The transaction's type, `Deposit` or `Withdrawal`.
+    pub fn r#type(&self) -> TransactionType {
+        self.kind.into()
+    }
+}
+
+/// Transactions which can be disputed, by ID.
+#[derive(Default)]
+pub struct DisputableStore {
+    /// Most recently stored transactions.
+    newer: HashMap<u32, DisputableTx>,
+    /// Transactions which will be spilled once `newer` fills up.
+    older: HashMap<u32, DisputableTx>,
+    /// Most transactions held in each generation, if memory is limited.
+    generation_size: Option<usize>,
+    /// Transactions spilled to disk, once any have been.
+    spill: Option<Spill>,
+    /// The ledger every transaction is stored in instead, if there is one.
+    #[cfg(feature = "sqlite")]
+    pub(crate) ledger: Option<crate::ledger::Ledger>,
+}
+
+impl DisputableStore {
+    /// A store which keeps roughly `bytes` of transactions in memory, spilling the rest to disk.
+    pub fn with_memory_limit(bytes: usize) -> Self {
+        DisputableStore {
+            generation_size: Some((bytes / ENTRY_BYTES / 2).max(1)),
+            ..Default::default()
+        }
+    }
+
+    /// Whether some transaction has been stored.
+    pub(crate) fn contains(&self, tx_id: u32) -> io::Result<bool> {
+        Ok(self.get(tx_id)?.is_some())
+    }
+
+    /// The stored transaction with some ID, if there is one.
+    pub(crate) fn get(&self, tx_id: u32) -> io::Result<Option<DisputableTx>> {
+        #[cfg(feature = "sqlite")]
+        if let Some(ledger) = &self.ledger {
+            return ledger.get(tx_id);
+        }
+        if let Some(tx) = self.newer.get(&tx_id).or_else(|| self.older.get(&tx_id)) {
+            return Ok(Some(*tx));
+        }
+
+        match &self.spill {
+            Some(spill) => spill.get(tx_id),
+            None => Ok(None),
+        }
+    }
+
+    /// Store a deposit or withdrawal.
+    pub(crate) fn insert(&mut self, tx_id: u32, tx: DisputableTx) -> io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Some(ledger) = &self.ledger {
+            return ledger.insert(tx_id, tx);
+        }
+        if let Some(generation_size) = self.generation_size {
+            if self.newer.len() >= generation_size {
+                let older = mem::replace(&mut self.older, mem::take(&mut self.newer));
+                if !older.is_empty() {
+                    let spill = match self.spill.as_mut() {
+                        Some(spill) => spill,
+                        None => self.spill.insert(Spill::create()?),
+                    };
+                    spill.write_run(older)?;
+                }
+            }
+        }
+
+        self.newer.insert(tx_id, tx);
+
+        Ok(())
+    }
+
+    /// Number of stored transactions, in memory or spilled.
+    pub(crate) fn stored_count(&self) -> io::Result<usize> {
+        #[cfg(feature = "sqlite")]
+        if let Some(ledger) = &self.ledger {
+            return ledger.stored_count();
+        }
+        let spilled = self
+            .spill
+            .as_ref()
+            .map_or(0, |spill| spill.runs.iter().map(|run| run.records).sum());
+
+        Ok(self.newer.len() + self.older.len() + spilled)
+    }
+
+    /// Every stored transaction, in no particular order.
+    pub(crate) fn transactions(&self) -> io::Result<Vec<(u32, DisputableTx)>> {
+        #[cfg(feature = "sqlite")]
+        if let Some(ledger) = &self.ledger {
+            return ledger.transactions();
+        }
+        let mut transactions: Vec<(u32, DisputableTx)> = self
+            .newer
+            .iter()
+            .chain(self.older.iter())
+            .map(|(&tx_id, &tx)| (tx_id, tx))
+            .collect();
+        if let Some(spill) = &self.spill {
+            spill.read_all(&mut transactions)?;
+        }
+
+        Ok(transactions)
+    }
+
+    /// Forget transactions applied before `cutoff`, other than those in `keep` (e.g. because
+    /// they're under dispute).
+    pub(crate) fn evict(&mut self, cutoff: u64, keep: &HashSet<u32>) -> io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Some(ledger) = &self.ledger {
+            return ledger.evict(cutoff, keep);
+        }
+        let current = |tx_id: &u32, tx: &mut DisputableTx| tx.at >= cutoff || keep.contains(tx_id);
+        self.newer.retain(current);
+        self.older.retain(current);
+
+        if let Some(spill) = &mut self.spill {
+            spill.runs.retain(|run| {
+                run.latest >= cutoff
+                    || keep
+                        .iter()
+                        .any(|&tx_id| tx_id >= run.block_starts[0] && tx_id <= run.last)
+            });
+            if spill.runs.is_empty() {
+                self.spill = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove every stored transaction, keeping the memory limit (or ledger).
+    pub(crate) fn clear(&mut self) -> io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Some(ledger) = &self.ledger {
+            return ledger.clear();
+        }
+        self.newer.clear();
+        self.older.clear();
+        self.spill = None;
+
+        Ok(())
+    }
+}
+
+/// A temporary file of spilled runs, removed when dropped.
+struct Spill {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// Bytes written to the file so far.
+    len: u64,
+    runs: Vec<Run>,
+}
+
+/// Transactions spilled at the same time, sorted by ID.
+struct Run {
+    /// Where the run starts in the spill file.
+    offset: u64,
+    records: usize,
+    /// ID of the first transaction in each block.
+    block_starts: Vec<u32>,
+    /// ID of the last transaction in the run.
+    last: u32,
+    /// When the most recently applied transaction in the run was applied.
+    latest: u64,
+}
+
+impl Spill {
+    fn create() -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "payment-engine-spill-{}-{}",
+            std::process::id(),
+            SPILL_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        Ok(Spill {
+            path,
+            file: Mutex::new(file),
+            len: 0,
+            runs: Vec::new(),
+        })
+    }
+
+    fn write_run(&mut self, transactions: HashMap<u32, DisputableTx>) -> io::Result<()> {
+        let mut transactions: Vec<(u32, DisputableTx)> = transactions.into_iter().collect();
+        transactions.sort_unstable_by_key(|&(tx_id, _)| tx_id);
+
+        let file = self.file.get_mut().unwrap_or_else(PoisonError::into_inner);
+        file.seek(SeekFrom::Start(self.len))?;
+        let mut writer = io::BufWriter::new(file);
+        for &(tx_id, tx) in &transactions {
+            writer.write_all(&encode(tx_id, tx))?;
+        }
+        writer.flush()?;
+
+        self.runs.push(Run {
+            offset: self.len,
+            records: transactions.len(),
+            block_starts: transactions
+                .chunks(BLOCK_RECORDS)
+                .map(|block| block[0].0)
+                .collect(),
+            last: transactions[transactions.len() - 1].0,
+            latest: transactions.iter().map(|(_, tx)| tx.at).max().unwrap_or(0),
+        });
+        self.len += (transactions.len() * RECORD_BYTES) as u64;
+
+        Ok(())
+    }
+
+    fn get(&self, tx_id: u32) -> io::Result<Option<DisputableTx>> {
+        for run in &self.runs {
+            if tx_id < run.block_starts[0] || tx_id > run.last {
+                continue;
+            }
+
+            let block = run.block_starts.partition_point(|&start| start <= tx_id) - 1;
+            let first = block * BLOCK_RECORDS;
+            let count = BLOCK_RECORDS.min(run.records - first);
+            let bytes = self.read(run.offset + (first * RECORD_BYTES) as u64, count)?;
+
+            let records: Vec<&[u8]> = bytes.chunks_exact(RECORD_BYTES).collect();
+            if let Ok(index) = records.binary_search_by_key(&tx_id, |record| record_id(record)) {
+                return Ok(Some(decode(records[index]).1));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn read_all(&self, transactions: &mut Vec<(u32, DisputableTx)>) -> io::Result<()> {
+        for run in &self.runs {
+            let bytes = self.read(run.offset, run.records)?;
+            transactions.extend(bytes.chunks_exact(RECORD_BYTES).map(decode));
+        }
+
+        Ok(())
+    }
+
+    /// Read `count` records starting at `offset`.
+    fn read(&self, offset: u64, count: usize) -> io::Result<Vec<u8>> {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut bytes = vec![0; count * RECORD_BYTES];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut bytes)?;
+
+        Ok(bytes)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn encode(tx_id: u32, tx: DisputableTx) -> [u8; RECORD_BYTES] {
+    let mut record = [0; RECORD_BYTES];
+    record[0..4].copy_from_slice(&tx_id.to_le_bytes());
+    record[4..6].copy_from_slice(&tx.client_id.to_le_bytes());
+    record[6] = match tx.kind {
+        DisputableKind::Deposit => 0,
+        DisputableKind::Withdrawal => 1,
+    };
+    let amount: Decimal = tx.amount.into();
+    record[7..23].copy_from_slice(&amount.serialize());
+    record[23..26].copy_from_slice(&tx.currency.to_bytes());
+    record[26..].copy_from_slice(&tx.at.to_le_bytes());
+
+    record
+}
+
+fn record_id(record: &[u8]) -> u32 {
+    u32::from_le_bytes([record[0], record[1], record[2], record[3]])
+}
+
+fn decode(record: &[u8]) -> (u32, DisputableTx) {
+    let mut amount = [0; 16];
+    amount.copy_from_slice(&record[7..23]);
+    let mut currency = [0; 3];
+    currency.copy_from_slice(&record[23..26]);
+    let mut at = [0; 8];
+    at.copy_from_slice(&record[26..]);
+
+    let tx = DisputableTx {
+        kind: match record[6] {
+            0 => DisputableKind::Deposit,
+            _ => DisputableKind::Withdrawal,
+        },
+        client_id: u16::from_le_bytes([record[4], record[5]]),
+        amount: Money::new(Decimal::deserialize(amount)),
+        currency: Currency::from_bytes(currency),
+        at: u64::from_le_bytes(at),
+    };
+    (record_id(record), tx)
+}
+
+/// Parse a memory size given in bytes, or with a `K`, `M`, or `G` suffix (powers of 1024, e.g.
+/// `512M`).
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let error = || {
+        format!(
+            "invalid memory size '{}', expected bytes or a size like 512M or 2G",
+            s
+        )
+    };
+
+    let (digits, scale) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    match digits.parse::<usize>() {
+        Ok(0) | Err(_) => Err(error()),
+        Ok(size) => size.checked_mul(scale).ok_or_else(error),
+    }
+}
@@ -0,0 +1,51 @@
+/// Async ingestion of transactions (requires the `tokio` feature).
+///
+/// Lets the engine consume transactions from async sources (e.g. TCP sockets or message queues)
+/// without dedicating a thread to each source.
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::poll_fn;
+
+use futures_core::Stream;
+
+use crate::error::TransactionError;
+use crate::{fatal_error, report_released, ClientState, Engine, Transaction};
+
+/// Number of transactions applied between yielding to the runtime, so a stream which is always
+/// ready can't starve other tasks.
+const STREAM_YIELD_INTERVAL: usize = 1024;
+
+impl Engine {
+    /// Apply every transaction from an async stream, in the order they arrive. Rejected
+    /// transactions are handled the same way as `process_transactions`, and the final client
+    /// states are returned once the stream ends.
+    pub async fn process_stream<S, F>(
+        mut self,
+        transactions: S,
+        mut on_reject: F,
+    ) -> Result<HashMap<u16, ClientState>, Box<dyn Error>>
+    where
+        S: Stream<Item = Transaction>,
+        F: FnMut(&Transaction, &TransactionError) -> Result<(), Box<dyn Error>>,
+    {
+        let mut transactions = std::pin::pin!(transactions);
+        let mut applied = 0;
+
+        while let Some(tx) = poll_fn(|cx| transactions.as_mut().poll_next(cx)).await {
+            if let Err(e) = self.apply(&tx) {
+                if self.is_fatal(&e) {
+                    return Err(fatal_error(&tx, e));
+                }
+                on_reject(&tx, &e)?;
+            }
+            report_released(&mut self, &mut on_reject)?;
+
+            applied += 1;
+            if applied % STREAM_YIELD_INTERVAL == 0 {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        Ok(self.into_client_states())
+    }
+}
@@ -0,0 +1,161 @@
+/// Summary statistics for a batch run (`--summary`), as a sanity check of what it did.
+///
+/// The summary sees each transaction as it's read and is told about each rejection, so it doesn't
+/// depend on how the log is applied. It counts transactions by type, rejections by reason, and
+/// skipped rows, and totals the deposits and withdrawals which were applied (per currency, as
+/// amounts in different currencies can't be added up). With the number of locked accounts at the end of the run and the
+/// throughput, it's written as plain text, a line per figure.
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::currency::Currency;
+use crate::error::TransactionError;
+use crate::metrics::{type_index, TYPES};
+use crate::money::Money;
+use crate::{Transaction, TransactionKind, TransactionType};
+
+/// Totals for a run, built up as transactions are read and rejected.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    started: Instant,
+    /// Transactions read, in the order of `metrics::TYPES`.
+    transactions: [u64; TYPES.len()],
+    /// Rejected transactions, by reason.
+    rejects: BTreeMap<&'static str, u64>,
+    /// Rows which couldn't be parsed, and were skipped (`--skip-bad-rows`).
+    skipped_rows: u64,
+    deposited: BTreeMap<Currency, Money>,
+    withdrawn: BTreeMap<Currency, Money>,
+    /// Whether the run was interrupted before the whole log was read.
+    interrupted: bool,
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Summary {
+            started: Instant::now(),
+            transactions: Default::default(),
+            rejects: BTreeMap::new(),
+            skipped_rows: 0,
+            deposited: BTreeMap::new(),
+            withdrawn: BTreeMap::new(),
+            interrupted: false,
+        }
+    }
+}
+
+impl Summary {
+    /// Start a summary, timing the run from now.
+    pub fn new() -> Self {
+        Summary::default()
+    }
+
+    /// Record a transaction which was read. Deposits and withdrawals count towards the volume
+    /// until they're rejected.
+    pub fn observe(&mut self, tx: &Transaction) {
+        self.transactions[type_index(tx.r#type)] += 1;
+        match tx.kind() {
+            Ok(TransactionKind::Deposit { amount }) => {
+                *self.deposited.entry(tx.currency).or_default() += amount
+            }
+            Ok(TransactionKind::Withdrawal { amount }) => {
+                *self.withdrawn.entry(tx.currency).or_default() += amount
+            }
+            _ => {}
+        }
+    }
+
+    /// Record that a transaction (which has already been observed) was rejected.
+    pub fn reject(&mut self, tx: &Transaction, e: &TransactionError) {
+        *self.rejects.entry(e.reason()).or_default() += 1;
+        match tx.kind() {
+            Ok(TransactionKind::Deposit { amount }) => {
+                *self.deposited.entry(tx.currency).or_default() -= amount
+            }
+            Ok(TransactionKind::Withdrawal { amount }) => {
+                *self.withdrawn.entry(tx.currency).or_default() -= amount
+            }
+            _ => {}
+        }
+    }
+
+    /// Record rows which couldn't be parsed, and were skipped.
+    pub fn skip_rows(&mut self, rows: u64) {
+        self.skipped_rows += rows;
+    }
+
+    /// Record that the run was interrupted, so the summary only covers part of the log.
+    pub fn interrupt(&mut self) {
+        self.interrupted = true;
+    }
+
+    /// Number of rows which were skipped.
+    pub fn skipped_rows(&self) -> u64 {
+        self.skipped_rows
+    }
+
+    /// Number of transactions of some type which were read (of any custom type, for a custom
+    /// type).
+    pub fn transactions(&self, r#type: TransactionType) -> u64 {
+        self.transactions[type_index(r#type)]
+    }
+
+    /// Total of the deposits applied in some currency.
+    pub fn deposited(&self, currency: Currency) -> Money {
+        self.deposited.get(&currency).copied().unwrap_or_default()
+    }
+
+    /// Total of the withdrawals applied in some currency.
+    pub fn withdrawn(&self, currency: Currency) -> Money {
+        self.withdrawn.get(&currency).copied().unwrap_or_default()
+    }
+
+    /// Write the summary, given the number of accounts locked at the end of the run.
+    pub fn write<W: Write>(&self, mut writer: W, locked_accounts: usize) -> io::Result<()> {
+        let total: u64 = self.transactions.iter().sum();
+        if self.interrupted {
+            writeln!(writer, "interrupted: only part of the log was read")?;
+        }
+        writeln!(writer, "transactions: {}", total)?;
+        for (name, count) in TYPES.iter().zip(&self.transactions) {
+            if *count > 0 {
+                writeln!(writer, "  {}: {}", name, count)?;
+            }
+        }
+
+        writeln!(writer, "rejected: {}", self.rejects.values().sum::<u64>())?;
+        for (reason, count) in &self.rejects {
+            writeln!(writer, "  {}: {}", reason, count)?;
+        }
+        if self.skipped_rows > 0 {
+            writeln!(writer, "skipped rows: {}", self.skipped_rows)?;
+        }
+
+        for (label, totals) in [
+            ("deposited", &self.deposited),
+            ("withdrawn", &self.withdrawn),
+        ] {
+            if totals.is_empty() {
+                writeln!(writer, "{}: {}", label, Money::ZERO)?;
+            }
+            for (currency, amount) in totals {
+                if currency.is_implicit() {
+                    writeln!(writer, "{}: {}", label, amount)?;
+                } else {
+                    writeln!(writer, "{}: {} {}", label, amount, currency)?;
+                }
+            }
+        }
+
+        writeln!(writer, "locked accounts: {}", locked_accounts)?;
+
+        let elapsed = self.started.elapsed().max(Duration::from_micros(1));
+        writeln!(
+            writer,
+            "throughput: {:.0} transactions/s ({:.3}s)",
+            total as f64 / elapsed.as_secs_f64(),
+            elapsed.as_secs_f64()
+        )
+    }
+}
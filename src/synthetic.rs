@@ -0,0 +1,155 @@
+/// Synthetic transaction logs, for benchmarking and load testing.
+///
+/// Logs are generated from a seed, so the same options always produce the same log. Deposits and
+/// withdrawals are spread evenly across clients, and withdrawals are mostly (but not always) within
+/// the client's balance. A fraction of deposits are disputed shortly after they're made, and every
+/// dispute is settled a little later by a resolve or (less often) a chargeback.
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::io::{self, Write};
+
+/// Number of recent deposits which can be picked for a dispute.
+const RECENT_DEPOSITS: usize = 1024;
+/// Most rows between a dispute and its settlement.
+const MAX_SETTLEMENT_DELAY: u64 = 1000;
+
+/// Options for a synthetic transaction log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generator {
+    /// Number of rows to generate (excluding the header).
+    pub rows: u64,
+    /// Number of distinct clients, with IDs starting at 1.
+    pub clients: u16,
+    /// Fraction of rows which are disputes.
+    pub dispute_rate: f64,
+    /// Fraction of disputes settled by a chargeback rather than a resolve.
+    pub chargeback_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Generator {
+            rows: 1_000_000,
+            clients: 5000,
+            dispute_rate: 0.01,
+            chargeback_rate: 0.1,
+            seed: 0,
+        }
+    }
+}
+
+impl Generator {
+    /// Write the log as CSV, with the same header as real transaction logs.
+    pub fn write_csv<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(writer);
+        let mut rng = SplitMix64(self.seed);
+        let mut balances = vec![0u64; usize::from(self.clients)];
+        let mut recent_deposits = VecDeque::with_capacity(RECENT_DEPOSITS);
+        // Disputes waiting to be settled, by the row they'll be settled on.
+        let mut unsettled = BinaryHeap::new();
+        let mut next_tx_id: u32 = 1;
+
+        writeln!(writer, "type,client,tx,amount")?;
+        for row in 0..self.rows {
+            if let Some(&Reverse((due, client, tx_id))) = unsettled.peek() {
+                if due <= row {
+                    unsettled.pop();
+                    let settlement = if rng.chance(self.chargeback_rate) {
+                        "chargeback"
+                    } else {
+                        "resolve"
+                    };
+                    writeln!(writer, "{},{},{},", settlement, client, tx_id)?;
+                    continue;
+                }
+            }
+
+            if rng.chance(self.dispute_rate) && !recent_deposits.is_empty() {
+                let index = rng.below(recent_deposits.len() as u64) as usize;
+                if let Some((client, tx_id)) = recent_deposits.swap_remove_back(index) {
+                    writeln!(writer, "dispute,{},{},", client, tx_id)?;
+                    let due = row + 1 + rng.below(MAX_SETTLEMENT_DELAY);
+                    unsettled.push(Reverse((due, client, tx_id)));
+                    continue;
+                }
+            }
+
+            let client = 1 + rng.below(u64::from(self.clients)) as u16;
+            let balance = &mut balances[usize::from(client - 1)];
+            let tx_id = next_tx_id;
+            next_tx_id = next_tx_id.wrapping_add(1);
+
+            // Amounts are in hundredths, and mostly small.
+            if *balance > 0 && rng.chance(0.3) {
+                // Around 1 in 20 withdrawals are for more than the client has.
+                let amount = if rng.chance(0.05) {
+                    *balance + 1 + rng.below(10_000)
+                } else {
+                    1 + rng.below(*balance)
+                };
+                *balance = balance.saturating_sub(amount);
+                writeln!(
+                    writer,
+                    "withdrawal,{},{},{}.{:02}",
+                    client,
+                    tx_id,
+                    amount / 100,
+                    amount % 100
+                )?;
+            } else {
+                let amount = 1 + rng.below(100_000);
+                *balance += amount;
+                writeln!(
+                    writer,
+                    "deposit,{},{},{}.{:02}",
+                    client,
+                    tx_id,
+                    amount / 100,
+                    amount % 100
+                )?;
+
+                if recent_deposits.len() == RECENT_DEPOSITS {
+                    recent_deposits.pop_front();
+                }
+                recent_deposits.push_back((client, tx_id));
+            }
+        }
+
+        writer.flush()
+    }
+}
+
+/// Parse a rate given as a percentage (e.g. `2.5%`), returning it as a fraction.
+pub fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.trim_end_matches('%').parse::<f64>() {
+        Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent / 100.0),
+        _ => Err(format!(
+            "invalid rate '{}', expected a percentage between 0% and 100%",
+            s
+        )),
+    }
+}
+
+/// A small, fast pseudo-random number generator. Not suitable for anything but test data.
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// True with probability `p`.
+    pub(crate) fn chance(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
@@ -0,0 +1,212 @@
+/// Per-terminal activity, for spotting terminals with unusual reject or dispute rates.
+///
+/// Transactions can name the terminal (or session) they were submitted from in an optional
+/// `terminal` column. Transactions without a terminal aren't counted.
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+
+use serde::Serialize;
+
+use crate::money::Money;
+use crate::output::OutputDialect;
+use crate::{Transaction, TransactionKind, TransactionType};
+
+/// Terminals are only flagged once they've submitted at least this many transactions, so a
+/// handful of rejects at a quiet terminal doesn't stand out.
+pub const MIN_TRANSACTIONS_TO_FLAG: usize = 20;
+
+/// A terminal is flagged when its reject or dispute rate is more than this many times the rate
+/// across all terminals.
+pub const ANOMALY_FACTOR: f64 = 2.0;
+
+#[derive(Debug, Default, Clone)]
+struct TerminalCounts {
+    /// Every transaction submitted from the terminal.
+    transactions: usize,
+    /// Deposits and withdrawals submitted from the terminal.
+    payments: usize,
+    /// Total amount of deposits and withdrawals submitted (whether or not they were applied).
+    volume: Money,
+    /// Transactions from the terminal which were rejected.
+    rejected: usize,
+    /// Disputes against deposits or withdrawals from the terminal (wherever the dispute was
+    /// submitted from).
+    disputed: usize,
+}
+
+/// A row in the terminal report.
+#[derive(Debug, Serialize)]
+struct TerminalRecord<'a> {
+    terminal: &'a str,
+    transactions: usize,
+    volume: Money,
+    rejected: usize,
+    disputed: usize,
+    reject_rate: String,
+    dispute_rate: String,
+    flagged: bool,
+}
+
+/// Counts of activity per terminal, built up as transactions are read and rejected.
+#[derive(Debug, Default)]
+pub struct TerminalStats {
+    terminals: Vec<(String, TerminalCounts)>,
+    indices: HashMap<String, usize>,
+    /// Terminal each deposit/withdrawal was submitted from, so disputes can be counted against it.
+    tx_terminals: HashMap<u32, usize>,
+}
+
+impl TerminalStats {
+    /// Record a transaction as it's read.
+    pub fn observe(&mut self, tx: &Transaction) {
+        if let TransactionType::Dispute = tx.r#type {
+            if let Some(&index) = self.tx_terminals.get(&tx.tx_id) {
+                self.terminals[index].1.disputed += 1;
+            }
+        }
+
+        let index = match &tx.terminal {
+            Some(terminal) => self.index(terminal),
+            None => return,
+        };
+        let counts = &mut self.terminals[index].1;
+        counts.transactions += 1;
+        if let TransactionType::Deposit | TransactionType::Withdrawal = tx.r#type {
+            counts.payments += 1;
+            if let Ok(
+                TransactionKind::Deposit { amount } | TransactionKind::Withdrawal { amount },
+            ) = tx.kind()
+            {
+                counts.volume += amount;
+            }
+            self.tx_terminals.insert(tx.tx_id, index);
+        }
+    }
+
+    /// Record that a transaction (which has already been observed) was rejected.
+    pub fn reject(&mut self, tx: &Transaction) {
+        // Disputes which were rejected didn't actually dispute anything.
+        if let TransactionType::Dispute = tx.r#type {
+            if let Some(&index) = self.tx_terminals.get(&tx.tx_id) {
+                self.terminals[index].1.disputed -= 1;
+            }
+        }
+
+        if let Some(terminal) = &tx.terminal {
+            let index = self.index(terminal);
+            self.terminals[index].1.rejected += 1;
+        }
+    }
+
+    /// Terminals whose reject or dispute rate is anomalous, compared to all terminals.
+    pub fn flagged(&self) -> Vec<&str> {
+        let (reject_threshold, dispute_threshold) = self.thresholds();
+
+        self.sorted()
+            .into_iter()
+            .filter(|(_, counts)| is_anomalous(counts, reject_threshold, dispute_threshold))
+            .map(|(terminal, _)| terminal)
+            .collect()
+    }
+
+    /// Write a CSV report with one row per terminal (ordered by terminal).
+    pub fn write_report<W>(&self, writer: W, dialect: &OutputDialect) -> Result<(), Box<dyn Error>>
+    where
+        W: io::Write,
+    {
+        let (reject_threshold, dispute_threshold) = self.thresholds();
+
+        // Headers are written explicitly, so the report has one even without any terminals.
+        let mut writer = dialect
+            .writer_builder()
+            .has_headers(false)
+            .from_writer(writer);
+        writer.write_record([
+            "terminal",
+            "transactions",
+            "volume",
+            "rejected",
+            "disputed",
+            "reject_rate",
+            "dispute_rate",
+            "flagged",
+        ])?;
+        for (terminal, counts) in self.sorted() {
+            writer.serialize(TerminalRecord {
+                terminal,
+                transactions: counts.transactions,
+                volume: counts.volume,
+                rejected: counts.rejected,
+                disputed: counts.disputed,
+                reject_rate: format!("{:.4}", reject_rate(counts)),
+                dispute_rate: format!("{:.4}", dispute_rate(counts)),
+                flagged: is_anomalous(counts, reject_threshold, dispute_threshold),
+            })?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    fn index(&mut self, terminal: &str) -> usize {
+        if let Some(&index) = self.indices.get(terminal) {
+            return index;
+        }
+
+        let index = self.terminals.len();
+        self.terminals
+            .push((terminal.to_string(), TerminalCounts::default()));
+        self.indices.insert(terminal.to_string(), index);
+
+        index
+    }
+
+    fn sorted(&self) -> Vec<(&str, &TerminalCounts)> {
+        let mut terminals: Vec<_> = self
+            .terminals
+            .iter()
+            .map(|(terminal, counts)| (terminal.as_str(), counts))
+            .collect();
+        terminals.sort_by_key(|(terminal, _)| *terminal);
+
+        terminals
+    }
+
+    /// Reject and dispute rates above which a terminal is flagged.
+    fn thresholds(&self) -> (f64, f64) {
+        let mut total = TerminalCounts::default();
+        for (_, counts) in &self.terminals {
+            total.transactions += counts.transactions;
+            total.payments += counts.payments;
+            total.rejected += counts.rejected;
+            total.disputed += counts.disputed;
+        }
+
+        (
+            reject_rate(&total) * ANOMALY_FACTOR,
+            dispute_rate(&total) * ANOMALY_FACTOR,
+        )
+    }
+}
+
+fn reject_rate(counts: &TerminalCounts) -> f64 {
+    ratio(counts.rejected, counts.transactions)
+}
+
+fn dispute_rate(counts: &TerminalCounts) -> f64 {
+    ratio(counts.disputed, counts.payments)
+}
+
+fn ratio(count: usize, of: usize) -> f64 {
+    if of == 0 {
+        0.0
+    } else {
+        count as f64 / of as f64
+    }
+}
+
+fn is_anomalous(counts: &TerminalCounts, reject_threshold: f64, dispute_threshold: f64) -> bool {
+    counts.transactions >= MIN_TRANSACTIONS_TO_FLAG
+        && (reject_rate(counts) > reject_threshold || dispute_rate(counts) > dispute_threshold)
+}
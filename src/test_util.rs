@@ -0,0 +1,388 @@
+/// Property testing support for code embedding the engine (the `test-util` feature).
+///
+/// `transactions` and `transaction_logs` are `proptest` strategies for arbitrary transactions, and
+/// for well-formed logs of deposits, withdrawals, disputes, resolves, and chargebacks (with
+/// `Shape` for other sizes). Logs aren't all valid (withdrawals overdraw, disputes reference
+/// unknown or already disputed transactions, and so on), so rejections are exercised as well.
+/// Disputes, resolves, and chargebacks are generated against the deposits and withdrawals before
+/// them, so a failing log shrinks (by dropping transactions, and making amounts smaller) without
+/// its references going astray. `Oracle` is a reference model of the specification, simple enough
+/// to check by eye, which should agree with an `Engine` using the default policies
+/// (`matches_oracle`).
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use proptest::prelude::*;
+use proptest::sample::Index;
+use rust_decimal::Decimal;
+
+use crate::currency::Currency;
+use crate::money::Money;
+use crate::{Engine, Transaction, TransactionType};
+
+/// The shape of generated transactions and logs.
+#[derive(Debug, Clone, Copy)]
+pub struct Shape {
+    /// Most transactions in a log.
+    pub max_len: usize,
+    /// Distinct clients. Few clients means more interplay between each client's transactions.
+    pub clients: u16,
+    /// Largest deposit or withdrawal, in ten-thousandths.
+    pub max_amount: i64,
+}
+
+impl Default for Shape {
+    fn default() -> Self {
+        Shape {
+            max_len: 50,
+            clients: 3,
+            max_amount: 100_0000,
+        }
+    }
+}
+
+impl Shape {
+    /// Single transactions of any type, with an amount if their type takes one, and IDs from a
+    /// small range so they sometimes reference each other.
+    pub fn transactions(&self) -> impl Strategy<Value = Transaction> {
+        let shape = *self;
+        (
+            transaction_type(),
+            shape.clients(),
+            1..=shape.max_len.max(1) as u32,
+            shape.amount(),
+        )
+            .prop_map(|(r#type, client_id, tx_id, amount)| {
+                let amount = is_payment(r#type).then(|| ten_thousandths(amount));
+                transaction(r#type, client_id, tx_id, amount)
+            })
+    }
+
+    /// Logs of up to `max_len` transactions, with disputes, resolves, and chargebacks mostly of
+    /// the deposits and withdrawals before them.
+    pub fn logs(&self) -> impl Strategy<Value = Vec<Transaction>> {
+        let shape = *self;
+        let step = (
+            transaction_type(),
+            shape.clients(),
+            shape.amount(),
+            // Now and then an ID is reused.
+            proptest::option::weighted(0.05, any::<Index>()),
+            // Mostly the client's own transactions, sometimes anyone's, rarely nothing at all.
+            prop_oneof![
+                8 => any::<Index>().prop_map(Target::Payment),
+                1 => any::<Index>().prop_map(Target::AnyClients),
+                1 => (0..10u32).prop_map(Target::Unknown),
+            ],
+        )
+            .prop_map(|(r#type, client_id, amount, reused, target)| {
+                if is_payment(r#type) {
+                    Step::Payment {
+                        r#type,
+                        client_id,
+                        amount,
+                        reused,
+                    }
+                } else {
+                    Step::Reference {
+                        r#type,
+                        client_id,
+                        target,
+                    }
+                }
+            });
+
+        proptest::collection::vec(step, 0..=shape.max_len).prop_map(resolve)
+    }
+
+    fn clients(&self) -> impl Strategy<Value = u16> {
+        1..=self.clients.max(1)
+    }
+
+    fn amount(&self) -> impl Strategy<Value = i64> {
+        1..=self.max_amount.max(1)
+    }
+}
+
+/// Arbitrary transactions, of the default shape (see `Shape::transactions`).
+pub fn transactions() -> impl Strategy<Value = Transaction> {
+    Shape::default().transactions()
+}
+
+/// Arbitrary logs, of the default shape (see `Shape::logs`).
+pub fn transaction_logs() -> impl Strategy<Value = Vec<Transaction>> {
+    Shape::default().logs()
+}
+
+/// Deposits and withdrawals more often than anything else.
+fn transaction_type() -> impl Strategy<Value = TransactionType> {
+    prop_oneof![
+        35 => Just(TransactionType::Deposit),
+        20 => Just(TransactionType::Withdrawal),
+        20 => Just(TransactionType::Dispute),
+        15 => Just(TransactionType::Resolve),
+        10 => Just(TransactionType::Chargeback),
+    ]
+}
+
+fn is_payment(r#type: TransactionType) -> bool {
+    matches!(
+        r#type,
+        TransactionType::Deposit | TransactionType::Withdrawal
+    )
+}
+
+fn ten_thousandths(amount: i64) -> Money {
+    Decimal::new(amount, 4).into()
+}
+
+/// A transaction in a generated log, before the transactions it references are known.
+#[derive(Debug, Clone)]
+enum Step {
+    Payment {
+        r#type: TransactionType,
+        client_id: u16,
+        amount: i64,
+        /// An earlier deposit or withdrawal whose ID is reused.
+        reused: Option<Index>,
+    },
+    Reference {
+        r#type: TransactionType,
+        client_id: u16,
+        target: Target,
+    },
+}
+
+/// What a dispute, resolve, or chargeback references.
+#[derive(Debug, Clone)]
+enum Target {
+    /// An earlier deposit or withdrawal, by its own client.
+    Payment(Index),
+    /// An earlier deposit or withdrawal, by whichever client the step has.
+    AnyClients(Index),
+    /// An ID past any used so far.
+    Unknown(u32),
+}
+
+/// The transactions of a generated log, with references resolved against the deposits and
+/// withdrawals before them. References with nothing before them are to unknown transactions.
+fn resolve(steps: Vec<Step>) -> Vec<Transaction> {
+    // Deposits and withdrawals so far, which references pick from.
+    let mut payments: Vec<(u16, u32)> = Vec::new();
+    let mut next_id = 1;
+
+    steps
+        .into_iter()
+        .map(|step| match step {
+            Step::Payment {
+                r#type,
+                client_id,
+                amount,
+                reused,
+            } => {
+                let tx_id = match reused {
+                    Some(index) if !payments.is_empty() => payments[index.index(payments.len())].1,
+                    _ => {
+                        next_id += 1;
+                        next_id - 1
+                    }
+                };
+                payments.push((client_id, tx_id));
+                transaction(r#type, client_id, tx_id, Some(ten_thousandths(amount)))
+            }
+            Step::Reference {
+                r#type,
+                client_id,
+                target,
+            } => {
+                let (client_id, tx_id) = match target {
+                    Target::Payment(index) if !payments.is_empty() => {
+                        payments[index.index(payments.len())]
+                    }
+                    Target::AnyClients(index) if !payments.is_empty() => {
+                        (client_id, payments[index.index(payments.len())].1)
+                    }
+                    Target::Unknown(offset) => (client_id, next_id + offset),
+                    _ => (client_id, next_id),
+                };
+                transaction(r#type, client_id, tx_id, None)
+            }
+        })
+        .collect()
+}
+
+fn transaction(
+    r#type: TransactionType,
+    client_id: u16,
+    tx_id: u32,
+    amount: Option<Money>,
+) -> Transaction {
+    Transaction {
+        r#type,
+        client_id,
+        tx_id,
+        amount,
+        raw_amount: None,
+        line: None,
+        terminal: None,
+        currency: Currency::IMPLICIT,
+        to_currency: Currency::IMPLICIT,
+        timestamp: None,
+    }
+}
+
+/// A client account in the reference model.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OracleAccount {
+    pub available: Money,
+    pub held: Money,
+    pub locked: bool,
+    /// Transactions the client has under dispute.
+    disputed: HashSet<u32>,
+}
+
+/// A reference model of the specification, for an engine with the default policies.
+#[derive(Debug, Default, Clone)]
+pub struct Oracle {
+    accounts: BTreeMap<u16, OracleAccount>,
+    /// Deposits and withdrawals which were applied, by ID.
+    payments: HashMap<u32, (TransactionType, Money)>,
+    /// Deposits and withdrawals which were charged back, which can't be disputed again by any
+    /// client.
+    charged_back: HashSet<u32>,
+}
+
+impl Oracle {
+    pub fn new() -> Self {
+        Oracle::default()
+    }
+
+    /// Apply a transaction, returning whether it was applied (rather than rejected).
+    pub fn apply(&mut self, tx: &Transaction) -> bool {
+        let account = self.accounts.entry(tx.client_id).or_default();
+        if account.locked {
+            return false;
+        }
+
+        match tx.r#type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                let amount = match tx.amount {
+                    Some(amount) if amount > Money::ZERO => amount,
+                    _ => return false,
+                };
+                if self.payments.contains_key(&tx.tx_id) {
+                    return false;
+                }
+                // Deposits can't leave available funds negative (after a dispute) either.
+                if tx.r#type == TransactionType::Deposit {
+                    if account.available + amount < Money::ZERO {
+                        return false;
+                    }
+                    account.available += amount;
+                } else if account.available < amount {
+                    return false;
+                } else {
+                    account.available -= amount;
+                }
+                self.payments.insert(tx.tx_id, (tx.r#type, amount));
+            }
+            TransactionType::Dispute => {
+                let (r#type, amount) = match self.payments.get(&tx.tx_id) {
+                    Some(&payment) => payment,
+                    None => return false,
+                };
+                if self.charged_back.contains(&tx.tx_id) || !account.disputed.insert(tx.tx_id) {
+                    return false;
+                }
+                // A disputed deposit is held, a disputed withdrawal is provisionally credited.
+                if r#type == TransactionType::Deposit {
+                    account.available -= amount;
+                }
+                account.held += amount;
+            }
+            TransactionType::Resolve | TransactionType::Chargeback => {
+                let (r#type, amount) = match self.payments.get(&tx.tx_id) {
+                    Some(&payment) => payment,
+                    None => return false,
+                };
+                if !account.disputed.remove(&tx.tx_id) {
+                    return false;
+                }
+                account.held -= amount;
+                // A resolved deposit and a charged back withdrawal are available again.
+                let reversed = tx.r#type == TransactionType::Chargeback;
+                if (r#type == TransactionType::Deposit) != reversed {
+                    account.available += amount;
+                }
+                account.locked |= reversed;
+                if reversed {
+                    self.charged_back.insert(tx.tx_id);
+                }
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Every client account, by client ID.
+    pub fn accounts(&self) -> &BTreeMap<u16, OracleAccount> {
+        &self.accounts
+    }
+
+    /// Compare the model's accounts with an engine's client states, describing the first
+    /// difference.
+    pub fn compare(&self, engine: &Engine) -> Result<(), String> {
+        let states = engine.client_states();
+        if states.len() != self.accounts.len() {
+            return Err(format!(
+                "the engine has {} clients, the oracle has {}",
+                states.len(),
+                self.accounts.len()
+            ));
+        }
+
+        for (client_id, account) in &self.accounts {
+            let state = states
+                .get(client_id)
+                .ok_or_else(|| format!("the engine has no client {}", client_id))?;
+            let expected = (account.available, account.held, account.locked);
+            let actual = (state.available, state.held, state.locked);
+            if expected != actual || state.total != account.available + account.held {
+                return Err(format!(
+                    "client {}: the engine has available {}, held {}, total {}, locked {}, the \
+                     oracle has available {}, held {}, locked {}",
+                    client_id,
+                    state.available,
+                    state.held,
+                    state.total,
+                    state.locked,
+                    account.available,
+                    account.held,
+                    account.locked
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The property that `engine` (new, with the default policies) agrees with the `Oracle` about
+/// which transactions are applied, and about every client's funds at the end.
+pub fn matches_oracle(mut engine: Engine, transactions: &[Transaction]) -> Result<(), String> {
+    let mut oracle = Oracle::new();
+    for tx in transactions {
+        let applied = engine.apply(tx).is_ok();
+        if applied != oracle.apply(tx) {
+            return Err(format!(
+                "the engine {} {:?} {} for client {}, the oracle didn't",
+                if applied { "applied" } else { "rejected" },
+                tx.r#type,
+                tx.tx_id,
+                tx.client_id
+            ));
+        }
+    }
+
+    oracle.compare(&engine)
+}
@@ -3,7 +3,14 @@
 /// Testing would be easier if the processor was a state machine that could be inspected halfway
 /// through execution (i.e. feed transactions in one at a time rather than bulk processing).
 use super::*;
+use cli::Options;
 use csv::{ReaderBuilder, Trim};
+use input::{csv_transactions, fast_csv_transactions};
+use output::{
+    BalanceWriter, JsonBalanceWriter, OutputDialect, OutputFormat, RejectWriter,
+    ShardedBalanceWriter,
+};
+use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 
 /// Utility function which accepts inline CSV and provides a `csv::Reader` usable for testing.
@@ -21,6 +28,71 @@ where
         .from_reader(csv)
 }
 
+/// Apply all the transactions in some CSV data (parsed the same way as the binary parses them).
+fn process_csv<R, F>(
+    engine: Engine,
+    reader: csv::Reader<R>,
+    on_reject: F,
+) -> Result<HashMap<u16, ClientState>, Box<dyn Error>>
+where
+    R: std::io::Read,
+    F: FnMut(&Transaction, &TransactionError) -> Result<(), Box<dyn Error>>,
+{
+    process_transactions(engine, fast_csv_transactions(reader), on_reject)
+}
+
+/// Rejection handler for tests which only inspect the final client states.
+fn ignore_rejects(_: &Transaction, _: &TransactionError) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+/// Processes inline CSV, collecting the reasons for any rejected transactions.
+fn collect_rejects(csv: &str) -> Vec<TransactionError> {
+    let mut rejects = Vec::new();
+    process_csv(
+        Engine::new(),
+        csv_reader_from_str(csv.as_bytes()),
+        |_, e| {
+            rejects.push(e.clone());
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    rejects
+}
+
+/// A rejected transaction's line, ID, and reason.
+type Reject = (Option<u64>, u32, &'static str);
+
+/// Processes inline CSV with exact amounts (as `validate` does), collecting the rejected
+/// transactions.
+fn validate_csv(engine: Engine, csv: &str) -> (Vec<Reject>, HashMap<u16, ClientState>) {
+    let mut rejects = Vec::new();
+    let states = process_csv(
+        engine.with_exact_amounts(true),
+        csv_reader_from_str(csv.as_bytes()),
+        |tx, e| {
+            rejects.push((tx.line, tx.tx_id, e.reason()));
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    (rejects, states)
+}
+
+/// Collects the balance change events an engine sends, for tests to inspect.
+#[derive(Clone, Default)]
+struct CollectEvents(std::sync::Arc<std::sync::Mutex<Vec<events::BalanceEvent>>>);
+
+impl events::EventSink for CollectEvents {
+    fn event(&mut self, event: &events::BalanceEvent) -> io::Result<()> {
+        self.0.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+}
+
 /// A client with sufficient available funds can withdraw them.
 /// A client without sufficient available funds will maintain their balance.
 #[test]
@@ -36,7 +108,7 @@ withdrawal, 2,      4,  2.0
         .as_bytes(),
     );
 
-    let records = process_csv(reader).unwrap();
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
 
     let client_1: &ClientState = records.get(&1).unwrap();
     let client_2: &ClientState = records.get(&2).unwrap();
@@ -70,7 +142,7 @@ resolve,    1,      7
 
     // If the parser correctly populates the `Transaction` struct for disputes, we can assume the
     // same is true for the other 2 resolution transactions.
-    assert_eq!(process_csv(reader).is_ok(), true);
+    assert!(process_csv(Engine::new(), reader, ignore_rejects).is_ok());
 }
 
 /// The parser makes the `amount` column optional, and transactions should have no effect if they're
@@ -85,7 +157,7 @@ deposit,    1,      1,
         .as_bytes(),
     );
 
-    let deposit_records = process_csv(deposit_reader).unwrap();
+    let deposit_records = process_csv(Engine::new(), deposit_reader, ignore_rejects).unwrap();
     let client_1: &ClientState = deposit_records.get(&1).unwrap();
     assert_eq!(client_1.available, dec!(0));
 
@@ -98,7 +170,7 @@ withdrawal, 1,      2,
         .as_bytes(),
     );
 
-    let withdrawal_records = process_csv(withdrawal_reader).unwrap();
+    let withdrawal_records = process_csv(Engine::new(), withdrawal_reader, ignore_rejects).unwrap();
     let client_1: &ClientState = withdrawal_records.get(&1).unwrap();
     assert_eq!(client_1.available, dec!(1.0));
 }
@@ -127,7 +199,7 @@ deposit,    1,      6,  1.00016
         .as_bytes(),
     );
 
-    let records = process_csv(reader).unwrap();
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
     let client_1: &ClientState = records.get(&1).unwrap();
     assert_eq!(client_1.available, dec!(6.0006));
 }
@@ -147,16 +219,16 @@ deposit,    2,      2,  1.0
         .as_bytes(),
     );
 
-    let records = process_csv(reader).unwrap();
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
     let client_1: &ClientState = records.get(&1).unwrap();
     assert_eq!(client_1.available, dec!(0));
     assert_eq!(client_1.held, dec!(1.0));
-    assert_eq!(client_1.locked, false);
+    assert!(!client_1.locked);
 
     let client_2: &ClientState = records.get(&2).unwrap();
     assert_eq!(client_2.available, dec!(1.0));
     assert_eq!(client_2.held, dec!(0));
-    assert_eq!(client_2.locked, false);
+    assert!(!client_2.locked);
 }
 
 /// A resolve moves funds from held to available.
@@ -172,11 +244,11 @@ resolve,    1,      1
         .as_bytes(),
     );
 
-    let records = process_csv(reader).unwrap();
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
     let client_1: &ClientState = records.get(&1).unwrap();
     assert_eq!(client_1.available, dec!(1.0));
     assert_eq!(client_1.held, dec!(0));
-    assert_eq!(client_1.locked, false);
+    assert!(!client_1.locked);
 }
 
 /// A dispute causes an account to become locked/frozen, and no further transactions will apply.
@@ -201,11 +273,11 @@ withdrawal, 1,      4,  1.0
         .as_bytes(),
     );
 
-    let records = process_csv(reader).unwrap();
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
     let client_1: &ClientState = records.get(&1).unwrap();
     assert_eq!(client_1.available, dec!(1.0));
     assert_eq!(client_1.held, dec!(0.0));
-    assert_eq!(client_1.locked, true);
+    assert!(client_1.locked);
 }
 
 /// A resolve/chargeback only applies to a disputed transaction.
@@ -221,16 +293,5751 @@ resolve,    1,      1
         .as_bytes(),
     );
 
-    let records = process_csv(reader).unwrap();
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
     let client_1: &ClientState = records.get(&1).unwrap();
     assert_eq!(client_1.available, dec!(1.0));
     assert_eq!(client_1.held, dec!(0.0));
-    assert_eq!(client_1.locked, false);
+    assert!(!client_1.locked);
 }
 
-// TODO:
-// 1. {Dispute, Resolve, Chargeback} reference the same transaction twice (i.e. ensure no
-//    double-counting of balance-altering transactions).
-// 2. [{Dispute}, {Resolve, Chargeback}] on both of {Deposit, Withdrawal}. (spec wasn't clear on
-//    whether each of {Resolve, Chargeback} should only apply to one of {Deposit, Withdrawal}, a
-//    test would help clarify what makes sense)
+/// Transactions which can't be applied are reported along with the reason, rather than being
+/// silently ignored.
+#[test]
+fn rejected_transactions_are_reported_with_reason() {
+    let rejects = collect_rejects(
+        "\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+withdrawal, 1,      2,  2.0
+deposit,    1,      3,
+dispute,    1,      9
+dispute,    1,      1
+dispute,    1,      1
+resolve,    1,      1
+resolve,    1,      1
+dispute,    1,      1
+chargeback, 1,      1
+deposit,    1,      4,  1.0
+",
+    );
+
+    assert_eq!(
+        rejects,
+        vec![
+            TransactionError::InsufficientFunds {
+                client_id: 1,
+                tx_id: 2
+            },
+            TransactionError::MissingAmount {
+                client_id: 1,
+                tx_id: 3
+            },
+            TransactionError::UnknownTx {
+                client_id: 1,
+                tx_id: 9
+            },
+            TransactionError::AlreadyDisputed {
+                client_id: 1,
+                tx_id: 1
+            },
+            TransactionError::NotDisputed {
+                client_id: 1,
+                tx_id: 1
+            },
+            TransactionError::AccountLocked {
+                client_id: 1,
+                tx_id: 4
+            },
+        ]
+    );
+}
+
+/// A rejected withdrawal never happened, so it can't be disputed later.
+#[test]
+fn rejected_withdrawal_is_not_disputable() {
+    let rejects = collect_rejects(
+        "\
+type,       client, tx, amount
+withdrawal, 1,      1,  1.0
+dispute,    1,      1
+",
+    );
+
+    assert_eq!(
+        rejects.last(),
+        Some(&TransactionError::UnknownTx {
+            client_id: 1,
+            tx_id: 1
+        })
+    );
+}
+
+/// Balances can be exported in a different CSV dialect.
+#[test]
+fn balances_are_written_in_requested_dialect() {
+    let reader = csv_reader_from_str(
+        "\
+type,       client, tx, amount
+deposit,    1,      1,  1.5
+"
+        .as_bytes(),
+    );
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
+
+    let dialect = OutputDialect {
+        delimiter: b';',
+        quote_style: csv::QuoteStyle::Always,
+        line_ending: output::LineEnding::CrLf,
+    };
+    let mut buffer = Vec::new();
+    write_balances(
+        &mut BalanceWriter::new(&mut buffer, &dialect),
+        &records,
+        SortBy::Client,
+    )
+    .unwrap();
+
+    assert_eq!(
+        String::from_utf8(buffer).unwrap(),
+        "\"client\";\"available\";\"held\";\"total\";\"locked\";\"version\"\r\n\
+         \"1\";\"1.5000\";\"0.0000\";\"1.5000\";\"false\";\"1\"\r\n"
+    );
+}
+
+/// Output dialect flags are parsed alongside the positional CSV path.
+#[test]
+fn output_dialect_options_are_parsed() {
+    let args = [
+        "--output-delimiter",
+        "tab",
+        "in.csv",
+        "--line-ending",
+        "crlf",
+    ];
+    let options = Options::from_args(args.iter().map(|s| s.to_string())).unwrap();
+
+    assert_eq!(options.csv_path.as_deref(), Some("in.csv"));
+    assert_eq!(options.output_dialect.delimiter, b'\t');
+    assert_eq!(options.output_dialect.line_ending, output::LineEnding::CrLf);
+
+    assert!(
+        Options::from_args(vec!["--quote-style".to_string(), "sometimes".to_string()]).is_err()
+    );
+}
+
+/// Transactions come from stdin when the path is `-` or omitted, which two-pass mode can't support.
+#[test]
+fn stdin_is_used_without_a_path() {
+    let parse = |args: &[&str]| Options::from_args(args.iter().map(|s| s.to_string()));
+
+    assert_eq!(parse(&[]).unwrap().csv_path, None);
+    assert_eq!(
+        parse(&["-"]).unwrap().csv_path.as_deref(),
+        Some(input::STDIN_PATH)
+    );
+    assert!(parse(&["--two-pass"]).is_err());
+    assert!(parse(&["--two-pass", "-"]).is_err());
+    assert!(parse(&["--two-pass", "in.csv"]).is_ok());
+}
+
+/// Options which can't be used together are reported as conflicts, whichever comes first.
+#[test]
+fn conflicting_options_are_rejected() {
+    use clap::error::ErrorKind;
+
+    let parse = |args: &[&str]| Options::from_args(args.iter().map(|s| s.to_string()));
+    let conflict = |args: &[&str]| parse(args).unwrap_err().kind() == ErrorKind::ArgumentConflict;
+
+    assert!(conflict(&["in.csv", "--threads", "2", "--two-pass"]));
+    assert!(conflict(&["in.csv", "--two-pass", "--threads", "2"]));
+    assert!(conflict(&[
+        "in.csv",
+        "-o",
+        "out.csv",
+        "--output-shards",
+        "2"
+    ]));
+    assert!(conflict(&[
+        "in.csv",
+        "--snapshot-in",
+        "a",
+        "--initial-balances",
+        "b"
+    ]));
+    assert!(conflict(&[
+        "in.csv",
+        "--chronological",
+        "--reorder-window",
+        "8"
+    ]));
+    assert!(parse(&["in.csv", "--threads", "2", "--sort-by", "total"]).is_ok());
+
+    // Options other subcommands don't use aren't accepted by them at all.
+    let command = |args: &[&str]| cli::Command::from_args(args.iter().map(|s| s.to_string()));
+    assert_eq!(
+        command(&["validate", "in.csv", "--snapshot-out", "s"])
+            .unwrap_err()
+            .kind(),
+        ErrorKind::UnknownArgument
+    );
+}
+
+/// Rejected transactions are logged with the original row and the reason for rejection.
+#[test]
+fn rejects_are_logged_with_reason() {
+    let reader = csv_reader_from_str(
+        "\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+withdrawal, 1,      2,  2.5
+dispute,    2,      3
+"
+        .as_bytes(),
+    );
+
+    let mut buffer = Vec::new();
+    let mut rejects = RejectWriter::new(&mut buffer, &OutputDialect::default());
+    process_csv(Engine::new(), reader, |tx, e| rejects.write(tx, e)).unwrap();
+    rejects.flush().unwrap();
+    drop(rejects);
+
+    assert_eq!(
+        String::from_utf8(buffer).unwrap(),
+        "\
+type,client,tx,amount,reason
+withdrawal,1,2,2.5000,insufficient_funds
+dispute,2,3,,unknown_tx
+"
+    );
+}
+
+/// Deposits/withdrawals which reuse a transaction ID are handled according to the configured
+/// policy, and never overwrite the original transaction.
+#[test]
+fn duplicate_tx_ids_follow_policy() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+deposit,    1,      1,  5.0
+dispute,    1,      1
+";
+
+    // Rejected duplicates are reported, and a later dispute applies to the original transaction.
+    let mut rejects = Vec::new();
+    let records = process_csv(
+        Engine::new().with_duplicate_tx_policy(DuplicateTxPolicy::Reject),
+        csv_reader_from_str(csv.as_bytes()),
+        |_, e| {
+            rejects.push(e.clone());
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        rejects,
+        vec![TransactionError::DuplicateTxId {
+            client_id: 1,
+            tx_id: 1
+        }]
+    );
+    let client_1: &ClientState = records.get(&1).unwrap();
+    assert_eq!(client_1.available, dec!(0));
+    assert_eq!(client_1.held, dec!(1.0));
+
+    // Ignored duplicates aren't reported.
+    let mut rejects = 0;
+    process_csv(
+        Engine::new().with_duplicate_tx_policy(DuplicateTxPolicy::Ignore),
+        csv_reader_from_str(csv.as_bytes()),
+        |_, _| {
+            rejects += 1;
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(rejects, 0);
+
+    // Processing stops at the first duplicate.
+    assert!(process_csv(
+        Engine::new().with_duplicate_tx_policy(DuplicateTxPolicy::ErrorOut),
+        csv_reader_from_str(csv.as_bytes()),
+        ignore_rejects,
+    )
+    .is_err());
+}
+
+/// Sharded output puts every client in exactly one shard (the one picked by its ID), and the
+/// manifest accounts for every row.
+#[test]
+fn sharded_output_partitions_clients() {
+    let reader = csv_reader_from_str(
+        "\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+deposit,    2,      2,  1.0
+deposit,    3,      3,  1.0
+deposit,    4,      4,  1.0
+deposit,    5,      5,  1.0
+"
+        .as_bytes(),
+    );
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("payment-engine-shards-{}", std::process::id()));
+    let mut sink = ShardedBalanceWriter::create(&dir, &OutputDialect::default(), 3).unwrap();
+    write_balances(&mut sink, &records, SortBy::Client).unwrap();
+
+    let manifest = std::fs::read_to_string(dir.join("manifest.csv")).unwrap();
+    let mut total_rows = 0;
+    for (shard, line) in manifest.lines().skip(1).enumerate() {
+        let fields: Vec<&str> = line.split(',').collect();
+        assert_eq!(fields[0], shard.to_string());
+        total_rows += fields[2].parse::<usize>().unwrap();
+
+        let contents = std::fs::read_to_string(dir.join(fields[1])).unwrap();
+        assert!(contents.starts_with("client,available,held,total,locked,version\n"));
+        for row in contents.lines().skip(1) {
+            let client_id: u16 = row.split(',').next().unwrap().parse().unwrap();
+            assert_eq!(output::shard_for_client(client_id, 3), shard);
+        }
+    }
+    assert_eq!(total_rows, 5);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Deposits and withdrawals must have a positive amount (after rounding), anything else is
+/// rejected without affecting balances.
+#[test]
+fn negative_and_zero_amounts_are_rejected() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  2.0
+deposit,    1,      2,  -1.0
+withdrawal, 1,      3,  -1.0
+deposit,    1,      4,  0
+withdrawal, 1,      5,  0.00001
+";
+
+    assert_eq!(
+        collect_rejects(csv),
+        vec![
+            TransactionError::NegativeAmount {
+                client_id: 1,
+                tx_id: 2
+            },
+            TransactionError::NegativeAmount {
+                client_id: 1,
+                tx_id: 3
+            },
+            TransactionError::ZeroAmount {
+                client_id: 1,
+                tx_id: 4
+            },
+            TransactionError::ZeroAmount {
+                client_id: 1,
+                tx_id: 5
+            },
+        ]
+    );
+
+    let records = process_csv(
+        Engine::new(),
+        csv_reader_from_str(csv.as_bytes()),
+        ignore_rejects,
+    )
+    .unwrap();
+    let client_1: &ClientState = records.get(&1).unwrap();
+    assert_eq!(client_1.available, dec!(2.0));
+    assert_eq!(client_1.total, dec!(2.0));
+}
+
+/// In two-pass mode each client is written as soon as its last transaction is applied, and no
+/// longer held by the engine. Final states are the same as single pass processing.
+#[test]
+fn two_pass_writes_clients_once_final() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+deposit,    2,      2,  2.0
+deposit,    3,      3,  3.0
+withdrawal, 2,      4,  0.5
+dispute,    1,      1
+";
+
+    let mut buffer = Vec::new();
+    let mut sink = BalanceWriter::new(&mut buffer, &OutputDialect::default());
+    process_two_pass(
+        Engine::new(),
+        csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        ignore_rejects,
+        &mut sink,
+    )
+    .unwrap();
+    drop(sink);
+
+    // Clients appear in the order of their last transaction.
+    assert_eq!(
+        String::from_utf8(buffer).unwrap(),
+        "\
+client,available,held,total,locked,version
+3,3.0000,0.0000,3.0000,false,1
+2,1.5000,0.0000,1.5000,false,2
+1,0.0000,1.0000,1.0000,false,2
+"
+    );
+}
+
+/// Disputing a withdrawal provisionally credits the withdrawn amount to held funds. A resolve
+/// removes the credit (the withdrawal stands).
+#[test]
+fn withdrawal_dispute_and_resolve() {
+    let reader = csv_reader_from_str(
+        "\
+type,       client, tx, amount
+deposit,    1,      1,  5.0
+withdrawal, 1,      2,  2.0
+dispute,    1,      2
+"
+        .as_bytes(),
+    );
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
+    let client_1: &ClientState = records.get(&1).unwrap();
+    assert_eq!(client_1.available, dec!(3.0));
+    assert_eq!(client_1.held, dec!(2.0));
+    assert_eq!(client_1.total, dec!(5.0));
+
+    let reader = csv_reader_from_str(
+        "\
+type,       client, tx, amount
+deposit,    1,      1,  5.0
+withdrawal, 1,      2,  2.0
+dispute,    1,      2
+resolve,    1,      2
+"
+        .as_bytes(),
+    );
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
+    let client_1: &ClientState = records.get(&1).unwrap();
+    assert_eq!(client_1.available, dec!(3.0));
+    assert_eq!(client_1.held, dec!(0));
+    assert_eq!(client_1.total, dec!(3.0));
+    assert!(!client_1.locked);
+}
+
+/// A chargeback on a disputed withdrawal reverses it, returning the funds to the client, and locks
+/// the account.
+#[test]
+fn withdrawal_dispute_and_chargeback() {
+    let reader = csv_reader_from_str(
+        "\
+type,       client, tx, amount
+deposit,    1,      1,  5.0
+withdrawal, 1,      2,  2.0
+dispute,    1,      2
+chargeback, 1,      2
+"
+        .as_bytes(),
+    );
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
+    let client_1: &ClientState = records.get(&1).unwrap();
+    assert_eq!(client_1.available, dec!(5.0));
+    assert_eq!(client_1.held, dec!(0));
+    assert_eq!(client_1.total, dec!(5.0));
+    assert!(client_1.locked);
+}
+
+/// Withdrawal disputes can be configured to have no effect, while deposit disputes still apply.
+#[test]
+fn withdrawal_disputes_can_be_ignored() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  5.0
+withdrawal, 1,      2,  2.0
+dispute,    1,      2
+chargeback, 1,      2
+dispute,    1,      1
+";
+
+    let mut rejects = Vec::new();
+    let records = process_csv(
+        Engine::new().with_withdrawal_dispute_policy(WithdrawalDisputePolicy::Ignore),
+        csv_reader_from_str(csv.as_bytes()),
+        |_, e| {
+            rejects.push(e.reason());
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    assert_eq!(rejects, vec!["withdrawal_dispute_ignored", "not_disputed"]);
+    let client_1: &ClientState = records.get(&1).unwrap();
+    assert_eq!(client_1.available, dec!(-2.0));
+    assert_eq!(client_1.held, dec!(5.0));
+    assert!(!client_1.locked);
+}
+
+/// Balances can be exported as a JSON array or NDJSON, with amounts as strings.
+#[test]
+fn balances_are_written_as_json() {
+    let reader = csv_reader_from_str(
+        "\
+type,       client, tx, amount
+deposit,    1,      1,  1.5
+"
+        .as_bytes(),
+    );
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
+
+    let mut buffer = Vec::new();
+    write_balances(
+        &mut JsonBalanceWriter::array(&mut buffer),
+        &records,
+        SortBy::Client,
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(buffer).unwrap(),
+        "[\n{\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false,\"version\":1}\n]\n"
+    );
+
+    let mut buffer = Vec::new();
+    write_balances(
+        &mut JsonBalanceWriter::ndjson(&mut buffer),
+        &records,
+        SortBy::Client,
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(buffer).unwrap(),
+        "{\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false,\"version\":1}\n"
+    );
+
+    // An empty export is still a valid JSON document.
+    let mut buffer = Vec::new();
+    write_balances(
+        &mut JsonBalanceWriter::array(&mut buffer),
+        &HashMap::new(),
+        SortBy::Client,
+    )
+    .unwrap();
+    assert_eq!(String::from_utf8(buffer).unwrap(), "[]\n");
+}
+
+/// NDJSON transactions are processed the same way as CSV. Amounts may be strings or numbers, and
+/// blank lines are skipped.
+#[test]
+fn ndjson_input_matches_csv() {
+    let ndjson = r#"{"type":"deposit","client":1,"tx":1,"amount":"1.5"}
+{"type":"deposit","client":2,"tx":2,"amount":2}
+
+{"type":"withdrawal","client":1,"tx":3,"amount":"0.5"}
+{"type":"dispute","client":2,"tx":2}
+"#;
+
+    let records = process_transactions(
+        Engine::new(),
+        ndjson_transactions(ndjson.as_bytes()),
+        ignore_rejects,
+    )
+    .unwrap();
+
+    let client_1: &ClientState = records.get(&1).unwrap();
+    assert_eq!(client_1.available, dec!(1.0));
+    let client_2: &ClientState = records.get(&2).unwrap();
+    assert_eq!(client_2.available, dec!(0));
+    assert_eq!(client_2.held, dec!(2));
+}
+
+/// Malformed NDJSON aborts processing, and the error includes the line number.
+#[test]
+fn ndjson_errors_include_line_number() {
+    let ndjson = r#"{"type":"deposit","client":1,"tx":1,"amount":"1.5"}
+{"type":"deposit","client":1,"tx":
+"#;
+
+    let error = process_transactions(
+        Engine::new(),
+        ndjson_transactions(ndjson.as_bytes()),
+        ignore_rejects,
+    )
+    .unwrap_err();
+    let error = error.downcast::<error::ParseError>().unwrap();
+    assert_eq!((error.line, error.byte), (Some(2), Some(52)));
+    assert_eq!(error.record, r#"{"type":"deposit","client":1,"tx":"#);
+    assert!(error.to_string().starts_with("line 2 (byte 52): "));
+}
+
+/// Balances are written in a deterministic order, by client ID unless another order is requested.
+#[test]
+fn balances_are_sorted() {
+    let reader = csv_reader_from_str(
+        "\
+type,       client, tx, amount
+deposit,    3,      1,  1.0
+deposit,    1,      2,  3.0
+deposit,    2,      3,  2.0
+deposit,    4,      4,  2.0
+dispute,    4,      4
+chargeback, 4,      4
+"
+        .as_bytes(),
+    );
+    let records = process_csv(Engine::new(), reader, ignore_rejects).unwrap();
+
+    let client_order = |sort_by: SortBy| {
+        let mut buffer = Vec::new();
+        write_balances(
+            &mut BalanceWriter::new(&mut buffer, &OutputDialect::default()),
+            &records,
+            sort_by,
+        )
+        .unwrap();
+
+        String::from_utf8(buffer)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(client_order(SortBy::Client), vec!["1", "2", "3", "4"]);
+    assert_eq!(client_order(SortBy::Total), vec!["1", "2", "3", "4"]);
+    assert_eq!(client_order(SortBy::Locked), vec!["4", "1", "2", "3"]);
+}
+
+/// Amounts are always written with exactly four decimal places, and rounded when parsed.
+#[test]
+fn money_has_four_decimal_places() {
+    assert_eq!(Money::from(dec!(1.5)).to_string(), "1.5000");
+    assert_eq!(Money::from(dec!(-2)).to_string(), "-2.0000");
+    assert_eq!(Money::from(dec!(1.00015)).to_string(), "1.0002");
+    assert_eq!(Money::from(dec!(1.00005)).to_string(), "1.0000");
+
+    let parsed: Money = serde_json::from_str("\"0.123456\"").unwrap();
+    assert_eq!(parsed, dec!(0.1235));
+    assert_eq!(serde_json::to_string(&parsed).unwrap(), "\"0.1235\"");
+}
+
+/// Digests detect changes to the artifacts they cover, and to the digest itself.
+#[test]
+fn audit_digest_detects_tampering() {
+    use digest::{AuditDigest, HashingWriter};
+    use std::io::Write;
+
+    let dir = std::env::temp_dir().join(format!("payment-engine-digest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let rejects_path = dir.join("rejects.csv");
+    let digest_path = dir.join("digest.txt");
+    let balances_path = dir.join("balances.csv");
+    std::fs::write(&rejects_path, "type,client,tx,amount,reason\n").unwrap();
+
+    let (mut stdout, hash) = HashingWriter::new(Vec::new());
+    stdout
+        .write_all(b"client,available,held,total,locked\n")
+        .unwrap();
+    std::fs::write(&balances_path, b"client,available,held,total,locked\n").unwrap();
+
+    let rejects_name = rejects_path.display().to_string();
+    let written = AuditDigest {
+        created: 1_650_000_000,
+        artifacts: vec![
+            (digest::STDOUT_ARTIFACT.to_string(), hash.finish()),
+            (
+                rejects_name.clone(),
+                digest::hash_file(&rejects_path).unwrap(),
+            ),
+        ],
+    };
+    written.write(&digest_path, b"key").unwrap();
+
+    let locate = |name: &str| {
+        if name == digest::STDOUT_ARTIFACT {
+            Some(balances_path.display().to_string())
+        } else {
+            None
+        }
+    };
+    let read = AuditDigest::read(&digest_path, b"key").unwrap();
+    assert_eq!(read, written);
+    assert!(read.verify(locate).unwrap().is_empty());
+
+    // Altered artifacts are reported by name.
+    std::fs::write(
+        &rejects_path,
+        "type,client,tx,amount,reason\ndeposit,1,1,,missing_amount\n",
+    )
+    .unwrap();
+    assert_eq!(read.verify(locate).unwrap(), vec![rejects_name]);
+
+    // Balances written to stdout can only be checked against an archived copy.
+    assert!(read.verify(|_| None).is_err());
+
+    // The wrong key, or any change to the digest, fails the signature check.
+    assert!(AuditDigest::read(&digest_path, b"other key").is_err());
+    let contents = std::fs::read_to_string(&digest_path).unwrap();
+    std::fs::write(
+        &digest_path,
+        contents.replace("created 1650000000", "created 1650000001"),
+    )
+    .unwrap();
+    assert!(AuditDigest::read(&digest_path, b"key").is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Sampling includes every transaction for a stable subset of clients.
+#[test]
+fn sample_includes_all_transactions_for_sampled_clients() {
+    use input::Sample;
+
+    let sample: Sample = "10%".parse().unwrap();
+    assert_eq!(sample.to_string(), "10.00%");
+    assert_eq!("0.25".parse::<Sample>().unwrap().to_string(), "0.25%");
+    for invalid in &["0%", "0.001%", "101%", "ten%"] {
+        assert!(invalid.parse::<Sample>().is_err());
+    }
+
+    let sampled = (0..=u16::MAX)
+        .filter(|&client_id| sample.includes(client_id))
+        .count();
+    assert!((6_000..7_100).contains(&sampled));
+
+    let mut csv = String::from("type,client,tx,amount\n");
+    for client_id in 1..=100u16 {
+        csv.push_str(&format!("deposit,{},{},2.0\n", client_id, client_id * 2));
+        csv.push_str(&format!(
+            "withdrawal,{},{},1.0\n",
+            client_id,
+            client_id * 2 + 1
+        ));
+    }
+    let transactions: input::TransactionStream = Box::new(input::csv_transactions(
+        csv_reader_from_str(io::Cursor::new(csv)),
+    ));
+    let client_states =
+        process_transactions(Engine::new(), sample.filter(transactions), ignore_rejects).unwrap();
+
+    assert!(!client_states.is_empty());
+    for (client_id, state) in &client_states {
+        assert!(sample.includes(*client_id));
+        assert_eq!(state.available, dec!(1));
+    }
+    assert_eq!(
+        client_states.len(),
+        (1..=100)
+            .filter(|&client_id| sample.includes(client_id))
+            .count()
+    );
+}
+
+/// Subcommands are optional, so a bare path still processes the transaction log.
+#[test]
+fn subcommands_are_parsed() {
+    use cli::Command;
+
+    let parse = |args: &[&str]| Command::from_args(args.iter().map(|s| s.to_string()));
+
+    match parse(&["in.csv"]).unwrap() {
+        Command::Process(options) => assert_eq!(options.csv_path.as_deref(), Some("in.csv")),
+        command => panic!("expected process, got {:?}", command),
+    }
+    match parse(&["process", "in.csv", "--output-format", "json"]).unwrap() {
+        Command::Process(options) => {
+            assert_eq!(options.csv_path.as_deref(), Some("in.csv"));
+            assert_eq!(options.output_format, OutputFormat::Json);
+        }
+        command => panic!("expected process, got {:?}", command),
+    }
+    assert!(matches!(
+        parse(&["in.csv", "--allow-admin-ops"]).unwrap(),
+        Command::Process(options) if options.allow_admin_ops
+    ));
+    match parse(&["validate", "in.csv", "--rejects", "rejects.csv"]).unwrap() {
+        Command::Validate(options) => {
+            assert_eq!(options.rejects_path.as_deref(), Some("rejects.csv"))
+        }
+        command => panic!("expected validate, got {:?}", command),
+    }
+    match parse(&["verify-digest", "digest.txt"]).unwrap() {
+        Command::VerifyDigest(options) => assert_eq!(options.digest_path, "digest.txt"),
+        command => panic!("expected verify-digest, got {:?}", command),
+    }
+    match parse(&[
+        "gen",
+        "--rows",
+        "10",
+        "--clients",
+        "3",
+        "--dispute-rate",
+        "5%",
+    ])
+    .unwrap()
+    {
+        Command::Gen(generator) => {
+            assert_eq!(generator.rows, 10);
+            assert_eq!(generator.clients, 3);
+            assert_eq!(generator.dispute_rate, 0.05);
+        }
+        command => panic!("expected gen, got {:?}", command),
+    }
+
+    assert!(parse(&["validate", "in.csv", "--output-shards", "2"]).is_err());
+    match parse(&["process", "in.csv", "extra.csv"]).unwrap() {
+        Command::Process(options) => assert_eq!(options.more_paths, ["extra.csv"]),
+        command => panic!("expected process, got {:?}", command),
+    }
+    match parse(&[
+        "in.csv",
+        "--merge",
+        "a.csv",
+        "--merge",
+        "b.csv",
+        "--lateness",
+        "500",
+    ])
+    .unwrap()
+    {
+        Command::Process(options) => {
+            assert_eq!(options.merge_paths, ["a.csv", "b.csv"]);
+            assert_eq!(options.lateness, 500);
+        }
+        command => panic!("expected process, got {:?}", command),
+    }
+    assert!(parse(&["in.csv", "--lateness", "500"]).is_err());
+    assert!(parse(&["in.csv", "--merge", "a.csv", "--journal", "j.log"]).is_err());
+    assert!(parse(&["gen", "--clients", "0"]).is_err());
+    match parse(&["compact", "in.csv", "-o", "out.csv"]).unwrap() {
+        Command::Compact(options) => {
+            assert_eq!(options.output_path, "out.csv");
+            assert_eq!(options.options.csv_path.as_deref(), Some("in.csv"));
+        }
+        command => panic!("expected compact, got {:?}", command),
+    }
+    assert!(parse(&["compact", "in.csv"]).is_err());
+    assert!(parse(&["compact", "in.csv", "-o", "out.csv", "--threads", "2"]).is_err());
+    match parse(&[
+        "follow",
+        "--leader",
+        "10.0.0.1:7000",
+        "--listen",
+        "127.0.0.1:0",
+    ])
+    .unwrap()
+    {
+        Command::Follow(options) => {
+            assert_eq!(options.leader_addr, "10.0.0.1:7000");
+            assert_eq!(options.listen_addr, "127.0.0.1:0");
+        }
+        command => panic!("expected follow, got {:?}", command),
+    }
+    assert!(parse(&["follow", "--leader", "10.0.0.1:7000"]).is_err());
+    assert!(parse(&[
+        "follow",
+        "--leader",
+        "10.0.0.1:7000",
+        "--listen",
+        "127.0.0.1:0",
+        "--strict"
+    ])
+    .is_err());
+}
+
+/// Strict mode stops at the first rejected transaction, and reports which line it came from.
+#[test]
+fn strict_mode_stops_at_first_rejection() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+dispute,    1,      7,
+withdrawal, 1,      2,  5.0
+";
+
+    let mut rejects = 0;
+    let error = process_csv(
+        Engine::new().with_strict(true),
+        csv_reader_from_str(csv.as_bytes()),
+        |_, _| {
+            rejects += 1;
+            Ok(())
+        },
+    )
+    .unwrap_err();
+    assert_eq!(rejects, 0);
+    assert_eq!(
+        error.to_string(),
+        "line 3: client 1 referenced unknown transaction 7"
+    );
+
+    let ndjson = r#"{"type":"deposit","client":1,"tx":1,"amount":"1.0"}
+
+{"type":"withdrawal","client":1,"tx":2,"amount":"5.0"}
+"#;
+    let error = process_transactions(
+        Engine::new().with_strict(true),
+        input::ndjson_transactions(ndjson.as_bytes()),
+        ignore_rejects,
+    )
+    .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "line 3: insufficient funds for client 1 (tx 2)"
+    );
+}
+
+/// Processing on several threads gives the same client states and rejections as a single thread.
+#[test]
+fn parallel_processing_matches_single_thread() {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx_id in 1..=5_000u32 {
+        let client_id = tx_id % 97;
+        match tx_id % 5 {
+            0 | 1 => csv.push_str(&format!(
+                "deposit,{},{},{}.5\n",
+                client_id,
+                tx_id,
+                tx_id % 13
+            )),
+            2 | 3 => csv.push_str(&format!(
+                "withdrawal,{},{},{}\n",
+                client_id,
+                tx_id,
+                tx_id % 17
+            )),
+            _ => csv.push_str(&format!("dispute,{},{},\n", (tx_id - 4) % 97, tx_id - 4)),
+        }
+    }
+    let transactions = || input::csv_transactions(csv_reader_from_str(csv.as_bytes()));
+    let reason_counts = |rejects: Vec<TransactionError>| {
+        let mut counts = HashMap::new();
+        for e in rejects {
+            *counts.entry(e.to_string()).or_insert(0) += 1;
+        }
+        counts
+    };
+
+    let mut expected_rejects = Vec::new();
+    let expected = process_transactions(Engine::new(), transactions(), |_, e| {
+        expected_rejects.push(e.clone());
+        Ok(())
+    })
+    .unwrap();
+
+    for &threads in &[1, 3, 8] {
+        let mut rejects = Vec::new();
+        let client_states = process_parallel(Engine::new, threads, transactions(), |_, e| {
+            rejects.push(e.clone());
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(client_states.len(), expected.len());
+        for (client_id, state) in &client_states {
+            let expected = &expected[client_id];
+            assert_eq!(
+                (state.available, state.held, state.total, state.locked),
+                (
+                    expected.available,
+                    expected.held,
+                    expected.total,
+                    expected.locked
+                )
+            );
+        }
+        assert_eq!(
+            reason_counts(rejects),
+            reason_counts(expected_rejects.clone())
+        );
+    }
+
+    // Fatal errors still stop processing.
+    assert!(process_parallel(
+        || Engine::new().with_strict(true),
+        4,
+        transactions(),
+        ignore_rejects
+    )
+    .is_err());
+}
+
+/// Transactions from an async stream are applied the same way as any other source.
+#[cfg(feature = "tokio")]
+#[test]
+fn transactions_can_be_streamed() {
+    use futures_core::Stream;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    struct IterStream<I>(I);
+
+    impl<I: Iterator + Unpin> Stream for IterStream<I> {
+        type Item = I::Item;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.0.next())
+        }
+    }
+
+    let transactions: Vec<Transaction> = input::csv_transactions(csv_reader_from_str(
+        "\
+type,       client, tx, amount
+deposit,    1,      1,  2.0
+withdrawal, 1,      2,  5.0
+dispute,    1,      1,
+deposit,    2,      3,  1.0
+"
+        .as_bytes(),
+    ))
+    .collect::<Result<_, _>>()
+    .unwrap();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut rejects = Vec::new();
+    let client_states = runtime
+        .block_on(
+            Engine::new().process_stream(IterStream(transactions.into_iter()), |_, e| {
+                rejects.push(e.clone());
+                Ok(())
+            }),
+        )
+        .unwrap();
+
+    assert_eq!(client_states[&1].held, dec!(2));
+    assert_eq!(client_states[&2].available, dec!(1));
+    assert_eq!(
+        rejects,
+        vec![TransactionError::InsufficientFunds {
+            client_id: 1,
+            tx_id: 2
+        }]
+    );
+}
+
+/// Terminals are tracked from an optional column, and flagged when their reject or dispute rate
+/// stands out from the rest.
+#[test]
+fn terminals_with_anomalous_rates_are_flagged() {
+    use terminal::{TerminalStats, MIN_TRANSACTIONS_TO_FLAG};
+
+    let mut csv = String::from("type,client,tx,amount,terminal\n");
+    for tx_id in 0..(MIN_TRANSACTIONS_TO_FLAG as u32 * 4) {
+        let terminal = ["quiet", "rejects", "disputes", "normal"][tx_id as usize % 4];
+        match terminal {
+            "rejects" => csv.push_str(&format!("withdrawal,1,{},100,{}\n", tx_id, terminal)),
+            _ => csv.push_str(&format!("deposit,1,{},1,{}\n", tx_id, terminal)),
+        }
+        if terminal == "disputes" {
+            csv.push_str(&format!("dispute,1,{},,\n", tx_id));
+        }
+    }
+    // Transactions without a terminal aren't tracked, and rejected disputes don't count.
+    csv.push_str("deposit,2,1000,1,\ndispute,2,1000,,\ndispute,2,1000,,normal\n");
+
+    let mut stats = TerminalStats::default();
+    let transactions =
+        input::csv_transactions(csv_reader_from_str(csv.as_bytes())).inspect(|result| {
+            if let Ok(tx) = result {
+                stats.observe(tx);
+            }
+        });
+    let mut rejected = Vec::new();
+    process_transactions(Engine::new(), transactions, |tx, _| {
+        rejected.push(tx.clone());
+        Ok(())
+    })
+    .unwrap();
+    for tx in &rejected {
+        stats.reject(tx);
+    }
+
+    assert_eq!(stats.flagged(), vec!["disputes", "rejects"]);
+
+    let mut buffer = Vec::new();
+    stats
+        .write_report(&mut buffer, &OutputDialect::default())
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(buffer).unwrap(),
+        "\
+terminal,transactions,volume,rejected,disputed,reject_rate,dispute_rate,flagged
+disputes,20,20.0000,0,20,0.0000,1.0000,true
+normal,21,20.0000,1,0,0.0476,0.0000,false
+quiet,20,20.0000,0,0,0.0000,0.0000,false
+rejects,20,2000.0000,20,0,1.0000,0.0000,true
+"
+    );
+}
+
+/// Every client's proof leads to the same root, and proofs don't verify for altered balances.
+#[test]
+fn balance_proofs_verify_against_root() {
+    use output::BalanceSink;
+    use proof::BalanceProofs;
+
+    // An empty tree still has a root.
+    assert_eq!(BalanceProofs::new(b"key".to_vec()).build().1, vec![]);
+
+    // Odd numbers of leaves exercise nodes being carried up without a sibling.
+    for clients in 1..=9u16 {
+        let mut proofs = BalanceProofs::new(b"key".to_vec());
+        for client_id in (1..=clients).rev() {
+            let mut state = ClientState::new(client_id);
+            state.total = Money::from(Decimal::from(client_id) * dec!(1.5));
+            proofs.write(&state).unwrap();
+        }
+        let (root, client_proofs) = proofs.build();
+
+        assert_eq!(client_proofs.len(), clients as usize);
+        for (proof, client_id) in client_proofs.iter().zip(1..) {
+            assert_eq!(proof.client, client_id);
+            assert!(proof.verify(&root));
+
+            let mut altered = proof.clone();
+            altered.total += Money::from(dec!(0.0001));
+            assert!(!altered.verify(&root));
+        }
+
+        // Salts depend on the key, so a different key gives a different root.
+        let mut other_key = BalanceProofs::new(b"other key".to_vec());
+        for proof in &client_proofs {
+            let mut state = ClientState::new(proof.client);
+            state.total = proof.total;
+            other_key.write(&state).unwrap();
+        }
+        assert_ne!(other_key.build().0, root);
+    }
+}
+
+/// Connections to the server share one engine, and get a response to every transaction.
+#[test]
+fn server_connections_share_an_engine() {
+    use std::sync::Mutex;
+
+    let engine = Mutex::new(Engine::new());
+    let respond = |input: &str, format| {
+        let mut output = Vec::new();
+        server::handle_connection(input.as_bytes(), &mut output, &engine, format).unwrap();
+        String::from_utf8(output).unwrap()
+    };
+
+    assert_eq!(
+        respond(
+            "type,client,tx,amount\ndeposit,1,1,10.0\n\nwithdrawal, 1, 2, 50\nrefund,1,3,1\n",
+            InputFormat::Csv
+        ),
+        "OK\nREJECTED insufficient_funds\nERROR transaction 3 for client 1 has an unknown type\n"
+    );
+    assert_eq!(
+        respond(
+            "{\"type\":\"deposit\",\"client\":2,\"tx\":3,\"amount\":\"2.5\"}\n\
+             {\"type\":\"dispute\",\"client\":1,\"tx\":1}\nBALANCES\n",
+            InputFormat::Ndjson
+        ),
+        "OK\nOK\nclient,available,held,total,locked,version\n1,0.0000,10.0000,10.0000,false,2\n\
+         2,2.5000,0.0000,2.5000,false,1\n\n"
+    );
+
+    let parse = |args: &[&str]| cli::ServeOptions::from_args(args.iter().map(|s| s.to_string()));
+    let options = parse(&["--listen", "127.0.0.1:0", "--strict"]).unwrap();
+    assert_eq!(options.listen_addr, "127.0.0.1:0");
+    assert!(options.options.strict);
+    assert!(parse(&["--strict"]).is_err());
+    assert!(parse(&["--listen", "127.0.0.1:0", "in.csv"]).is_err());
+    assert!(parse(&["--listen", "127.0.0.1:0", "--output-shards", "2"]).is_err());
+    assert!(
+        parse(&["--listen", "127.0.0.1:0", "--history"])
+            .unwrap()
+            .history
+    );
+    let command = |args: &[&str]| cli::Command::from_args(args.iter().map(|s| s.to_string()));
+    assert_eq!(
+        parse(&["--listen", "127.0.0.1:0", "--replicate", "127.0.0.1:7000"])
+            .unwrap()
+            .replicate_addr
+            .as_deref(),
+        Some("127.0.0.1:7000")
+    );
+    assert!(command(&["serve", "--listen", "127.0.0.1:0", "--history"]).is_err());
+}
+
+/// The HTTP API applies transactions and exposes client states as JSON.
+#[cfg(feature = "http")]
+#[test]
+fn http_api_submits_and_queries() {
+    use std::sync::Mutex;
+
+    let engine = Mutex::new(Engine::new());
+    let request = |method, url, body| http::handle(&engine, method, url, body);
+
+    assert_eq!(
+        request(
+            "POST",
+            "/transactions",
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}"#
+        ),
+        (200, r#"{"status":"ok"}"#.to_string())
+    );
+    assert_eq!(
+        request(
+            "POST",
+            "/transactions",
+            r#"{"type":"withdrawal","client":1,"tx":2,"amount":"50"}"#
+        ),
+        (
+            422,
+            r#"{"status":"rejected","reason":"insufficient_funds","message":"insufficient funds for client 1 (tx 2)"}"#
+                .to_string()
+        )
+    );
+    assert_eq!(request("POST", "/transactions", "not json").0, 400);
+
+    let client = r#"{"client":1,"available":"10.0000","held":"0.0000","total":"10.0000","locked":false,"version":1}"#;
+    assert_eq!(request("GET", "/clients/1", ""), (200, client.to_string()));
+    assert_eq!(
+        request("GET", "/balances?x=1", ""),
+        (200, format!("[{}]", client))
+    );
+    assert_eq!(request("GET", "/clients/2", "").0, 404);
+    assert_eq!(request("GET", "/clients/abc", "").0, 400);
+    assert_eq!(request("DELETE", "/balances", "").0, 405);
+    assert_eq!(request("GET", "/nowhere", "").0, 404);
+    assert_eq!(request("GET", "/clients/1?as_of=1", "").0, 400);
+
+    let engine = Mutex::new(Engine::new().with_history());
+    let request = |method, url| http::handle(&engine, method, url, "");
+    for tx in 1..=2 {
+        let deposit = format!(
+            r#"{{"type":"deposit","client":1,"tx":{},"amount":"10"}}"#,
+            tx
+        );
+        http::handle(&engine, "POST", "/transactions", &deposit);
+    }
+    let (status, body) = request("GET", "/clients/1?as_of=1");
+    assert_eq!(status, 200);
+    assert!(body.contains(r#""total":"10.0000""#));
+    assert!(request("GET", "/clients/1")
+        .1
+        .contains(r#""total":"20.0000""#));
+    assert_eq!(request("GET", "/clients/1?as_of=0").0, 404);
+    assert_eq!(
+        request("GET", "/clients/1?as_of=2100-01-01T00:00:00%2B01:00").1,
+        request("GET", "/clients/1").1
+    );
+    assert_eq!(
+        request("GET", "/clients/1?as_of=1970-01-01T00:00:00Z").0,
+        404
+    );
+    assert_eq!(request("GET", "/clients/1?as_of=yesterday").0, 400);
+}
+
+/// Restoring a snapshot picks up where the snapshotted engine left off, including outstanding
+/// disputes and transaction IDs which have already been used.
+#[test]
+fn snapshots_resume_processing() {
+    let first_batch = "\
+type,       client, tx, amount
+deposit,    1,      1,  10.0
+deposit,    1,      2,  5.0
+dispute,    1,      1,
+deposit,    2,      3,  1.0
+";
+    let second_batch = "\
+type,       client, tx, amount
+resolve,    1,      1,
+deposit,    1,      2,  7.0
+dispute,    2,      3,
+chargeback, 2,      3,
+";
+
+    let mut engine = Engine::new();
+    apply_transactions(
+        &mut engine,
+        csv_transactions(csv_reader_from_str(first_batch.as_bytes())),
+        ignore_rejects,
+    )
+    .unwrap();
+    let mut snapshot = Vec::new();
+    engine.write_snapshot(&mut snapshot).unwrap();
+
+    // Snapshots of the same state are identical.
+    let mut rewritten = Vec::new();
+    Engine::new()
+        .restore_snapshot(snapshot.as_slice())
+        .unwrap()
+        .write_snapshot(&mut rewritten)
+        .unwrap();
+    assert_eq!(snapshot, rewritten);
+
+    // The dispute and earlier transaction IDs carry over into the next batch.
+    let mut rejects = Vec::new();
+    let client_states = process_csv(
+        Engine::new().restore_snapshot(snapshot.as_slice()).unwrap(),
+        csv_reader_from_str(second_batch.as_bytes()),
+        |_, e| {
+            rejects.push(e.clone());
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        rejects,
+        vec![TransactionError::DuplicateTxId {
+            client_id: 1,
+            tx_id: 2
+        }]
+    );
+
+    let client_1 = &client_states[&1];
+    assert_eq!(client_1.available, dec!(15));
+    assert_eq!(client_1.held, dec!(0));
+    let client_2 = &client_states[&2];
+    assert_eq!(client_2.total, dec!(0));
+    assert!(client_2.locked);
+
+    let unsupported = format!(
+        r#"{{"version":{},"clients":[],"transactions":[]}}"#,
+        snapshot::SNAPSHOT_VERSION + 1
+    );
+    let error = Engine::new()
+        .restore_snapshot(unsupported.as_bytes())
+        .err()
+        .unwrap();
+    assert!(error
+        .to_string()
+        .starts_with("unsupported snapshot version"));
+}
+
+/// Replaying a journal cut short by a crash brings a new engine back to the state the journal
+/// recorded, and the journal can be continued from there.
+#[test]
+fn journal_replays_applied_transactions() {
+    let dir = std::env::temp_dir().join(format!("payment-engine-journal-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("journal.log");
+
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  10.0
+withdrawal, 1,      2,  20.0
+deposit,    2,      3,  5.0
+dispute,    1,      1,
+";
+    let mut engine = Engine::new();
+    let mut journal =
+        journal::JournalWriter::create(&path, journal::JournalSync::Never, &engine).unwrap();
+    apply_transactions_with(
+        &mut engine,
+        csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        ignore_rejects,
+        |tx, rejected| Ok(journal.record(tx, rejected)?),
+    )
+    .unwrap();
+    journal.sync().unwrap();
+
+    // Rejected transactions are journaled too, with their reasons, after the starting state.
+    // Simulate a crash part way through the last entry.
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(contents.lines().count(), 5);
+    assert!(contents.contains(r#""rejected":"insufficient_funds""#));
+    std::fs::write(&path, &contents[..contents.len() - 10]).unwrap();
+
+    let mut recovered = Engine::new();
+    let last_line = journal::replay(&mut recovered, &path).unwrap();
+    assert_eq!(last_line, Some(4));
+    assert_eq!(recovered.client_states()[&1].available, dec!(10));
+    assert_eq!(recovered.client_states()[&2].available, dec!(5));
+
+    // The partial entry is dropped before the journal is continued.
+    let mut journal = journal::JournalWriter::append(&path, journal::JournalSync::Always).unwrap();
+    let dispute = csv_transactions(csv_reader_from_str(csv.as_bytes()))
+        .nth(3)
+        .unwrap()
+        .unwrap();
+    journal.record(&dispute, None).unwrap();
+
+    let mut replayed = Engine::new();
+    assert_eq!(journal::replay(&mut replayed, &path).unwrap(), Some(5));
+    assert_eq!(replayed.client_states()[&1].held, dec!(10));
+
+    // Replaying on top of any other state (e.g. the state the journaled run ended in) is refused.
+    assert!(journal::replay(&mut replayed, &path)
+        .unwrap_err()
+        .to_string()
+        .starts_with("journal was started from a different engine state"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Run `csv` through a new engine, journaling it, as far as the first `crashed_at` transactions,
+/// then recover another new engine from the journal and apply the rest of `csv`, as `--recover`
+/// does. Returns the recovered engine's client states.
+fn recovered_run(
+    new_engine: impl Fn() -> Engine,
+    csv: &str,
+    crashed_at: usize,
+) -> HashMap<u16, ClientState> {
+    let path = std::env::temp_dir().join(format!(
+        "payment-engine-recovered-{}-{}.log",
+        std::process::id(),
+        crashed_at
+    ));
+    let transactions = || csv_transactions(csv_reader_from_str(csv.as_bytes()));
+
+    let mut engine = new_engine();
+    let mut journal =
+        journal::JournalWriter::create(&path, journal::JournalSync::Always, &engine).unwrap();
+    apply_transactions_with(
+        &mut engine,
+        transactions().take(crashed_at),
+        ignore_rejects,
+        |tx, rejected| Ok(journal.record(tx, rejected)?),
+    )
+    .unwrap();
+    drop(journal);
+
+    let mut engine = new_engine();
+    let last_line = journal::replay(&mut engine, &path).unwrap();
+    let states = process_transactions(
+        engine,
+        transactions().filter(|tx| tx.as_ref().unwrap().line > last_line),
+        ignore_rejects,
+    )
+    .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    states
+}
+
+/// Journals keep transactions' timestamps, so a run recovered from one sees the same times as an
+/// uninterrupted run, and keeps the same disputes within a duration window.
+#[test]
+fn recovered_runs_keep_timestamps() {
+    let csv = "\
+type,    client, tx, amount, timestamp
+deposit, 1,      1,  10.0,   1000
+deposit, 2,      2,  1.0,    1500
+dispute, 1,      1,,
+";
+    let new_engine = || Engine::new().with_dispute_window("1s".parse().unwrap());
+    let states = process_csv(
+        new_engine(),
+        csv_reader_from_str(csv.as_bytes()),
+        ignore_rejects,
+    )
+    .unwrap();
+    assert_eq!(states[&1].held, dec!(10));
+
+    let recovered = recovered_run(new_engine, csv, 1);
+    assert_eq!(recovered[&1].held, dec!(10));
+    assert_eq!(recovered[&1].available, dec!(0));
+}
+
+/// Rejected transactions count towards a window of transactions, so they're journaled (and
+/// replayed) too, and a run recovered from a journal counts them as an uninterrupted run does.
+#[test]
+fn recovered_runs_count_rejected_transactions() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  10.0
+withdrawal, 1,      2,  50.0
+deposit,    1,      3,  1.0
+dispute,    1,      1,
+";
+    let new_engine = || Engine::new().with_dispute_window("2".parse().unwrap());
+    let states = process_csv(
+        new_engine(),
+        csv_reader_from_str(csv.as_bytes()),
+        ignore_rejects,
+    )
+    .unwrap();
+    assert_eq!(states[&1].available, dec!(11));
+
+    let recovered = recovered_run(new_engine, csv, 2);
+    assert_eq!(recovered[&1].available, dec!(11));
+    assert_eq!(recovered[&1].held, dec!(0));
+}
+
+/// Every disputable transaction is kept, so they have to stay small: the kind of transaction is a
+/// byte rather than a whole `TransactionType` (which carries custom type names).
+#[test]
+fn disputable_transactions_stay_small() {
+    assert_eq!(std::mem::size_of::<store::DisputableKind>(), 1);
+    assert_eq!(std::mem::size_of::<store::DisputableTx>(), 32);
+    assert_eq!(std::mem::size_of::<(u32, store::DisputableTx)>(), 40);
+}
+
+/// Spilling disputable transactions to disk doesn't change how any transaction is handled.
+#[test]
+fn spilled_transactions_can_be_disputed() {
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx_id in 1..=2000 {
+        csv.push_str(&format!(
+            "deposit,{},{},1.5\n",
+            tx_id % 7,
+            tx_id * 7919 % 10007
+        ));
+    }
+    for tx_id in (1..=2000).step_by(13) {
+        let id = tx_id * 7919 % 10007;
+        csv.push_str(&format!("dispute,{},{},\n", tx_id % 7, id));
+        csv.push_str(&format!("deposit,{},{},1.0\n", tx_id % 7, id));
+        if tx_id % 2 == 0 {
+            csv.push_str(&format!("chargeback,{},{},\n", tx_id % 7, id));
+        }
+    }
+
+    let process = |engine: Engine| {
+        let mut rejects = Vec::new();
+        let mut engine = engine;
+        apply_transactions(
+            &mut engine,
+            csv_transactions(csv_reader_from_str(csv.as_bytes())),
+            |_, e| {
+                rejects.push(e.clone());
+                Ok(())
+            },
+        )
+        .unwrap();
+        let mut snapshot = Vec::new();
+        engine.write_snapshot(&mut snapshot).unwrap();
+        (rejects, snapshot)
+    };
+
+    let (rejects, snapshot) = process(Engine::new());
+    let limit = store::parse_size("10K").unwrap();
+    assert_eq!(limit, 10240);
+    let (spilled_rejects, spilled_snapshot) = process(Engine::new().with_memory_limit(limit));
+    assert_eq!(rejects.len(), 367);
+    assert_eq!(spilled_rejects, rejects);
+    assert_eq!(spilled_snapshot, snapshot);
+
+    assert!(store::parse_size("0").is_err());
+    assert!(store::parse_size("2X").is_err());
+}
+
+/// Synthetic logs are the same for the same seed, and every row is a valid transaction.
+#[test]
+fn synthetic_logs_are_deterministic() {
+    use crate::synthetic::Generator;
+
+    let generate = |seed| {
+        let mut log = Vec::new();
+        Generator {
+            rows: 10_000,
+            clients: 50,
+            dispute_rate: 0.05,
+            seed,
+            ..Default::default()
+        }
+        .write_csv(&mut log)
+        .unwrap();
+        log
+    };
+
+    let log = generate(1);
+    assert_eq!(log, generate(1));
+    assert_ne!(log, generate(2));
+
+    let reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(log.as_slice());
+    let transactions: Vec<_> = input::csv_transactions(reader)
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(transactions.len(), 10_000);
+    assert!(transactions
+        .iter()
+        .any(|tx| matches!(tx.r#type, TransactionType::Chargeback)));
+}
+
+/// Balances after applying every transaction in `transactions`, ordered by client.
+fn final_balances(transactions: TransactionStream) -> Vec<(u16, Money, Money, bool)> {
+    let mut balances: Vec<_> = process_transactions(Engine::new(), transactions, |_, _| Ok(()))
+        .unwrap()
+        .into_values()
+        .map(|state| (state.client_id, state.available, state.held, state.locked))
+        .collect();
+    balances.sort_unstable_by_key(|balance| balance.0);
+    balances
+}
+
+/// A synthetic CSV log of `rows` transactions.
+fn synthetic_log(rows: u64) -> Vec<u8> {
+    let mut log = Vec::new();
+    synthetic::Generator {
+        rows,
+        clients: 20,
+        dispute_rate: 0.05,
+        ..Default::default()
+    }
+    .write_csv(&mut log)
+    .unwrap();
+    log
+}
+
+/// Short, interrupted, and delayed reads don't change which transactions are read, for files or
+/// server connections.
+#[test]
+fn flaky_reads_are_handled() {
+    use flaky::FlakyReader;
+    use std::io::{BufReader, Cursor};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    let log = synthetic_log(2000);
+    let flaky = |seed| {
+        FlakyReader::new(Cursor::new(log.clone()), seed)
+            .with_short_reads(7)
+            .with_interruptions(0.2)
+            .with_delays(0.001, Duration::from_millis(1))
+    };
+
+    let expected = final_balances(read_transactions(
+        Cursor::new(log.clone()),
+        InputFormat::Csv,
+    ));
+    for seed in 0..4 {
+        assert_eq!(
+            final_balances(read_transactions(flaky(seed), InputFormat::Csv)),
+            expected
+        );
+    }
+
+    let engine = Mutex::new(Engine::new());
+    let mut responses = Vec::new();
+    server::handle_connection(
+        BufReader::new(flaky(4)),
+        &mut responses,
+        &engine,
+        InputFormat::Csv,
+    )
+    .unwrap();
+    assert_eq!(String::from_utf8(responses).unwrap().lines().count(), 2000);
+}
+
+/// An early EOF ends input at the end of a line, and reading again picks up from the next line.
+#[test]
+fn early_eofs_end_input_between_lines() {
+    use flaky::FlakyReader;
+    use input::RetryInterrupted;
+    use std::io::{BufReader, Cursor};
+
+    // NDJSON has no header, so every read after an EOF can be parsed the same way.
+    let log: String = String::from_utf8(synthetic_log(2000))
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            match fields[3] {
+                "" => format!(
+                    "{{\"type\":\"{}\",\"client\":{},\"tx\":{}}}\n",
+                    fields[0], fields[1], fields[2]
+                ),
+                amount => format!(
+                    "{{\"type\":\"{}\",\"client\":{},\"tx\":{},\"amount\":\"{}\"}}\n",
+                    fields[0], fields[1], fields[2], amount
+                ),
+            }
+        })
+        .collect();
+
+    let mut reader = RetryInterrupted(
+        FlakyReader::new(Cursor::new(log.clone().into_bytes()), 0)
+            .with_short_reads(64)
+            .with_interruptions(0.1)
+            .with_eofs(0.05),
+    );
+    let mut transactions = Vec::new();
+    let mut reads = 0;
+    loop {
+        let before = transactions.len();
+        transactions
+            .extend(input::ndjson_transactions(BufReader::new(&mut reader)).map(Result::unwrap));
+        reads += 1;
+        // Every read ends after a whole line.
+        assert_eq!(
+            reader.0.bytes_read() as usize,
+            log.lines()
+                .take(transactions.len())
+                .map(|line| line.len() + 1)
+                .sum::<usize>()
+        );
+        if transactions.len() == before {
+            break;
+        }
+    }
+    assert!(reads > 2);
+    assert_eq!(transactions.len(), 2000);
+
+    assert_eq!(
+        final_balances(Box::new(transactions.into_iter().map(Ok))),
+        final_balances(read_transactions(
+            Cursor::new(log.into_bytes()),
+            InputFormat::Ndjson
+        ))
+    );
+}
+
+/// The fast CSV parser reads every row the same way as serde, falling back to serde for rows it
+/// can't parse itself.
+#[test]
+fn fast_parsing_matches_serde() {
+    let rows = "deposit, 1, 1, 1.23456, t1, x\n\
+               deposit, 1, 2, 12345678901234.56789\n\
+               deposit, 1, 3, 1e2\n\
+               withdrawal, 1, 4, +5\n\
+               withdrawal, 1, 5, -0.5\n\
+               dispute, 1, 1,\n\
+               dispute, 1, 2\n\
+               deposit, +2, 6, 3\n\
+               deposit, 2, 7, \u{a0}4.5\u{a0}, \u{a0}t2\n\
+               deposit, 70000, 8, 1\n\
+               Deposit, 2, 9, 1\n\
+               deposit, 2, 10, true\n\
+               deposit, 2, 11, .5\n\
+               resolve, 1, 1, 0.0001\n";
+    let describe = |result: Result<Transaction, Box<dyn Error>>| match result {
+        Ok(tx) => format!(
+            "{:?} {} {} {:?} {:?} {:?}",
+            tx.r#type, tx.client_id, tx.tx_id, tx.amount, tx.terminal, tx.line
+        ),
+        Err(e) => e.to_string(),
+    };
+
+    // Serde needs a field for every column it ignores, but not for optional columns.
+    for header in [
+        "type, client, tx, amount, terminal",
+        "type, client, tx, amount, terminal, extra",
+    ] {
+        let csv = format!("{}\n{}", header, rows);
+        let fast: Vec<String> = fast_csv_transactions(csv_reader_from_str(csv.as_bytes()))
+            .map(describe)
+            .collect();
+        let serde: Vec<String> = csv_transactions(csv_reader_from_str(csv.as_bytes()))
+            .map(describe)
+            .collect();
+        assert_eq!(fast, serde);
+        assert_eq!(fast.len(), 14);
+    }
+
+    // Columns can be in any order, and the amount column is optional.
+    let csv = "tx,type,client\n1,dispute,3\n";
+    let fast: Vec<String> = fast_csv_transactions(csv_reader_from_str(csv.as_bytes()))
+        .map(describe)
+        .collect();
+    assert_eq!(fast, vec!["Dispute 3 1 None None Some(2)"]);
+}
+
+/// Balance exports in every format can be read back into the same client states.
+#[test]
+fn balance_exports_can_be_read_back() {
+    let csv = "type, client, tx, amount\n\
+               deposit, 1, 1, 999999999999999\n\
+               deposit, 1, 4, 0.0001\n\
+               deposit, 2, 2, 2.5\n\
+               dispute, 2, 2,\n\
+               deposit, 3, 3, 1\n\
+               dispute, 3, 3,\n\
+               chargeback, 3, 3,\n";
+    let states = process_csv(
+        Engine::new(),
+        csv_reader_from_str(csv.as_bytes()),
+        ignore_rejects,
+    )
+    .unwrap();
+
+    // Too precise for a float.
+    assert_eq!(states[&1].total, dec!(999999999999999.0001));
+
+    let dir = std::env::temp_dir().join(format!("payment-engine-balances-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let summarize = |states: &HashMap<u16, ClientState>| {
+        let mut states: Vec<_> = states
+            .values()
+            .map(|state| {
+                (
+                    state.client_id,
+                    state.available,
+                    state.held,
+                    state.total,
+                    state.locked,
+                    state.version,
+                )
+            })
+            .collect();
+        states.sort_unstable_by_key(|state| state.0);
+        states
+    };
+
+    for name in ["balances.csv", "balances.json", "balances.ndjson"] {
+        let path = dir.join(name);
+        let mut file = std::fs::File::create(&path).unwrap();
+        let mut sink: Box<dyn BalanceSink> = match name {
+            "balances.csv" => Box::new(BalanceWriter::new(&mut file, &OutputDialect::default())),
+            "balances.json" => Box::new(JsonBalanceWriter::array(&mut file)),
+            _ => Box::new(JsonBalanceWriter::ndjson(&mut file)),
+        };
+        write_balances(&mut *sink, &states, SortBy::Client).unwrap();
+        drop(sink);
+
+        let read = read_balances(path.to_str().unwrap()).unwrap();
+        assert_eq!(summarize(&read), summarize(&states), "{}", name);
+    }
+
+    let duplicated = dir.join("duplicated.csv");
+    std::fs::write(
+        &duplicated,
+        "client,available,held,total,locked\n1,1.0,0,1.0,false\n1,2.0,0,2.0,false\n",
+    )
+    .unwrap();
+    assert!(read_balances(duplicated.to_str().unwrap()).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Fees are taken from available funds, tracked per client, and only exported when charged.
+#[test]
+fn fees_are_charged() {
+    use fees::FeeSchedule;
+
+    let fees = FeeSchedule::from_toml(
+        "[deposit]\npercent = 1.5\n\n[withdrawal]\nflat = \"0.50\"\npercent = \"0.1\"\n",
+    )
+    .unwrap();
+    let csv = "type, client, tx, amount\n\
+               deposit, 1, 1, 100\n\
+               withdrawal, 1, 2, 50\n\
+               dispute, 1, 1,\n\
+               deposit, 2, 3, 0.3\n\
+               withdrawal, 2, 4, 0.1\n";
+    let mut rejects = Vec::new();
+    let states = process_csv(
+        Engine::new().with_fees(fees.clone()),
+        csv_reader_from_str(csv.as_bytes()),
+        |tx, e| {
+            rejects.push((tx.tx_id, e.reason()));
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    // Disputes hold the deposited amount, not the amount left after its fee.
+    assert_eq!(states[&1].available, dec!(-52.05));
+    assert_eq!(states[&1].held, dec!(100));
+    assert_eq!(states[&1].fees_collected, Some(Money::new(dec!(2.05))));
+    // The withdrawal would leave too little to cover its fee.
+    assert_eq!(states[&2].total, dec!(0.2955));
+    assert_eq!(rejects, vec![(4, "insufficient_funds")]);
+
+    let mut output = Vec::new();
+    let mut sink = BalanceWriter::new(&mut output, &OutputDialect::default());
+    write_balances(&mut sink, &states, SortBy::Client).unwrap();
+    drop(sink);
+    assert!(String::from_utf8(output)
+        .unwrap()
+        .starts_with("client,available,held,total,locked,version,fees_collected\n"));
+
+    let states = process_csv(
+        Engine::new(),
+        csv_reader_from_str(csv.as_bytes()),
+        ignore_rejects,
+    )
+    .unwrap();
+    assert_eq!(states[&1].fees_collected, None);
+
+    assert!(FeeSchedule::from_toml("[deposit]\nflat = -1\n").is_err());
+    assert!(FeeSchedule::from_toml("[withdrawal]\npercent = 101\n").is_err());
+    assert!(FeeSchedule::from_toml("[chargeback]\nflat = 1\n").is_err());
+}
+
+/// A compacted log gives the same client states, and transactions still under dispute can be
+/// settled afterwards.
+#[test]
+fn compacted_logs_have_the_same_outcome() {
+    let csv = "type, client, tx, amount\n\
+               deposit, 1, 1, 10\n\
+               deposit, 1, 2, 5\n\
+               withdrawal, 1, 3, 12\n\
+               dispute, 1, 2,\n\
+               dispute, 1, 3,\n\
+               deposit, 2, 4, 10\n\
+               withdrawal, 2, 5, 10\n\
+               dispute, 2, 4,\n\
+               chargeback, 2, 4,\n\
+               deposit, 3, 6, 1\n\
+               dispute, 3, 6,\n\
+               chargeback, 3, 6,\n\
+               deposit, 4, 7, 2\n\
+               withdrawal, 4, 8, 2\n\
+               withdrawal, 5, 9, 1\n";
+    let mut engine = Engine::new();
+    apply_transactions(
+        &mut engine,
+        csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        ignore_rejects,
+    )
+    .unwrap();
+
+    let dir = std::env::temp_dir().join(format!("payment-engine-compact-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("compacted.csv");
+    compact::write_file(&engine, &path).unwrap();
+
+    let compacted = open_transactions(path.to_str().unwrap(), InputFormat::Csv).unwrap();
+    let mut replayed = Engine::new();
+    apply_transactions(&mut replayed, compacted, |_, e| Err(e.to_string().into())).unwrap();
+    for (client_id, state) in engine.client_states() {
+        let compacted = &replayed.client_states()[client_id];
+        assert_eq!(
+            (compacted.available, compacted.held, compacted.locked),
+            (state.available, state.held, state.locked)
+        );
+    }
+    assert_eq!(replayed.client_states()[&2].available, dec!(-10));
+
+    // The disputes carried over can still be settled.
+    let settle = |engine: &mut Engine| {
+        for tx_id in [2, 3] {
+            let resolve = Transaction {
+                r#type: TransactionType::Resolve,
+                client_id: 1,
+                tx_id,
+                amount: None,
+                raw_amount: None,
+                line: None,
+                terminal: None,
+                currency: Default::default(),
+                to_currency: Default::default(),
+                timestamp: None,
+            };
+            engine.apply(&resolve).unwrap();
+        }
+        engine.client_states()[&1].available
+    };
+    assert_eq!(settle(&mut replayed), settle(&mut engine));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Funds are tracked separately in each currency, disputes only match their own currency, and
+/// exports (and everything built from them) have a row per currency.
+#[test]
+fn currencies_are_tracked_separately() {
+    use currency::Currency;
+
+    let eur: Currency = "eur".parse().unwrap();
+    let csv = "type, client, tx, amount, currency\n\
+               deposit, 1, 1, 10,\n\
+               deposit, 1, 2, 5, eur\n\
+               withdrawal, 1, 3, 7, EUR\n\
+               dispute, 1, 2,, usd\n\
+               dispute, 1, 2,, EUR\n\
+               deposit, 2, 4, 3, USD\n\
+               deposit, 3, 5, 4, EUR\n\
+               withdrawal, 3, 6, 4, EUR\n\
+               dispute, 3, 5,, EUR\n\
+               chargeback, 3, 5,, EUR\n\
+               deposit, 3, 7, 1\n";
+    let mut rejects = Vec::new();
+    let mut engine = Engine::new();
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        |tx, e| {
+            rejects.push((tx.tx_id, e.reason()));
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        rejects,
+        vec![
+            (3, "insufficient_funds"),
+            (2, "currency_mismatch"),
+            (7, "account_locked")
+        ]
+    );
+
+    let states = engine.client_states();
+    assert_eq!(states[&1].available, dec!(10));
+    let balance = states[&1].balance(eur);
+    assert_eq!(
+        (balance.available, balance.held),
+        (Money::ZERO, Money::from(dec!(5)))
+    );
+    assert_eq!(states[&3].balance(eur).total, dec!(-4));
+    assert!(states[&3].locked);
+
+    let mut output = Vec::new();
+    let mut sink = BalanceWriter::new(&mut output, &OutputDialect::default());
+    write_balances(&mut sink, states, SortBy::Client).unwrap();
+    drop(sink);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,currency,available,held,total,locked,version\n\
+         1,,10.0000,0.0000,10.0000,false,3\n\
+         1,EUR,0.0000,5.0000,5.0000,false,3\n\
+         2,USD,3.0000,0.0000,3.0000,false,1\n\
+         3,EUR,-4.0000,0.0000,-4.0000,true,4\n"
+    );
+
+    let dir =
+        std::env::temp_dir().join(format!("payment-engine-currencies-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join("balances.csv");
+    let mut file = std::fs::File::create(&path).unwrap();
+    write_balances(
+        &mut BalanceWriter::new(&mut file, &OutputDialect::default()),
+        states,
+        SortBy::Client,
+    )
+    .unwrap();
+    let read = read_balances(path.to_str().unwrap()).unwrap();
+    assert_eq!(read[&1].currencies, states[&1].currencies);
+    assert_eq!(read[&1].available, states[&1].available);
+
+    let mut snapshot = Vec::new();
+    engine.write_snapshot(&mut snapshot).unwrap();
+    let restored = Engine::new().restore_snapshot(snapshot.as_slice()).unwrap();
+    assert_eq!(
+        restored.client_states()[&1].currencies,
+        states[&1].currencies
+    );
+
+    // Compaction keeps each currency's funds, and verifies them before writing the log.
+    compact::write_file(&engine, &dir.join("compacted.csv")).unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Engines keeping history can look up any past client state, by version or by time.
+#[test]
+fn past_states_can_be_queried() {
+    use history::{AsOf, StateHistory};
+
+    let csv = "type, client, tx, amount\n\
+               deposit, 1, 1, 10\n\
+               withdrawal, 1, 2, 50\n\
+               withdrawal, 1, 3, 4\n\
+               dispute, 1, 1,\n";
+    let mut engine = Engine::new().with_history();
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        ignore_rejects,
+    )
+    .unwrap();
+
+    let history = engine.history().unwrap();
+    let available = |as_of| history.get(1, as_of).map(|state| state.available);
+    assert_eq!(available(AsOf::Version(0)), None);
+    assert_eq!(available(AsOf::Version(1)), Some(Money::from(dec!(10))));
+    // The rejected withdrawal didn't make a version.
+    assert_eq!(available(AsOf::Version(2)), Some(Money::from(dec!(6))));
+    assert_eq!(available(AsOf::Version(9)), Some(Money::from(dec!(-4))));
+    assert_eq!(available(AsOf::Time(0)), None);
+    assert!(history.get(2, AsOf::Version(9)).is_none());
+
+    // Times are inclusive, and never go backwards.
+    let mut history = StateHistory::default();
+    let mut state = ClientState::new(1);
+    for (version, at) in [(1, 1_000), (2, 2_000), (3, 1_500)] {
+        state.version = version;
+        history.record_at(&state, at);
+    }
+    let version = |at| history.get(1, AsOf::Time(at)).map(|state| state.version);
+    assert_eq!(version(999), None);
+    assert_eq!(version(1_000), Some(1));
+    assert_eq!(version(1_999), Some(1));
+    assert_eq!(version(2_000), Some(3));
+
+    assert_eq!("42".parse(), Ok(AsOf::Version(42)));
+    assert_eq!("1970-01-01T00:00:01Z".parse(), Ok(AsOf::Time(1_000)));
+    assert_eq!(
+        "2022-03-01T00:00:00.25+01:00".parse(),
+        Ok(AsOf::Time(1_646_089_200_250))
+    );
+    assert_eq!(
+        "2000-02-29 12:00:00z".parse(),
+        Ok(AsOf::Time(951_825_600_000))
+    );
+    for invalid in [
+        "",
+        "-1",
+        "2022-13-01T00:00:00Z",
+        "2022-03-01T00:00:00",
+        "2022-03-01",
+    ] {
+        assert!(invalid.parse::<AsOf>().is_err(), "{}", invalid);
+    }
+}
+
+/// Conversions move funds between a client's currencies at the table's rate, and anything which
+/// can't be converted is rejected with the reason.
+#[test]
+fn conversions_use_the_rate_table() {
+    use currency::Currency;
+    use rates::RateTable;
+
+    let rates = RateTable::from_reader("from,to,rate\nusd,EUR,0.92\n".as_bytes()).unwrap();
+    let csv = "type, client, tx, amount, currency, to_currency\n\
+               deposit, 1, 1, 100, USD,\n\
+               convert, 1, 2, 1.2345, USD, EUR\n\
+               convert, 1, 3, 1, EUR, USD\n\
+               convert, 1, 4, 500, USD, EUR\n\
+               convert, 1, 5, 1, USD,\n\
+               dispute, 1, 2,, USD\n";
+    let convert = |engine: Engine, transactions: TransactionStream| {
+        let mut rejects = Vec::new();
+        let states = process_transactions(engine, transactions, |tx, e| {
+            rejects.push((tx.tx_id, e.reason()));
+            Ok(())
+        })
+        .unwrap();
+        (states, rejects)
+    };
+
+    let (states, rejects) = convert(
+        Engine::new().with_rates(rates.clone()),
+        Box::new(fast_csv_transactions(csv_reader_from_str(csv.as_bytes()))),
+    );
+    let usd: Currency = "USD".parse().unwrap();
+    let eur: Currency = "EUR".parse().unwrap();
+    assert_eq!(states[&1].balance(usd).available, dec!(98.7655));
+    // 1.2345 * 0.92 = 1.13574, rounded like any other amount.
+    assert_eq!(states[&1].balance(eur).available, dec!(1.1357));
+    assert_eq!(
+        rejects,
+        vec![
+            (3, "unknown_rate"),
+            (4, "insufficient_funds"),
+            (5, "unknown_rate"),
+            (2, "unknown_tx")
+        ]
+    );
+
+    let serde = convert(
+        Engine::new().with_rates(rates),
+        Box::new(csv_transactions(csv_reader_from_str(csv.as_bytes()))),
+    );
+    assert_eq!(serde.1, rejects);
+
+    let (_, rejects) = convert(
+        Engine::new(),
+        Box::new(fast_csv_transactions(csv_reader_from_str(csv.as_bytes()))),
+    );
+    assert_eq!(rejects[0], (2, "unknown_rate"));
+
+    for invalid in [
+        "from,to,rate\nUSD,EUR,0\n",
+        "from,to,rate\nUSD,EUR,-1\n",
+        "from,to,rate\nUSD,USD,1\n",
+        "from,to,rate\nUSD,,1\n",
+        "from,to,rate\nUSD,EUR,1\nUSD,EUR,2\n",
+        "from,to,rate\nUSD,EUR,lots\n",
+    ] {
+        assert!(
+            RateTable::from_reader(invalid.as_bytes()).is_err(),
+            "{}",
+            invalid
+        );
+    }
+}
+
+/// Followers replicate every client state from the leader's snapshot and deltas, and only serve
+/// reads.
+#[test]
+fn followers_replicate_the_leader() {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    let apply = |engine: &Mutex<Engine>, csv: &str| {
+        let mut engine = engine.lock().unwrap();
+        apply_transactions(
+            &mut engine,
+            fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+            ignore_rejects,
+        )
+        .unwrap();
+    };
+    let leader = Arc::new(Mutex::new(Engine::new().with_replication()));
+    apply(&leader, "type, client, tx, amount\ndeposit, 1, 1, 10\n");
+
+    let stream = {
+        let leader = leader.clone();
+        thread::spawn(move || {
+            let mut stream = Vec::new();
+            replication::stream_to_follower(&mut stream, &leader).unwrap();
+            stream
+        })
+    };
+    while leader
+        .lock()
+        .unwrap()
+        .replication
+        .as_ref()
+        .unwrap()
+        .followers
+        .is_empty()
+    {
+        thread::yield_now();
+    }
+    apply(
+        &leader,
+        "type, client, tx, amount\n\
+         deposit, 2, 2, 5\n\
+         withdrawal, 1, 3, 4\n\
+         dispute, 2, 2,\n",
+    );
+    // Forgetting the followers ends the stream.
+    leader.lock().unwrap().replication = None;
+    let stream = stream.join().unwrap();
+    assert_eq!(String::from_utf8_lossy(&stream).lines().count(), 4);
+
+    let follower = Mutex::new(Engine::new());
+    replication::follow(stream.as_slice(), &follower).unwrap();
+    let mut expected = Vec::new();
+    {
+        let mut sink = BalanceWriter::new(&mut expected, &OutputDialect::default());
+        write_balances(
+            &mut sink,
+            leader.lock().unwrap().client_states(),
+            SortBy::Client,
+        )
+        .unwrap();
+    }
+    expected.extend_from_slice(b"\nERROR followers only serve reads\n");
+    let mut output = Vec::new();
+    replication::handle_read_connection(
+        "BALANCES\ndeposit,1,9,1.0\n".as_bytes(),
+        &mut output,
+        &follower,
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        String::from_utf8(expected).unwrap()
+    );
+
+    // A follower notices a missed delta, and a stream which doesn't start with a snapshot.
+    let lines: Vec<&str> = std::str::from_utf8(&stream).unwrap().lines().collect();
+    let gap = format!("{}\n{}\n", lines[0], lines[2]);
+    assert_eq!(
+        replication::follow(gap.as_bytes(), &Mutex::new(Engine::new()))
+            .unwrap_err()
+            .to_string(),
+        "replication stream skipped from 1 to 3"
+    );
+    assert!(replication::follow(lines[1].as_bytes(), &Mutex::new(Engine::new())).is_err());
+
+    // Engines which aren't replicating have nothing to stream.
+    assert!(replication::stream_to_follower(Vec::new(), &Mutex::new(Engine::new())).is_err());
+}
+
+/// Locked accounts can only be unlocked by engines which allow admin ops. Unlocked accounts take
+/// transactions again, and compact to logs which unlock them too.
+#[test]
+fn admin_ops_unlock_accounts() {
+    let csv = "type, client, tx, amount\n\
+               deposit, 1, 1, 10\n\
+               withdrawal, 1, 2, 8\n\
+               dispute, 1, 1,\n\
+               chargeback, 1, 1,\n\
+               unlock, 1, 3,\n\
+               deposit, 1, 4, 20\n\
+               unlock, 1, 5,\n\
+               unlock, 2, 6,\n";
+    let reasons = |engine: &mut Engine| {
+        let mut reasons = Vec::new();
+        apply_transactions(
+            engine,
+            fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+            |_, e| {
+                reasons.push(e.reason());
+                Ok(())
+            },
+        )
+        .unwrap();
+        reasons
+    };
+
+    let mut engine = Engine::new();
+    assert_eq!(
+        reasons(&mut engine),
+        [
+            "admin_ops_disabled",
+            "account_locked",
+            "admin_ops_disabled",
+            "admin_ops_disabled"
+        ]
+    );
+    assert!(engine.client_states()[&1].locked);
+
+    let mut engine = Engine::new().with_admin_ops(true);
+    assert_eq!(reasons(&mut engine), ["not_locked", "not_locked"]);
+    let state = &engine.client_states()[&1];
+    assert!(!state.locked);
+    assert_eq!(state.available, dec!(12));
+    assert_eq!(state.version, 6);
+    assert!(engine
+        .apply(&Transaction {
+            r#type: TransactionType::Unlock,
+            client_id: 1,
+            tx_id: 7,
+            amount: None,
+            raw_amount: None,
+            line: None,
+            terminal: None,
+            currency: "EUR".parse().unwrap(),
+            to_currency: Default::default(),
+            timestamp: None,
+        })
+        .is_err());
+    assert!(engine.client_states()[&1].currencies.is_empty());
+
+    // An account left short of funds by its chargeback needs an unlock in the compacted log.
+    let mut engine = Engine::new().with_admin_ops(true);
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10\n\
+             withdrawal, 1, 2, 10\n\
+             dispute, 1, 1,\n\
+             chargeback, 1, 1,\n\
+             unlock, 1, 3,\n"
+                .as_bytes(),
+        )),
+        ignore_rejects,
+    )
+    .unwrap();
+    let mut compacted = Vec::new();
+    engine.write_compacted(&mut compacted).unwrap();
+    assert!(String::from_utf8(compacted).unwrap().contains("unlock,1,"));
+    let dir = std::env::temp_dir().join(format!("payment-engine-unlock-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    compact::write_file(&engine, &dir.join("compacted.csv")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Transactions of an unknown type are handled according to the configured policy, and never
+/// create a client.
+#[test]
+fn unknown_types_follow_policy() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+refund,     2,      2,  5.0
+withdrawal, 1,      3,  0.5
+";
+    let process = |policy| {
+        let mut buffer = Vec::new();
+        let mut rejects = RejectWriter::new(&mut buffer, &OutputDialect::default());
+        let result = process_csv(
+            Engine::new().with_unknown_type_policy(policy),
+            csv_reader_from_str(csv.as_bytes()),
+            |tx, e| rejects.write(tx, e),
+        );
+        rejects.flush().unwrap();
+        drop(rejects);
+        (result, String::from_utf8(buffer).unwrap())
+    };
+
+    // Rejected transactions are kept in the rejects log, and the rest of the input is processed.
+    let (records, rejects) = process(UnknownTypePolicy::Reject);
+    let records = records.unwrap();
+    assert_eq!(
+        rejects,
+        "type,client,tx,amount,reason\nrefund,2,2,5.0000,unknown_type\n"
+    );
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[&1].available, dec!(0.5));
+
+    let (records, rejects) = process(UnknownTypePolicy::Ignore);
+    assert_eq!(records.unwrap().len(), 1);
+    assert_eq!(rejects, "");
+
+    // Processing stops at the first unknown type, as it does by default.
+    let (result, _) = process(UnknownTypePolicy::ErrorOut);
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "line 3: transaction 2 for client 2 has an unknown type"
+    );
+    assert_eq!(UnknownTypePolicy::default(), UnknownTypePolicy::ErrorOut);
+    assert!("skip".parse::<UnknownTypePolicy>().is_err());
+}
+
+/// Transactions for locked accounts are rejected, ignored, or held until the account is unlocked,
+/// according to the configured policy.
+#[test]
+fn locked_accounts_follow_policy() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  10.0
+dispute,    1,      1
+chargeback, 1,      1
+deposit,    1,      2,  5.0
+withdrawal, 1,      3,  20.0
+unlock,     1,      4
+";
+    let process = |policy| {
+        let mut rejects = Vec::new();
+        let records = process_csv(
+            Engine::new()
+                .with_admin_ops(true)
+                .with_locked_account_policy(policy),
+            csv_reader_from_str(csv.as_bytes()),
+            |tx, e| {
+                rejects.push((tx.tx_id, e.reason()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        (records[&1].available, rejects)
+    };
+
+    assert_eq!(
+        process(LockedAccountPolicy::Reject),
+        (
+            Money::ZERO,
+            vec![(2, "account_locked"), (3, "account_locked")]
+        )
+    );
+    assert_eq!(process(LockedAccountPolicy::Ignore), (Money::ZERO, vec![]));
+    // Held transactions are applied in order once the account is unlocked, and any rejected then
+    // are reported.
+    assert_eq!(
+        process(LockedAccountPolicy::Queue),
+        (Money::from(dec!(5)), vec![(3, "insufficient_funds")])
+    );
+
+    // Held transactions are kept in snapshots.
+    let mut engine = Engine::new().with_locked_account_policy(LockedAccountPolicy::Queue);
+    let (held, unlock) = csv.rsplit_once("unlock").unwrap();
+    apply_transactions(
+        &mut engine,
+        csv_transactions(csv_reader_from_str(held.as_bytes())),
+        ignore_rejects,
+    )
+    .unwrap();
+    assert_eq!(engine.queued_count(), 2);
+    assert!(engine.write_compacted(Vec::new()).is_err());
+    let mut snapshot = Vec::new();
+    engine.write_snapshot(&mut snapshot).unwrap();
+    let mut restored = Engine::new()
+        .with_admin_ops(true)
+        .with_locked_account_policy(LockedAccountPolicy::Queue)
+        .restore_snapshot(snapshot.as_slice())
+        .unwrap();
+    let unlock = format!("type,client,tx,amount\nunlock{}", unlock);
+    apply_transactions(
+        &mut restored,
+        csv_transactions(csv_reader_from_str(unlock.as_bytes())),
+        ignore_rejects,
+    )
+    .unwrap();
+    assert_eq!(restored.queued_count(), 0);
+    assert_eq!(restored.client_states()[&1].available, dec!(5));
+    assert!("hold".parse::<LockedAccountPolicy>().is_err());
+}
+
+/// Merged logs are applied in timestamp order, allowing each log to run up to the lateness behind
+/// itself.
+#[test]
+fn merged_logs_are_ordered_by_timestamp() {
+    let merge = |deposits: &'static str, disputes: &'static str, lateness| {
+        let sources: Vec<TransactionStream> = vec![
+            Box::new(fast_csv_transactions(csv_reader_from_str(
+                deposits.as_bytes(),
+            ))),
+            Box::new(csv_transactions(csv_reader_from_str(disputes.as_bytes()))),
+        ];
+        merge::merge_by_timestamp(sources, lateness)
+            .map(|result| result.map(|tx| tx.tx_id))
+            .collect::<Result<Vec<u32>, _>>()
+    };
+
+    let deposits = "type, client, tx, amount, timestamp\n\
+                    deposit, 1, 1, 10, 1\n\
+                    withdrawal, 1, 2, 4, 4\n\
+                    deposit, 1, 3, 1, 6\n";
+    let disputes = "type, client, tx, amount, timestamp\n\
+                    dispute, 1, 1, , 2\n\
+                    resolve, 1, 1, , 5\n";
+    assert_eq!(merge(deposits, disputes, 0).unwrap(), [1, 1, 2, 1, 3]);
+
+    // A transaction behind its own log is only put in its place if it's within the lateness.
+    let late = "type, client, tx, amount, timestamp\n\
+                deposit, 1, 1, 10, 1\n\
+                deposit, 1, 2, 10, 3\n\
+                deposit, 1, 3, 10, 2\n";
+    let other = "type, client, tx, amount, timestamp\n\
+                 deposit, 2, 4, 10, 2\n\
+                 deposit, 2, 5, 10, 10\n";
+    assert_eq!(merge(late, other, 1).unwrap(), [1, 4, 3, 2, 5]);
+    assert_eq!(
+        merge(late, other, 0).unwrap_err().to_string(),
+        "transaction 3 from input 1 (line 4) arrived later than the lateness allows"
+    );
+    assert_eq!(
+        merge(late, "type, client, tx, amount\ndeposit, 2, 4, 1\n", 0)
+            .unwrap_err()
+            .to_string(),
+        "transaction 4 from input 2 (line 2) has no timestamp"
+    );
+
+    // Timestamps can also be RFC 3339 (as milliseconds since the epoch).
+    let tx = |line: &'static str| {
+        read_transactions(line.as_bytes(), InputFormat::Ndjson)
+            .next()
+            .unwrap()
+            .map(|tx| tx.timestamp)
+    };
+    assert_eq!(
+        tx(r#"{"type":"deposit","client":1,"tx":1,"amount":"1","timestamp":"2022-03-01T12:00:00.5Z"}"#)
+            .unwrap(),
+        Some(1_646_136_000_500)
+    );
+    assert_eq!(
+        tx(r#"{"type":"deposit","client":1,"tx":1,"amount":"1","timestamp":7}"#).unwrap(),
+        Some(7)
+    );
+    assert!(tx(r#"{"type":"deposit","client":1,"tx":1,"amount":"1","timestamp":"soon"}"#).is_err());
+}
+
+/// Withdrawals can overdraw down to the client's credit limit (or the default one), but no
+/// further, and overdrawn accounts compact to logs which need the same limits.
+#[test]
+fn credit_limits_allow_overdrafts() {
+    let limits = limits::CreditLimits::from_reader(
+        "client, limit\n1, 50\n".as_bytes(),
+        Money::new(dec!(10)),
+    )
+    .unwrap();
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  5.0
+withdrawal, 1,      2,  45.0
+withdrawal, 1,      3,  10.1
+withdrawal, 2,      4,  10.0
+withdrawal, 3,      5,  0.0001
+";
+    let process = |engine| {
+        let mut rejects = Vec::new();
+        let states = process_transactions(
+            engine,
+            fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+            |tx, e| {
+                rejects.push((tx.tx_id, e.reason()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        (states, rejects)
+    };
+
+    let (states, rejects) = process(Engine::new().with_credit_limits(limits.clone()));
+    assert_eq!(states[&1].available, dec!(-40));
+    assert_eq!(states[&1].total, dec!(-40));
+    assert_eq!(states[&2].available, dec!(-10));
+    assert_eq!(rejects, vec![(3, "insufficient_funds")]);
+
+    let (_, rejects) = process(Engine::new());
+    assert_eq!(rejects.len(), 4);
+
+    let mut engine = Engine::new().with_credit_limits(limits);
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        ignore_rejects,
+    )
+    .unwrap();
+    let dir = std::env::temp_dir().join(format!("payment-engine-limits-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    compact::write_file(&engine, &dir.join("compacted.csv")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    for invalid in ["client,limit\n1,-5\n", "client,limit\n1,5\n1,6\n"] {
+        assert!(limits::CreditLimits::from_reader(invalid.as_bytes(), Money::ZERO).is_err());
+    }
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert_eq!(
+        args(&["--credit-limit", "25.5"]).unwrap().credit_limit,
+        Some(Money::new(dec!(25.5)))
+    );
+    assert!(args(&["--credit-limit", "-1"]).is_err());
+}
+
+/// Disputes are only allowed within the dispute window, and transactions which can no longer be
+/// disputed are forgotten (unless they're under dispute), whether or not they've been spilled.
+#[test]
+fn dispute_window_expires_disputes() {
+    let csv = "\
+type,     client, tx, amount
+deposit,  1,      1,  1.0
+deposit,  1,      2,  1.0
+deposit,  1,      3,  1.0
+deposit,  1,      4,  1.0
+dispute,  1,      1,
+dispute,  1,      2,
+dispute,  1,      4,
+dispute,  1,      3,
+resolve,  1,      4,
+";
+    for engine in [Engine::new(), Engine::new().with_memory_limit(1)] {
+        let mut engine = engine.with_dispute_window("3".parse().unwrap());
+        let mut rejects = Vec::new();
+        apply_transactions(
+            &mut engine,
+            fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+            |tx, e| {
+                rejects.push((tx.tx_id, e.reason()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            rejects,
+            vec![
+                (1, "dispute_expired"),
+                (2, "dispute_expired"),
+                (3, "unknown_tx")
+            ]
+        );
+        assert_eq!(engine.client_states()[&1].held, dec!(0));
+        assert_eq!(
+            engine.disputable_transactions.transactions().unwrap().len(),
+            1
+        );
+    }
+
+    let csv = "\
+type,     client, tx, amount, timestamp
+deposit,  1,      3,  1.0,    1000
+deposit,  1,      1,  1.0,    1500
+deposit,  1,      2,  1.0,    2100
+dispute,  1,      1,,         2600
+dispute,  1,      2,,
+dispute,  1,      3,,
+";
+    let mut rejects = Vec::new();
+    let states = process_transactions(
+        Engine::new().with_dispute_window("1s".parse().unwrap()),
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        |tx, e| {
+            rejects.push((tx.tx_id, e.reason()));
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(rejects, vec![(1, "dispute_expired"), (3, "unknown_tx")]);
+    assert_eq!(states[&1].held, dec!(1));
+
+    assert_eq!(
+        "10000".parse::<DisputeWindow>(),
+        Ok(DisputeWindow::Transactions(10000))
+    );
+    assert_eq!(
+        "90d".parse::<DisputeWindow>(),
+        Ok(DisputeWindow::Duration(90 * 86_400_000))
+    );
+    for invalid in ["0", "", "d", "5x", "-5", "5 d"] {
+        assert!(invalid.parse::<DisputeWindow>().is_err(), "{}", invalid);
+    }
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["--dispute-window", "100", "--threads", "2"]).is_err());
+    assert!(args(&["--dispute-window", "100s", "--threads", "2"]).is_ok());
+}
+
+/// Dispute windows follow the engine's clock, which can be simulated, rather than transaction
+/// timestamps.
+#[test]
+fn clocks_drive_dispute_windows() {
+    let csv = "\
+type,     client, tx, amount, timestamp
+deposit,  1,      1,  1.0,    0
+deposit,  1,      2,  1.0,    0
+deposit,  1,      3,  1.0,    0
+dispute,  1,      2,,         0
+dispute,  1,      3,,         0
+dispute,  1,      1,,         0
+";
+    let transactions: Vec<Transaction> = fast_csv_transactions(csv_reader_from_str(csv.as_bytes()))
+        .map(Result::unwrap)
+        .collect();
+    let clock = clock::SimulatedClock::new(5000);
+    let mut engine = Engine::new()
+        .with_dispute_window("1s".parse().unwrap())
+        .with_clock(clock.clone());
+    let mut rejects = Vec::new();
+    for (tx, millis) in transactions
+        .iter()
+        .zip([5000, 5800, 6200, 6900, 6900, 6900])
+    {
+        clock.set(millis);
+        if let Err(e) = engine.apply(tx) {
+            rejects.push((tx.tx_id, e.reason()));
+        }
+    }
+    assert_eq!(rejects, vec![(2, "dispute_expired"), (1, "unknown_tx")]);
+    assert_eq!(engine.client_states()[&1].held, dec!(1));
+
+    let mut transaction_clock = clock::TransactionClock::default();
+    let mut at = |timestamp| {
+        let tx = Transaction {
+            timestamp,
+            ..transactions[0].clone()
+        };
+        clock::Clock::now(&mut transaction_clock, &tx)
+    };
+    assert_eq!(at(Some(20)), 20);
+    assert_eq!(at(Some(10)), 20);
+    assert_eq!(at(None), 20);
+    assert_eq!(at(Some(30)), 30);
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert_eq!(
+        args(&["--clock", "system"]).unwrap().clock,
+        clock::ClockKind::System
+    );
+    assert!(args(&["--clock", "sundial"]).is_err());
+    assert!(args(&["--clock", "system", "--journal", "journal.log"]).is_err());
+}
+
+/// History which overlaps a snapshot can be replayed onto it, skipping deposits, withdrawals, and
+/// conversions the snapshot already reflects.
+#[test]
+fn snapshots_skip_backfilled_transactions() {
+    let mut engine = Engine::new();
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10\n\
+             withdrawal, 1, 2, 20\n\
+             deposit, 1, 3, 15\n\
+             deposit, 2, 4, 1\n"
+                .as_bytes(),
+        )),
+        ignore_rejects,
+    )
+    .unwrap();
+    let mut snapshot = Vec::new();
+    engine.write_snapshot(&mut snapshot).unwrap();
+
+    let history = "\
+type,       client, tx, amount
+withdrawal, 1,      2,  20
+deposit,    1,      3,  15
+deposit,    1,      5,  1
+deposit,    2,      4,  1
+deposit,    3,      6,  7
+dispute,    1,      3,
+";
+    let mut restored = Engine::new()
+        .with_skip_backfilled(true)
+        .restore_snapshot(snapshot.as_slice())
+        .unwrap();
+    let mut rejects = Vec::new();
+    apply_transactions(
+        &mut restored,
+        fast_csv_transactions(csv_reader_from_str(history.as_bytes())),
+        |tx, e| {
+            rejects.push((tx.tx_id, e.reason()));
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert!(rejects.is_empty());
+    assert_eq!(restored.backfilled_count(), 3);
+    let states = restored.client_states();
+    assert_eq!(states[&1].available, dec!(11));
+    assert_eq!(states[&1].held, dec!(15));
+    assert_eq!(states[&2].total, dec!(1));
+    assert_eq!(states[&3].total, dec!(7));
+
+    // Without skipping, the overlap is applied (or rejected as a duplicate) a second time,
+    // including the withdrawal which was originally rejected.
+    let mut rejects = Vec::new();
+    let mut restored = Engine::new().restore_snapshot(snapshot.as_slice()).unwrap();
+    apply_transactions(
+        &mut restored,
+        fast_csv_transactions(csv_reader_from_str(history.as_bytes())),
+        |tx, e| {
+            rejects.push((tx.tx_id, e.reason()));
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        rejects,
+        vec![(3, "duplicate_tx_id"), (4, "duplicate_tx_id")]
+    );
+    assert_eq!(restored.client_states()[&1].total, dec!(6));
+
+    // High-water marks survive being snapshotted again.
+    let mut rewritten = Vec::new();
+    Engine::new()
+        .restore_snapshot(snapshot.as_slice())
+        .unwrap()
+        .write_snapshot(&mut rewritten)
+        .unwrap();
+    assert_eq!(rewritten, snapshot);
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["--skip-backfilled"]).is_err());
+    assert!(args(&["--skip-backfilled", "--snapshot-in", "snapshot.json"]).is_ok());
+}
+
+/// A single log can be checked to be in timestamp order (reordering within the lateness), and each
+/// client's last activity is exported when activity is tracked.
+#[test]
+fn chronological_logs_track_last_activity() {
+    let csv = "\
+type,       client, tx, amount, timestamp
+deposit,    1,      1,  10,     100
+deposit,    2,      2,  5,      120
+withdrawal, 1,      3,  4,      110
+withdrawal, 2,      4,  50,     130
+";
+    let chronological = |lateness| {
+        let transactions: TransactionStream =
+            Box::new(fast_csv_transactions(csv_reader_from_str(csv.as_bytes())));
+        merge::merge_by_timestamp(vec![transactions], lateness)
+    };
+    let order: Vec<u32> = chronological(10).map(|tx| tx.unwrap().tx_id).collect();
+    assert_eq!(order, [1, 3, 2, 4]);
+    assert!(chronological(0).any(|result| result.is_err()));
+
+    let states = process_transactions(
+        Engine::new().with_activity_tracking(true),
+        chronological(10),
+        ignore_rejects,
+    )
+    .unwrap();
+    assert_eq!(states[&1].last_activity, Some(110));
+    // Rejected transactions count as activity too.
+    assert_eq!(states[&2].last_activity, Some(130));
+    let mut output = Vec::new();
+    let mut sink = BalanceWriter::new(&mut output, &OutputDialect::default());
+    write_balances(&mut sink, &states, SortBy::Client).unwrap();
+    drop(sink);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked,version,last_activity\n\
+         1,6.0000,0.0000,6.0000,false,2,110\n\
+         2,5.0000,0.0000,5.0000,false,1,130\n"
+    );
+
+    let untracked = process_transactions(Engine::new(), chronological(10), ignore_rejects);
+    assert_eq!(untracked.unwrap()[&1].last_activity, None);
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["--lateness", "5"]).is_err());
+    assert!(
+        args(&["--chronological", "--lateness", "5"])
+            .unwrap()
+            .chronological
+    );
+    assert!(args(&["--merge", "other.csv"]).unwrap().chronological);
+    assert!(args(&[
+        "--chronological",
+        "--lateness",
+        "5",
+        "--journal",
+        "journal.log"
+    ])
+    .is_err());
+}
+
+/// Buffering a window of transactions applies them in ID order, with disputes after the
+/// transactions they reference, so a dispute arriving early isn't dropped.
+#[test]
+fn reorder_window_applies_transactions_in_id_order() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  10
+resolve,    1,      2,
+dispute,    1,      2,
+deposit,    1,      2,  5
+withdrawal, 1,      3,  1
+";
+    let reordered = |window| {
+        let transactions: TransactionStream =
+            Box::new(fast_csv_transactions(csv_reader_from_str(csv.as_bytes())));
+        reorder::reorder_by_id(transactions, window)
+    };
+    let order: Vec<(u32, TransactionType)> = reordered(3)
+        .map(|tx| tx.map(|tx| (tx.tx_id, tx.r#type)).unwrap())
+        .collect();
+    assert_eq!(
+        order,
+        [
+            (1, TransactionType::Deposit),
+            (2, TransactionType::Deposit),
+            (2, TransactionType::Dispute),
+            (2, TransactionType::Resolve),
+            (3, TransactionType::Withdrawal)
+        ]
+    );
+
+    let mut rejects = Vec::new();
+    let states = process_transactions(Engine::new(), reordered(3), |tx, e| {
+        rejects.push((tx.tx_id, e.reason()));
+        Ok(())
+    })
+    .unwrap();
+    assert!(rejects.is_empty());
+    assert_eq!(states[&1].available, dec!(14));
+
+    // Transactions arriving later than the window allows are passed on out of order.
+    let lines: Vec<u64> = reordered(1).map(|tx| tx.unwrap().line.unwrap()).collect();
+    assert_eq!(lines, [2, 4, 5, 3, 6]);
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert_eq!(
+        args(&["--reorder-window", "64"]).unwrap().reorder_window,
+        Some(64)
+    );
+    assert!(args(&["--reorder-window", "0"]).is_err());
+    assert!(args(&["--reorder-window", "64", "--chronological"]).is_err());
+    assert!(args(&["--reorder-window", "64", "--journal", "journal.log"]).is_err());
+}
+
+/// Policy reports run every scenario under the given configuration, so a policy change shows up
+/// as a change in the outcomes of the scenarios it affects.
+#[test]
+fn policy_reports_cover_every_scenario() {
+    use policy_report::{PolicyReport, SCENARIOS};
+
+    let outcomes = |report: &PolicyReport, name: &str| -> Vec<(u32, &'static str)> {
+        let scenario = report.scenarios.iter().find(|s| s.name == name).unwrap();
+        scenario
+            .outcomes
+            .iter()
+            .map(|o| (o.tx, o.outcome))
+            .collect()
+    };
+
+    let report = PolicyReport::run(vec![], Engine::new).unwrap();
+    assert_eq!(report.scenarios.len(), SCENARIOS.len());
+    assert!(report.scenarios.iter().all(|s| !s.outcomes.is_empty()));
+    assert_eq!(
+        outcomes(&report, "unknown_type"),
+        [(1, "applied"), (2, "error")]
+    );
+    let unlock = report
+        .scenarios
+        .iter()
+        .find(|s| s.name == "unlock")
+        .unwrap();
+    assert_eq!(unlock.outcomes[4].reason, Some("admin_ops_disabled"));
+    assert!(unlock.clients[0].locked);
+
+    let configuration = vec!["--unknown-type-policy".to_string(), "reject".to_string()];
+    let report = PolicyReport::run(configuration.clone(), || {
+        Engine::new().with_unknown_type_policy(UnknownTypePolicy::Reject)
+    })
+    .unwrap();
+    assert_eq!(report.configuration, configuration);
+    assert_eq!(
+        outcomes(&report, "unknown_type"),
+        [(1, "applied"), (2, "rejected"), (3, "applied")]
+    );
+
+    let mut json = Vec::new();
+    report.write_json(&mut json).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(json["scenarios"][0]["name"], "deposits_and_withdrawals");
+
+    let command = |args: &[&str]| cli::Command::from_args(args.iter().map(|s| s.to_string()));
+    assert!(matches!(
+        command(&["policy-report", "--allow-admin-ops"]),
+        Ok(cli::Command::PolicyReport(_))
+    ));
+    assert!(command(&["policy-report", "transactions.csv"]).is_err());
+    assert!(command(&["policy-report", "--snapshot-out", "snapshot.json"]).is_err());
+}
+
+/// An embedded engine applies transactions sent to it from async tasks, publishes its progress,
+/// and sends its final state once every sender has gone. An engine which stops early stops
+/// accepting transactions.
+#[cfg(feature = "tokio")]
+#[test]
+fn embedded_engines_apply_submitted_transactions() {
+    use embedded::{spawn_engine, EngineStats};
+
+    let transactions: Vec<Transaction> = input::csv_transactions(csv_reader_from_str(
+        "\
+type,       client, tx, amount
+deposit,    1,      1,  2.0
+withdrawal, 1,      2,  5.0
+dispute,    1,      1,
+deposit,    2,      3,  1.0
+"
+        .as_bytes(),
+    ))
+    .collect::<Result<_, _>>()
+    .unwrap();
+
+    let withdrawal = transactions[1].clone();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let (sender, stats, result) = spawn_engine(Engine::new(), 1, |_, _| Ok(()));
+    runtime.block_on(async {
+        for tx in transactions {
+            sender.send(tx).await.unwrap();
+        }
+    });
+    drop(sender);
+
+    let client_states = result.blocking_recv().unwrap().unwrap();
+    assert_eq!(client_states[&1].held, dec!(2));
+    assert_eq!(client_states[&2].available, dec!(1));
+    assert_eq!(
+        *stats.borrow(),
+        EngineStats {
+            received: 4,
+            rejected: 1,
+            queued: 0,
+            clients: 2
+        }
+    );
+
+    let (sender, _, result) = spawn_engine(Engine::new(), 1, |_, e| Err(e.to_string().into()));
+    sender.blocking_send(withdrawal.clone()).unwrap();
+    let error = result.blocking_recv().unwrap().unwrap_err();
+    assert_eq!(error.to_string(), "insufficient funds for client 1 (tx 2)");
+    assert!(sender.blocking_send(withdrawal).is_err());
+}
+
+/// Several logs are read one after another as a single log, each with its own header, and
+/// patterns in their file names are expanded in order of name.
+#[test]
+fn several_logs_are_read_in_sequence() {
+    let dir = std::env::temp_dir().join(format!("payment-engine-logs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, csv: &str| {
+        let path = dir.join(name);
+        std::fs::write(&path, csv).unwrap();
+        path.to_string_lossy().into_owned()
+    };
+    let a = write("a.csv", "type,client,tx,amount\ndeposit,1,1,5\n");
+    let b = write(
+        "b.csv",
+        "client,type,amount,tx\n1,withdrawal,2,2\n1,dispute,,1\n",
+    );
+    write("c.txt", "not a log\n");
+
+    let pattern = dir.join("?.csv").to_string_lossy().into_owned();
+    let paths = input::expand_path(&pattern).unwrap();
+    assert_eq!(paths, [a.clone(), b.clone()]);
+    assert_eq!(input::expand_path(&a).unwrap(), vec![a.clone()]);
+    assert!(input::expand_path(&dir.join("*.json").to_string_lossy()).is_err());
+
+    let transactions =
+        open_transaction_logs(&paths, InputFormat::Csv, &Default::default()).unwrap();
+    let states = process_transactions(Engine::new(), transactions, |_, _| Ok(())).unwrap();
+    assert_eq!(states[&1].available, dec!(-2));
+    assert_eq!(states[&1].held, dec!(5));
+
+    // Errors say which log they came from.
+    let c = write("c.csv", "type,client,tx,amount\ndeposit,1,3,x\n");
+    let error = open_transaction_logs(&[a, c.clone()], InputFormat::Csv, &Default::default())
+        .unwrap()
+        .find_map(Result::err)
+        .unwrap();
+    assert!(error.to_string().starts_with(&format!("{}: ", c)));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    let options = args(&["a.csv", "b.csv", "c.csv"]).unwrap();
+    assert_eq!(options.csv_path.as_deref(), Some("a.csv"));
+    assert_eq!(options.more_paths, ["b.csv", "c.csv"]);
+    assert!(args(&["a.csv", "b.csv", "--journal", "journal.log"]).is_err());
+    assert!(args(&["a.csv", "-", "--two-pass"]).is_err());
+}
+
+/// Clients with as many disputes open as allowed can't open more until one is settled.
+#[test]
+fn open_disputes_are_capped_per_client() {
+    let csv = "\
+type,    client, tx, amount
+deposit, 1,      1,  1
+deposit, 1,      2,  1
+deposit, 1,      3,  1
+deposit, 2,      4,  1
+dispute, 1,      1,
+dispute, 1,      2,
+dispute, 1,      3,
+dispute, 2,      4,
+dispute, 1,      2,
+resolve, 1,      1,
+dispute, 1,      3,
+";
+    let run = |engine: Engine| {
+        let mut rejects = Vec::new();
+        let states = process_transactions(
+            engine,
+            fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+            |tx, e| {
+                rejects.push((tx.tx_id, e.reason()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        (states, rejects)
+    };
+
+    let (states, rejects) = run(Engine::new().with_max_open_disputes(2));
+    // Disputes already open are still reported as such, and other clients aren't affected.
+    assert_eq!(rejects, [(3, "too_many_disputes"), (2, "already_disputed")]);
+    assert_eq!(states[&1].held, dec!(2));
+    assert_eq!(states[&2].held, dec!(1));
+
+    let (states, rejects) = run(Engine::new());
+    assert_eq!(rejects, [(2, "already_disputed"), (3, "already_disputed")]);
+    assert_eq!(states[&1].held, dec!(2));
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert_eq!(
+        args(&["--max-open-disputes", "2"])
+            .unwrap()
+            .max_open_disputes,
+        Some(2)
+    );
+    assert!(args(&["--max-open-disputes", "0"]).is_err());
+}
+
+/// Compressed logs are decompressed as they're read, with a failed decompression reported as an
+/// error rather than the end of the log. Formats which weren't built in are an error.
+#[test]
+fn compressed_logs_are_decompressed() {
+    use decompress::Compression;
+
+    assert_eq!(Compression::of("logs/a.csv.gz"), Some(Compression::Gzip));
+    assert_eq!(Compression::of("a.csv.zst"), Some(Compression::Zstd));
+    assert_eq!(Compression::of("a.csv"), None);
+
+    let dir = std::env::temp_dir().join(format!("payment-engine-gz-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let csv = dir.join("a.csv");
+    std::fs::write(
+        &csv,
+        "type,client,tx,amount\ndeposit,1,1,5\nwithdrawal,1,2,2\n",
+    )
+    .unwrap();
+
+    for (compression, extension) in [(Compression::Gzip, "gz"), (Compression::Zstd, "zst")] {
+        let archive = dir.join(format!("a.csv.{}", extension));
+        let archive = archive.to_str().unwrap();
+        if !compression.is_enabled() {
+            std::fs::write(archive, "").unwrap();
+            let error = open_transactions(archive, InputFormat::Csv).err().unwrap();
+            assert!(error.to_string().contains("feature"));
+            continue;
+        }
+
+        std::fs::write(
+            archive,
+            compress(compression, &std::fs::read(&csv).unwrap()),
+        )
+        .unwrap();
+        let transactions = open_transactions(archive, InputFormat::Csv).unwrap();
+        let states = process_transactions(Engine::new(), transactions, |_, _| Ok(())).unwrap();
+        assert_eq!(states[&1].available, dec!(3));
+
+        // A truncated archive is an error, not a shorter log.
+        let bytes = std::fs::read(archive).unwrap();
+        std::fs::write(archive, &bytes[..bytes.len() / 2]).unwrap();
+        let result = open_transactions(archive, InputFormat::Csv)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>();
+        assert!(result.is_err());
+
+        // So is one which isn't an archive at all, and the error says which log it was.
+        std::fs::write(archive, "type,client,tx,amount\n").unwrap();
+        let error = open_transactions(archive, InputFormat::Csv)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_err();
+        assert!(error.to_string().contains(archive), "{}", error);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Compress `bytes` in the given (built in) format.
+#[allow(unused_imports, unused_variables, unreachable_patterns)]
+fn compress(compression: decompress::Compression, bytes: &[u8]) -> Vec<u8> {
+    use decompress::Compression;
+
+    match compression {
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => {
+            use std::io::Write;
+
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+            encoder.write_all(bytes).unwrap();
+            encoder.finish().unwrap()
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => zstd::encode_all(bytes, 0).unwrap(),
+        _ => unreachable!("{:?} wasn't built in", compression),
+    }
+}
+
+/// Transactions are checked into kinds carrying only what their type uses, so deposits always
+/// have a valid amount, and amounts given to disputes are dropped.
+#[test]
+fn transaction_kinds_carry_only_their_payload() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1.5
+withdrawal, 1,      2,
+deposit,    1,      3,  -1
+dispute,    1,      1,  9.0
+chargeback, 1,      1,
+unlock,     1,      4,
+refund,     1,      5,  1.0
+";
+    let kinds: Vec<Result<TransactionKind, &'static str>> =
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes()))
+            .map(|tx| tx.unwrap().kind().map_err(|e| e.reason()))
+            .collect();
+    assert_eq!(
+        kinds,
+        [
+            Ok(TransactionKind::Deposit {
+                amount: Money::new(dec!(1.5))
+            }),
+            Err("missing_amount"),
+            Err("negative_amount"),
+            Ok(TransactionKind::Dispute { ref_tx: 1 }),
+            Ok(TransactionKind::Chargeback { ref_tx: 1 }),
+            Ok(TransactionKind::Unlock),
+            Ok(TransactionKind::Custom("refund".parse().unwrap())),
+        ]
+    );
+}
+
+/// Engines with metrics count transactions by type and rejections by reason (including held
+/// transactions rejected after an unlock), and serve them in the Prometheus text format.
+#[test]
+fn metrics_count_transactions_and_rejects() {
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  5
+withdrawal, 1,      2,  9
+dispute,    1,      1,
+chargeback, 1,      1,
+withdrawal, 1,      3,  1
+unlock,     1,      4,
+";
+    let mut engine = Engine::new()
+        .with_locked_account_policy(LockedAccountPolicy::Queue)
+        .with_admin_ops(true)
+        .with_metrics();
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        |_, _| Ok(()),
+    )
+    .unwrap();
+
+    let metrics = engine.metrics().unwrap();
+    assert_eq!(metrics.transactions(TransactionType::Withdrawal), 2);
+    assert_eq!(metrics.transactions(TransactionType::Resolve), 0);
+    assert_eq!(metrics.rejects("insufficient_funds"), 2);
+    let text = metrics::render(&engine).unwrap();
+    for line in [
+        "payment_engine_transactions_total{type=\"deposit\"} 1",
+        "payment_engine_rejects_total{reason=\"insufficient_funds\"} 2",
+        "payment_engine_locked_accounts 0",
+        "payment_engine_disputable_transactions 1",
+        "payment_engine_apply_seconds_bucket{le=\"+Inf\"} 6",
+        "payment_engine_apply_seconds_count 6",
+    ] {
+        assert!(text.lines().any(|l| l == line), "missing {}", line);
+    }
+    assert!(metrics::render(&Engine::new()).is_none());
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    metrics::spawn_metrics_server(listener, Arc::new(Mutex::new(engine)));
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.ends_with(&text));
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["--metrics", "metrics.prom", "--threads", "4"]).is_err());
+    assert!(cli::ServeOptions::from_args(
+        ["--listen", "127.0.0.1:0", "--metrics-listen", "127.0.0.1:0"]
+            .iter()
+            .map(|arg| arg.to_string())
+    )
+    .is_ok());
+
+    #[cfg(feature = "http")]
+    {
+        let engine = Mutex::new(Engine::new().with_metrics());
+        let (status, body) = http::handle(&engine, "GET", "/metrics", "");
+        assert_eq!(status, 200);
+        assert!(body.contains("payment_engine_clients 0"));
+        let (status, _) = http::handle(&Mutex::new(Engine::new()), "GET", "/metrics", "");
+        assert_eq!(status, 404);
+    }
+}
+
+/// Log events are written as plain messages or JSON objects carrying their fields and spans, and
+/// the logging options can be given anywhere on the command line. Runs are quiet by default.
+#[test]
+fn logs_are_written_as_text_or_json() {
+    use std::sync::{Arc, Mutex};
+
+    use logging::{LogFormat, Logger};
+    use tracing_subscriber::filter::LevelFilter;
+
+    /// Collects what's logged.
+    struct Written(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let log = |level, format| {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let writer = written.clone();
+        let subscriber = Logger::new(level, format).subscriber(move || Written(writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let _run = tracing::info_span!("run", command = "process").entered();
+            let _tx = tracing::info_span!("tx", client = 2, tx = 5).entered();
+            tracing::warn!(client = 2, tx = 5, line = Some(3), "rejected transaction");
+            tracing::debug!("applying");
+        });
+        let written = written.lock().unwrap().clone();
+        String::from_utf8(written).unwrap()
+    };
+    assert_eq!(
+        log(LevelFilter::INFO, LogFormat::Text),
+        "rejected transaction\n"
+    );
+    assert_eq!(
+        log(LevelFilter::DEBUG, LogFormat::Text),
+        "rejected transaction\napplying\n"
+    );
+    assert_eq!(log(LevelFilter::ERROR, LogFormat::Text), "");
+
+    let json: serde_json::Value =
+        serde_json::from_str(&log(LevelFilter::INFO, LogFormat::Json)).unwrap();
+    assert_eq!(json["level"], "WARN");
+    assert_eq!(json["target"], "payment_engine::tests");
+    assert_eq!(json["message"], "rejected transaction");
+    assert_eq!(json["client"], 2);
+    assert_eq!(json["tx"], 5);
+    assert_eq!(json["line"], 3);
+    assert!(json["timestamp"].is_string());
+    assert_eq!(json["spans"][0]["name"], "run");
+    assert_eq!(json["spans"][0]["command"], "process");
+    assert_eq!(json["spans"][1]["name"], "tx");
+    assert_eq!(json["spans"][1]["client"], 2);
+
+    assert_eq!(logging::parse_level("debug"), Ok(LevelFilter::DEBUG));
+    assert!(logging::parse_level("loud").is_err());
+    assert!("xml".parse::<LogFormat>().is_err());
+
+    let args = [
+        "validate",
+        "--log-level",
+        "warn",
+        "in.csv",
+        "--log-format",
+        "json",
+    ];
+    let (options, command) = cli::parse(args.iter().map(|s| s.to_string())).unwrap();
+    assert_eq!(options.level, LevelFilter::WARN);
+    assert_eq!(options.format, LogFormat::Json);
+    assert!(matches!(command, cli::Command::Validate(options)
+        if options.csv_path.as_deref() == Some("in.csv")));
+    assert!(cli::parse(vec!["--log-level".to_string()]).is_err());
+    assert_eq!(
+        cli::parse(Vec::new()).unwrap().0,
+        cli::LogOptions::default()
+    );
+    assert_eq!(cli::LogOptions::default().level, LevelFilter::ERROR);
+    // Validating reports rejected transactions unless it's asked not to.
+    let (options, _) = cli::parse(vec!["validate".to_string()]).unwrap();
+    assert_eq!(options.level, LevelFilter::WARN);
+    let args = ["validate", "--log-level", "error"];
+    let (options, _) = cli::parse(args.iter().map(|s| s.to_string())).unwrap();
+    assert_eq!(options.level, LevelFilter::ERROR);
+}
+
+/// Summaries count every transaction read and every reject, and only total the deposits and
+/// withdrawals which were applied.
+#[test]
+fn summaries_total_applied_transactions() {
+    use std::cell::RefCell;
+    use summary::Summary;
+
+    let csv = "\
+type,       client, tx, amount, currency
+deposit,    1,      1,  5,
+deposit,    1,      2,  3,      USD
+withdrawal, 1,      3,  9,
+withdrawal, 1,      4,  2,
+dispute,    1,      2,  ,       USD
+chargeback, 1,      2,  ,       USD
+";
+    let summary = RefCell::new(Summary::new());
+    let mut engine = Engine::new();
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes())).inspect(|result| {
+            if let Ok(tx) = result {
+                summary.borrow_mut().observe(tx);
+            }
+        }),
+        |tx, e| {
+            summary.borrow_mut().reject(tx, e);
+            Ok(())
+        },
+    )
+    .unwrap();
+
+    let summary = summary.into_inner();
+    assert_eq!(summary.transactions(TransactionType::Deposit), 2);
+    assert_eq!(summary.transactions(TransactionType::Withdrawal), 2);
+    assert_eq!(summary.deposited(Currency::IMPLICIT), dec!(5));
+    assert_eq!(summary.deposited("USD".parse().unwrap()), dec!(3));
+    assert_eq!(summary.withdrawn(Currency::IMPLICIT), dec!(2));
+
+    let mut text = Vec::new();
+    summary.write(&mut text, 1).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    for line in [
+        "transactions: 6",
+        "  chargeback: 1",
+        "rejected: 1",
+        "  insufficient_funds: 1",
+        "deposited: 5.0000",
+        "deposited: 3.0000 USD",
+        "withdrawn: 2.0000",
+        "locked accounts: 1",
+    ] {
+        assert!(text.lines().any(|l| l == line), "missing {}", line);
+    }
+    assert!(!text.contains("resolve"));
+}
+
+/// Balance invariants are checked in every currency, and an engine checking them stops at the
+/// first transaction which breaks one.
+#[test]
+fn invariant_checks_report_the_breaking_transaction() {
+    use invariants::{check, InvariantCheck};
+
+    let mut state = ClientState {
+        available: dec!(1).into(),
+        held: dec!(2).into(),
+        total: dec!(3).into(),
+        ..Default::default()
+    };
+    assert_eq!(check(&state, InvariantCheck::NonNegative), Ok(()));
+    state.total = dec!(4).into();
+    assert_eq!(
+        check(&state, InvariantCheck::Balances),
+        Err("total 4.0000 isn't available 1.0000 plus held 2.0000".to_string())
+    );
+    state.total = dec!(3).into();
+    state.currencies.insert(
+        "usd".parse().unwrap(),
+        currency::Balance {
+            available: dec!(1).into(),
+            held: dec!(-1).into(),
+            total: dec!(0).into(),
+            fees_collected: None,
+        },
+    );
+    assert_eq!(
+        check(&state, InvariantCheck::Balances),
+        Err("held -1.0000 is negative in USD".to_string())
+    );
+    assert_eq!("non-negative".parse(), Ok(InvariantCheck::NonNegative));
+    assert!("all".parse::<InvariantCheck>().is_err());
+
+    // Disputing a deposit which was mostly withdrawn takes available funds below zero.
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  5
+withdrawal, 1,      2,  4
+dispute,    1,      1,
+";
+    let mut engine = Engine::new().with_invariant_checks(InvariantCheck::Balances);
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        |_, _| Ok(()),
+    )
+    .unwrap();
+
+    let mut engine = Engine::new().with_invariant_checks(InvariantCheck::NonNegative);
+    let e = apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        |_, _| Ok(()),
+    )
+    .unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "line 4: transaction 1 for client 1 broke a balance invariant: available -4.0000 is \
+         negative"
+    );
+}
+
+/// A deterministic proptest runner for `cases` cases, which doesn't persist failures.
+#[cfg(feature = "test-util")]
+fn proptest_runner(cases: u32) -> proptest::test_runner::TestRunner {
+    use proptest::test_runner::{Config, RngAlgorithm, TestRng, TestRunner};
+
+    let config = Config {
+        failure_persistence: None,
+        ..Config::with_cases(cases)
+    };
+    TestRunner::new_with_rng(config, TestRng::deterministic_rng(RngAlgorithm::default()))
+}
+
+/// Generated logs are applied by the engine just as the reference model applies them.
+#[cfg(feature = "test-util")]
+#[test]
+fn engine_matches_the_oracle_on_generated_logs() {
+    use proptest::test_runner::TestCaseError;
+    use test_util::{matches_oracle, transaction_logs};
+
+    let result = proptest_runner(500).run(&transaction_logs(), |transactions| {
+        matches_oracle(Engine::new(), &transactions).map_err(TestCaseError::fail)
+    });
+    if let Err(e) = result {
+        panic!("{}", e);
+    }
+}
+
+/// A log a property fails for shrinks to the fewest transactions which still fail.
+#[cfg(feature = "test-util")]
+#[test]
+fn generated_logs_shrink_to_minimal_counterexamples() {
+    use proptest::test_runner::{TestCaseError, TestError};
+
+    let result =
+        proptest_runner(100).run(
+            &test_util::transaction_logs(),
+            |transactions| match transactions
+                .iter()
+                .any(|tx| tx.r#type == TransactionType::Chargeback)
+            {
+                true => Err(TestCaseError::fail("a chargeback")),
+                false => Ok(()),
+            },
+        );
+    let transactions = match result {
+        Err(TestError::Fail(_, transactions)) => transactions,
+        result => panic!("expected a failure, got {:?}", result),
+    };
+    assert_eq!(transactions.len(), 1);
+    assert_eq!(transactions[0].r#type, TransactionType::Chargeback);
+}
+
+/// Generated logs have at most `max_len` transactions of the shape's clients and amounts, and
+/// only deposits and withdrawals have amounts, in logs and single transactions alike.
+#[cfg(feature = "test-util")]
+#[test]
+fn generated_transactions_have_the_shape_asked_for() {
+    use proptest::strategy::{Strategy, ValueTree};
+    use test_util::Shape;
+
+    let shape = Shape {
+        max_len: 10,
+        clients: 2,
+        max_amount: 5_0000,
+    };
+    let check = |tx: &Transaction| {
+        assert!((1..=2).contains(&tx.client_id));
+        match tx.r#type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                let amount = tx.amount.unwrap();
+                assert!(amount > Money::ZERO && amount <= Money::from(dec!(5)));
+            }
+            _ => assert_eq!(tx.amount, None),
+        }
+    };
+
+    let mut runner = proptest_runner(1);
+    for _ in 0..100 {
+        let log = shape.logs().new_tree(&mut runner).unwrap().current();
+        assert!(log.len() <= 10);
+        log.iter().for_each(check);
+        check(
+            &shape
+                .transactions()
+                .new_tree(&mut runner)
+                .unwrap()
+                .current(),
+        );
+    }
+}
+
+/// `process_bytes` doesn't panic on arbitrary input (as fuzzing would find), and funds can't grow
+/// until their arithmetic overflows.
+#[test]
+fn processing_bytes_survives_arbitrary_input() {
+    let pieces: [&[u8]; 16] = [
+        b"deposit",
+        b"withdrawal",
+        b"dispute",
+        b"chargeback",
+        b",",
+        b"\n",
+        b"1",
+        b"65536",
+        b"4294967296",
+        b"79228162514264337593543950.335",
+        b"-1",
+        b"0.00001",
+        b"\"",
+        b"\xff",
+        b"1e30",
+        b"type,client,tx,amount,currency\n",
+    ];
+    let mut rng = synthetic::SplitMix64(0);
+    for case in 0..2000 {
+        let mut input = Vec::new();
+        if case % 2 == 0 {
+            input.extend_from_slice(b"type,client,tx,amount\n");
+        }
+        for _ in 0..rng.below(60) {
+            input.extend_from_slice(pieces[rng.below(pieces.len() as u64) as usize]);
+        }
+        let _ = process_bytes(&input);
+    }
+
+    let mut input = String::from("type,client,tx,amount\n");
+    input += "deposit,1,1,79228162514264337593543950.335\n";
+    for tx_id in 2..=20 {
+        input += &format!("deposit,1,{},10000000000000000000\n", tx_id);
+    }
+    let processed = process_bytes(input.as_bytes()).unwrap();
+    assert_eq!(processed.client_states[&1].total, money::Money::MAX);
+    assert_eq!(processed.rejected.len(), 10);
+    assert!(processed
+        .rejected
+        .iter()
+        .all(|(_, e)| e.reason() == "overflow"));
+}
+
+/// Deposits and withdrawals without a usable amount (which the flexible parsers let through) are
+/// never recorded as disputable, so disputes against them are rejected rather than panicking, and
+/// snapshots can't smuggle such records in either.
+#[test]
+fn transactions_without_amounts_cant_be_disputed() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  2.0
+deposit,    1,      2,
+withdrawal, 1,      3
+deposit,    1,      4,  0
+dispute,    1,      2,
+resolve,    1,      2,
+dispute,    1,      3,
+chargeback, 1,      3,
+dispute,    1,      4,
+";
+    let reasons: Vec<&str> = process_bytes(csv.as_bytes())
+        .unwrap()
+        .rejected
+        .iter()
+        .map(|(_, e)| e.reason())
+        .collect();
+    assert_eq!(
+        reasons,
+        [
+            "missing_amount",
+            "missing_amount",
+            "zero_amount",
+            "unknown_tx",
+            "unknown_tx",
+            "unknown_tx",
+            "unknown_tx",
+            "unknown_tx",
+        ]
+    );
+
+    let ndjson = r#"{"type":"deposit","client":1,"tx":1,"amount":null}
+{"type":"dispute","client":1,"tx":1}
+"#;
+    let mut rejects = Vec::new();
+    let client_states = process_transactions(
+        Engine::new(),
+        read_transactions(ndjson.as_bytes(), InputFormat::Ndjson),
+        |_, e| {
+            rejects.push(e.reason());
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(rejects, ["missing_amount", "unknown_tx"]);
+    assert_eq!(client_states[&1].held, dec!(0));
+
+    for transaction in [
+        r#"{"type":"dispute","client":1,"tx":1,"amount":"1.0"}"#,
+        r#"{"type":"deposit","client":1,"tx":1,"amount":"-1.0"}"#,
+        r#"{"type":"withdrawal","client":1,"tx":1,"amount":"0"}"#,
+    ] {
+        let snapshot = format!(
+            r#"{{"version":{},"clients":[],"transactions":[{}]}}"#,
+            snapshot::SNAPSHOT_VERSION,
+            transaction
+        );
+        let error = Engine::new()
+            .restore_snapshot(snapshot.as_bytes())
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "snapshot has an invalid disputable transaction 1"
+        );
+    }
+}
+
+/// Rows which can't be parsed are reported with their line, byte offset, and contents, whichever
+/// parser reads them.
+#[test]
+fn parse_errors_include_position_and_record() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+deposit,    1,      x,  2.0
+";
+    let errors = [
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes()))
+            .find_map(Result::err)
+            .unwrap(),
+        csv_transactions(csv_reader_from_str(csv.as_bytes()))
+            .find_map(Result::err)
+            .unwrap(),
+    ];
+    for error in errors {
+        let error = error.downcast::<error::ParseError>().unwrap();
+        assert_eq!(error.line, Some(3));
+        assert_eq!(error.byte, Some(59));
+        assert_eq!(error.record, "deposit,1,x,2.0");
+        assert_eq!(
+            error.to_string(),
+            format!("line 3 (byte 59): {}, in `deposit,1,x,2.0`", error.message)
+        );
+    }
+
+    let invalid = b"type,client,tx,amount\ndeposit,1,1,\xff\n";
+    let error = fast_csv_transactions(csv_reader_from_str(&invalid[..]))
+        .find_map(Result::err)
+        .unwrap()
+        .downcast::<error::ParseError>()
+        .unwrap();
+    assert_eq!(error.line, Some(2));
+    assert_eq!(error.record, "deposit,1,1,\u{fffd}");
+    assert!(error.message.starts_with("invalid UTF-8"));
+}
+
+/// Rows which can't be parsed can be skipped rather than stopping processing, while any other
+/// error still stops it, and skipping rejects unknown types unless another policy is given.
+#[test]
+fn malformed_rows_can_be_skipped() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1.0
+deposit,    1,      2,  1.x
+withdrawal, 1,      3,  0.5
+";
+    let mut skipped = Vec::new();
+    let transactions =
+        input::skip_bad_rows(csv_transactions(csv_reader_from_str(csv.as_bytes())), |e| {
+            skipped.push((e.line, e.record.clone()))
+        });
+    let states = process_transactions(Engine::new(), transactions, |_, _| Ok(())).unwrap();
+    assert_eq!(states[&1].available, dec!(0.5));
+    assert_eq!(skipped, [(Some(3), "deposit,1,2,1.x".to_string())]);
+
+    // Other errors aren't skipped.
+    let failing: Vec<Result<Transaction, Box<dyn Error>>> = vec![Err("disk on fire".into())];
+    let mut transactions = input::skip_bad_rows(failing, |_| panic!("skipped an I/O error"));
+    assert!(transactions.next().unwrap().is_err());
+
+    // Rows from one of several logs say which.
+    let dir = std::env::temp_dir().join(format!("payment-engine-skip-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("a.csv").to_string_lossy().into_owned();
+    std::fs::write(&path, csv).unwrap();
+    let error = open_transaction_logs(
+        &[path.clone(), path.clone()],
+        InputFormat::Csv,
+        &Default::default(),
+    )
+    .unwrap()
+    .find_map(Result::err)
+    .unwrap()
+    .downcast::<error::ParseError>()
+    .unwrap();
+    assert_eq!(error.path.as_deref(), Some(path.as_str()));
+    assert!(error.to_string().starts_with(&format!("{}: line 3", path)));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    let options = args(&["a.csv", "--skip-bad-rows"]).unwrap();
+    assert!(options.skip_bad_rows);
+    assert_eq!(options.unknown_type_policy, UnknownTypePolicy::Reject);
+    let options = args(&[
+        "a.csv",
+        "--skip-bad-rows",
+        "--unknown-type-policy",
+        "ignore",
+    ])
+    .unwrap();
+    assert_eq!(options.unknown_type_policy, UnknownTypePolicy::Ignore);
+    assert_eq!(
+        args(&["a.csv"]).unwrap().unknown_type_policy,
+        UnknownTypePolicy::ErrorOut
+    );
+
+    let mut summary = summary::Summary::new();
+    summary.skip_rows(2);
+    let mut report = Vec::new();
+    summary.write(&mut report, 0).unwrap();
+    assert!(String::from_utf8(report)
+        .unwrap()
+        .contains("\nskipped rows: 2\n"));
+}
+
+/// Amounts, fees, and converted amounts can be rounded to fewer decimal places, half up or
+/// truncated, and amounts which round to zero are rejected.
+#[test]
+fn amounts_are_rounded_to_the_configured_precision() {
+    use fees::FeeSchedule;
+    use money::{Precision, Rounding};
+
+    let round = |places, rounding, amount| Precision::new(places, rounding).unwrap().round(amount);
+    assert_eq!(round(2, Rounding::Bankers, dec!(0.125)), dec!(0.12));
+    assert_eq!(round(2, Rounding::HalfUp, dec!(0.125)), dec!(0.13));
+    assert_eq!(round(2, Rounding::HalfUp, dec!(-0.125)), dec!(-0.13));
+    assert_eq!(round(2, Rounding::Truncate, dec!(0.129)), dec!(0.12));
+    assert!(Precision::new(5, Rounding::Bankers).is_err());
+
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1.005
+deposit,    1,      2,  0.004
+withdrawal, 1,      3,  0.125
+deposit,    2,      4,  0.33
+";
+    let fees = FeeSchedule::from_toml("[deposit]\npercent = 1.5\n").unwrap();
+    let engine = Engine::new()
+        .with_precision(Precision::new(2, Rounding::HalfUp).unwrap())
+        .with_fees(fees);
+    let mut rejects = Vec::new();
+    let states = process_csv(engine, csv_reader_from_str(csv.as_bytes()), |tx, e| {
+        rejects.push((tx.tx_id, e.reason()));
+        Ok(())
+    })
+    .unwrap();
+    // 1.01 deposited, less a fee of 0.01515 (0.02), and 0.13 withdrawn.
+    assert_eq!(states[&1].available, dec!(0.86));
+    assert_eq!(rejects, [(2, "zero_amount")]);
+    // The fee of 0.00495 is rounded once, to nothing.
+    assert_eq!(states[&2].available, dec!(0.33));
+
+    // The default is unchanged.
+    let states = process_csv(
+        Engine::new().with_precision(Precision::default()),
+        csv_reader_from_str(csv.as_bytes()),
+        ignore_rejects,
+    )
+    .unwrap();
+    assert_eq!(states[&1].available, dec!(0.884));
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    let options = args(&["--rounding", "half-up", "--decimal-places", "2"]).unwrap();
+    assert_eq!(
+        options.precision,
+        Precision::new(2, Rounding::HalfUp).unwrap()
+    );
+    assert_eq!(args(&[]).unwrap().precision, Precision::default());
+    assert!(args(&["--decimal-places", "5"]).is_err());
+    assert!(args(&["--decimal-places", "two"]).is_err());
+    assert!(args(&["--rounding", "up"]).is_err());
+}
+
+/// Amounts are rounded once, as they were written, rather than rounded to `DECIMAL_PLACES` when
+/// they're read and then rounded again with the configured strategy.
+#[test]
+fn amounts_are_only_rounded_once() {
+    use money::{Precision, Rounding};
+
+    let deposit = |rounding, amount: &str| {
+        let csv = format!("type,client,tx,amount\ndeposit,1,1,{}\n", amount);
+        let engine = Engine::new().with_precision(Precision::new(2, rounding).unwrap());
+        let states = process_csv(engine, csv_reader_from_str(csv.as_bytes()), ignore_rejects);
+        states.unwrap()[&1].available
+    };
+    assert_eq!(deposit(Rounding::Truncate, "0.12999"), dec!(0.12));
+    assert_eq!(deposit(Rounding::HalfUp, "1.00495"), dec!(1.00));
+
+    // Transactions read with serde (rather than the CSV fast path) are rounded the same way.
+    let mut engine = Engine::new().with_precision(Precision::new(2, Rounding::Truncate).unwrap());
+    let json = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "0.12999"}"#;
+    engine
+        .apply(&input::parse_line(json, input::InputFormat::Ndjson).unwrap())
+        .unwrap();
+    assert_eq!(engine.client_states()[&1].available, dec!(0.12));
+}
+
+/// Engines can be configured from a TOML file, with fee schedules and the like relative to it, and
+/// command line flags override the file.
+#[test]
+fn engines_are_configured_from_a_config_file() {
+    use config::{ConfigFile, EngineConfig};
+    use money::{Precision, Rounding};
+
+    let dir = std::env::temp_dir().join(format!("payment-engine-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("fees.toml"), "[deposit]\nflat = \"0.1\"\n").unwrap();
+    let path = dir.join("engine.toml");
+    std::fs::write(
+        &path,
+        "withdrawal-disputes = \"ignore\"\n\
+         decimal-places = 2\n\
+         rounding = \"half-up\"\n\
+         max-open-disputes = 1\n\
+         fees = \"fees.toml\"\n",
+    )
+    .unwrap();
+
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1.005
+withdrawal, 1,      2,  0.5
+dispute,    1,      2,
+";
+    let mut rejects = Vec::new();
+    let engine = EngineConfig::read_file(&path).unwrap().build();
+    let states = process_csv(engine, csv_reader_from_str(csv.as_bytes()), |tx, e| {
+        rejects.push((tx.tx_id, e.reason()));
+        Ok(())
+    })
+    .unwrap();
+    // 1.01 deposited less a fee of 0.10, and 0.50 withdrawn, which can't be disputed.
+    assert_eq!(states[&1].available, dec!(0.41));
+    assert_eq!(rejects, [(2, "withdrawal_dispute_ignored")]);
+
+    // The default configuration builds the default engine.
+    let states = process_csv(
+        EngineConfig::new().build(),
+        csv_reader_from_str(csv.as_bytes()),
+        ignore_rejects,
+    )
+    .unwrap();
+    assert_eq!(states[&1].available, dec!(0.505));
+    assert_eq!(states[&1].held, dec!(0.5));
+
+    let parse = |toml: &str| ConfigFile::from_toml(toml, &dir);
+    assert!(parse("").is_ok());
+    assert!(parse("stritc = true\n").is_err());
+    assert!(parse("locked-accounts = \"freeze\"\n").is_err());
+    assert!(parse("decimal-places = 5\n").is_err());
+    assert!(parse("max-open-disputes = 0\n").is_err());
+    assert_eq!(
+        parse("max-memory = \"1K\"\ncredit-limit = \"5\"\n")
+            .unwrap()
+            .max_memory,
+        Some(1024)
+    );
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    let config = path.to_string_lossy().into_owned();
+    let options = args(&["--decimal-places", "3", "--config", &config, "--strict"]).unwrap();
+    assert_eq!(
+        options.precision,
+        Precision::new(3, Rounding::HalfUp).unwrap()
+    );
+    assert_eq!(
+        options.withdrawal_dispute_policy,
+        WithdrawalDisputePolicy::Ignore
+    );
+    assert_eq!(options.max_open_disputes, Some(1));
+    assert_eq!(
+        options.fees_path.as_deref(),
+        Some(dir.join("fees.toml").to_string_lossy().as_ref())
+    );
+    assert!(options.strict);
+    assert!(args(&["--config"]).is_err());
+    assert!(args(&["--config", "missing.toml"]).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// An engine keeping history lists the transactions applied to each client, in order, with the
+/// state each left the client in, and `history` writes them as the client's ledger.
+#[test]
+fn client_history_lists_applied_transactions() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  5
+deposit,    2,      2,  3
+withdrawal, 1,      3,  9
+withdrawal, 1,      4,  2
+dispute,    1,      1,
+chargeback, 1,      1,
+";
+    let mut engine = Engine::new().with_history();
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        ignore_rejects,
+    )
+    .unwrap();
+
+    // The rejected withdrawal isn't listed.
+    let ledger = engine.client_history(1).unwrap();
+    let applied: Vec<_> = (ledger.iter())
+        .map(|entry| (entry.transaction.r#type, entry.transaction.tx_id))
+        .collect();
+    assert_eq!(
+        applied,
+        [
+            (TransactionType::Deposit, 1),
+            (TransactionType::Withdrawal, 4),
+            (TransactionType::Dispute, 1),
+            (TransactionType::Chargeback, 1),
+        ]
+    );
+    assert_eq!(ledger[2].state.held, dec!(5));
+    assert!(ledger[3].state.locked);
+    assert_eq!(engine.client_history(2).unwrap().len(), 1);
+    assert!(engine.client_history(3).unwrap().is_empty());
+    assert!(Engine::new().client_history(1).is_none());
+
+    let mut written = Vec::new();
+    output::write_ledger(&mut written, ledger, &OutputDialect::default()).unwrap();
+    assert_eq!(
+        String::from_utf8(written).unwrap(),
+        "version,type,tx,amount,available,held,total,locked\n\
+         1,deposit,1,5.0000,5.0000,0.0000,5.0000,false\n\
+         2,withdrawal,4,2.0000,3.0000,0.0000,3.0000,false\n\
+         3,dispute,1,,-2.0000,5.0000,3.0000,false\n\
+         4,chargeback,1,,-2.0000,0.0000,-2.0000,true\n"
+    );
+    let mut written = Vec::new();
+    output::write_ledger(&mut written, Vec::new(), &OutputDialect::default()).unwrap();
+    assert_eq!(
+        String::from_utf8(written).unwrap(),
+        "version,type,tx,amount,available,held,total,locked\n"
+    );
+
+    let command = |args: &[&str]| cli::Command::from_args(args.iter().map(|s| s.to_string()));
+    match command(&["history", "7", "log.csv", "--strict"]) {
+        Ok(cli::Command::History(options)) => {
+            assert_eq!(options.client_id, 7);
+            assert_eq!(options.options.csv_path.as_deref(), Some("log.csv"));
+            assert!(options.options.strict);
+        }
+        other => panic!("unexpected command {:?}", other),
+    }
+    assert!(command(&["history", "log.csv"]).is_err());
+    assert!(command(&["history", "7", "log.csv", "--output-format", "json"]).is_err());
+    assert!(command(&["history", "7", "log.csv", "--summary"]).is_err());
+}
+
+/// A transaction which was charged back can't be disputed again (even once its account is
+/// unlocked), but one which was resolved can be, and only transactions still under dispute are
+/// reported as open disputes.
+#[test]
+fn charged_back_transactions_cant_be_disputed_again() {
+    let mut rejects = Vec::new();
+    let mut engine = Engine::new().with_admin_ops(true);
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10\n\
+             deposit, 1, 2, 5\n\
+             deposit, 2, 3, 7\n\
+             dispute, 1, 1,\n\
+             chargeback, 1, 1,\n\
+             unlock, 1, 4,\n\
+             dispute, 1, 1,\n\
+             resolve, 1, 1,\n\
+             dispute, 1, 2,\n\
+             resolve, 1, 2,\n\
+             dispute, 1, 2,\n\
+             dispute, 2, 3,\n"
+                .as_bytes(),
+        )),
+        |_, e| {
+            rejects.push(e.reason());
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(rejects, ["already_charged_back", "not_disputed"]);
+
+    let state = &engine.client_states()[&1];
+    assert_eq!(state.dispute_state(1), DisputeState::ChargedBack);
+    assert_eq!(state.dispute_state(2), DisputeState::Disputed);
+    assert_eq!(state.dispute_state(3), DisputeState::Undisputed);
+    assert_eq!(state.available, dec!(0));
+    assert_eq!(state.held, dec!(5));
+
+    let open = engine.open_disputes().unwrap();
+    assert_eq!(
+        open.iter()
+            .map(|(tx_id, tx)| (tx.client_id, *tx_id))
+            .collect::<Vec<_>>(),
+        [(1, 2), (2, 3)]
+    );
+    let mut report = Vec::new();
+    output::write_open_disputes(&mut report, &open).unwrap();
+    assert_eq!(
+        String::from_utf8(report).unwrap(),
+        "client,tx,type,amount\n1,2,deposit,5.0000\n2,3,deposit,7.0000\n"
+    );
+    let mut report = Vec::new();
+    output::write_open_disputes(&mut report, &[]).unwrap();
+    assert_eq!(
+        String::from_utf8(report).unwrap(),
+        "client,tx,type,amount\n"
+    );
+
+    // Dispute states carry over through snapshots.
+    let mut snapshot = Vec::new();
+    engine.write_snapshot(&mut snapshot).unwrap();
+    let mut restored = Engine::new()
+        .with_admin_ops(true)
+        .restore_snapshot(snapshot.as_slice())
+        .unwrap();
+    assert_eq!(
+        restored.client_states()[&1].dispute_state(1),
+        DisputeState::ChargedBack
+    );
+    let dispute = Transaction {
+        r#type: TransactionType::Dispute,
+        client_id: 1,
+        tx_id: 1,
+        amount: None,
+        raw_amount: None,
+        line: None,
+        terminal: None,
+        currency: Default::default(),
+        to_currency: Default::default(),
+        timestamp: None,
+    };
+    assert_eq!(
+        restored.apply(&dispute).unwrap_err().reason(),
+        "already_charged_back"
+    );
+}
+
+/// Resolved transactions can be disputed again unless each transaction may only be disputed once,
+/// and charged back transactions can never be disputed again, even by another client.
+#[test]
+fn redisputes_follow_policy() {
+    let csv = "\
+        type, client, tx, amount\n\
+        deposit, 1, 1, 10\n\
+        deposit, 2, 2, 5\n\
+        dispute, 1, 1,\n\
+        resolve, 1, 1,\n\
+        dispute, 1, 1,\n\
+        dispute, 2, 2,\n\
+        chargeback, 2, 2,\n\
+        dispute, 1, 2,\n";
+    let run = |engine: &mut Engine| {
+        let mut rejects = Vec::new();
+        apply_transactions(
+            engine,
+            fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+            |_, e| {
+                rejects.push(e.reason());
+                Ok(())
+            },
+        )
+        .unwrap();
+        rejects
+    };
+
+    let mut engine = Engine::new();
+    assert_eq!(run(&mut engine), ["already_charged_back"]);
+    assert_eq!(
+        engine.client_states()[&1].dispute_state(1),
+        DisputeState::Disputed
+    );
+    assert_eq!(engine.client_states()[&1].held, dec!(10));
+
+    let mut engine = Engine::new().with_redispute_policy(RedisputePolicy::Once);
+    assert_eq!(
+        run(&mut engine),
+        ["already_resolved", "already_charged_back"]
+    );
+    let state = &engine.client_states()[&1];
+    assert_eq!(state.dispute_state(1), DisputeState::Resolved);
+    assert_eq!(
+        (state.available, state.held),
+        (dec!(10).into(), Money::ZERO)
+    );
+
+    // Settled disputes carry over through snapshots.
+    let mut snapshot = Vec::new();
+    engine.write_snapshot(&mut snapshot).unwrap();
+    let mut restored = Engine::new()
+        .with_redispute_policy(RedisputePolicy::Once)
+        .restore_snapshot(snapshot.as_slice())
+        .unwrap();
+    let dispute = |client_id, tx_id| Transaction {
+        r#type: TransactionType::Dispute,
+        client_id,
+        tx_id,
+        amount: None,
+        raw_amount: None,
+        line: None,
+        terminal: None,
+        currency: Default::default(),
+        to_currency: Default::default(),
+        timestamp: None,
+    };
+    let reason = |engine: &mut Engine, tx| engine.apply(&tx).unwrap_err().reason();
+    assert_eq!(reason(&mut restored, dispute(1, 1)), "already_resolved");
+    assert_eq!(reason(&mut restored, dispute(1, 2)), "already_charged_back");
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert_eq!(
+        args(&["--redisputes", "once"]).unwrap().redispute_policy,
+        RedisputePolicy::Once
+    );
+    assert_eq!(
+        args(&[]).unwrap().redispute_policy,
+        RedisputePolicy::Repeatable
+    );
+    assert!(args(&["--redisputes", "twice"]).is_err());
+    assert_eq!(
+        config::ConfigFile::from_toml("redisputes = \"once\"\n", std::path::Path::new(""))
+            .unwrap()
+            .redisputes,
+        Some(RedisputePolicy::Once)
+    );
+}
+
+/// An engine skipping replays applies each deposit and withdrawal once, however often the input
+/// overlaps, remembers what it processed through snapshots, and keeps the set compact.
+#[test]
+fn replayed_transactions_are_skipped() {
+    use dedup::IdBitmap;
+
+    let first_batch = "\
+        type, client, tx, amount\n\
+        deposit, 1, 1, 10\n\
+        withdrawal, 1, 2, 3\n\
+        deposit, 2, 3, 5\n";
+    // The second batch replays the end of the first.
+    let second_batch = "\
+        type, client, tx, amount\n\
+        withdrawal, 1, 2, 3\n\
+        deposit, 2, 3, 5\n\
+        deposit, 2, 4, 1\n\
+        deposit, 1, 4, 2\n";
+    let run = |engine: &mut Engine, csv: &str| {
+        let mut rejects = Vec::new();
+        apply_transactions(
+            engine,
+            fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+            |_, e| {
+                rejects.push(e.reason());
+                Ok(())
+            },
+        )
+        .unwrap();
+        rejects
+    };
+
+    let mut engine = Engine::new().with_dedup();
+    assert!(run(&mut engine, first_batch).is_empty());
+    let mut snapshot = Vec::new();
+    engine.write_snapshot(&mut snapshot).unwrap();
+    // A client reusing another client's ID is still rejected as a duplicate.
+    assert_eq!(run(&mut engine, second_batch), ["duplicate_tx_id"]);
+    assert_eq!(engine.replayed_count(), 2);
+    assert_eq!(engine.client_states()[&1].available, dec!(7));
+    assert_eq!(engine.client_states()[&2].available, dec!(6));
+    let processed = engine.processed().unwrap();
+    assert_eq!(processed.len(), 5);
+    assert!(processed.contains(1, 4) && !processed.contains(1, 3));
+
+    // Without dedup, replays are rejected as duplicates.
+    let mut engine = Engine::new();
+    run(&mut engine, first_batch);
+    assert_eq!(
+        run(&mut engine, second_batch),
+        ["duplicate_tx_id", "duplicate_tx_id", "duplicate_tx_id"]
+    );
+
+    // The set carries over through snapshots.
+    let mut restored = Engine::new()
+        .with_dedup()
+        .restore_snapshot(snapshot.as_slice())
+        .unwrap();
+    assert_eq!(run(&mut restored, second_batch), ["duplicate_tx_id"]);
+    assert_eq!(restored.replayed_count(), 2);
+    assert_eq!(restored.client_states()[&1].available, dec!(7));
+
+    // Sparse IDs take a couple of bytes each, and dense IDs a bit each.
+    let mut ids = IdBitmap::new();
+    for id in (0..1000).map(|i| i * 1_000_003) {
+        assert!(ids.insert(id));
+    }
+    assert!(!ids.insert(1_000_003));
+    assert!(ids.heap_size() < 1000 * 64);
+    let mut ids = IdBitmap::new();
+    for id in 0..1_000_000 {
+        ids.insert(id);
+    }
+    ids.insert(2_000_000);
+    assert_eq!(ids.len(), 1_000_001);
+    assert!(ids.contains(999_999) && !ids.contains(1_000_000));
+    assert!(ids.heap_size() < 200 * 1024);
+    assert_eq!(ids.ranges(), [(0, 999_999), (2_000_000, 2_000_000)]);
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["--dedup"]).unwrap().dedup);
+}
+
+/// A balance diff only has the clients whose funds or lock changed since an earlier export, with
+/// the change in each of the funds, including clients which are new or no longer exported.
+#[test]
+fn balance_diffs_only_have_changed_clients() {
+    let dir = std::env::temp_dir().join(format!("payment-engine-diff-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let previous = dir.join("previous.csv");
+    std::fs::write(
+        &previous,
+        "client,available,held,total,locked,version\n\
+         1,10.0000,0.0000,10.0000,false,1\n\
+         2,5.0000,0.0000,5.0000,false,1\n\
+         3,1.0000,0.0000,1.0000,false,1\n\
+         5,0.0000,0.0000,0.0000,false,1\n",
+    )
+    .unwrap();
+    let previous = || read_balances(previous.to_str().unwrap()).unwrap();
+
+    let client_states = process_csv(
+        Engine::new(),
+        csv_reader_from_str(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10\n\
+             deposit, 2, 2, 5\n\
+             deposit, 4, 3, 2.5\n\
+             dispute, 2, 2,\n\
+             deposit, 3, 4, 1\n\
+             chargeback, 2, 2,\n"
+                .as_bytes(),
+        ),
+        ignore_rejects,
+    )
+    .unwrap();
+    let mut diff = Vec::new();
+    let mut sink = output::DiffWriter::new(&mut diff, &OutputDialect::default(), previous());
+    write_balances(&mut sink, &client_states, SortBy::Client).unwrap();
+    drop(sink);
+    assert_eq!(
+        String::from_utf8(diff).unwrap(),
+        "client,available,held,total,locked,version,available_delta,held_delta,total_delta\n\
+         2,0.0000,0.0000,0.0000,true,3,-5.0000,0.0000,-5.0000\n\
+         4,2.5000,0.0000,2.5000,false,1,2.5000,0.0000,2.5000\n"
+    );
+
+    // Nothing changing still gives a header, and clients no longer exported have no funds.
+    let mut diff = Vec::new();
+    let mut sink = output::DiffWriter::new(&mut diff, &OutputDialect::default(), previous());
+    write_balances(&mut sink, &previous(), SortBy::Client).unwrap();
+    drop(sink);
+    assert_eq!(
+        String::from_utf8(diff).unwrap(),
+        "client,available,held,total,locked,version,available_delta,held_delta,total_delta\n"
+    );
+    let mut diff = Vec::new();
+    let mut sink = output::DiffWriter::new(&mut diff, &OutputDialect::default(), previous());
+    write_balances(&mut sink, &HashMap::new(), SortBy::Client).unwrap();
+    drop(sink);
+    assert_eq!(
+        String::from_utf8(diff)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .collect::<Vec<_>>(),
+        [
+            "1,0.0000,0.0000,0.0000,false,0,-10.0000,0.0000,-10.0000",
+            "2,0.0000,0.0000,0.0000,false,0,-5.0000,0.0000,-5.0000",
+            "3,0.0000,0.0000,0.0000,false,0,-1.0000,0.0000,-1.0000",
+        ]
+    );
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["--diff", "previous.csv"]).is_ok());
+    assert!(args(&["--diff", "previous.csv", "--output-format", "json"]).is_err());
+    assert!(args(&["--diff", "previous.csv", "--output-shards", "2"]).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Balance diffs have each client's current version, but a client whose version changed without
+/// its funds or lock changing isn't written.
+#[test]
+fn balance_diffs_have_versions_without_comparing_them() {
+    let dir = std::env::temp_dir().join(format!(
+        "payment-engine-diff-versions-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let previous = dir.join("previous.csv");
+    std::fs::write(
+        &previous,
+        "client,available,held,total,locked,version\n\
+         1,10.0000,0.0000,10.0000,false,1\n\
+         2,5.0000,0.0000,5.0000,false,1\n",
+    )
+    .unwrap();
+
+    let client_states = process_csv(
+        Engine::new(),
+        csv_reader_from_str(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 10\n\
+             dispute, 1, 1,\n\
+             resolve, 1, 1,\n\
+             deposit, 2, 2, 5\n\
+             withdrawal, 2, 3, 2\n"
+                .as_bytes(),
+        ),
+        ignore_rejects,
+    )
+    .unwrap();
+    let mut diff = Vec::new();
+    let mut sink = output::DiffWriter::new(
+        &mut diff,
+        &OutputDialect::default(),
+        read_balances(previous.to_str().unwrap()).unwrap(),
+    );
+    write_balances(&mut sink, &client_states, SortBy::Client).unwrap();
+    drop(sink);
+    assert_eq!(
+        String::from_utf8(diff).unwrap(),
+        "client,available,held,total,locked,version,available_delta,held_delta,total_delta\n\
+         2,3.0000,0.0000,3.0000,false,2,-2.0000,0.0000,-2.0000\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Initial balances seed the client states, so only the transactions since an earlier export need
+/// to be applied, while held funds stay held and locked accounts stay locked.
+#[test]
+fn initial_balances_seed_client_states() {
+    let dir = std::env::temp_dir().join(format!(
+        "payment-engine-initial-balances-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    let balances = dir.join("balances.csv");
+    std::fs::write(
+        &balances,
+        "client,available,held,total,locked,version\n\
+         1,10.0000,2.0000,12.0000,false,3\n\
+         2,5.0000,0.0000,5.0000,true,2\n",
+    )
+    .unwrap();
+    let balances = read_balances(balances.to_str().unwrap()).unwrap();
+
+    let mut rejects = 0;
+    let client_states = process_csv(
+        Engine::new().with_initial_balances(balances),
+        csv_reader_from_str(
+            "type, client, tx, amount\n\
+             withdrawal, 1, 10, 4\n\
+             dispute, 1, 1,\n\
+             deposit, 2, 11, 1\n\
+             deposit, 3, 12, 2\n"
+                .as_bytes(),
+        ),
+        |_, _| {
+            rejects += 1;
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(rejects, 2);
+
+    let client = &client_states[&1];
+    assert_eq!(client.available, dec!(6));
+    assert_eq!(client.held, dec!(2));
+    assert_eq!(client.total, dec!(8));
+    assert_eq!(client.version, 4);
+    let client = &client_states[&2];
+    assert_eq!(client.available, dec!(5));
+    assert!(client.locked);
+    assert_eq!(client.version, 2);
+    assert_eq!(client_states[&3].total, dec!(2));
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["--initial-balances", "balances.csv"]).is_ok());
+    assert!(args(&[
+        "--initial-balances",
+        "balances.csv",
+        "--snapshot-in",
+        "state.json"
+    ])
+    .is_err());
+    assert!(args(&["--initial-balances", "balances.csv", "--threads", "2"]).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Filtering by client keeps every transaction for the requested clients, and no state is kept for
+/// anyone else.
+#[test]
+fn client_filter_only_tracks_requested_clients() {
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    let options = args(&["--client", "2", "--client", "4"]).unwrap();
+    let clients = options.clients.unwrap();
+    assert!(clients.includes(2) && clients.includes(4) && !clients.includes(3));
+    assert!(args(&[]).unwrap().clients.is_none());
+    assert!(args(&["--client", "x"]).is_err());
+    assert!(args(&["--client", "70000"]).is_err());
+
+    let transactions: input::TransactionStream = Box::new(csv_transactions(csv_reader_from_str(
+        "type, client, tx, amount\n\
+         deposit, 1, 1, 3\n\
+         deposit, 2, 2, 5\n\
+         withdrawal, 2, 3, 1\n\
+         deposit, 3, 4, 2\n\
+         dispute, 2, 2,\n"
+            .as_bytes(),
+    )));
+    let client_states =
+        process_transactions(Engine::new(), clients.filter(transactions), ignore_rejects).unwrap();
+    assert_eq!(client_states.len(), 1);
+    let client = &client_states[&2];
+    assert_eq!(client.available, dec!(-1));
+    assert_eq!(client.held, dec!(5));
+}
+
+/// With exact amounts (as `validate` checks), amounts with no more decimal places than the ledger
+/// keeps are applied as they are.
+#[test]
+fn exact_amounts_within_the_precision_are_applied() {
+    use money::{Precision, Rounding};
+
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  2.5
+withdrawal, 1,      2,  0.25
+";
+    let engine = Engine::new().with_precision(Precision::new(2, Rounding::HalfUp).unwrap());
+    let (rejects, states) = validate_csv(engine, csv);
+    assert_eq!(rejects, []);
+    assert_eq!(states[&1].available, dec!(2.25));
+}
+
+/// An amount with more decimal places than the ledger keeps is rejected rather than rounded.
+#[test]
+fn exact_amounts_with_more_places_than_the_precision_are_rejected() {
+    use money::{Precision, Rounding};
+
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1
+withdrawal, 1,      2,  0.125
+deposit,    1,      3,  1.005
+";
+    let engine = Engine::new().with_precision(Precision::new(2, Rounding::HalfUp).unwrap());
+    let (rejects, states) = validate_csv(engine, csv);
+    assert_eq!(
+        rejects,
+        [
+            (Some(3), 2, "too_many_decimal_places"),
+            (Some(4), 3, "too_many_decimal_places")
+        ]
+    );
+    assert_eq!(states[&1].available, dec!(1));
+}
+
+/// Exact amounts count the decimal places an amount was written with, so amounts with more places
+/// than are read are rejected at the default precision too, rather than being rounded first.
+#[test]
+fn exact_amounts_with_more_places_than_are_read_are_rejected() {
+    let csv = "\
+type,    client, tx, amount
+deposit, 1,      1,  1.00005
+deposit, 1,      2,  1.123456789
+";
+    let (rejects, _) = validate_csv(Engine::new(), csv);
+    assert_eq!(
+        rejects,
+        [
+            (Some(2), 1, "too_many_decimal_places"),
+            (Some(3), 2, "too_many_decimal_places")
+        ]
+    );
+}
+
+/// Trailing zeros aren't places the ledger loses.
+#[test]
+fn exact_amounts_ignore_trailing_zeros() {
+    let csv = "\
+type,    client, tx, amount
+deposit, 1,      1,  2.50000000
+";
+    let (rejects, states) = validate_csv(Engine::new(), csv);
+    assert_eq!(rejects, []);
+    assert_eq!(states[&1].available, dec!(2.5));
+}
+
+/// Transactions read with serde (rather than the CSV fast path) count places the same way.
+#[test]
+fn exact_amounts_are_checked_on_transactions_read_with_serde() {
+    let mut engine = Engine::new().with_exact_amounts(true);
+    let json = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.00005"}"#;
+    let tx = input::parse_line(json, input::InputFormat::Ndjson).unwrap();
+    assert_eq!(tx.raw_amount, Some(dec!(1.00005)));
+    assert!(matches!(
+        engine.apply(&tx),
+        Err(TransactionError::TooManyDecimalPlaces { tx_id: 1, .. })
+    ));
+}
+
+/// Without exact amounts, amounts are rounded to the precision rather than rejected.
+#[test]
+fn inexact_amounts_are_rounded_unless_amounts_must_be_exact() {
+    use money::{Precision, Rounding};
+
+    let csv = "\
+type,    client, tx, amount
+deposit, 1,      1,  1.005
+";
+    let engine = Engine::new().with_precision(Precision::new(2, Rounding::HalfUp).unwrap());
+    let states = process_csv(engine, csv_reader_from_str(csv.as_bytes()), ignore_rejects).unwrap();
+    assert_eq!(states[&1].available, dec!(1.01));
+}
+
+/// `validate` reports negative amounts with their lines.
+#[test]
+fn validation_rejects_negative_amounts() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1
+withdrawal, 1,      2,  -0.5
+";
+    let (rejects, states) = validate_csv(Engine::new(), csv);
+    assert_eq!(rejects, [(Some(3), 2, "negative_amount")]);
+    assert_eq!(states[&1].available, dec!(1));
+}
+
+/// `validate` reports deposits and withdrawals without amounts with their lines.
+#[test]
+fn validation_rejects_missing_amounts() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,
+";
+    let (rejects, _) = validate_csv(Engine::new(), csv);
+    assert_eq!(rejects, [(Some(2), 1, "missing_amount")]);
+}
+
+/// `validate` reports rows of an unknown type as rejected, and carries on checking the rows after
+/// them, unless another policy is chosen.
+#[test]
+fn validation_rejects_unknown_types_and_carries_on() {
+    use cli::Command;
+
+    let parse = |args: &[&str]| match Command::from_args(args.iter().map(|s| s.to_string())) {
+        Ok(Command::Validate(options)) => Ok(options),
+        Ok(command) => panic!("expected validate, got {:?}", command),
+        Err(e) => Err(e),
+    };
+    let options = parse(&["validate", "in.csv"]).unwrap();
+    assert!(options.skip_bad_rows);
+    assert_eq!(options.unknown_type_policy, UnknownTypePolicy::Reject);
+
+    let csv = "\
+type,       client, tx, amount
+refund,     1,      1,  1
+deposit,    1,      2,  -1
+";
+    let engine = Engine::new().with_unknown_type_policy(options.unknown_type_policy);
+    let (rejects, _) = validate_csv(engine, csv);
+    assert_eq!(
+        rejects,
+        [
+            (Some(2), 1, "unknown_type"),
+            (Some(3), 2, "negative_amount")
+        ]
+    );
+
+    let args = ["validate", "in.csv", "--unknown-type-policy", "ignore"];
+    assert_eq!(
+        parse(&args).unwrap().unknown_type_policy,
+        UnknownTypePolicy::Ignore
+    );
+    let options = parse(&["validate", "in.csv", "--strict"]).unwrap();
+    assert!(!options.skip_bad_rows);
+    assert_eq!(options.unknown_type_policy, UnknownTypePolicy::ErrorOut);
+    // Validation runs on a single engine.
+    assert!(parse(&["validate", "in.csv", "--threads", "2"]).is_err());
+}
+
+/// `validate` reports disputes of transactions which don't exist with their lines.
+#[test]
+fn validation_rejects_references_to_unknown_transactions() {
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  1
+dispute,    1,      2,
+chargeback, 1,      3,
+";
+    let (rejects, states) = validate_csv(Engine::new(), csv);
+    assert_eq!(
+        rejects,
+        [(Some(3), 2, "unknown_tx"), (Some(4), 3, "unknown_tx")]
+    );
+    assert_eq!(states[&1].held, dec!(0));
+}
+
+/// A schema reads transaction fields from other columns, ignoring columns it doesn't know
+/// (including columns named after a field which is read from elsewhere).
+#[test]
+fn schema_maps_columns_to_fields() {
+    use input::Schema;
+
+    let schema: Schema = "type=txn_type, client=cust_id, tx=txn_id, amount=value"
+        .parse()
+        .unwrap();
+    let csv = "\
+txn_id,txn_type,note,cust_id,amount,value
+1,deposit,first,1,100,2.5
+2,withdrawal,,1,100,1
+3,dispute,,1,,
+";
+    let transactions = read_transactions_with_schema(csv.as_bytes(), InputFormat::Csv, &schema);
+    let client_states = process_transactions(Engine::new(), transactions, ignore_rejects).unwrap();
+    let client = &client_states[&1];
+    assert_eq!(client.available, dec!(1.5));
+    assert_eq!(client.total, dec!(1.5));
+
+    // Without a schema, the columns aren't found.
+    let transactions = read_transactions(csv.as_bytes(), InputFormat::Csv);
+    assert!(process_transactions(Engine::new(), transactions, ignore_rejects).is_err());
+
+    for invalid in &[
+        "type",
+        "type=",
+        "kind=txn_type",
+        "type=a,type=b",
+        "type=a,client=a",
+    ] {
+        assert!(invalid.parse::<Schema>().is_err(), "{}", invalid);
+    }
+    let file = config::ConfigFile::from_toml(
+        "[schema]\ntype = \"txn_type\"\nclient = \"cust_id\"\n",
+        std::path::Path::new(""),
+    )
+    .unwrap();
+    assert_eq!(
+        file.schema.unwrap(),
+        "type=txn_type,client=cust_id".parse().unwrap()
+    );
+    assert!(
+        config::ConfigFile::from_toml("[schema]\nkind = \"x\"\n", std::path::Path::new(""))
+            .is_err()
+    );
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["--schema", "type=txn_type"]).is_ok());
+    assert!(args(&["--schema", "type=txn_type", "--input-format", "ndjson"]).is_err());
+}
+
+/// A log without a header has its fields in a fixed order, so its first row is a transaction.
+#[test]
+fn headerless_logs_are_read_by_position() {
+    let csv = "\
+deposit, 1, 1, 2.5
+deposit, 2, 2, 4.0, till-7
+withdrawal, 1, 3, 1
+dispute, 2, 2
+";
+    let schema = input::Schema::positional();
+    let transactions: Vec<_> =
+        read_transactions_with_schema(csv.as_bytes(), InputFormat::Csv, &schema)
+            .collect::<Result<_, _>>()
+            .unwrap();
+    assert_eq!(transactions.len(), 4);
+    assert_eq!(transactions[0].line, Some(1));
+    assert_eq!(transactions[1].terminal.as_deref(), Some("till-7"));
+
+    let client_states = process_transactions(
+        Engine::new(),
+        transactions.into_iter().map(Ok),
+        ignore_rejects,
+    )
+    .unwrap();
+    assert_eq!(client_states[&1].available, dec!(1.5));
+    assert_eq!(client_states[&2].held, dec!(4));
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["--no-header"]).unwrap().schema.is_positional());
+    assert!(args(&["--no-header", "--schema", "type=txn_type"]).is_err());
+    assert!(args(&["--no-header", "--input-format", "ndjson"]).is_err());
+}
+
+/// Logs can be separated by something other than commas, and the delimiter is used for the balance
+/// export too, unless it's given separately.
+#[test]
+fn logs_can_have_other_delimiters() {
+    let csv = "type;client;tx;amount\ndeposit;1;1;2,5\ndeposit;1;2;1.5\n";
+    let schema = input::Schema::default().with_delimiter(b';');
+    let results: Vec<_> =
+        read_transactions_with_schema(csv.as_bytes(), InputFormat::Csv, &schema).collect();
+    // A decimal comma isn't a decimal point.
+    assert!(results[0].is_err());
+    assert_eq!(
+        results[1].as_ref().unwrap().amount,
+        Some(Money::new(dec!(1.5)))
+    );
+
+    let tsv = "deposit\t1\t1\t2.5\nwithdrawal\t1\t2\t1\n";
+    let schema = input::Schema::positional().with_delimiter(b'\t');
+    let transactions = read_transactions_with_schema(tsv.as_bytes(), InputFormat::Csv, &schema);
+    let client_states = process_transactions(Engine::new(), transactions, ignore_rejects).unwrap();
+    assert_eq!(client_states[&1].available, dec!(1.5));
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    let options = args(&["--delimiter", "tab"]).unwrap();
+    assert_eq!(options.schema.delimiter(), b'\t');
+    assert_eq!(options.output_dialect.delimiter, b'\t');
+    let options = args(&["--output-delimiter", ",", "--delimiter", ";", "--no-header"]).unwrap();
+    assert_eq!(options.schema.delimiter(), b';');
+    assert!(options.schema.is_positional());
+    assert_eq!(options.output_dialect.delimiter, b',');
+    assert!(args(&["--delimiter", ";;"]).is_err());
+    assert!(args(&["--delimiter", ";", "--input-format", "ndjson"]).is_err());
+}
+
+/// Files written atomically go to a unique temporary file in the same directory, which is only
+/// renamed into place once it's complete and removed if it never is, and `--output` can't be
+/// combined with sharded output.
+#[test]
+fn atomic_files_are_committed_into_place() {
+    use output::AtomicFile;
+
+    let dir = std::env::temp_dir().join(format!("payment-engine-output-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("balances.csv");
+    std::fs::write(&path, "previous").unwrap();
+
+    let mut file = AtomicFile::create(&path).unwrap();
+    let other = AtomicFile::create(&path).unwrap();
+    assert_eq!(file.temp_path().parent(), Some(dir.as_path()));
+    assert_ne!(file.temp_path(), other.temp_path());
+    let temp_path = file.temp_path().to_path_buf();
+    let mut sink = BalanceWriter::new(&mut file, &OutputDialect::default());
+    let client_states = process_bytes(b"type,client,tx,amount\ndeposit,1,1,2.5\n")
+        .unwrap()
+        .client_states;
+    write_balances(&mut sink, &client_states, SortBy::Client).unwrap();
+    drop(sink);
+    // Until it's committed, the previous file is untouched.
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous");
+
+    file.commit().unwrap();
+    assert!(!temp_path.exists());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "client,available,held,total,locked,version\n1,2.5000,0.0000,2.5000,false,1\n"
+    );
+
+    // A file which is never committed (e.g. after an error) leaves nothing behind.
+    let other_path = other.temp_path().to_path_buf();
+    assert!(other_path.exists());
+    drop(other);
+    assert!(!other_path.exists());
+    let file = AtomicFile::create(&path).unwrap();
+    let temp_path = file.temp_path().to_path_buf();
+    file.discard();
+    assert!(!temp_path.exists());
+    drop(file);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    assert!(AtomicFile::create(&dir.join("missing").join("balances.csv")).is_err());
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert_eq!(
+        args(&["in.csv", "-o", "out.csv"])
+            .unwrap()
+            .output_path
+            .as_deref(),
+        Some("out.csv")
+    );
+    assert!(args(&["--output", "out.csv", "--output-shards", "2"]).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Progress counts the bytes read through its readers, and estimates the time left from them when
+/// the logs' size is known (which it isn't for stdin or compressed logs).
+#[test]
+fn progress_is_estimated_from_bytes_read() {
+    use progress::{CountingReader, Progress};
+    use std::time::Duration;
+
+    let mut progress = Progress::new(Some(4096));
+    let mut reader = CountingReader::new(&[0u8; 1024][..], progress.bytes_read());
+    std::io::copy(&mut reader, &mut std::io::sink()).unwrap();
+    for _ in 0..100 {
+        progress.observe();
+    }
+    assert_eq!(
+        progress.status(Duration::from_secs(2)),
+        "100 rows, 1.0K of 4.0K (25.0%), 50 rows/s, ETA 6s"
+    );
+    assert_eq!(
+        Progress::new(None).status(Duration::from_secs(1)),
+        "0 rows, 0B, 0 rows/s"
+    );
+    assert_eq!(
+        Progress::new(Some(1 << 30)).status(Duration::from_secs(1)),
+        "0 rows, 0B of 1.0G (0.0%), 0 rows/s, ETA unknown"
+    );
+
+    let path = std::env::temp_dir().join(format!(
+        "payment-engine-progress-{}.csv",
+        std::process::id()
+    ));
+    std::fs::write(&path, "type,client,tx,amount\n").unwrap();
+    let path = path.to_string_lossy().into_owned();
+    assert_eq!(
+        progress::total_size(&[path.clone(), path.clone()]),
+        Some(44)
+    );
+    assert_eq!(progress::total_size(&[path.clone(), "-".to_string()]), None);
+    assert_eq!(progress::total_size(&["log.csv.gz".to_string()]), None);
+    std::fs::remove_file(&path).unwrap();
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["in.csv", "--progress"]).unwrap().progress);
+    assert!(!args(&["in.csv"]).unwrap().progress);
+}
+
+/// Once interrupted, a stream stops before reading another row, and the summary says it's partial.
+#[test]
+fn interrupted_runs_stop_reading() {
+    use interrupt::{until_interrupted, Interrupt};
+
+    let interrupt = Interrupt::default();
+    let log = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\ndeposit,1,3,3.0\n";
+    let mut transactions = until_interrupted(
+        read_transactions(log.as_bytes(), InputFormat::Csv),
+        &interrupt,
+    );
+    assert_eq!(transactions.next().unwrap().unwrap().tx_id, 1);
+    interrupt.raise();
+    assert!(transactions.next().is_none());
+    assert!(transactions.next().is_none());
+
+    let mut summary = summary::Summary::new();
+    summary.interrupt();
+    let mut written = Vec::new();
+    summary.write(&mut written, 0).unwrap();
+    assert!(String::from_utf8(written)
+        .unwrap()
+        .starts_with("interrupted: only part of the log was read\ntransactions: 0\n"));
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert_eq!(
+        args(&["in.csv", "--interrupt-snapshot", "state.json"])
+            .unwrap()
+            .interrupt_snapshot
+            .as_deref(),
+        Some("state.json")
+    );
+    assert!(args(&["--interrupt-snapshot", "state.json", "--threads", "2"]).is_err());
+}
+
+/// The C API applies rows one at a time, reporting why a row wasn't applied, and exports balances
+/// as JSON.
+#[cfg(feature = "ffi")]
+#[test]
+fn ffi_applies_rows_and_exports_balances() {
+    use ffi::*;
+    use std::ffi::{CStr, CString};
+
+    let apply = |engine, line: &str| {
+        let line = CString::new(line).unwrap();
+        unsafe { engine_apply_csv_line(engine, line.as_ptr()) }
+    };
+    let last_error = |engine| unsafe {
+        let error = engine_last_error(engine);
+        (!error.is_null()).then(|| CStr::from_ptr(error).to_str().unwrap().to_string())
+    };
+
+    let engine = engine_new();
+    assert_eq!(apply(engine, "deposit,1,1,2.5"), EngineStatus::Ok);
+    assert_eq!(last_error(engine), None);
+    assert_eq!(
+        apply(engine, "withdrawal, 1, 2, 5.0"),
+        EngineStatus::Rejected
+    );
+    assert_eq!(
+        last_error(engine).as_deref(),
+        Some("insufficient funds for client 1 (tx 2)")
+    );
+    assert_eq!(apply(engine, "deposit,x"), EngineStatus::InvalidInput);
+    assert!(last_error(engine).is_some());
+    assert_eq!(apply(engine, ""), EngineStatus::InvalidInput);
+    assert_eq!(
+        apply(engine, "deposit,1,3,1\ndeposit,1,4,1"),
+        EngineStatus::InvalidInput
+    );
+    let not_utf8 = b"deposit,1,3,\xff\0";
+    assert_eq!(
+        unsafe { engine_apply_csv_line(engine, not_utf8.as_ptr().cast()) },
+        EngineStatus::InvalidInput
+    );
+    assert_eq!(apply(engine, "deposit,2,5,1"), EngineStatus::Ok);
+    assert_eq!(last_error(engine), None);
+
+    // Null pointers are reported or ignored, never dereferenced.
+    let line = CString::new("deposit,1,1,1.0").unwrap();
+    unsafe {
+        assert_eq!(
+            engine_apply_csv_line(engine, std::ptr::null()),
+            EngineStatus::NullPointer
+        );
+        assert_eq!(
+            engine_apply_csv_line(std::ptr::null_mut(), line.as_ptr()),
+            EngineStatus::NullPointer
+        );
+        assert!(engine_last_error(std::ptr::null()).is_null());
+        assert!(engine_export_balances_json(std::ptr::null()).is_null());
+        engine_free_string(std::ptr::null_mut());
+    }
+
+    unsafe {
+        let json = engine_export_balances_json(engine);
+        let states: Vec<serde_json::Value> =
+            serde_json::from_str(CStr::from_ptr(json).to_str().unwrap()).unwrap();
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0]["client"], 1);
+        assert_eq!(states[0]["available"], "2.5000");
+        assert_eq!(states[1]["client"], 2);
+        engine_free_string(json);
+        engine_free(engine);
+        engine_free(std::ptr::null_mut());
+    }
+}
+
+/// A ledger forgets transactions outside the dispute window, other than ones still disputed.
+#[cfg(feature = "sqlite")]
+#[test]
+fn ledger_evicts_transactions_outside_the_dispute_window() {
+    let path = std::env::temp_dir().join(format!(
+        "payment-engine-ledger-evict-{}.sqlite",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let csv = "\
+type,     client, tx, amount
+deposit,  1,      1,  1.0
+dispute,  1,      1,
+deposit,  1,      2,  1.0
+deposit,  1,      3,  1.0
+deposit,  1,      4,  1.0
+deposit,  1,      5,  1.0
+dispute,  1,      2,
+resolve,  1,      1,
+";
+    let mut engine = Engine::new()
+        .with_ledger(&path)
+        .unwrap()
+        .with_dispute_window("3".parse().unwrap());
+    let mut rejects = Vec::new();
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        |tx, e| {
+            rejects.push((tx.tx_id, e.reason()));
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(rejects, vec![(2, "unknown_tx")]);
+    assert_eq!(engine.client_states()[&1].held, dec!(0));
+    assert_eq!(engine.client_states()[&1].available, dec!(5));
+    let mut kept: Vec<_> = (engine.disputable_transactions.transactions().unwrap())
+        .into_iter()
+        .map(|(tx_id, _)| tx_id)
+        .collect();
+    kept.sort_unstable();
+    assert_eq!(kept, [1, 3, 4, 5]);
+    drop(engine);
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// A ledger keeps the state it was last committed with across engines, and forgets anything
+/// applied since.
+#[cfg(feature = "sqlite")]
+#[test]
+fn ledger_keeps_committed_state_across_runs() {
+    let path = std::env::temp_dir().join(format!(
+        "payment-engine-ledger-{}.sqlite",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let apply = |engine: &mut Engine, log: &str| {
+        let log = format!("type,client,tx,amount\n{}", log);
+        apply_transactions(
+            engine,
+            read_transactions(io::Cursor::new(log), InputFormat::Csv),
+            |_, _| Ok(()),
+        )
+        .unwrap();
+    };
+
+    let mut engine = Engine::new().with_ledger(&path).unwrap();
+    apply(&mut engine, "deposit,1,1,10.0\ndeposit,2,2,5.0\n");
+    engine.commit_ledger().unwrap();
+    // Never committed, so the next run doesn't see it.
+    apply(&mut engine, "withdrawal,2,3,5.0\n");
+    drop(engine);
+
+    let mut engine = Engine::new().with_ledger(&path).unwrap();
+    assert_eq!(engine.client_states()[&2].available, dec!(5));
+    let mut rejected = Vec::new();
+    let log = "type,client,tx,amount\ndispute,1,1,\ndeposit,1,1,1.0\n";
+    apply_transactions(
+        &mut engine,
+        read_transactions(io::Cursor::new(log), InputFormat::Csv),
+        |_, e| {
+            rejected.push(e.reason());
+            Ok(())
+        },
+    )
+    .unwrap();
+    assert_eq!(rejected, ["duplicate_tx_id"]);
+    assert_eq!(engine.client_states()[&1].held, dec!(10));
+    assert_eq!(engine.open_disputes().unwrap().len(), 1);
+    engine.commit_ledger().unwrap();
+    drop(engine);
+
+    let engine = Engine::new().with_ledger(&path).unwrap();
+    assert_eq!(
+        engine.client_states()[&1].dispute_state(1),
+        DisputeState::Disputed
+    );
+    assert_eq!(
+        engine.disputable_transactions.transactions().unwrap().len(),
+        2
+    );
+    drop(engine);
+    std::fs::remove_file(&path).unwrap();
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["in.csv", "--db", "ledger.sqlite"]).is_ok());
+    assert!(args(&["--db", "ledger.sqlite", "--threads", "2"]).is_err());
+    assert!(args(&["--db", "ledger.sqlite", "--snapshot-in", "state.json"]).is_err());
+}
+
+/// Every change an applied transaction makes to a client's funds is sent as an event, with how
+/// much the field changed, its new value, and the transaction which changed it.
+#[test]
+fn balance_changes_are_sent_as_events() {
+    use events::{BalanceField, EventSink, EventWriter};
+
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  10
+withdrawal, 1,      2,  20
+dispute,    1,      1,
+chargeback, 1,      1,
+";
+    let events = CollectEvents::default();
+    let mut engine = Engine::new().with_events(events.clone());
+    apply_transactions(
+        &mut engine,
+        fast_csv_transactions(csv_reader_from_str(csv.as_bytes())),
+        ignore_rejects,
+    )
+    .unwrap();
+    engine.finish_events().unwrap();
+
+    // The rejected withdrawal doesn't change anything, and totals only change when funds come or
+    // go, not when they're held.
+    let events = events.0.lock().unwrap();
+    let changes: Vec<_> = (events.iter())
+        .map(|event| {
+            (
+                event.tx,
+                event.cause,
+                event.field,
+                event.delta,
+                event.new_value,
+            )
+        })
+        .collect();
+    use BalanceField::*;
+    use TransactionType::*;
+    assert_eq!(
+        changes,
+        [
+            (1, Deposit, Available, dec!(10).into(), dec!(10).into()),
+            (1, Deposit, Total, dec!(10).into(), dec!(10).into()),
+            (1, Dispute, Available, dec!(-10).into(), Money::ZERO),
+            (1, Dispute, Held, dec!(10).into(), dec!(10).into()),
+            (1, Chargeback, Held, dec!(-10).into(), Money::ZERO),
+            (1, Chargeback, Total, dec!(-10).into(), Money::ZERO),
+        ]
+    );
+    assert_eq!(events[5].version, 3);
+
+    let mut written = Vec::new();
+    EventWriter::new(&mut written).event(&events[2]).unwrap();
+    assert_eq!(
+        String::from_utf8(written).unwrap(),
+        "{\"client\":1,\"tx\":1,\"field\":\"available\",\"delta\":\"-10.0000\",\"new_value\":\"0.0000\",\
+         \"cause\":\"dispute\",\"version\":2}\n"
+    );
+
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|arg| arg.to_string()));
+    assert!(args(&["in.csv", "--events", "-"])
+        .unwrap()
+        .events_replace_balances());
+    assert!(!args(&["in.csv", "--events", "-", "-o", "out.csv"])
+        .unwrap()
+        .events_replace_balances());
+    assert!(args(&["in.csv", "--events", "-", "--digest", "digest.json"]).is_err());
+    assert!(args(&["in.csv", "--events", "events.ndjson", "--threads", "2"]).is_err());
+}
+
+/// Transactions only change funds with balanced postings, so whatever a client gains comes from
+/// somewhere, and the events reconstructed from them add up to the final balances, fees and
+/// conversions included.
+#[test]
+fn postings_balance_and_add_up_to_balances() {
+    use currency::Currency;
+    use events::BalanceField;
+    use fees::FeeSchedule;
+    use posting::{Account, Posting};
+    use rates::RateTable;
+
+    let usd: Currency = "USD".parse().unwrap();
+    let eur: Currency = "EUR".parse().unwrap();
+    let postings = [
+        Posting::new(Account::External, Account::Available, usd, dec!(10).into()),
+        Posting::new(Account::Available, Account::Fees, usd, dec!(0.15).into()),
+        Posting::new(Account::Available, Account::Held, usd, dec!(4).into()),
+        Posting::new(Account::External, Account::Available, eur, dec!(2).into()),
+    ];
+    let accounts = [
+        Account::Available,
+        Account::Held,
+        Account::Fees,
+        Account::External,
+    ];
+    for currency in [usd, eur] {
+        let sum = (accounts.iter())
+            .map(|&account| posting::net(&postings, account, currency))
+            .fold(Money::ZERO, |sum, net| sum + net);
+        assert!(sum.is_zero());
+    }
+    assert_eq!(posting::net(&postings, Account::Available, usd), dec!(5.85));
+    assert_eq!(posting::currencies(&postings), [usd, eur]);
+
+    let csv = "type, client, tx, amount, currency, to_currency\n\
+               deposit, 1, 1, 100, USD,\n\
+               withdrawal, 1, 2, 30, USD,\n\
+               convert, 1, 3, 10, USD, EUR\n\
+               dispute, 1, 2,, USD\n\
+               chargeback, 1, 2,, USD\n\
+               deposit, 2, 4, 5,,\n\
+               dispute, 2, 4,,\n\
+               resolve, 2, 4,,\n";
+    let events = CollectEvents::default();
+    let engine = Engine::new()
+        .with_fees(FeeSchedule::from_toml("[deposit]\npercent = 1\n").unwrap())
+        .with_rates(RateTable::from_reader("from,to,rate\nusd,EUR,0.92\n".as_bytes()).unwrap())
+        .with_events(events.clone());
+    let states = process_csv(engine, csv_reader_from_str(csv.as_bytes()), ignore_rejects).unwrap();
+
+    let mut replayed: HashMap<(u16, Option<Currency>, &str), Money> = HashMap::new();
+    for event in events.0.lock().unwrap().iter() {
+        let field = match event.field {
+            BalanceField::Available => "available",
+            BalanceField::Held => "held",
+            BalanceField::Total => "total",
+            BalanceField::FeesCollected => "fees_collected",
+        };
+        let value = replayed
+            .entry((event.client, event.currency, field))
+            .or_insert(Money::ZERO);
+        *value += event.delta;
+        assert_eq!(*value, event.new_value);
+    }
+    for state in states.values() {
+        for currency in [Currency::IMPLICIT, usd, eur] {
+            let balance = state.balance(currency);
+            let currency = (!currency.is_implicit()).then_some(currency);
+            let replayed = |field| {
+                (replayed.get(&(state.client_id, currency, field)))
+                    .copied()
+                    .unwrap_or(Money::ZERO)
+            };
+            assert_eq!(replayed("available"), balance.available);
+            assert_eq!(replayed("held"), balance.held);
+            assert_eq!(replayed("total"), balance.total);
+            assert_eq!(
+                replayed("fees_collected"),
+                balance.fees_collected.unwrap_or(Money::ZERO)
+            );
+        }
+    }
+    assert_eq!(states[&1].balance(usd).available, dec!(89));
+    assert_eq!(states[&1].balance(eur).available, dec!(9.2));
+    assert_eq!(states[&2].available, dec!(4.95));
+}
+
+#[test]
+fn custom_types_are_applied_by_their_handlers() {
+    use handler::{HandlerContext, Outcome, TransactionHandler, TypeName};
+    use posting::{Account, Posting};
+
+    /// Charges a flat fee of 1, as long as the client has the funds.
+    struct FlatFee;
+
+    impl TransactionHandler for FlatFee {
+        fn apply(
+            &self,
+            tx: &Transaction,
+            context: &HandlerContext<'_>,
+        ) -> Result<Outcome, TransactionError> {
+            let fee = Money::from(dec!(1));
+            if context.balance(tx.currency).available < fee {
+                return Err(TransactionError::Rejected {
+                    client_id: tx.client_id,
+                    tx_id: tx.tx_id,
+                    reason: "fee_unpaid",
+                    message: "not enough funds for the fee".to_string(),
+                });
+            }
+            Ok(Outcome::new(vec![Posting::new(
+                Account::Available,
+                Account::External,
+                tx.currency,
+                fee,
+            )]))
+        }
+    }
+
+    /// Credits the transaction's amount.
+    struct Interest;
+
+    impl TransactionHandler for Interest {
+        fn apply(
+            &self,
+            tx: &Transaction,
+            _: &HandlerContext<'_>,
+        ) -> Result<Outcome, TransactionError> {
+            let amount = tx.validated_amount()?;
+            Ok(Outcome::new(vec![Posting::new(
+                Account::External,
+                Account::Available,
+                tx.currency,
+                amount,
+            )]))
+        }
+    }
+
+    let fee = TypeName::new("fee").unwrap();
+    let interest = TypeName::new("interest").unwrap();
+    assert_eq!(fee.as_str(), "fee");
+    assert_eq!("fee".parse(), Ok(fee));
+    assert!(TypeName::new("deposit").is_err());
+    assert!(TypeName::new("unknown").is_err());
+    assert!(TypeName::new("not a type").is_err());
+    assert!(TypeName::new(&"x".repeat(handler::MAX_TYPE_NAME_LEN + 1)).is_err());
+
+    let csv = "\
+type,       client, tx, amount
+deposit,    1,      1,  10
+fee,        1,      2,
+interest,   1,      3,  0.5
+fee,        2,      4,
+interest,   1,      5,
+adjustment, 1,      6,  3
+chargeback, 1,      1,
+";
+    let process = |engine: Engine| {
+        let engine = engine.with_unknown_type_policy(UnknownTypePolicy::Reject);
+        let mut rejects = Vec::new();
+        let records = process_csv(engine, csv_reader_from_str(csv.as_bytes()), |tx, e| {
+            rejects.push((tx.r#type, e.reason()));
+            Ok(())
+        })
+        .unwrap();
+        (records, rejects)
+    };
+
+    // Handlers reject transactions just like the built-in types do.
+    let (records, rejects) = process(
+        Engine::new()
+            .with_handler(fee, FlatFee)
+            .with_handler(interest, Interest),
+    );
+    let names = |rejects: &[(TransactionType, &'static str)]| {
+        (rejects.iter())
+            .map(|(r#type, reason)| (r#type.name().to_string(), *reason))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        names(&rejects),
+        [
+            ("fee".to_string(), "fee_unpaid"),
+            ("interest".to_string(), "missing_amount"),
+            ("adjustment".to_string(), "unknown_type"),
+            ("chargeback".to_string(), "not_disputed"),
+        ]
+    );
+    assert_eq!(records[&1].available, dec!(9.5));
+    assert_eq!(records[&2].available, dec!(0));
+
+    // Rows of a custom type are handled like unknown types by engines without its handler, so
+    // their clients aren't tracked.
+    let (records, rejects) = process(Engine::new());
+    assert_eq!(rejects.iter().filter(|r| r.1 == "unknown_type").count(), 5);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[&1].available, dec!(10));
+}
+
+// TODO:
+// 1. {Dispute, Resolve, Chargeback} reference the same transaction twice (i.e. ensure no
+//    double-counting of balance-altering transactions).
+
+/// Build a Parquet transaction log from columns, as a data lake export would have them.
+#[cfg(feature = "parquet")]
+fn parquet_log(columns: Vec<(&str, arrow_array::ArrayRef)>) -> Vec<u8> {
+    let batch = arrow_array::RecordBatch::try_from_iter(columns).unwrap();
+    let mut writer =
+        ::parquet::arrow::ArrowWriter::try_new(Vec::new(), batch.schema(), None).unwrap();
+    writer.write(&batch).unwrap();
+    writer.into_inner().unwrap()
+}
+
+/// Parquet logs are read by column name, whatever type each column has, with null amounts as
+/// missing amounts.
+#[cfg(feature = "parquet")]
+#[test]
+fn parquet_logs_are_read_by_column() {
+    use arrow_array::{Decimal128Array, StringArray, UInt16Array, UInt32Array};
+    use std::sync::Arc;
+
+    let amounts = Decimal128Array::from(vec![Some(15_000), Some(5_000), None])
+        .with_precision_and_scale(10, 4)
+        .unwrap();
+    let log = parquet_log(vec![
+        (
+            "type",
+            Arc::new(StringArray::from(vec!["deposit", "deposit", "dispute"])),
+        ),
+        ("client", Arc::new(UInt16Array::from(vec![1, 1, 1]))),
+        ("tx", Arc::new(UInt32Array::from(vec![1, 2, 2]))),
+        ("amount", Arc::new(amounts)),
+    ]);
+
+    let transactions: Vec<_> = read_transactions(std::io::Cursor::new(log), InputFormat::Parquet)
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(transactions.len(), 3);
+    assert_eq!(transactions[2].line, Some(3));
+    assert!(transactions[2].amount.is_none());
+
+    let states = process_transactions(Engine::new(), transactions.into_iter().map(Ok), |_, _| {
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(states[&1].available, dec!(1.5));
+    assert_eq!(states[&1].held, dec!(0.5));
+}
+
+/// A Parquet row which can't be parsed is reported with its row number, like a CSV line.
+#[cfg(feature = "parquet")]
+#[test]
+fn parquet_rows_which_cant_be_parsed_give_their_row() {
+    use arrow_array::StringArray;
+    use std::sync::Arc;
+
+    let log = parquet_log(vec![
+        (
+            "type",
+            Arc::new(StringArray::from(vec!["deposit", "deposit"])),
+        ),
+        ("client", Arc::new(StringArray::from(vec!["1", "one"]))),
+        ("tx", Arc::new(StringArray::from(vec!["1", "2"]))),
+        ("amount", Arc::new(StringArray::from(vec!["1.0", "2.0"]))),
+    ]);
+
+    let mut transactions = read_transactions(std::io::Cursor::new(log), InputFormat::Parquet);
+    assert!(transactions.next().unwrap().is_ok());
+    let error = transactions.next().unwrap().unwrap_err();
+    let error = error.downcast::<ParseError>().unwrap();
+    assert_eq!(error.line, Some(2));
+    assert_eq!(error.record, "deposit,one,2,2.0");
+}
+
+/// Input which isn't Parquet at all is an error, rather than an empty log.
+#[cfg(feature = "parquet")]
+#[test]
+fn input_which_isnt_parquet_is_an_error() {
+    let csv = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+    let mut transactions = read_transactions(csv.as_bytes(), InputFormat::Parquet);
+    assert!(transactions.next().unwrap().is_err());
+}
+
+/// Balances exported as Parquet have the CSV export's columns, with amounts as decimals.
+#[cfg(feature = "parquet")]
+#[test]
+fn balances_are_exported_as_parquet() {
+    use crate::parquet::ParquetBalanceWriter;
+    use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use arrow_array::{Array, BooleanArray, Decimal128Array, UInt16Array};
+
+    let csv = "\
+type,       client, tx, amount
+deposit,    2,      1,  2.5
+deposit,    1,      2,  1.25
+dispute,    1,      2,
+";
+    let states = process_csv(
+        Engine::new(),
+        csv_reader_from_str(csv.as_bytes()),
+        |_, _| Ok(()),
+    )
+    .unwrap();
+    let mut export = Vec::new();
+    write_balances(
+        &mut ParquetBalanceWriter::new(&mut export),
+        &states,
+        output::SortBy::Client,
+    )
+    .unwrap();
+
+    let batches: Vec<_> = ParquetRecordBatchReaderBuilder::try_new(bytes::Bytes::from(export))
+        .unwrap()
+        .build()
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    let batch = &batches[0];
+    let columns: Vec<_> = (batch.schema().fields().iter())
+        .map(|field| field.name().clone())
+        .collect();
+    assert_eq!(
+        columns,
+        ["client", "available", "held", "total", "locked", "version"]
+    );
+    let column = |name: &str| batch.column_by_name(name).unwrap().clone();
+    let clients = column("client");
+    let clients = clients.as_any().downcast_ref::<UInt16Array>().unwrap();
+    assert_eq!(clients.values(), &[1, 2]);
+    let held = column("held");
+    let held = held.as_any().downcast_ref::<Decimal128Array>().unwrap();
+    assert_eq!(held.value_as_string(0), "1.2500");
+    assert_eq!(held.value_as_string(1), "0.0000");
+    let locked = column("locked");
+    assert!(!locked
+        .as_any()
+        .downcast_ref::<BooleanArray>()
+        .unwrap()
+        .value(0));
+}
+
+/// An export without any clients is still a Parquet file, with the usual columns.
+#[cfg(feature = "parquet")]
+#[test]
+fn empty_balances_are_exported_as_parquet() {
+    use crate::parquet::ParquetBalanceWriter;
+    use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let mut export = Vec::new();
+    write_balances(
+        &mut ParquetBalanceWriter::new(&mut export),
+        &HashMap::new(),
+        output::SortBy::Client,
+    )
+    .unwrap();
+
+    let reader = ParquetRecordBatchReaderBuilder::try_new(bytes::Bytes::from(export)).unwrap();
+    assert_eq!(reader.metadata().file_metadata().num_rows(), 0);
+    assert_eq!(reader.schema().fields().len(), 6);
+}
+
+/// Parquet logs and exports need the `parquet` feature.
+#[cfg(not(feature = "parquet"))]
+#[test]
+fn parquet_requires_its_feature() {
+    let mut transactions = read_transactions(&b""[..], InputFormat::Parquet);
+    let error = transactions.next().unwrap().unwrap_err();
+    assert!(error.to_string().contains("feature"));
+    assert!(Options::from_args(["--output-format".to_string(), "parquet".to_string()]).is_err());
+}
+
+/// Parse `consume` options, for applying messages without a broker.
+#[cfg(feature = "kafka")]
+fn consume_options(args: &[&str]) -> cli::ConsumeOptions {
+    let args = [
+        "consume",
+        "--brokers",
+        "localhost:9092",
+        "--topic",
+        "transactions",
+    ]
+    .iter()
+    .chain(args)
+    .map(|s| s.to_string());
+    match cli::Command::from_args(args).unwrap() {
+        cli::Command::Consume(options) => options,
+        command => panic!("expected consume, not {:?}", command),
+    }
+}
+
+/// Every line of a message is applied in order, skipping blank lines and a CSV header, and
+/// rejections don't stop the consumer.
+#[cfg(feature = "kafka")]
+#[test]
+fn kafka_messages_are_applied_a_line_at_a_time() {
+    let options = consume_options(&["--snapshot", "unused.json"]);
+    let mut engine = Engine::new();
+
+    let message = "type,client,tx,amount\ndeposit,1,1,2.0\n\nwithdrawal,1,2,5.0\n";
+    assert_eq!(
+        kafka::apply_message(&mut engine, message.as_bytes(), &options).unwrap(),
+        2
+    );
+    assert_eq!(engine.client_states()[&1].available, dec!(2));
+
+    let options = consume_options(&["--snapshot", "unused.json", "--input-format", "ndjson"]);
+    let message = r#"{"type":"withdrawal","client":1,"tx":3,"amount":"0.5"}"#;
+    kafka::apply_message(&mut engine, message.as_bytes(), &options).unwrap();
+    assert_eq!(engine.client_states()[&1].available, dec!(1.5));
+}
+
+/// A message which can't be applied is an error (so its offset isn't stored), unless bad rows are
+/// skipped.
+#[cfg(feature = "kafka")]
+#[test]
+fn kafka_messages_which_cant_be_applied_are_errors() {
+    let mut engine = Engine::new();
+    let message = b"deposit,1,x,2.0\ndeposit,1,2,1.0";
+
+    let options = consume_options(&["--snapshot", "unused.json"]);
+    assert!(kafka::apply_message(&mut engine, message, &options).is_err());
+    assert!(kafka::apply_message(&mut engine, b"\xff", &options).is_err());
+
+    let options = consume_options(&["--snapshot", "unused.json", "--skip-bad-rows"]);
+    assert_eq!(
+        kafka::apply_message(&mut engine, message, &options).unwrap(),
+        1
+    );
+
+    // Rejections stop a strict consumer.
+    let options = consume_options(&["--snapshot", "unused.json", "--strict"]);
+    let mut engine = Engine::new().with_strict(true);
+    assert!(kafka::apply_message(&mut engine, b"withdrawal,1,1,1.0", &options).is_err());
+}
+
+/// Checkpoints write a snapshot the consumer can restart from, and the balances with `-o`.
+#[cfg(feature = "kafka")]
+#[test]
+fn kafka_checkpoints_write_a_snapshot_and_balances() {
+    let dir = std::env::temp_dir().join(format!("payment-engine-kafka-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let snapshot_path = dir.join("state.json");
+    let balances_path = dir.join("balances.csv");
+    let options = consume_options(&[
+        "--snapshot",
+        snapshot_path.to_str().unwrap(),
+        "-o",
+        balances_path.to_str().unwrap(),
+    ]);
+
+    let mut engine = Engine::new();
+    kafka::apply_message(&mut engine, b"deposit,1,1,2.0\ndeposit,2,2,1.0", &options).unwrap();
+    kafka::checkpoint(&engine, &options).unwrap();
+
+    let restored = snapshot::read_file(Engine::new(), &snapshot_path).unwrap();
+    assert_eq!(restored.client_states()[&2].total, dec!(1));
+    let balances = std::fs::read_to_string(&balances_path).unwrap();
+    assert_eq!(balances.lines().count(), 3);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// `consume` needs brokers, a topic, and a snapshot, and can't read Parquet.
+#[test]
+fn consume_options_are_parsed() {
+    let command = |args: &[&str]| cli::Command::from_args(args.iter().map(|s| s.to_string()));
+    let args = [
+        "consume",
+        "--brokers",
+        "localhost:9092",
+        "--topic",
+        "transactions",
+        "--snapshot",
+        "state.json",
+    ];
+
+    if cfg!(feature = "kafka") {
+        assert!(command(&args).is_ok());
+        assert!(command(&args[..5]).is_err());
+        assert!(command(&[&args[..], &["--input-format", "parquet"]].concat()).is_err());
+        assert!(command(&[&args[..], &["--snapshot-interval", "0"]].concat()).is_err());
+        assert!(command(&[&args[..], &["--threads", "2"]].concat()).is_err());
+    } else {
+        assert!(command(&args).is_err());
+    }
+}
+
+/// The committed C header is the one cbindgen generates from the `ffi` module.
+#[cfg(feature = "ffi")]
+#[test]
+fn c_header_is_current() {
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/payment_engine.h"));
+    let committed = include_str!("../include/payment_engine.h");
+    assert!(
+        generated == committed,
+        "include/payment_engine.h is out of date, copy {}/payment_engine.h over it",
+        env!("OUT_DIR")
+    );
+}